@@ -0,0 +1,87 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{Display,Formatter};
+use std::time::{Duration,Instant};
+use serde::Deserialize;
+use log::info;
+use crate::clock;
+
+///
+/// With several control inputs enabled (midi, network, console), arbitration keeps
+/// them from fighting: once a source fires a cue, sources of lower priority are
+/// locked out for a hold time, so a stray tablet can't override the keyboard operator
+///
+
+const DEFAULT_HOLD_MILLIS: u64 = 5000;
+
+/// where a human-initiated action came from
+#[derive(Debug,Deserialize,Clone,Copy,PartialEq,Eq,Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlSource {
+    Midi,
+    Osc,
+    Http,
+    Console,
+    Socket,
+    WebSocket,
+    Mqtt
+}
+
+impl Display for ControlSource {
+    fn fmt(self: &Self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ControlSource::Midi => write!(f, "midi"),
+            ControlSource::Osc => write!(f, "osc"),
+            ControlSource::Http => write!(f, "http"),
+            ControlSource::Console => write!(f, "console"),
+            ControlSource::Socket => write!(f, "socket"),
+            ControlSource::WebSocket => write!(f, "websocket"),
+            ControlSource::Mqtt => write!(f, "mqtt")
+        }
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct ArbitrationConfig {
+    /// priority of each source, higher wins. unlisted sources have priority 0
+    pub priorities: HashMap<ControlSource,u8>,
+
+    /// how long after a source fires a cue that lower priority sources
+    /// are locked out, will use a default value if not supplied
+    pub hold_millis: Option<u64>
+}
+
+pub struct Arbiter {
+    priorities: HashMap<ControlSource,u8>,
+    hold: Duration,
+    /// the source that last fired a cue, and when
+    last: Cell<Option<(ControlSource, Instant)>>
+}
+
+impl Arbiter {
+
+    pub fn new(config: &Option<ArbitrationConfig>) -> Arbiter {
+        Arbiter {
+            priorities: config.as_ref().map_or_else(HashMap::new, |c| c.priorities.clone()),
+            hold: Duration::from_millis(config.as_ref().and_then(|c| c.hold_millis).unwrap_or(DEFAULT_HOLD_MILLIS)),
+            last: Cell::new(None)
+        }
+    }
+
+    fn priority(self: &Self, source: ControlSource) -> u8 {
+        *self.priorities.get(&source).unwrap_or(&0)
+    }
+
+    /// may the source fire a cue now? if so, it becomes the source holding control
+    pub fn permit(self: &Self, source: ControlSource) -> bool {
+        let now = clock::now();
+        if let Some((holder, at)) = self.last.get() {
+            if holder != source && self.priority(holder) > self.priority(source) && now - at < self.hold {
+                info!("{} locked out, {} has control", source, holder);
+                return false
+            }
+        }
+        self.last.set(Some((source, now)));
+        true
+    }
+}
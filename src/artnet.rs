@@ -0,0 +1,304 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::UdpSocket;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use crossbeam_channel::{Sender, Receiver, bounded, RecvTimeoutError};
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::packet::{Command, DecodedPacket, HeaderMode, PacketPayload, ShowPacket, GROUP_ID_RANGE};
+
+///
+/// The Art-Net mirror shows a visualizer (or a console) what every receiver is being
+/// told to do, so a show can be programmed with nothing on the field to watch. It hears
+/// every packet the radio sends, as a local receiver does, follows the group each
+/// receiver is assigned, and keeps eight DMX channels per receiver up to date with the
+/// last effect it was sent: effect id, hue, saturation, value, attack, sustain,
+/// release, then the receiver's master brightness. Receiver n's channels start at
+/// n * 8 + 1, running on into the universes after the first
+///
+
+const DEFAULT_ADDRESS: &str = "255.255.255.255:6454";
+const DEFAULT_BIND: &str = "0.0.0.0:0";
+/// the channels of each receiver
+const FOOTPRINT: usize = 8;
+const UNIVERSE_SIZE: usize = 512;
+/// universes are sent again this often when nothing changes, as Art-Net nodes expect
+const REFRESH_PERIOD: Duration = Duration::from_secs(1);
+/// packets waiting for the mirror thread, beyond which they're dropped
+const QUEUE_DEPTH: usize = 64;
+
+const ART_NET_ID: &[u8; 8] = b"Art-Net\0";
+/// the ArtDmx opcode, little endian
+const OP_DMX: [u8; 2] = [0x00, 0x50];
+const PROTOCOL_VERSION: [u8; 2] = [0x00, 0x0E];
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct ArtNetConfig {
+    /// where to send the DMX, eg "192.168.1.20:6454" for a visualizer on another machine.
+    /// defaults to broadcasting on port 6454
+    pub address: Option<String>,
+    /// the local address to send from, defaults to any free port
+    pub bind: Option<String>,
+    /// the Art-Net port address (net, sub-net and universe) of the first universe, defaults to 0
+    pub universe: Option<u16>
+}
+
+/// the radio's handle on the mirror thread
+pub struct ArtNetMirror {
+    tx: Sender<Vec<u8>>
+}
+
+impl ArtNetMirror {
+
+    pub fn start(config: &ArtNetConfig, header_mode: HeaderMode) -> Result<ArtNetMirror> {
+        let address = config.address.as_deref().unwrap_or(DEFAULT_ADDRESS);
+        let socket = UdpSocket::bind(config.bind.as_deref().unwrap_or(DEFAULT_BIND)).context("Could not open a socket for Art-Net")?;
+        socket.set_broadcast(true)?;
+        socket.connect(address).with_context(|| format!("Could not send Art-Net to {}", address))?;
+        info!("Mirroring receivers to Art-Net at {}", address);
+
+        let (tx, rx) = bounded(QUEUE_DEPTH);
+        let mut mirror = Mirror {
+            socket,
+            first_universe: config.universe.unwrap_or(0),
+            sequence: 0,
+            groups: HashMap::new(),
+            receivers: BTreeMap::new()
+        };
+        thread::spawn(move || mirror.run(rx, header_mode));
+        Ok(ArtNetMirror { tx })
+    }
+
+    /// offer a packet that was just sent over the air
+    pub fn deliver(self: &Self, marshalled: &[u8]) {
+        if self.tx.try_send(marshalled.to_vec()).is_err() {
+            debug!("Art-Net mirror queue full, dropping packet");
+        }
+    }
+}
+
+/// what a receiver was last told
+#[derive(Debug,Clone,Copy)]
+struct Mirrored {
+    packet: ShowPacket,
+    brightness: u8
+}
+
+impl Mirrored {
+    const DARK: Mirrored = Mirrored { packet: ShowPacket::OFF_PACKET, brightness: 255 };
+
+    fn channels(self: &Self) -> [u8; FOOTPRINT] {
+        let p = &self.packet;
+        [p.effect as u8, p.color.h, p.color.s, p.color.v, p.attack, p.sustain, p.release, self.brightness]
+    }
+}
+
+struct Mirror {
+    socket: UdpSocket,
+    first_universe: u16,
+    /// the ArtDmx sequence number, 1-255 (0 would turn sequencing off)
+    sequence: u8,
+    /// each receiver's group
+    groups: HashMap<u8,u8>,
+    /// the receivers heard of, and what each was last told
+    receivers: BTreeMap<u8,Mirrored>
+}
+
+impl Mirror {
+
+    fn run(self: &mut Self, rx: Receiver<Vec<u8>>, header_mode: HeaderMode) {
+        let mut refresh_at = Instant::now();
+        loop {
+            let changed = match rx.recv_timeout(refresh_at.saturating_duration_since(Instant::now())) {
+                Ok(buf) => match DecodedPacket::unmarshal(&buf, header_mode) {
+                    Ok(packet) => self.receive(packet),
+                    Err(e) => {
+                        warn!("Art-Net mirror could not decode packet: {}", e);
+                        BTreeSet::new()
+                    }
+                },
+                Err(RecvTimeoutError::Timeout) => self.universes(),
+                Err(RecvTimeoutError::Disconnected) => return
+            };
+            // with no receivers known yet, there's nothing to refresh
+            if changed.is_empty() && refresh_at > Instant::now() {
+                continue
+            }
+            for universe in changed {
+                if let Err(e) = self.send_universe(universe) {
+                    error!("Could not send Art-Net, giving up on it. Error: {}", e);
+                    return
+                }
+            }
+            refresh_at = Instant::now() + REFRESH_PERIOD;
+        }
+    }
+
+    /// follow a packet, returning the universes it changed
+    fn receive(self: &mut Self, packet: DecodedPacket) -> BTreeSet<usize> {
+        let addressed = if packet.to == 0xFF { packet.recipients.clone() } else { vec![packet.to] };
+        // receivers are learned as they're configured or sent to directly
+        for id in addressed.iter().filter(|id| !GROUP_ID_RANGE.contains(*id)) {
+            self.receivers.entry(*id).or_insert(Mirrored::DARK);
+        }
+        let targets: Vec<u8> = self.receivers.keys().copied()
+            .filter(|id| addressed.is_empty() || addressed.iter().any(|a| a == id || self.groups.get(id) == Some(a)))
+            .collect();
+        for id in targets.iter() {
+            let mirrored = self.receivers.get_mut(id).unwrap();
+            match packet.payload {
+                PacketPayload::Show(show) => mirrored.packet = show,
+                PacketPayload::Control(Command::SetGroup { group_id }) => {
+                    self.groups.insert(*id, group_id);
+                },
+                PacketPayload::Control(Command::NewBrightness { brightness }) => mirrored.brightness = brightness,
+                PacketPayload::Control(Command::Reset) => *mirrored = Mirrored::DARK,
+                PacketPayload::Control(_) => {}
+            }
+        }
+        targets.iter().map(|id| *id as usize * FOOTPRINT / UNIVERSE_SIZE).collect()
+    }
+
+    /// every universe with a receiver in it
+    fn universes(self: &Self) -> BTreeSet<usize> {
+        self.receivers.keys().map(|id| *id as usize * FOOTPRINT / UNIVERSE_SIZE).collect()
+    }
+
+    /// send the levels of one of the mirror's universes, counting from the first
+    fn send_universe(self: &mut Self, universe: usize) -> std::io::Result<()> {
+        let mut levels = [0u8; UNIVERSE_SIZE];
+        for (id, mirrored) in self.receivers.iter() {
+            let start = *id as usize * FOOTPRINT;
+            if start / UNIVERSE_SIZE == universe {
+                levels[start % UNIVERSE_SIZE..][..FOOTPRINT].copy_from_slice(&mirrored.channels());
+            }
+        }
+        self.sequence = self.sequence % 255 + 1;
+        let [net, sub_uni] = (self.first_universe + universe as u16).to_be_bytes();
+        let mut packet = ART_NET_ID.to_vec();
+        packet.extend(OP_DMX);
+        packet.extend(PROTOCOL_VERSION);
+        packet.extend([self.sequence, 0, sub_uni, net & 0x7F]);
+        packet.extend((UNIVERSE_SIZE as u16).to_be_bytes());
+        packet.extend(levels);
+        self.socket.send(&packet)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{EffectId, Packet};
+    use crate::show::Color;
+
+    /// a mirror sending to a socket the test reads from
+    fn mirror(first_universe: u16) -> (Mirror, UdpSocket) {
+        let visualizer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        visualizer.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(visualizer.local_addr().unwrap()).unwrap();
+        let mirror = Mirror { socket, first_universe, sequence: 0, groups: HashMap::new(), receivers: BTreeMap::new() };
+        (mirror, visualizer)
+    }
+
+    fn send(mirror: &mut Mirror, to: &[u8], payload: PacketPayload) -> BTreeSet<usize> {
+        let buf = Packet { recipients: &to.to_vec(), payload }.marshal(HeaderMode::default(), 1, 0, 0);
+        mirror.receive(DecodedPacket::unmarshal(&buf, HeaderMode::default()).unwrap())
+    }
+
+    fn show(effect: EffectId, h: u8) -> PacketPayload {
+        PacketPayload::Show(ShowPacket { effect, color: Color { h, s: 255, v: 255 }, attack: 1, sustain: 2, release: 3,
+            param1: 0, param2: 0, tempo: 120 })
+    }
+
+    fn hue(mirror: &Mirror, id: u8) -> u8 {
+        mirror.receivers[&id].packet.color.h
+    }
+
+    #[test]
+    fn receivers_follow_the_group_they_are_set_to() {
+        let (mut mirror, _) = mirror(0);
+        let group = GROUP_ID_RANGE.start;
+        send(&mut mirror, &[80, 81], PacketPayload::Control(Command::SetGroup { group_id: group }));
+        send(&mut mirror, &[82], show(EffectId::Off, 0));
+        send(&mut mirror, &[group], show(EffectId::Pop, 10));
+        assert_eq!((hue(&mirror, 80), hue(&mirror, 81), hue(&mirror, 82)), (10, 10, 0));
+
+        // moved to another group, 81 no longer hears the first
+        send(&mut mirror, &[81], PacketPayload::Control(Command::SetGroup { group_id: group + 1 }));
+        send(&mut mirror, &[group], show(EffectId::Pop, 20));
+        send(&mut mirror, &[group + 1], show(EffectId::Pop, 30));
+        assert_eq!((hue(&mirror, 80), hue(&mirror, 81), hue(&mirror, 82)), (20, 30, 0));
+        // a group is never taken for a receiver
+        assert!(!mirror.receivers.contains_key(&group));
+    }
+
+    #[test]
+    fn broadcasts_reach_every_receiver_and_recipient_lists_only_theirs() {
+        let (mut mirror, _) = mirror(0);
+        for id in [80, 81, 82] {
+            send(&mut mirror, &[id], show(EffectId::Off, 0));
+        }
+        send(&mut mirror, &[], show(EffectId::Pop, 10));
+        assert_eq!((hue(&mirror, 80), hue(&mirror, 81), hue(&mirror, 82)), (10, 10, 10));
+        send(&mut mirror, &[80, 82], show(EffectId::Pop, 20));
+        assert_eq!((hue(&mirror, 80), hue(&mirror, 81), hue(&mirror, 82)), (20, 10, 20));
+        // a receiver first heard of in a list is learned from it
+        send(&mut mirror, &[81, 83], show(EffectId::Pop, 30));
+        assert_eq!((hue(&mirror, 81), hue(&mirror, 83)), (30, 30));
+        // a broadcast teaches nothing
+        send(&mut mirror, &[], PacketPayload::Control(Command::Reset));
+        assert_eq!(mirror.receivers.keys().copied().collect::<Vec<_>>(), vec![80, 81, 82, 83]);
+        assert_eq!(hue(&mirror, 83), 0);
+    }
+
+    #[test]
+    fn receivers_land_eight_channels_apiece_across_universes() {
+        let (mut mirror, visualizer) = mirror(0);
+        // 127 fills the last eight channels of the second universe, 128 starts the third
+        assert_eq!(send(&mut mirror, &[127], show(EffectId::Pop, 10)), BTreeSet::from([1]));
+        assert_eq!(send(&mut mirror, &[128], show(EffectId::Pop, 20)), BTreeSet::from([2]));
+        assert_eq!(send(&mut mirror, &[], show(EffectId::Pop, 30)), BTreeSet::from([1, 2]));
+        assert_eq!(send(&mut mirror, &[100], PacketPayload::Control(Command::NewBrightness { brightness: 128 })), BTreeSet::from([1]));
+        assert_eq!(mirror.universes(), BTreeSet::from([1, 2]));
+
+        let mut buf = [0u8; 1024];
+        mirror.send_universe(1).unwrap();
+        let len = visualizer.recv(&mut buf).unwrap();
+        let levels = &buf[18..len];
+        assert_eq!(levels.len(), UNIVERSE_SIZE);
+        assert_eq!(&levels[504..], &[EffectId::Pop as u8, 30, 255, 255, 1, 2, 3, 255]);
+        // 100 starts at channel 800, 288 into the second universe, dark but dimmed
+        assert_eq!(&levels[288..296], &[EffectId::Off as u8, 0, 0, 0, 0, 0, 0, 128]);
+        assert!(levels[..288].iter().chain(&levels[296..504]).all(|l| *l == 0));
+
+        mirror.send_universe(2).unwrap();
+        let len = visualizer.recv(&mut buf).unwrap();
+        let levels = &buf[18..len];
+        assert_eq!(&levels[..8], &[EffectId::Pop as u8, 30, 255, 255, 1, 2, 3, 255]);
+        assert!(levels[8..].iter().all(|l| *l == 0));
+    }
+
+    #[test]
+    fn art_dmx_headers_split_the_port_address_into_net_and_sub_uni() {
+        // net 0x12, sub-net 3, universe 15, so the second universe rolls over into sub-net 4
+        let (mut mirror, visualizer) = mirror(0x123F);
+        send(&mut mirror, &[80], show(EffectId::Pop, 10));
+        let mut buf = [0u8; 1024];
+        for (universe, sequence, sub_uni) in [(0, 1, 0x3F), (1, 2, 0x40), (0, 3, 0x3F)] {
+            mirror.send_universe(universe).unwrap();
+            let len = visualizer.recv(&mut buf).unwrap();
+            assert_eq!(len, 18 + UNIVERSE_SIZE);
+            assert_eq!(&buf[..8], b"Art-Net\0");
+            assert_eq!(&buf[8..18], &[0x00, 0x50, 0x00, 0x0E, sequence, 0, sub_uni, 0x12, 0x02, 0x00]);
+        }
+        // the sequence runs 1-255, skipping the 0 that would turn it off
+        mirror.sequence = 255;
+        mirror.send_universe(0).unwrap();
+        visualizer.recv(&mut buf).unwrap();
+        assert_eq!(buf[12], 1);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use anyhow::{Result, anyhow};
+use log::info;
+
+use crate::clip::{ClipAction, ClipState};
+use crate::clock;
+use crate::config::ConfigFile;
+use crate::matrix;
+use crate::radio::Radio;
+use crate::show::{ClipStep, MidiMappingType, ShowAssertion, ShowDefinition, parse_note, substitute_targets};
+use crate::showstate::{build_target_lookup, resolve_targets, ShowState};
+
+///
+/// Checks of a show beyond what it takes to load it: the assertions the show makes
+/// about itself, and a dry run of every clip against a radio that sends nothing,
+/// so broken steps turn up before a performance rather than during one
+///
+
+const DEFAULT_TEMPO: f32 = 120.0;
+
+/// check the show's assertions, and dry run its clips if asked to, reporting every problem found
+pub fn check_show(show: &ShowDefinition, config: &ConfigFile, simulate_clips: bool) -> Result<()> {
+    let problems = find_problems(show, config, simulate_clips)?;
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Show failed its checks:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// every problem the show's checks turn up, one line each
+pub fn find_problems(show: &ShowDefinition, config: &ConfigFile, simulate_clips: bool) -> Result<Vec<String>> {
+    let mut problems: Vec<String> = vec![];
+    for assertion in show.assertions.iter().flatten() {
+        check_assertion(show, assertion, &mut problems)?;
+    }
+    if simulate_clips {
+        simulate(show, config, &mut problems)?;
+    }
+    Ok(problems)
+}
+
+fn check_assertion(show: &ShowDefinition, assertion: &ShowAssertion, problems: &mut Vec<String>) -> Result<()> {
+    match assertion {
+        ShowAssertion::EveryReceiverTargeted => {
+            let targeted = targeted_receivers(show)?;
+            for r in show.receivers.iter().filter(|r| !targeted.contains(&r.id)) {
+                problems.push(format!("receiver: {} is not targeted by any cue", r.name.as_ref().map_or(r.id.to_string(), |n| n.clone())));
+            }
+        },
+        ShowAssertion::ClipMaxDuration { clip, max_seconds } => {
+            let steps = show.clips.get(clip).ok_or_else(|| anyhow!("Assertion refers to unknown clip: {}", clip))?;
+            let duration = clip_duration(steps);
+            if duration.as_secs_f32() > *max_seconds {
+                problems.push(format!("clip: {} takes {:.1}s, more than {}s", clip, duration.as_secs_f32(), max_seconds));
+            }
+        },
+        ShowAssertion::UniqueTriggers => {
+            let mut seen: HashMap<(Option<&String>, String), &str> = HashMap::new();
+            for m in show.mappings.iter() {
+                let trigger = match &m.midi {
+                    Some(MidiMappingType::Note { channel, note }) =>
+                        format!("channel {} note {}", channel, parse_note(note).map_or(note.clone(), |n| n.to_string())),
+                    Some(MidiMappingType::Controller { channel, cc }) => format!("channel {} cc {}", channel, cc),
+                    Some(MidiMappingType::Timecode { hh, mm, ss, ff }) => format!("timecode {:02}:{:02}:{:02}:{:02}", hh, mm, ss, ff),
+                    Some(MidiMappingType::NoteRange { channel, low, high }) => format!("channel {} notes {} to {}", channel, low, high),
+                    None => continue
+                };
+                if let Some(other) = seen.insert((m.song.as_ref(), trigger.clone()), &m.cue) {
+                    problems.push(format!("cues: {} and {} are both triggered by {}", other, m.cue, trigger));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// every receiver some cue (or clip step) reaches, directly, through a group, or by targeting everybody
+fn targeted_receivers(show: &ShowDefinition) -> Result<HashSet<u8>> {
+    let (target_lookup, group_members) = build_target_lookup(show);
+    let matrix_targets = matrix::matrix_targets(show)?;
+    let all_values = |name: &str| show.variables.as_ref()
+        .and_then(|v| v.get(name)).map_or(vec![], |v| v.values.clone());
+    let clip_mappings = show.clips.values().flatten().filter_map(|step|
+        if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+    let mut targeted: HashSet<u8> = HashSet::new();
+    for m in show.mappings.iter().chain(clip_mappings) {
+        let targets = resolve_targets(&substitute_targets(&m.targets, all_values), &target_lookup, &matrix_targets)?;
+        if targets.is_empty() {
+            return Ok(show.receivers.iter().map(|r| r.id).collect())
+        }
+        for t in targets {
+            targeted.extend(group_members.get(&t).map_or(vec![t], |members| members.clone()));
+        }
+    }
+    Ok(targeted)
+}
+
+/// how long one pass through a clip takes, up to its end or its first loop back that
+/// repeats forever, going round its counted loops: the clip is played on a virtual
+/// clock from the default tempo, asking nothing of the show
+pub fn clip_duration(steps: &Vec<ClipStep>) -> Duration {
+    clock::run_virtual(|start| {
+        let mut clip = ClipState::new(steps);
+        clip.start(None, DEFAULT_TEMPO).expect("clips always start");
+        while let Some(at) = clip.sequence(clock::now(), None, |action| !matches!(action, ClipAction::Loop(_))) {
+            clock::advance_to(at);
+        }
+        clock::now() - start
+    })
+}
+
+/// step through every clip once, without waiting, turning its cues on and off against a
+/// radio that records rather than sends, so any step that can't be performed is reported
+fn simulate(show: &ShowDefinition, config: &ConfigFile, problems: &mut Vec<String>) -> Result<()> {
+    let radio = Radio::mock(config);
+    let mut state = ShowState::new(show, &radio, config)?;
+    state.disable_hooks();
+    let mut mutable_state = state.create_mutable_state()?;
+    let mut names: Vec<&String> = show.clips.keys().collect();
+    names.sort();
+    for name in names {
+        for (index, step) in show.clips[name].iter().enumerate() {
+            let result = match step {
+                ClipStep::MappingOn(m) => state.activate(m.get_id(), None, &mut mutable_state),
+                ClipStep::MappingOff(on) => match show.clips[name].get(*on) {
+                    Some(ClipStep::MappingOn(m)) => state.deactivate(m.get_id(), &mut mutable_state),
+                    _ => Err(anyhow!("turns off step: {} which is not a mapping on step", on))
+                },
+                _ => Ok(())
+            };
+            if let Err(e) = result {
+                problems.push(format!("clip: {} step: {} failed in a dry run: {}", name, index, e));
+            }
+        }
+    }
+    info!("Dry ran {} clips, {} packets would have been sent", show.clips.len(), radio.take_sent().len());
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ClipStep::*;
+
+    fn seconds(steps: Vec<ClipStep>) -> f64 {
+        (clip_duration(&steps).as_secs_f64() * 1000.0).round() / 1000.0
+    }
+
+    #[test]
+    fn clips_last_as_long_as_their_waits_at_the_tempo_they_play_at() {
+        assert_eq!(seconds(vec![WaitBeats(2.0), WaitMillis(500), End]), 1.5);
+        assert_eq!(seconds(vec![WaitBeats(2.0), SetTempo(60.0), WaitBeats(2.0), End]), 3.0);
+        // half a beat in at 120, then the three beats to the second bar at 60
+        assert_eq!(seconds(vec![WaitBeats(1.0), SetTempo(60.0), WaitUntil { bar: 2, beat: 1.0 }, End]), 3.5);
+        assert_eq!(seconds(vec![SetMeter(3), WaitUntil { bar: 2, beat: 1.0 }, WaitUntil { bar: 1, beat: 2.0 }, End]), 1.5);
+        // with no end step, they end after the last
+        assert_eq!(seconds(vec![WaitMillis(100)]), 0.1);
+    }
+
+    #[test]
+    fn counted_loops_go_round_and_forever_loops_end_a_pass() {
+        assert_eq!(seconds(vec![WaitBeats(1.0), LoopN { index: 0, times: 3 }, WaitMillis(100), End]), 1.6);
+        // broken out of on the last time round, after the first half of it
+        assert_eq!(seconds(vec![WaitBeats(1.0), BreakLoop, WaitBeats(1.0), LoopN { index: 0, times: 2 }, End]), 1.5);
+        // positions count from the start of each time round
+        assert_eq!(seconds(vec![WaitUntil { bar: 1, beat: 3.0 }, LoopN { index: 0, times: 2 }, End]), 2.0);
+        assert_eq!(seconds(vec![WaitMillis(100), Loop(0), WaitMillis(100)]), 0.1);
+        assert_eq!(seconds(vec![Loop(0)]), 0.0);
+        assert_eq!(seconds(vec![WaitMillis(100), Stop, WaitMillis(100)]), 0.1);
+    }
+}
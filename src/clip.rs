@@ -1,6 +1,11 @@
-use std::{cell::RefCell, collections::{HashMap, HashSet}, time::{Duration, Instant}};
-use log::{info,error};
-use crate::{show::{ClipStep, Color}, showstate::{EffectOverrides, MutableShowState, ShowState}};
+use std::{cell::RefCell, collections::HashMap, time::{Duration, Instant}};
+use log::{debug,info,error};
+use anyhow::anyhow;
+use crate::{show::{ClipStep, Color, LightMapping}, showstate::{EffectOverrides, MutableShowState, ShowState}};
+use crate::clock;
+use crate::midiclock::SongPosition;
+
+const DEFAULT_BEATS_PER_BAR: u8 = 4;
 
 pub struct ClipEngine<'a> {
     clip_state: HashMap<String, RefCell<ClipState<'a>>>
@@ -17,12 +22,26 @@ impl <'a> ClipEngine<'a> {
 
     pub fn start_clip(self: &Self, clip_name: &str, override_color: Option<Color>, tempo: f32) -> anyhow::Result<()> {
         info!("Starting clip: {}", clip_name);
-        self.clip_state.get(clip_name).unwrap().borrow_mut().start(override_color, tempo)
+        self.clip_state.get(clip_name).ok_or_else(|| anyhow!("Cannot start unknown clip: {}", clip_name))?
+            .borrow_mut().start(override_color, tempo)
     }
 
     pub fn stop_clip(self: &Self, clip_name: &str, show_state: &ShowState, mut_state: &mut MutableShowState) -> anyhow::Result<()> {
         info!("Stopping clip: {}", clip_name);
-        self.clip_state.get(clip_name).unwrap().borrow_mut().stop(show_state, mut_state)
+        self.clip_state.get(clip_name).ok_or_else(|| anyhow!("Cannot stop unknown clip: {}", clip_name))?
+            .borrow_mut().stop(show_state, mut_state)
+    }
+
+    /// stop every playing clip
+    pub fn stop_all(self: &Self, show_state: &ShowState, mut_state: &mut MutableShowState) -> anyhow::Result<()> {
+        for (clip_name, state) in self.clip_state.iter() {
+            let mut state = state.borrow_mut();
+            if state.is_playing() {
+                info!("Stopping clip: {}", clip_name);
+                state.stop(show_state, mut_state)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn play_clips(self: &Self, show_state: &ShowState, mut_state: &mut MutableShowState) -> Option<Instant> {
@@ -41,16 +60,112 @@ impl <'a> ClipEngine<'a> {
         self.clip_state.values().any(|cs| cs.borrow().is_playing())
     }
 
+    /// the names of the clips playing, in order
+    pub fn playing_clips(self: &Self) -> Vec<String> {
+        let mut names: Vec<String> = self.clip_state.iter()
+            .filter(|(_, cs)| cs.borrow().is_playing())
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// change the tempo of every playing clip, taking effect from their next wait
+    /// (or straight away, for a wait until a bar and beat)
+    pub fn set_tempo(self: &Self, tempo: f32) {
+        let now = clock::now();
+        for state in self.clip_state.values() {
+            let mut state = state.borrow_mut();
+            if state.is_playing() {
+                state.change_tempo(tempo, now);
+            }
+        }
+    }
+
+    /// the tempo of the most recently started clip that's still playing
+    pub fn master_tempo(self: &Self) -> Option<f32> {
+        self.clip_state.values()
+            .map(|cs| cs.borrow())
+            .filter(|cs| cs.is_playing())
+            .max_by_key(|cs| cs.started_at)
+            .map(|cs| cs.tempo)
+    }
+
+}
+
+/// how many times each counted loop of a clip has played its steps, by the index of its loop step
+#[derive(Default)]
+pub struct LoopCounts(HashMap<usize, u32>);
+
+impl LoopCounts {
+
+    /// the step to go to from the counted loop step at: back to the index, or on once
+    /// the steps from it have played the number of times
+    pub fn loop_n(self: &mut Self, at: usize, index: usize, times: u32) -> usize {
+        let played = self.0.get(&at).copied().unwrap_or(0) + 1;
+        if played >= times {
+            // an outer loop coming round again starts the count afresh
+            self.0.remove(&at);
+            at + 1
+        } else {
+            self.0.insert(at, played);
+            index
+        }
+    }
+
+    /// the step to go to from the break step at: past its loop on the loop's last
+    /// time round, otherwise the next step
+    pub fn break_loop(self: &mut Self, steps: &[ClipStep], at: usize) -> usize {
+        match ClipStep::enclosing_loop(steps, at).map(|end| (end, &steps[end])) {
+            Some((end, ClipStep::LoopN { times, .. })) if self.0.get(&end).copied().unwrap_or(0) + 1 >= *times => {
+                self.0.remove(&end);
+                end + 1
+            },
+            _ => at + 1
+        }
+    }
+
+    pub fn clear(self: &mut Self) {
+        self.0.clear();
+    }
+}
+
+/// what a clip's steps ask of the show as they play, with the step asking
+pub enum ClipAction<'a> {
+    /// turn a mapping on, as the clip has it
+    On(usize, &'a LightMapping, EffectOverrides),
+    /// turn a mapping the clip turned on off again
+    Off(usize, &'a LightMapping),
+    /// stop another clip, by name
+    StopOther(usize, &'a str),
+    /// go back to an earlier step, forever
+    Loop(usize)
+}
+
+/// the position, in beats, a wait until a bar and beat is waiting for: from the start of
+/// the clip (or its last loop), or of the song the midi clock is playing
+#[derive(Clone,Copy)]
+enum Until {
+    Clip(f64),
+    Song(f64)
 }
 
 pub struct ClipState<'a> {
     playing: bool,
     step: usize,
     advance_at: Instant,
+    started_at: Instant,
     tempo: f32,
     override_color: Option<Color>,
-    active_mappings: HashSet<usize>,
-    steps: &'a Vec<ClipStep>
+    active_mappings: HashMap<usize, &'a LightMapping>,
+    steps: &'a Vec<ClipStep>,
+    /// the tempo map: beats played up to the last tempo change, and when that was
+    beats_played: f64,
+    beats_since: Instant,
+    beats_per_bar: u8,
+    /// the position a wait until a bar and beat is waiting for
+    until: Option<Until>,
+    loop_counts: LoopCounts
 }
 
 impl <'a> ClipState<'a> {
@@ -59,94 +174,216 @@ impl <'a> ClipState<'a> {
         ((beats * 60000f32)/self.tempo) as u64
     }
 
+    /// the clip's musical position, in beats from its start (or last loop)
+    fn beats_at(self: &Self, at: Instant) -> f64 {
+        self.beats_played + at.saturating_duration_since(self.beats_since).as_secs_f64() * self.tempo as f64 / 60.0
+    }
+
+    /// when the clip will reach a musical position at the current tempo
+    fn instant_of_beat(self: &Self, beat: f64) -> Instant {
+        self.beats_since + Duration::from_secs_f64(((beat - self.beats_played) * 60.0 / self.tempo as f64).max(0.0))
+    }
+
+    fn change_tempo(self: &mut Self, tempo: f32, now: Instant) {
+        self.beats_played = self.beats_at(now);
+        self.beats_since = now;
+        self.tempo = tempo;
+    }
+
+    fn restart_position(self: &mut Self, now: Instant) {
+        self.beats_played = 0.0;
+        self.beats_since = now;
+        self.until = None;
+    }
+
     pub fn new(steps: &'a Vec<ClipStep>) -> ClipState<'a> {
         ClipState {
             playing: false,
             step: 0,
-            advance_at: Instant::now(),
+            advance_at: clock::now(),
+            started_at: clock::now(),
             tempo: 120f32,
             override_color: None,
-            active_mappings: HashSet::new(),
-            steps
+            active_mappings: HashMap::new(),
+            steps,
+            beats_played: 0.0,
+            beats_since: clock::now(),
+            beats_per_bar: DEFAULT_BEATS_PER_BAR,
+            until: None,
+            loop_counts: LoopCounts::default()
         }
     }
 
     pub fn start(self: &mut Self, override_color: Option<Color>, tempo: f32) -> anyhow::Result<()> {
         self.playing = true;
         self.step = 0;
-        self.advance_at = Instant::now();
+        self.advance_at = clock::now();
+        self.started_at = self.advance_at;
         self.tempo = tempo;
         self.override_color = override_color;
+        self.beats_per_bar = DEFAULT_BEATS_PER_BAR;
+        self.loop_counts.clear();
+        self.restart_position(self.advance_at);
         Ok(())
     }
 
     pub fn play(self: &mut Self, show_state: &ShowState, engine: &ClipEngine, mut_state: &mut MutableShowState) -> Option<Instant> {
-        let now = Instant::now();
-        while self.playing && self.step < self.steps.len() {
+        let song = show_state.song_position(mut_state);
+        self.sequence(clock::now(), song, |action| {
+            match action {
+                ClipAction::On(step, mapping, overrides) => if let Err(e) = show_state.activate(mapping.get_id(), Some(overrides), mut_state) {
+                    error!("Clip step: {} failed to activate cue: {}, error: {}", step, mapping.cue, e);
+                },
+                ClipAction::Off(step, mapping) => if let Err(e) = show_state.deactivate(mapping.get_id(), mut_state) {
+                    error!("Clip step: {} failed to deactivate cue: {}, error: {}", step, mapping.cue, e);
+                },
+                ClipAction::StopOther(step, name) => if let Err(e) = engine.stop_clip(name, show_state, mut_state) {
+                    error!("Clip step: {} failed, error: {}", step, e);
+                },
+                ClipAction::Loop(_) => {}
+            }
+            true
+        })
+    }
+
+    /// play the clip's steps that are due by now, handing what they ask of the show to
+    /// act, which can stop the clip by returning false. waits for a bar and beat count from
+    /// the start of the song while a midi clock is playing one. returns when the next step
+    /// is due, or None if it waits for a song that has stopped
+    pub fn sequence(self: &mut Self, now: Instant, song: Option<SongPosition>, mut act: impl FnMut(ClipAction<'a>) -> bool) -> Option<Instant> {
+        let steps = self.steps;
+        while self.playing {
+            // a wait for a musical position follows the tempo as it changes
+            match (self.until, song) {
+                (Some(Until::Clip(beat)), _) => self.advance_at = self.instant_of_beat(beat),
+                (Some(Until::Song(beat)), Some(song)) => self.advance_at = song.at
+                    + Duration::from_secs_f64(((beat - song.beats) * 60.0 / self.tempo as f64).max(0.0)),
+                // holds until the song plays again
+                (Some(Until::Song(_)), None) => return None,
+                (None, _) => {}
+            }
             if self.advance_at > now {
                 return Some(self.advance_at)
             }
-            match &self.steps[self.step] {
+            self.until = None;
+            // running off the end is the end, once the last wait is up
+            let Some(step) = steps.get(self.step) else {
+                self.playing = false;
+                break
+            };
+            let carry_on = match step {
                 ClipStep::MappingOn(mapping) => {
-                    let overrides = Some(EffectOverrides {
+                    let overrides = EffectOverrides {
                         color: self.override_color,
                         tempo: Some(self.tempo),
                         attack: None,
                         sustain: None,
                         release: None
-                    });
-                    let _ = show_state.activate(mapping.get_id(), overrides, mut_state);
+                    };
                     if !mapping.one_shot.unwrap_or(false) {
-                        self.active_mappings.insert(mapping.get_id());
+                        self.active_mappings.insert(mapping.get_id(), mapping);
                     }
                     self.step = self.step + 1;
-
+                    act(ClipAction::On(self.step - 1, mapping, overrides))
                 },
                 ClipStep::MappingOff(index) => {
-                    if let ClipStep::MappingOn(mapping) = &self.steps[*index] {
-                        let _ = show_state.deactivate(mapping.get_id(), mut_state);
+                    let at = self.step;
+                    self.step = self.step + 1;
+                    if let ClipStep::MappingOn(mapping) = &steps[*index] {
                         self.active_mappings.remove(&mapping.get_id());
+                        act(ClipAction::Off(at, mapping))
                     } else {
-                        error!("Mapping off step at index: {} does not point to mapping on step with index: {}", self.step, *index);
+                        error!("Mapping off step at index: {} does not point to mapping on step with index: {}", at, *index);
+                        true
                     }
-                    self.step = self.step + 1;
                 },
                 ClipStep::End => {
                     self.playing = false;
+                    true
                 },
-                ClipStep::Loop(index) => { 
-                    self.step = *index 
+                ClipStep::Loop(index) => {
+                    let at = self.step;
+                    self.step = *index;
+                    self.restart_position(now);
+                    act(ClipAction::Loop(at))
+                },
+                ClipStep::LoopN { index, times } => {
+                    let next = self.loop_counts.loop_n(self.step, *index, *times);
+                    if next != self.step + 1 {
+                        self.restart_position(now);
+                    }
+                    self.step = next;
+                    true
+                },
+                ClipStep::BreakLoop => {
+                    self.step = self.loop_counts.break_loop(steps, self.step);
+                    true
                 },
                 ClipStep::SetColor(color) => {
                     self.override_color = Some(color.clone());
                     self.step = self.step + 1;
+                    true
                 },
                 ClipStep::SetTempo(tempo) => {
-                    self.tempo = *tempo;
+                    self.change_tempo(*tempo, now);
+                    self.step = self.step + 1;
+                    true
+                },
+                ClipStep::SetMeter(beats_per_bar) => {
+                    self.beats_per_bar = *beats_per_bar;
                     self.step = self.step + 1;
+                    true
+                },
+                ClipStep::WaitUntil { bar, beat } => {
+                    let target = (bar.saturating_sub(1) * self.beats_per_bar as u32) as f64 + (*beat as f64 - 1.0);
+                    let (position, until) = match song {
+                        Some(song) => (song.beats + now.saturating_duration_since(song.at).as_secs_f64() * self.tempo as f64 / 60.0, Until::Song(target)),
+                        None => (self.beats_at(now), Until::Clip(target))
+                    };
+                    if position > target {
+                        debug!("Clip step: {} is already past bar: {} beat: {}, carrying on", self.step, bar, beat);
+                    } else {
+                        self.until = Some(until);
+                    }
+                    self.step = self.step + 1;
+                    true
                 },
                 ClipStep::Stop => {
-                    let _ = self.stop(show_state, mut_state);
+                    let at = self.step;
+                    let active: Vec<&'a LightMapping> = self.active_mappings.drain().map(|(_, mapping)| mapping).collect();
+                    self.playing = false;
+                    self.step = 0;
+                    let mut carry_on = true;
+                    for mapping in active {
+                        carry_on &= act(ClipAction::Off(at, mapping));
+                    }
+                    carry_on
                 },
                 ClipStep::StopOther(name) => {
-                    let _ = engine.stop_clip(name, show_state, mut_state);
                     self.step = self.step + 1;
+                    act(ClipAction::StopOther(self.step - 1, name))
                 },
                 ClipStep::WaitBeats(beats) => {
                     self.advance_at = now + Duration::from_millis(self.beats_to_millis(*beats));
                     self.step = self.step + 1;
+                    true
                 },
                 ClipStep::WaitMillis(millis) => {
                     self.advance_at = now + Duration::from_millis(*millis as u64);
                     self.step = self.step + 1;
+                    true
                 }
+            };
+            if !carry_on {
+                self.playing = false;
+                self.step = 0;
             }
         }
         None
     }
 
     pub fn stop(self: &mut Self, show_state: &ShowState, mut_state: &mut MutableShowState) -> anyhow::Result<()> {
-        for id in self.active_mappings.drain() {
+        for (id, _) in self.active_mappings.drain() {
             show_state.deactivate(id, mut_state)?;
         }
         self.playing = false;
@@ -0,0 +1,43 @@
+use std::cell::Cell;
+use std::time::Instant;
+
+///
+/// The clock all of the show's time-based logic reads. Normally it's just the
+/// monotonic system clock, but tests and shadow runs can switch the current thread
+/// to a virtual clock that only moves when told to, so timed scenarios run instantly
+/// and deterministically
+///
+
+pub fn now() -> Instant {
+    VIRTUAL_NOW.with(|v| v.get()).unwrap_or_else(Instant::now)
+}
+
+thread_local! {
+    static VIRTUAL_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// switch this thread to a virtual clock starting at the current time
+pub fn start_virtual() -> Instant {
+    let start = Instant::now();
+    VIRTUAL_NOW.with(|v| v.set(Some(start)));
+    start
+}
+
+/// move the virtual clock forward to the given instant (it never goes backwards)
+pub fn advance_to(at: Instant) {
+    VIRTUAL_NOW.with(|v| v.set(Some(v.get().map_or(at, |now| now.max(at)))));
+}
+
+/// run f on a virtual clock of its own, starting at the current time, then put this
+/// thread's clock back as it was
+pub fn run_virtual<T>(f: impl FnOnce(Instant) -> T) -> T {
+    let was = VIRTUAL_NOW.with(|v| v.get());
+    let result = f(start_virtual());
+    VIRTUAL_NOW.with(|v| v.set(was));
+    result
+}
+
+/// switch this thread back to the system clock
+pub fn stop_virtual() {
+    VIRTUAL_NOW.with(|v| v.set(None));
+}
@@ -1,7 +1,37 @@
-use std::{ops::Range, time::Duration};
+use std::{collections::HashMap, ops::Range, time::{Duration, Instant}};
 
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{Local, NaiveTime, TimeZone, Timelike};
 use serde::Deserialize;
 
+use crate::packet::HeaderMode;
+use crate::radio::{PacketIdRange, RadioProfile, RadioType};
+use crate::lora::LoraConfig;
+use crate::udp::UdpConfig;
+use crate::rtpmidi::RtpMidiConfig;
+use crate::osc::OscConfig;
+use crate::sacn::SacnConfig;
+use crate::websocket::WebSocketConfig;
+use crate::http::HttpConfig;
+use crate::mqtt::MqttConfig;
+use crate::midifilter::MidiFilterConfig;
+use crate::metronome::MetronomeConfig;
+use crate::feedback::PadFeedbackConfig;
+use crate::padsetup::PadSetupConfig;
+use crate::arbitration::ArbitrationConfig;
+use crate::statusled::StatusLedConfig;
+use crate::localreceiver::LocalReceiverConfig;
+use crate::artnet::ArtNetConfig;
+use crate::hooks::ExecHookConfig;
+use crate::error::{ErrorCategory,Recovery};
+use crate::clock;
+
+const DEFAULT_SHOW_RESTART_DELAY: u64 = 1000;
+const DEFAULT_PITCH_BEND_HUE_RANGE: u8 = 32;
+const DEFAULT_TEMPO_INTERVAL_MILLIS: u64 = 50;
+const DEFAULT_PITCH_BEND_INTERVAL_MILLIS: u64 = 50;
+const DEFAULT_AFTERTOUCH_INTERVAL_MILLIS: u64 = 50;
+
 /// Mappings for a JSON config file that contains settings that are
 /// not a property of a show, but rather the configuration of the
 /// system (radio, etc). Notice details of modulation are hardcoded
@@ -16,17 +46,51 @@ pub struct ConfigFile {
     /// the path to the GPIO device to open
     pub gpio_device: String,
 
-    /// the line of the reset pin on the gpio device
+    /// the line of the reset pin on the gpio device (25 on the bonnet)
     pub reset_line: u32,
 
+    /// if populated, a line on the gpio device to drive the radio's chip select
+    /// from, for boards that don't wire it to the spi controller's chip select
+    pub cs_line: Option<u32>,
+
+    /// the spi clock speed, defaults to 1MHz
+    pub spi_speed_hz: Option<u32>,
+
     /// the frequency to use expressed as a long
     pub frequency: u32,
 
+    /// the radio module the transmitter has, defaults to the rfm69
+    pub radio_type: Option<RadioType>,
+
+    /// the modem settings to use with the rfm69, which must match the receivers' build.
+    /// defaults to the standard profile if not supplied
+    pub radio_profile: Option<RadioProfile>,
+
+    /// the modem settings to use with an sx127x, which must match the receivers' build.
+    /// defaults if not supplied
+    pub lora: Option<LoraConfig>,
+
+    /// where to send packets with the udp radio type. defaults if not supplied
+    pub udp: Option<UdpConfig>,
+
+    /// if supplied, every packet is encrypted over the air with the radio's hardware
+    /// aes, so nearby gear can't spoof cues. 16 bytes given as 32 hex digits, the
+    /// receivers must be built with the same key
+    pub aes_key: Option<String>,
+
     /// the id of this radio to use when transmitting.
     /// needs to be < 10 for the receivers to obey
     pub transmitter_id: u8,
 
-    /// the transmitter power to use in dBm, between -18 and +20
+    /// which header bytes the receivers expect after the recipient address,
+    /// defaults to the RadioHead-compatible header if not supplied
+    pub header_mode: Option<HeaderMode>,
+
+    /// if populated, the packet ids this transmitter uses, eg { "first": 1, "last": 127 },
+    /// so transmitters sharing the air can be given ranges that don't overlap
+    pub packet_id_range: Option<PacketIdRange>,
+
+    /// the transmitter power to use in dBm, between -18 and +20 (+2 and +20 for an sx127x)
     /// note that for most uses +17 is probably a good value as 
     /// it doesn't require toggling a "high power" state on/off
     /// during transmit
@@ -36,26 +100,119 @@ pub struct ConfigFile {
     /// resets etc, will use a default value if not supplied
     pub settle_time_millis: Option<u64>,
 
+    /// the pause between the packets sent to configure receivers when a show loads,
+    /// will use a default value if not supplied
+    pub config_packet_spacing_millis: Option<u64>,
+
+    /// receivers are configured in chunks of this many with a longer pause between
+    /// chunks, so large rosters don't overrun them. defaults if not supplied
+    pub config_chunk_size: Option<usize>,
+    pub config_chunk_pause_millis: Option<u64>,
+
+    /// with the reliable datagram header, receivers acknowledge the packets that
+    /// configure them. how long to wait for each acknowledgement, and how many times
+    /// to resend a packet that isn't acknowledged. defaults if not supplied
+    pub ack_timeout_millis: Option<u64>,
+    pub ack_retries: Option<u32>,
+
     /// the client name to pass to the midi library
     pub midi_client_name: String,
 
     /// the midi port to attach to for events. the string
     /// provided will be matched against the port name as a prefix.
-    /// omit altogether to disable midi functionality
+    /// omit altogether (with no virtual port) to disable midi functionality
     pub midi_port: Option<String>,
 
+    /// if true, also create a virtual midi input port named for the midi client, which
+    /// software on the same machine (eg a DAW) can connect to directly. can be used
+    /// alongside midi_port or instead of it. needs ALSA (or CoreMIDI)
+    pub midi_virtual_port: Option<bool>,
+
+    /// if populated, accept RTP-MIDI (AppleMIDI) network sessions, so a DAW elsewhere
+    /// on the network can send midi without a cable
+    pub rtp_midi: Option<RtpMidiConfig>,
+
+    /// if populated, drop or move midi channels and transpose notes before the show
+    /// sees them, wherever the midi comes from
+    pub midi_filter: Option<MidiFilterConfig>,
+
+    /// if populated, echo the tempo of playing clips on the midi output as
+    /// clock and/or a click note, so other gear can sync to the lights
+    pub metronome: Option<MetronomeConfig>,
+
+    /// if populated, light the pads of the pad controller on the midi port while the
+    /// cues they trigger are showing
+    pub pad_feedback: Option<PadFeedbackConfig>,
+
+    /// if populated, set up the pads of the pad controller on the midi port from the
+    /// show's mappings each time the show loads
+    pub pad_setup: Option<PadSetupConfig>,
+
+    /// if populated, a knob that controls the tempo live: of playing clips, of clips
+    /// started after, and of the receivers, which are sent the new tempo. if no cc is
+    /// given, the knob is bound by "learning" it from the control channel
+    pub tempo_control: Option<TempoControl>,
+
+    /// if populated, pitch bend on these channels shifts the hue of the cues triggered
+    /// from the same channel, live, including those already showing
+    pub pitch_bend_hue: Option<PitchBendHue>,
+
+    /// the least time between sends of the cues aftertouch modulates as the pressure
+    /// changes, defaults to 50. the last pressure is always sent, once the interval is up
+    pub aftertouch_interval_millis: Option<u64>,
+
+    /// if true, follow midi clock: the tempo it's played at overrides the tempos in the
+    /// show, for playing clips and the packets mappings send. defaults to false
+    pub midi_clock_sync: Option<bool>,
+
     /// the midi channel number to care about for out-of-show controls
     /// eg, sustain, test, reset
     pub midi_control_channel: u8,
 
-    /// the path to the show file to load on startup
+    /// the path to the show file to load on startup: JSON, or YAML (.yaml, .yml) or
+    /// JSON5 (.json5) by its extension, or a compiled cue file
     pub show_file: String,
 
+    /// if true, reload the show whenever its file (or a file it includes) changes, as
+    /// SIGHUP does, once the changed show validates. defaults to false
+    pub watch_show: Option<bool>,
+
     /// the depth of buffer to use on the internal channel between
     /// the MIDI read thread and the main thread, will use a default
-    /// value if none supplied
+    /// value if none supplied. the channel's high water mark and peak message
+    /// rate are logged whenever a show stops, to help tune this
     pub channel_buf_depth: Option<usize>,
 
+    /// if populated, how long after a note on to wait for the rest of a chord. the
+    /// chord's cues then go out together in a tight burst in a fixed (channel, note)
+    /// order, so one side of the field doesn't visibly light before the other
+    pub chord_window_millis: Option<u64>,
+
+    /// when several packets go out at once (a chord, a clip step, buffered
+    /// deactivations), merge those with identical effects to different targets
+    /// into one packet with all the targets, to save airtime. defaults to false:
+    /// each target gets its own packet, as before
+    pub merge_identical_packets: Option<bool>,
+
+    /// blind redundancy for sites where packets get lost: how many extra times to send
+    /// each show packet and each control packet, and the gap between the copies.
+    /// defaults to no repeats, with a gap of 5ms
+    pub show_packet_repeats: Option<u8>,
+    pub control_packet_repeats: Option<u8>,
+    pub repeat_gap_millis: Option<u64>,
+
+    /// how often a synthesized fade (see synth_fade on mappings) steps the brightness,
+    /// shorter is smoother but takes more airtime. defaults to 50ms
+    pub synth_fade_step_millis: Option<u64>,
+
+    /// if populated, a group in the show (eg receivers on the director's desk) that the
+    /// preview control redirects every cue to, so new cues can be checked during a show
+    pub preview_group: Option<String>,
+
+    /// how long a crossfade between scenes takes, when the scene doesn't say.
+    /// defaults to two seconds
+    pub crossfade_millis: Option<u32>,
+
     /// the amount of time to allow to elapse after the last
     /// show packet before we start periodically sending lights-out packets
     pub lights_out_window_open: f32,
@@ -65,11 +222,333 @@ pub struct ConfigFile {
     /// allow to elapse between packets (1/freq)
     pub lights_out_period: f32,
 
+    /// if populated, the number of seconds between periodic "transmitter alive"
+    /// beacon packets. receivers with firmware support will fall back to a safe
+    /// local idle animation if they stop hearing the beacon. omit to disable
+    pub heartbeat_period: Option<f32>,
+
+    /// if populated, the number of seconds between link quality polls. each poll asks
+    /// one receiver (in turn) how well it hears the transmitter, and the signal strength
+    /// both ways is logged and shown in the status. omit to disable
+    pub link_poll_period: Option<f32>,
+
+    /// how long to wait for a receiver to answer a poll or a status query, defaults
+    /// to 50 milliseconds
+    pub link_poll_timeout_millis: Option<u64>,
+
+    /// links weaker than this, in dBm, in either direction are reported as marginal.
+    /// defaults to -80
+    pub marginal_rssi: Option<i16>,
+
+    /// if populated, the most extra copies of a critical mapping's packets (see critical
+    /// on mappings) sent on to a receiver with a poor link, each to it alone. a receiver
+    /// gets its share of these by how many of its recent link polls came back marginal,
+    /// so it needs link_poll_period too. omit to disable
+    pub adaptive_repeats: Option<u8>,
+
+    /// the most adaptive repeats sent in any one second, over all receivers, so they
+    /// can't crowd the show off the air. defaults to 20
+    pub adaptive_repeat_budget: Option<u32>,
+
+    /// if populated, the number of seconds between status queries. each query asks one
+    /// receiver (in turn) for its battery voltage and uptime, which are shown in the
+    /// status. omit to disable; a report on every receiver can still be asked for
+    pub telemetry_poll_period: Option<f32>,
+
+    /// batteries below this many millivolts are reported as low, defaults to 3500
+    pub low_battery_millivolts: Option<u16>,
+
+    /// if populated, the number of seconds between listens for receivers announcing
+    /// themselves on power up, so one that joins late (eg after a battery swap) is
+    /// configured on its own. omit to disable
+    pub announcement_listen_period: Option<f32>,
+
     /// if populated, the name of a clip in the 
     /// show to automatically start playing on startup
     /// (makes the transmitter usable without midi input)
-    pub autoplay_clip: Option<String>
+    pub autoplay_clip: Option<String>,
+
+    /// if populated, led strips attached to the Pi itself that act as receivers,
+    /// for podium indicator lights or as a monitor of what was just sent
+    pub local_receivers: Option<Vec<LocalReceiverConfig>>,
+
+    /// if populated, mirror what every receiver is told onto Art-Net DMX, so a
+    /// visualizer can show the receivers during programming
+    pub artnet: Option<ArtNetConfig>,
+
+    /// if populated, log levels for individual modules (eg {"radio": "debug"}) that
+    /// SIGUSR2 switches on, and off again, without restarting
+    pub debug_log_levels: Option<HashMap<String,String>>,
+
+    /// if populated, a gpio line to drive a status LED from: solid when a show is
+    /// loaded, blinking while midi arrives, blinking fast after an error
+    pub status_led: Option<StatusLedConfig>,
+
+    /// if populated, the path of a CSV file to append a record of every operator
+    /// action (cues, signals, commands) to, for reviewing a show afterwards
+    pub session_log: Option<String>,
+
+    /// if populated, mappings with an exec hook run it when turned on or off, within
+    /// these limits. hooks never run unless this is present
+    pub exec_hooks: Option<ExecHookConfig>,
+
+    /// if populated, the path of a unix domain socket to listen on for control commands
+    /// (newline-delimited JSON), so local scripts can drive a running transmitter
+    pub control_socket: Option<String>,
+
+    /// if populated, listen for OSC messages firing and releasing cues by name, for
+    /// front of house software that speaks OSC
+    pub osc: Option<OscConfig>,
+
+    /// if populated, listen for websocket clients, which are streamed what the show is
+    /// doing and can send the control socket's commands, eg for a monitor on a tablet
+    pub websocket: Option<WebSocketConfig>,
+
+    /// if populated, serve an HTTP API (and a page using it) for listing and firing
+    /// cues, starting and stopping clips, setting the grand master and reloading
+    pub http: Option<HttpConfig>,
+
+    /// if populated, publish what the show does to an MQTT broker, and take the control
+    /// socket's commands from it
+    pub mqtt: Option<MqttConfig>,
+
+    /// if populated, listen for sACN (E1.31) from a lighting console, which drives the
+    /// receivers the show patches into DMX
+    pub sacn: Option<SacnConfig>,
+
+    /// if populated, the name of a clip in the show to play once when the transmitter
+    /// starts (not on reloads), after receivers are configured, so staff on the field
+    /// can see that the transmitter booted and receivers are listening
+    pub startup_clip: Option<String>,
+
+    /// if populated, priorities for the control inputs so that lower priority
+    /// sources are locked out for a while after a higher priority one fires a cue
+    pub arbitration: Option<ArbitrationConfig>,
+
+    /// if populated, when the show ends on its own: clips stop, receivers are turned
+    /// off, and the transmitter idles until reloaded (or exits), so a forgotten
+    /// transmitter doesn't keep the band's hats glowing in the equipment truck all night
+    pub show_end: Option<ShowEnd>,
+
+    /// if populated, the brightness of everything sent through the day, in percent, as
+    /// points in local time ("HH:MM") that the brightness ramps between, so one show file
+    /// suits a daylight rehearsal and a night game. applied on top of the grand master
+    pub brightness_schedule: Option<Vec<BrightnessPoint>>,
 
+    /// if populated, when receivers sleep in low power idle through a long gap (eg sitting
+    /// in the stands before the game), and when they're woken and reconfigured for the show
+    pub sleep: Option<SleepSchedule>,
+
+    /// how many recent live cue triggers to remember for instant replay,
+    /// will use a default value if not supplied
+    pub history_depth: Option<usize>,
+
+    /// how many times the show thread may be restarted after an error or panic
+    /// before the transmitter gives up and exits, will use a default value if not supplied
+    pub max_show_restarts: Option<u32>,
+
+    /// how long to wait before restarting a failed show thread,
+    /// will use a default value if not supplied
+    pub show_restart_delay_millis: Option<u64>,
+
+    /// how to recover from each category of failure, eg { "midi": "retry", "radio_fatal": "exit" }.
+    /// categories left out use the defaults: config errors exit, show and fatal radio
+    /// errors stop the show until a reload, and transient radio and midi errors are skipped
+    pub recovery: Option<HashMap<ErrorCategory, Recovery>>
+
+}
+
+#[derive(Debug,Deserialize)]
+pub struct ShowEnd {
+    /// a local time of day ("HH:MM") at which the show ends
+    pub at: Option<String>,
+
+    /// or a number of minutes after the transmitter starts. if both are given
+    /// whichever comes first ends the show
+    pub after_minutes: Option<u32>,
+
+    /// exit rather than idle once the show ends, defaults to false
+    pub exit: Option<bool>
+}
+
+/// how long until the clock next reads a local time of day ("HH:MM"), today or tomorrow
+fn until_time_of_day(at: &str) -> Result<Duration> {
+    let time = NaiveTime::parse_from_str(at, "%H:%M").with_context(|| format!("Invalid time of day: {}", at))?;
+    let now = Local::now();
+    let mut day = now.date_naive();
+    let next = loop {
+        match Local.from_local_datetime(&day.and_time(time)).earliest() {
+            Some(next) if next > now => break next,
+            _ => day = day.succ_opt().ok_or_else(|| anyhow!("Date out of range"))?
+        }
+    };
+    Ok((next - now).to_std()?)
+}
+
+impl ShowEnd {
+    /// when the show should end, counting from now
+    pub fn deadline(self: &Self) -> Result<Option<Instant>> {
+        let after = self.after_minutes.map(|minutes| Duration::from_secs(minutes as u64 * 60));
+        let at = self.at.as_deref().map(until_time_of_day).transpose().context("Invalid show end")?;
+        Ok([after, at].into_iter().flatten().min().map(|delay| clock::now() + delay))
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct SleepSchedule {
+    /// a local time of day ("HH:MM") to put the receivers to sleep, defaults to
+    /// as soon as the show loads
+    pub at: Option<String>,
+
+    /// the local time of day ("HH:MM") to wake and reconfigure them, ahead of the show window
+    pub wake_at: String,
+
+    /// whether any packet (eg a cue fired during the gap) wakes a receiver early, defaults to true
+    pub wake_on_packet: Option<bool>
+}
+
+impl SleepSchedule {
+    /// when to next put the receivers to sleep and when to next wake them, counting from
+    /// now. if the wake comes first we're inside the sleep window, so sleep is due now
+    pub fn deadlines(self: &Self) -> Result<(Instant, Instant)> {
+        let now = clock::now();
+        let wake = now + until_time_of_day(&self.wake_at).context("Invalid sleep wake_at")?;
+        let sleep = match &self.at {
+            Some(at) => now + until_time_of_day(at).context("Invalid sleep at")?,
+            None => now
+        };
+        Ok((if wake < sleep { now } else { sleep }, wake))
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct BrightnessPoint {
+    /// a local time of day ("HH:MM")
+    pub at: String,
+
+    /// the brightness at that time, in percent
+    pub percent: u8
+}
+
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+/// a brightness schedule with its times parsed, as seconds after midnight, in order
+pub struct BrightnessSchedule {
+    points: Vec<(i64, u8)>
+}
+
+impl BrightnessSchedule {
+    pub fn new(points: &[BrightnessPoint]) -> Result<BrightnessSchedule> {
+        if points.is_empty() {
+            return Err(anyhow!("A brightness schedule needs at least one point"))
+        }
+        let mut parsed = points.iter().map(|p| {
+            let time = NaiveTime::parse_from_str(&p.at, "%H:%M").with_context(|| format!("Invalid time of day: {}", p.at))?;
+            Ok((time.num_seconds_from_midnight() as i64, p.percent.min(100)))
+        }).collect::<Result<Vec<(i64,u8)>>>()?;
+        parsed.sort_by_key(|(at, _)| *at);
+        Ok(BrightnessSchedule { points: parsed })
+    }
+
+    /// the brightness at a time of day, ramping linearly from the point before to the point
+    /// after (wrapping around midnight)
+    pub fn percent_at(self: &Self, time: NaiveTime) -> u8 {
+        let now = time.num_seconds_from_midnight() as i64;
+        let (first, last) = (self.points[0], self.points[self.points.len() - 1]);
+        let before = self.points.iter().rev().find(|(at, _)| *at <= now).copied()
+            .unwrap_or((last.0 - SECONDS_PER_DAY, last.1));
+        let after = self.points.iter().find(|(at, _)| *at > now).copied()
+            .unwrap_or((first.0 + SECONDS_PER_DAY, first.1));
+        let progress = (now - before.0) as f32 / (after.0 - before.0) as f32;
+        (before.1 as f32 + (after.1 as f32 - before.1 as f32) * progress).round() as u8
+    }
+
+    /// the brightness right now
+    pub fn percent_now(self: &Self) -> u8 {
+        self.percent_at(Local::now().time())
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct PitchBendHue {
+    /// the midi channels whose pitch bend shifts hue
+    pub channels: Vec<u8>,
+
+    /// how far round the color wheel (256 steps) a full bend either way shifts the
+    /// hue, defaults to 32
+    pub range: Option<u8>,
+
+    /// the least time between sends of the bent cues as the bend moves, defaults to 50.
+    /// the last bend is always sent, once the interval is up
+    pub interval_millis: Option<u64>
+}
+
+impl PitchBendHue {
+
+    /// the offset to add to the hue for a bend from -8192 to 8191, wrapped to a u8
+    pub fn hue_offset(self: &Self, bend: i16) -> u8 {
+        let range = self.range.unwrap_or(DEFAULT_PITCH_BEND_HUE_RANGE) as i32;
+        (bend as i32 * range / 8192) as i8 as u8
+    }
+
+    pub fn interval(self: &Self) -> Duration {
+        Duration::from_millis(self.interval_millis.unwrap_or(DEFAULT_PITCH_BEND_INTERVAL_MILLIS))
+    }
+}
+
+#[derive(Debug,Deserialize)]
+pub struct TempoControl {
+    /// the channel and cc of the knob, the channel defaults to the control channel
+    pub channel: Option<u8>,
+    pub cc: Option<u8>,
+
+    /// the tempo range the knob sweeps
+    pub min_bpm: f32,
+    pub max_bpm: f32,
+
+    /// how the knob position maps to tempo, defaults to linear
+    pub curve: Option<TempoCurve>,
+
+    /// the least time between sends of the tempo to the receivers as the knob moves,
+    /// defaults to 50. the knob's last position is always sent, once the interval is up
+    pub interval_millis: Option<u64>
+}
+
+#[derive(Debug,Deserialize,Clone,Copy,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TempoCurve {
+    /// equal bpm steps across the range
+    #[default]
+    Linear,
+    /// equal ratio steps across the range, giving finer control at slow tempos
+    Exponential
+}
+
+impl TempoControl {
+
+    /// the range has to be above zero, as tempos divide, and the right way round
+    pub fn validate(self: &Self) -> Result<()> {
+        if self.min_bpm <= 0.0 {
+            bail!("tempo_control min_bpm must be above zero, not {}", self.min_bpm)
+        }
+        if self.min_bpm > self.max_bpm {
+            bail!("tempo_control min_bpm {} is above max_bpm {}", self.min_bpm, self.max_bpm)
+        }
+        Ok(())
+    }
+
+    pub fn interval(self: &Self) -> Duration {
+        Duration::from_millis(self.interval_millis.unwrap_or(DEFAULT_TEMPO_INTERVAL_MILLIS))
+    }
+
+    /// the tempo for a knob position from 0-127
+    pub fn bpm(self: &Self, value: u8) -> f32 {
+        let position = value.min(127) as f32 / 127.0;
+        match self.curve.unwrap_or_default() {
+            TempoCurve::Linear => self.min_bpm + (self.max_bpm - self.min_bpm) * position,
+            TempoCurve::Exponential => self.min_bpm * (self.max_bpm / self.min_bpm).powf(position)
+        }
+    }
 }
 
 /// convert a floating point number of seconds to a Duration
@@ -81,6 +560,30 @@ fn convert_secs(secs: f32) -> Duration {
 
 impl ConfigFile {
 
+    /// check the settings that parse but can't work, so the transmitter doesn't start with them
+    pub fn validate(self: &Self) -> Result<()> {
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.validate()?;
+        }
+        if let Some(tempo_control) = &self.tempo_control {
+            tempo_control.validate()?;
+        }
+        if let Some(rtp_midi) = &self.rtp_midi {
+            rtp_midi.validate()?;
+        }
+        if let Some(packet_id_range) = &self.packet_id_range {
+            packet_id_range.validate(self.header_mode.unwrap_or_default())?;
+        }
+        if let Some(lora) = &self.lora {
+            lora.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn merge_identical_packets(self: &Self) -> bool {
+        self.merge_identical_packets.unwrap_or(false)
+    }
+
     pub fn lights_out_window(self: &Self) -> Range<Duration> {
         convert_secs(self.lights_out_window_open)..convert_secs(self.lights_out_window_close)
     }
@@ -88,5 +591,67 @@ impl ConfigFile {
     pub fn lights_out_delay(self: &Self) -> Duration {
         convert_secs(self.lights_out_period)
     }
+
+    pub fn heartbeat_delay(self: &Self) -> Option<Duration> {
+        self.heartbeat_period.map(convert_secs)
+    }
+
+    pub fn link_poll_delay(self: &Self) -> Option<Duration> {
+        self.link_poll_period.map(convert_secs)
+    }
+
+    pub fn telemetry_poll_delay(self: &Self) -> Option<Duration> {
+        self.telemetry_poll_period.map(convert_secs)
+    }
+
+    pub fn announcement_listen_delay(self: &Self) -> Option<Duration> {
+        self.announcement_listen_period.map(convert_secs)
+    }
+
+    pub fn aftertouch_interval(self: &Self) -> Duration {
+        Duration::from_millis(self.aftertouch_interval_millis.unwrap_or(DEFAULT_AFTERTOUCH_INTERVAL_MILLIS))
+    }
+
+    pub fn show_restart_delay(self: &Self) -> Duration {
+        Duration::from_millis(self.show_restart_delay_millis.unwrap_or(DEFAULT_SHOW_RESTART_DELAY))
+    }
+
+    pub fn recovery(self: &Self, category: ErrorCategory) -> Recovery {
+        self.recovery.as_ref().and_then(|r| r.get(&category).copied()).unwrap_or_else(|| category.default_recovery())
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tempo_control(min_bpm: f32, max_bpm: f32) -> TempoControl {
+        TempoControl { channel: None, cc: None, min_bpm, max_bpm, curve: Some(TempoCurve::Exponential), interval_millis: None }
+    }
+
+    #[test]
+    fn tempo_ranges_have_to_be_above_zero_and_the_right_way_round() {
+        assert!(tempo_control(60.0, 180.0).validate().is_ok());
+        assert!(tempo_control(120.0, 120.0).validate().is_ok());
+        assert!(tempo_control(0.0, 180.0).validate().is_err());
+        assert!(tempo_control(-10.0, 180.0).validate().is_err());
+        assert!(tempo_control(180.0, 60.0).validate().is_err());
+    }
+
+    #[test]
+    fn packet_id_ranges_have_to_run_the_right_way_and_hold_a_usable_id() {
+        let range = |first, last| PacketIdRange { first, last };
+        assert!(range(1, 127).validate(HeaderMode::ReliableDatagram).is_ok());
+        assert!(range(0, 0).validate(HeaderMode::Raw).is_ok());
+        assert!(range(127, 1).validate(HeaderMode::Raw).is_err());
+        assert!(range(0, 0).validate(HeaderMode::ReliableDatagram).is_err());
+    }
+
+    #[test]
+    fn tempo_knob_sweeps_its_range() {
+        let knob = tempo_control(60.0, 240.0);
+        assert_eq!(knob.bpm(0), 60.0);
+        assert_eq!(knob.bpm(127), 240.0);
+        assert!((knob.bpm(64) - 120.0).abs() < 1.0);
+    }
+}
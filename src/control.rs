@@ -0,0 +1,209 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result, anyhow};
+use crossbeam_channel::{Sender, bounded};
+use log::{debug, info, warn, error};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::SIGTERM;
+use signal_hook::low_level::raise;
+
+use crate::arbitration::ControlSource;
+use crate::director::DirectorMessage;
+use crate::session::SessionLog;
+use crate::showstate::{CueListAction,CueListing,ShowStatus,TagOperation};
+
+///
+/// The control socket lets local scripts (and the ctl subcommand) drive a running
+/// transmitter without signals or a web server. Each connection sends commands as
+/// one JSON object per line, eg {"command": "fire", "cue": "intro"}, and gets one
+/// JSON reply per line: {"ok": true} or {"ok": false, "error": "..."}. Cue and status
+/// commands are answered once the director has acted on them, everything else once queued
+///
+
+/// how long to wait for the director to answer a cue or status command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// how long to wait for every receiver to be asked for its status
+const QUERY_REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// a command read from the socket, mostly mirroring the director's messages
+#[derive(Debug,Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum SocketCommand {
+    /// fire a cue by name
+    Fire { cue: String },
+    /// release a cue by name
+    Release { cue: String },
+    /// start or stop a clip by name
+    StartClip { clip: String },
+    StopClip { clip: String },
+    /// set the grand master, 0-100%
+    Brightness { percent: u8 },
+    Reload,
+    Shutdown,
+    /// replay the last cue, or everything in the trailing window
+    Replay { window_millis: Option<u64> },
+    Mute { groups: Vec<String> },
+    SetVariable { name: String, value: String },
+    Crossfade { scene: String },
+    /// step through the show's cue list: on to the next step, back one, or to a step
+    /// (counting from 0)
+    Go,
+    Back,
+    Jump { step: usize },
+    /// mute or adjust every cue carrying the tag, eg {"command": "tag", "tag": "strobe", "operation": {"op": "mute"}}
+    Tag { tag: String, operation: TagOperation },
+    /// wake_on_packet defaults to true
+    Sleep { wake_on_packet: Option<bool> },
+    Wake,
+    Blackout,
+    /// record every packet sent to the file at the path, for playing back with
+    /// --attract. with no path, stop recording
+    Record { path: Option<String> },
+    ResetPacketIds,
+    /// ask every receiver for its battery and uptime, returning the status once they've answered
+    QueryStatus,
+    /// what the show is doing, returned in the reply
+    Status
+}
+
+#[derive(Debug,Serialize,Deserialize)]
+pub struct SocketReply {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<ShowStatus>
+}
+
+impl SocketReply {
+    pub fn from(result: Result<Option<ShowStatus>>) -> SocketReply {
+        match result {
+            Ok(status) => SocketReply { ok: true, error: None, status },
+            Err(e) => SocketReply { ok: false, error: Some(e.to_string()), status: None }
+        }
+    }
+}
+
+/// listen on the socket at the path, replacing any left behind by an earlier run,
+/// and forward the commands that arrive to the director
+pub fn start(path: &str, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<()> {
+    if Path::new(path).exists() {
+        fs::remove_file(path).with_context(|| format!("Could not remove stale control socket: {}", path))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Could not listen on control socket: {}", path))?;
+    info!("Listening for control commands on {}", path);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let tx = tx.clone();
+                    let session_log = session_log.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(stream, tx, session_log) {
+                            warn!("control connection closed with error: {}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("Could not accept control connection: {}", e)
+            }
+        }
+    });
+    Ok(())
+}
+
+/// answer one connection's commands until it closes
+fn serve(stream: UnixStream, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        debug!("control command: {}", line);
+        let result = serde_json::from_str::<SocketCommand>(&line)
+            .map_err(|e| anyhow!("Could not parse command: {}", e))
+            .and_then(|command| execute(command, ControlSource::Socket, &tx, &session_log));
+        serde_json::to_writer(&mut writer, &SocketReply::from(result))?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// act on a command from the source, the websocket's as well as the socket's
+pub fn execute(command: SocketCommand, source: ControlSource, tx: &Sender<DirectorMessage>, session_log: &SessionLog) -> Result<Option<ShowStatus>> {
+    let message = match command {
+        SocketCommand::Fire { cue } => return fire(tx, source, cue, true).map(|_| None),
+        SocketCommand::Release { cue } => return fire(tx, source, cue, false).map(|_| None),
+        SocketCommand::StartClip { clip } => return play(tx, source, clip, true).map(|_| None),
+        SocketCommand::StopClip { clip } => return play(tx, source, clip, false).map(|_| None),
+        SocketCommand::Brightness { percent } if percent > 100 => return Err(anyhow!("Brightness must be 0-100%, not: {}", percent)),
+        SocketCommand::Brightness { percent } => DirectorMessage::GrandMaster { source, percent },
+        SocketCommand::Status => return status(tx, REPLY_TIMEOUT).map(Some),
+        SocketCommand::QueryStatus => {
+            let (reply_tx, reply_rx) = bounded(1);
+            tx.send(DirectorMessage::QueryStatus { source, reply: reply_tx }).map_err(|_| anyhow!("The show is not running"))?;
+            // the director answers once the last receiver has
+            return reply_rx.recv_timeout(QUERY_REPLY_TIMEOUT).map_err(|_| anyhow!("The show did not finish querying the receivers"))?.map(Some)
+        },
+        SocketCommand::Reload => {
+            session_log.record(&source.to_string(), "reload", "");
+            DirectorMessage::Reload
+        },
+        SocketCommand::Shutdown => {
+            // the signal handling loop shuts the show down and exits the process
+            session_log.record(&source.to_string(), "shutdown", "");
+            raise(SIGTERM)?;
+            return Ok(None)
+        },
+        SocketCommand::Replay { window_millis } => DirectorMessage::Replay { window: window_millis.map(Duration::from_millis) },
+        SocketCommand::Mute { groups } => DirectorMessage::Mute { source, groups },
+        SocketCommand::SetVariable { name, value } => DirectorMessage::SetVariable { source, name, value },
+        SocketCommand::Crossfade { scene } => DirectorMessage::Crossfade { source, scene },
+        SocketCommand::Go => DirectorMessage::CueList { source, action: CueListAction::Go },
+        SocketCommand::Back => DirectorMessage::CueList { source, action: CueListAction::Back },
+        SocketCommand::Jump { step } => DirectorMessage::CueList { source, action: CueListAction::Jump(step) },
+        SocketCommand::Tag { tag, operation } => DirectorMessage::Tag { source, tag, operation },
+        SocketCommand::Sleep { wake_on_packet } => DirectorMessage::Sleep { source, wake_on_packet: wake_on_packet.unwrap_or(true) },
+        SocketCommand::Wake => DirectorMessage::Wake { source },
+        SocketCommand::Blackout => DirectorMessage::Blackout { source },
+        SocketCommand::Record { path } => DirectorMessage::Record { source, path },
+        SocketCommand::ResetPacketIds => DirectorMessage::ResetPacketIds { source }
+    };
+    tx.send(message).map_err(|_| anyhow!("The show is not running"))?;
+    Ok(None)
+}
+
+/// have the director fire or release a cue, and wait to hear how it went
+fn fire(tx: &Sender<DirectorMessage>, source: ControlSource, cue: String, on: bool) -> Result<()> {
+    let (reply_tx, reply_rx) = bounded(1);
+    tx.send(DirectorMessage::Cue { source, cue, on, reply: Some(reply_tx) })
+        .map_err(|_| anyhow!("The show is not running"))?;
+    // the reply channel closes unanswered if the show isn't running to act on it
+    reply_rx.recv_timeout(REPLY_TIMEOUT).map_err(|_| anyhow!("The show did not act on the cue"))?
+}
+
+/// have the director start or stop a clip, and wait to hear how it went
+fn play(tx: &Sender<DirectorMessage>, source: ControlSource, clip: String, start: bool) -> Result<()> {
+    let (reply_tx, reply_rx) = bounded(1);
+    tx.send(DirectorMessage::Clip { source, clip, start, reply: Some(reply_tx) })
+        .map_err(|_| anyhow!("The show is not running"))?;
+    reply_rx.recv_timeout(REPLY_TIMEOUT).map_err(|_| anyhow!("The show did not act on the clip"))?
+}
+
+/// ask the director for every cue in the show
+pub fn cues(tx: &Sender<DirectorMessage>) -> Result<Vec<CueListing>> {
+    let (reply_tx, reply_rx) = bounded(1);
+    tx.send(DirectorMessage::Cues { reply: reply_tx }).map_err(|_| anyhow!("The show is not running"))?;
+    reply_rx.recv_timeout(REPLY_TIMEOUT).map_err(|_| anyhow!("The show is not running"))
+}
+
+/// ask the director what the show is doing, waiting up to the timeout for an answer
+fn status(tx: &Sender<DirectorMessage>, timeout: Duration) -> Result<ShowStatus> {
+    let (reply_tx, reply_rx) = bounded(1);
+    tx.send(DirectorMessage::Status { reply: reply_tx }).map_err(|_| anyhow!("The show is not running"))?;
+    reply_rx.recv_timeout(timeout).map_err(|_| anyhow!("The show is not running"))
+}
@@ -0,0 +1,207 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use anyhow::{Context, Result, anyhow};
+use clap::{Args, Subcommand};
+use serde_json::json;
+
+use crate::control::SocketReply;
+use crate::showstate::ShowStatus;
+
+///
+/// The client side of the control socket, so an operator (or a script) can drive
+/// a running transmitter from another shell, eg `lights-xmit ctl cue fire intro`
+///
+
+#[derive(Args, Debug)]
+pub struct CtlArgs {
+    /// the control socket to talk to, defaults to the control_socket in the config
+    #[arg(long, value_name = "PATH")]
+    pub socket: Option<PathBuf>,
+
+    /// print the transmitter's reply as JSON, for scripts
+    #[arg(long)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub action: CtlAction
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlAction {
+    /// fire or release a cue by name
+    Cue {
+        #[command(subcommand)]
+        action: CueAction
+    },
+    /// mute or adjust every cue carrying a tag
+    Tag {
+        tag: String,
+        #[command(subcommand)]
+        action: TagAction
+    },
+    /// stop everything and turn every receiver off
+    Blackout,
+    /// record every packet sent, for playing back with --attract
+    Record {
+        #[command(subcommand)]
+        action: RecordAction
+    },
+    /// start the packet ids again from a random point
+    ResetPacketIds,
+    /// ask every receiver for its battery and uptime, and report them
+    Battery,
+    /// reload the show
+    Reload,
+    /// show what the show is doing
+    Status
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CueAction {
+    Fire {
+        /// the cue's name (several words are joined with spaces)
+        #[arg(required = true)]
+        name: Vec<String>
+    },
+    Release {
+        #[arg(required = true)]
+        name: Vec<String>
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TagAction {
+    Mute,
+    Unmute,
+    /// override the envelope times of the tagged cues, in milliseconds
+    Adjust {
+        #[arg(long)]
+        attack: Option<u32>,
+        #[arg(long)]
+        sustain: Option<u32>,
+        #[arg(long)]
+        release: Option<u32>
+    },
+    /// drop the adjustments to the tagged cues
+    Reset
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RecordAction {
+    /// start recording to the file, replacing anything in it
+    Start { file: PathBuf },
+    Stop
+}
+
+/// send one command to the socket and report the reply. fails (so the process exits
+/// non-zero) if the transmitter couldn't carry the command out
+pub fn run(args: &CtlArgs, socket: &PathBuf) -> Result<()> {
+    let command = match &args.action {
+        CtlAction::Cue { action: CueAction::Fire { name } } => json!({ "command": "fire", "cue": name.join(" ") }),
+        CtlAction::Cue { action: CueAction::Release { name } } => json!({ "command": "release", "cue": name.join(" ") }),
+        CtlAction::Tag { tag, action } => {
+            let operation = match action {
+                TagAction::Mute => json!({ "op": "mute" }),
+                TagAction::Unmute => json!({ "op": "unmute" }),
+                TagAction::Adjust { attack, sustain, release } =>
+                    json!({ "op": "adjust", "attack": attack, "sustain": sustain, "release": release }),
+                TagAction::Reset => json!({ "op": "reset" })
+            };
+            json!({ "command": "tag", "tag": tag, "operation": operation })
+        },
+        CtlAction::Blackout => json!({ "command": "blackout" }),
+        // the transmitter may well be running somewhere else
+        CtlAction::Record { action: RecordAction::Start { file } } =>
+            json!({ "command": "record", "path": std::env::current_dir()?.join(file) }),
+        CtlAction::Record { action: RecordAction::Stop } => json!({ "command": "record", "path": null }),
+        CtlAction::ResetPacketIds => json!({ "command": "reset_packet_ids" }),
+        CtlAction::Battery => json!({ "command": "query_status" }),
+        CtlAction::Reload => json!({ "command": "reload" }),
+        CtlAction::Status => json!({ "command": "status" })
+    };
+    let mut stream = UnixStream::connect(socket)
+        .with_context(|| format!("Could not connect to control socket: {}, is the transmitter running?", socket.display()))?;
+    writeln!(stream, "{}", command)?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    let reply: SocketReply = serde_json::from_str(&line).context("Could not parse the transmitter's reply")?;
+    if args.json {
+        print!("{}", line);
+    } else if let (CtlAction::Battery, Some(status)) = (&args.action, &reply.status) {
+        print_battery_report(status);
+    } else if let Some(status) = &reply.status {
+        print_status(status);
+    } else if reply.ok {
+        println!("ok");
+    }
+    if reply.ok {
+        Ok(())
+    } else {
+        Err(anyhow!(reply.error.unwrap_or_else(|| "The transmitter refused the command".to_string())))
+    }
+}
+
+fn print_status(status: &ShowStatus) {
+    let list = |items: &[String]| if items.is_empty() { "none".to_string() } else { items.join(", ") };
+    println!("show:      {}", status.show_file);
+    if let Some(song) = &status.active_song {
+        println!("song:      {}", song);
+    }
+    println!("cues on:   {}", list(&status.active_cues));
+    println!("clips:     {}", list(&status.playing_clips));
+    if let Some(tempo) = status.tempo {
+        println!("tempo:     {:.1} bpm", tempo);
+    }
+    println!("muted:     {}", list(&status.muted_groups));
+    if let Some(step) = status.cue_list_step {
+        println!("cue list:  step {}", step);
+    }
+    if !status.muted_tags.is_empty() {
+        println!("muted tags: {}", list(&status.muted_tags));
+    }
+    for (name, value) in status.variables.iter() {
+        println!("variable:  {}={}", name, value);
+    }
+    if status.preview {
+        println!("preview:   on");
+    }
+    if status.asleep {
+        println!("receivers: asleep");
+    }
+    if let Some(packet_id) = status.packet_id {
+        println!("packet id: {}", packet_id);
+    }
+    for link in status.links.iter() {
+        let quality = match &link.quality {
+            Some(q) => format!("heard at {} dBm, answered at {}", q.at_receiver,
+                q.at_transmitter.map_or("?".to_string(), |rssi| format!("{:.0} dBm", rssi))),
+            None => "no answer".to_string()
+        };
+        println!("link:      {} {}{}", link.receiver, quality, if link.marginal { " (marginal)" } else { "" });
+    }
+    for receiver in status.telemetry.iter() {
+        let telemetry = match &receiver.telemetry {
+            Some(t) => format!("battery {:.2} V, up {} min", t.battery_millivolts as f32 / 1000.0, t.uptime_minutes),
+            None => "no answer".to_string()
+        };
+        println!("status:    {} {} ({}s ago){}", receiver.receiver, telemetry, receiver.age_secs,
+            if receiver.low_battery { " (low battery)" } else { "" });
+    }
+}
+
+/// every receiver's battery, lowest first, with those that didn't answer at the end
+fn print_battery_report(status: &ShowStatus) {
+    if status.telemetry.is_empty() {
+        println!("no receivers can report their battery");
+    }
+    let mut receivers: Vec<_> = status.telemetry.iter().collect();
+    receivers.sort_by_key(|r| r.telemetry.map_or(u16::MAX, |t| t.battery_millivolts));
+    for receiver in receivers {
+        match &receiver.telemetry {
+            Some(t) => println!("{:<12} {:.2} V  up {} min{}", receiver.receiver, t.battery_millivolts as f32 / 1000.0,
+                t.uptime_minutes, if receiver.low_battery { "  LOW" } else { "" }),
+            None => println!("{:<12} no answer", receiver.receiver)
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+use chrono::Local;
+
+use crate::showstate::{MutableShowState, ShowState};
+use crate::clock;
+
+///
+/// The dashboard turns the terminal into a live view of the show for rehearsals: a
+/// table of the receivers (what each is showing, and how long since a packet went to
+/// it or its group), the clips playing, and the latest midi and the cues it fired.
+/// It's redrawn in place a few times a second with plain ANSI escapes, so any terminal
+/// (or ssh session) will do. Logging still goes to stderr, so send it elsewhere
+///
+
+/// how often the dashboard is redrawn
+const REDRAW_PERIOD: Duration = Duration::from_millis(250);
+/// how many midi events are listed, newest first
+const MIDI_LINES: usize = 12;
+
+/// clear each line as it's drawn over, and the rest of the screen after the last
+const CLEAR_LINE: &str = "\x1b[K";
+const CLEAR_BELOW: &str = "\x1b[J";
+const HOME: &str = "\x1b[H";
+const CLEAR_SCREEN: &str = "\x1b[2J";
+
+pub struct Dashboard {
+    /// the latest midi events and what they did, with the local time they arrived
+    midi: RefCell<VecDeque<(String, String, String)>>,
+    drawn_at: Cell<Option<Instant>>
+}
+
+impl Dashboard {
+
+    /// clear the terminal for the dashboard to draw on
+    pub fn start() -> Dashboard {
+        print!("{}", CLEAR_SCREEN);
+        Dashboard { midi: RefCell::new(VecDeque::new()), drawn_at: Cell::new(None) }
+    }
+
+    /// note a midi event (or a cue it fired), as the session log records them
+    pub fn midi(self: &Self, action: &str, detail: &str) {
+        let mut midi = self.midi.borrow_mut();
+        if midi.len() >= MIDI_LINES {
+            midi.pop_back();
+        }
+        midi.push_front((Local::now().format("%H:%M:%S%.3f").to_string(), action.to_string(), detail.to_string()));
+    }
+
+    /// redraw the dashboard if it's due, returning when it's next due
+    pub fn refresh(self: &Self, state: &ShowState, mutable_state: &MutableShowState) -> Instant {
+        let now = clock::now();
+        if let Some(drawn_at) = self.drawn_at.get().filter(|at| now - *at < REDRAW_PERIOD) {
+            return drawn_at + REDRAW_PERIOD
+        }
+        self.drawn_at.set(Some(now));
+        let screen = self.render(state, mutable_state, now);
+        let mut stdout = io::stdout().lock();
+        // a closed terminal isn't the show's problem
+        let _ = stdout.write_all(screen.as_bytes()).and_then(|_| stdout.flush());
+        now + REDRAW_PERIOD
+    }
+
+    fn render(self: &Self, state: &ShowState, mutable_state: &MutableShowState, now: Instant) -> String {
+        let status = state.status(mutable_state);
+        let mut screen = String::from(HOME);
+        let mut line = |text: String| {
+            let _ = writeln!(screen, "{}{}", text, CLEAR_LINE);
+        };
+        line(format!("{}   song: {}   tempo: {}{}{}",
+            status.show_file,
+            status.active_song.as_deref().unwrap_or("-"),
+            status.tempo.map_or("-".to_string(), |t| format!("{:.1}", t)),
+            if status.preview { "   PREVIEW" } else { "" },
+            if status.asleep { "   ASLEEP" } else { "" }));
+        line(String::new());
+        line(format!("{:>4}  {:<16} {:<12} {:<24} {}", "id", "receiver", "group", "cue", "last packet"));
+        for receiver in state.receiver_summaries(mutable_state) {
+            line(format!("{:>4}  {:<16} {:<12} {:<24} {}",
+                receiver.id,
+                receiver.name.as_deref().unwrap_or("-"),
+                receiver.group.as_deref().unwrap_or("-"),
+                receiver.cue.as_deref().unwrap_or("-"),
+                receiver.last_sent.map_or("-".to_string(), |at| format!("{:.1}s ago", (now - at).as_secs_f32()))));
+        }
+        line(String::new());
+        line(format!("clips playing: {}", if status.playing_clips.is_empty() { "-".to_string() } else { status.playing_clips.join(", ") }));
+        line(String::new());
+        line("midi".to_string());
+        for (at, action, detail) in self.midi.borrow().iter() {
+            line(format!("  {}  {:<20} {}", at, action, detail));
+        }
+        screen.push_str(CLEAR_BELOW);
+        screen
+    }
+}
@@ -1,63 +1,530 @@
 use std::path::PathBuf;
+use std::cell::{Cell,RefCell};
 use anyhow::Context;
-use crossbeam_channel::Receiver;
-use crossbeam_channel::RecvTimeoutError;
+use crossbeam_channel::{Receiver,Sender,select};
+use crossbeam_channel::{RecvError,RecvTimeoutError};
 use midly::live::LiveEvent;
 use midly::MidiMessage;
-use std::fs::File;
-use log::{debug,info,error};
-use std::time::Duration;
-use json_comments::StripComments;
+use log::{debug,info,warn,error};
+use std::time::{Duration,Instant};
 
-use crate::show::ShowDefinition;
-use crate::config::ConfigFile;
+use crate::config::{BrightnessSchedule,ConfigFile};
 use crate::radio::Radio;
-use crate::showstate::ShowState;
+use crate::showstate::{CueListAction,CueListing,MutableShowState,RadioReport,ShowState,ShowStatus,TagOperation};
+use crate::show::ShowDefinition;
+use crate::showfile;
+use crate::check;
+use crate::session::SessionLog;
+use crate::metronome::Metronome;
+use crate::feedback::PadFeedback;
+use crate::padsetup::PadSetup;
+use crate::midifilter::MidiFilter;
+use crate::fastlane::{FastLaneMessage,Lanes,MidiRouter,RealtimeNotes};
+use crate::websocket::EventStream;
+use crate::mqtt::MqttLink;
+use crate::dashboard::Dashboard;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use crate::midi::MidiSender;
+use crate::arbitration::{Arbiter,ControlSource};
+use crate::clock;
+use crate::statusled::{Health,StatusLed};
+use crate::error::{ChsError,Recovery};
 
 /// This module is where a lot of the action happens. MIDI message
 /// meet show configuration to fire radio packets.
 
 const RESET_CONTROLLER: u8 = 103;
 
+/// warn when the channel is at least this full (as a percentage of capacity)
+const NEAR_CAPACITY_PERCENT: usize = 75;
+/// how long to wait before ticking again after a tick failed and the show carried on
+const FAILED_TICK_BACKOFF: Duration = Duration::from_millis(100);
+
 pub enum DirectorMessage {
-    /// deliver a payload of a midi event
-    MidiMessage { ts: u64, buf: Vec<u8> },
+    /// deliver a payload of a midi event, with when it arrived
+    MidiMessage { ts: u64, buf: Vec<u8>, received: Instant },
 
     /// shut down the event loop and exit the run_show routine
     Shutdown,
 
     /// reload the show config and then reinitialize receivers and show state
     Reload,
+
+    /// as reload, but with a show already loaded (and validated) from the show file,
+    /// rather than reading the file again
+    LoadShow(Box<ShowDefinition>),
+
+    /// re-fire the last cue (no window) or every cue in the trailing window,
+    /// with original relative timing
+    Replay { window: Option<Duration> },
+
+    /// mute every cue to the named groups for rehearsal (replacing any earlier
+    /// muting, an empty list unmutes). muting carries over show reloads
+    Mute { source: ControlSource, groups: Vec<String> },
+
+    /// set a runtime variable of the show to one of its values
+    SetVariable { source: ControlSource, name: String, value: String },
+
+    /// crossfade from whatever is showing to the named scene
+    Crossfade { source: ControlSource, scene: String },
+
+    /// go to the next, or previous, step of the show's cue list, or jump to a step
+    CueList { source: ControlSource, action: CueListAction },
+
+    /// mute or adjust every cue carrying a tag
+    Tag { source: ControlSource, tag: String, operation: TagOperation },
+
+    /// put the receivers into low power idle, until woken by any packet or only a reset
+    Sleep { source: ControlSource, wake_on_packet: bool },
+
+    /// wake sleeping receivers and configure them again
+    Wake { source: ControlSource },
+
+    /// fire or release a cue by name, as its midi trigger would, sending
+    /// the outcome back if a reply channel is given
+    Cue { source: ControlSource, cue: String, on: bool, reply: Option<Sender<anyhow::Result<()>>> },
+
+    /// start or stop a clip by name, sending the outcome back if a reply channel is given
+    Clip { source: ControlSource, clip: String, start: bool, reply: Option<Sender<anyhow::Result<()>>> },
+
+    /// set the grand master, in percent
+    GrandMaster { source: ControlSource, percent: u8 },
+
+    /// stop everything and turn every receiver off
+    Blackout { source: ControlSource },
+
+    /// start recording every packet sent to the file at the path (replacing any earlier
+    /// recording), for playing back later, or with no path stop recording
+    Record { source: ControlSource, path: Option<String> },
+
+    /// start the packet ids again from a random point
+    ResetPacketIds { source: ControlSource },
+
+    /// ask every receiver for its battery and uptime now, replying with the show's status
+    /// once they have all answered (or not)
+    QueryStatus { source: ControlSource, reply: Sender<anyhow::Result<ShowStatus>> },
+
+    /// report what the show is doing. unanswered if no show is running
+    Status { reply: Sender<ShowStatus> },
+
+    /// list every cue in the show. unanswered if no show is running
+    Cues { reply: Sender<Vec<CueListing>> },
+
+    /// the levels of a DMX universe from sACN, channel 1 first
+    Dmx { universe: u16, levels: Vec<u8> },
+
+    /// what the radio heard back from a receiver in the background
+    Radio(RadioReport),
+}
+
+/// where the director's messages come from: the channel fed by midi and signal
+/// handling (and the fast lane for realtime notes, taken from first), or in tests a
+/// script of messages timed on the virtual clock, whose messages for the same moment
+/// arrive together through the same lanes. either way, what the radio reports back
+/// from its thread is taken ahead of anything else waiting, as it's already late
+enum Inbox {
+    Channel { lanes: Lanes, reports: Receiver<RadioReport> },
+    #[cfg(test)]
+    Script { script: RefCell<std::collections::VecDeque<(Instant, DirectorMessage)>>, router: MidiRouter, tx: Sender<DirectorMessage>,
+        lanes: Lanes, reports: Receiver<RadioReport> }
+}
+
+impl Inbox {
+
+    fn recv(self: &Self) -> Result<DirectorMessage, RecvError> {
+        loop {
+            if let Some(message) = self.waiting() {
+                return Ok(message)
+            }
+            match self {
+                Inbox::Channel { lanes, reports } => {
+                    let (realtime, rx) = (lanes.realtime(), lanes.rx());
+                    select! {
+                        recv(realtime) -> message => if let Some(message) = lanes.arrived(message?) {
+                            return Ok(message)
+                        },
+                        recv(reports) -> report => return report.map(DirectorMessage::Radio),
+                        recv(rx) -> message => return message.map(|message| lanes.taken(message))
+                    }
+                },
+                #[cfg(test)]
+                Inbox::Script { script, .. } => {
+                    let at = script.borrow().front().ok_or(RecvError)?.0;
+                    clock::advance_to(at);
+                    self.deliver(at);
+                }
+            }
+        }
+    }
+
+    fn recv_timeout(self: &Self, timeout: Duration) -> Result<DirectorMessage, RecvTimeoutError> {
+        let deadline = std::time::Instant::now() + timeout;
+        #[cfg(test)]
+        let virtual_deadline = clock::now() + timeout;
+        loop {
+            if let Some(message) = self.waiting() {
+                return Ok(message)
+            }
+            match self {
+                Inbox::Channel { lanes, reports } => {
+                    let (realtime, rx) = (lanes.realtime(), lanes.rx());
+                    select! {
+                        recv(realtime) -> message => if let Some(message) = lanes.arrived(message.map_err(|_| RecvTimeoutError::Disconnected)?) {
+                            return Ok(message)
+                        },
+                        recv(reports) -> report => return report.map(DirectorMessage::Radio).map_err(|_| RecvTimeoutError::Disconnected),
+                        recv(rx) -> message => return message.map(|message| lanes.taken(message)).map_err(|_| RecvTimeoutError::Disconnected),
+                        default(deadline.saturating_duration_since(std::time::Instant::now())) => return Err(RecvTimeoutError::Timeout)
+                    }
+                },
+                #[cfg(test)]
+                Inbox::Script { script, .. } => {
+                    let front = script.borrow().front().map(|(at, _)| *at);
+                    match front {
+                        Some(at) if at <= virtual_deadline => {
+                            clock::advance_to(at);
+                            self.deliver(at);
+                        },
+                        Some(_) => {
+                            clock::advance_to(virtual_deadline);
+                            return Err(RecvTimeoutError::Timeout)
+                        },
+                        None => return Err(RecvTimeoutError::Disconnected)
+                    }
+                }
+            }
+        }
+    }
+
+    /// a message already waiting: a realtime note, a radio report, then anything else
+    fn waiting(self: &Self) -> Option<DirectorMessage> {
+        match self {
+            Inbox::Channel { lanes, reports } =>
+                lanes.realtime_waiting().or_else(|| reports.try_recv().ok().map(DirectorMessage::Radio)),
+            #[cfg(test)]
+            Inbox::Script { lanes, reports, .. } =>
+                lanes.realtime_waiting().or_else(|| reports.try_recv().ok().map(DirectorMessage::Radio)).or_else(|| lanes.try_recv())
+        }
+    }
+
+    /// the script's messages for a moment, queued up together as a backlog would be
+    #[cfg(test)]
+    fn deliver(self: &Self, at: Instant) {
+        if let Inbox::Script { script, router, tx, .. } = self {
+            let mut script = script.borrow_mut();
+            while script.front().is_some_and(|(due, _)| *due == at) {
+                match script.pop_front().unwrap().1 {
+                    DirectorMessage::MidiMessage { ts, buf, .. } => router.route(ts, &buf).unwrap(),
+                    message => tx.send(message).unwrap()
+                }
+            }
+        }
+    }
+
+    /// how many messages are waiting, and how many can wait before senders block
+    fn depth(self: &Self) -> (usize, Option<usize>) {
+        match self {
+            Inbox::Channel { lanes, reports } => (lanes.len() + reports.len(), lanes.capacity()),
+            #[cfg(test)]
+            Inbox::Script { .. } => (0, None)
+        }
+    }
+
+    fn drain(self: &Self) -> Vec<DirectorMessage> {
+        match self {
+            Inbox::Channel { lanes, reports } =>
+                reports.try_iter().map(DirectorMessage::Radio).chain(lanes.drain()).collect(),
+            #[cfg(test)]
+            Inbox::Script { lanes, reports, .. } =>
+                reports.try_iter().map(DirectorMessage::Radio).chain(lanes.drain()).collect()
+        }
+    }
+}
+
+/// back-pressure on the director's channel: how deep it gets and how fast messages
+/// arrive, so that channel_buf_depth can be tuned from data rather than guesswork
+struct ChannelStats {
+    high_water: Cell<usize>,
+    /// the start of the current one second rate window, and messages received in it
+    window_start: Cell<Instant>,
+    window_count: Cell<u32>,
+    peak_rate: Cell<u32>,
+    capacity: Cell<Option<usize>>,
+    last_warning: Cell<Option<Instant>>
+}
+
+impl ChannelStats {
+
+    fn new() -> ChannelStats {
+        ChannelStats {
+            high_water: Cell::new(0),
+            window_start: Cell::new(clock::now()),
+            window_count: Cell::new(0),
+            peak_rate: Cell::new(0),
+            capacity: Cell::new(None),
+            last_warning: Cell::new(None)
+        }
+    }
+
+    /// note a received message, given the depth of the channel (not counting it) at the time
+    fn record(self: &Self, depth: usize, capacity: Option<usize>) {
+        let now = clock::now();
+        self.capacity.set(capacity);
+        self.high_water.set(self.high_water.get().max(depth + 1));
+
+        if now - self.window_start.get() >= Duration::from_secs(1) {
+            debug!("midi channel: depth {}, high water {}, {} messages/sec",
+                depth, self.high_water.get(), self.window_count.get());
+            self.window_start.set(now);
+            self.window_count.set(0);
+        }
+        self.window_count.set(self.window_count.get() + 1);
+        self.peak_rate.set(self.peak_rate.get().max(self.window_count.get()));
+
+        if let Some(capacity) = capacity {
+            // no more than one warning a second, so the warnings don't add to the backlog
+            if (depth + 1) * 100 >= capacity * NEAR_CAPACITY_PERCENT &&
+                self.last_warning.get().map_or(true, |at| now - at >= Duration::from_secs(1)) {
+                warn!("midi channel near capacity: {} of {} messages waiting, consider raising channel_buf_depth",
+                    depth + 1, capacity);
+                self.last_warning.set(Some(now));
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ChannelStats {
+    fn fmt(self: &Self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "high water {} of {}, peak {} messages/sec", self.high_water.get(),
+            self.capacity.get().map_or("unbounded".to_string(), |c| c.to_string()), self.peak_rate.get())
+    }
 }
 
 pub struct Director {
     config: ConfigFile,
     radio: Radio,
-    rx: Receiver<DirectorMessage>
+    rx: Inbox,
+    session_log: SessionLog,
+    metronome: Option<Metronome>,
+    pad_feedback: Option<PadFeedback>,
+    pad_setup: Option<PadSetup>,
+    status_led: Option<StatusLed>,
+    /// websocket clients following the show
+    event_stream: Option<EventStream>,
+    /// the MQTT broker the show's events are published to
+    mqtt: Option<MqttLink>,
+    /// the terminal dashboard, if the transmitter was started with one
+    dashboard: Option<Dashboard>,
+    midi_filter: MidiFilter,
+    /// the notes of the show's realtime mappings, which the midi router sends down the fast lane
+    realtime_notes: Option<RealtimeNotes>,
+    /// where the radio reports what it hears back in the background, to the inbox
+    reports: Sender<RadioReport>,
+    /// has a show been loaded since the transmitter started
+    started: Cell<bool>,
+    /// a show handed over to load next, in place of reading the show file
+    next_show: RefCell<Option<ShowDefinition>>,
+    /// groups muted for rehearsal
+    muted_groups: RefCell<Vec<String>>,
+    /// changes made to tagged cues, made again when the show reloads
+    tag_operations: RefCell<Vec<(String,TagOperation)>>,
+    /// when the show is scheduled to end, until it has
+    show_end: Cell<Option<Instant>>,
+    /// did the show end on schedule with the transmitter set to exit, or fail
+    /// with an error the config says to exit on
+    exit_requested: Cell<bool>,
+    /// when the receivers are next scheduled to sleep, and to wake
+    sleep_at: Cell<Option<Instant>>,
+    wake_at: Cell<Option<Instant>>,
+    arbiter: Arbiter,
+    channel_stats: ChannelStats
 }
 
 impl Director {
 
-    pub fn new(config: ConfigFile, radio: Radio, rx: Receiver<DirectorMessage>) -> Director {
+    /// the metronome and the pad controller, if configured, share the midi output
+    pub fn new(config: ConfigFile, radio: Radio, rx: Receiver<DirectorMessage>, session_log: SessionLog,
+        midi_out: Option<Arc<MidiSender>>, status_led: Option<StatusLed>, event_stream: Option<EventStream>) -> anyhow::Result<Director> {
+        let show_end = config.show_end.as_ref().map(|e| e.deadline()).transpose().map_err(ChsError::Config)?.flatten();
+        // check the sleep schedule's times now rather than when the show loads
+        config.sleep.as_ref().map(|s| s.deadlines()).transpose().map_err(ChsError::Config)?;
+        config.brightness_schedule.as_deref().map(BrightnessSchedule::new).transpose()
+            .context("Invalid brightness schedule").map_err(ChsError::Config)?;
+        let (metronome, pad_feedback, pad_setup) = Self::midi_out_users(&config, midi_out)?;
+        let midi_filter = MidiFilter::new(config.midi_filter.as_ref()).context("Invalid midi filter").map_err(ChsError::Config)?;
+        let (reports, reports_rx) = crossbeam_channel::unbounded();
+        Ok(Director {
+            arbiter: Arbiter::new(&config.arbitration),
+            config,
+            radio,
+            rx: Inbox::Channel { lanes: Lanes::new(rx), reports: reports_rx },
+            session_log,
+            metronome,
+            pad_feedback,
+            pad_setup,
+            status_led,
+            event_stream,
+            mqtt: None,
+            dashboard: None,
+            midi_filter,
+            realtime_notes: None,
+            reports,
+            started: Cell::new(false),
+            next_show: RefCell::new(None),
+            muted_groups: RefCell::new(vec![]),
+            tag_operations: RefCell::new(vec![]),
+            show_end: Cell::new(show_end),
+            exit_requested: Cell::new(false),
+            sleep_at: Cell::new(None),
+            wake_at: Cell::new(None),
+            channel_stats: ChannelStats::new()
+        })
+    }
+
+    /// the metronome and the pad controller parts the config asks for, sharing the midi output
+    fn midi_out_users(config: &ConfigFile, midi_out: Option<Arc<MidiSender>>)
+        -> anyhow::Result<(Option<Metronome>, Option<PadFeedback>, Option<PadSetup>)> {
+        let output_for = |what: &str| {
+            if midi_out.is_none() {
+                warn!("{} configured without a MIDI port, ignoring", what);
+            }
+            midi_out.clone()
+        };
+        let metronome = config.metronome.as_ref()
+            .and_then(|c| output_for("Metronome").map(|output| Metronome::new(c, output)));
+        let pad_feedback = config.pad_feedback.as_ref()
+            .and_then(|c| output_for("Pad feedback").map(|output| PadFeedback::new(c, output)));
+        let pad_setup = config.pad_setup.as_ref()
+            .and_then(|c| output_for("Pad setup").map(|output| PadSetup::new(c, output)))
+            .transpose().context("Invalid sysex prefix for pad setup").map_err(ChsError::Config)?;
+        Ok((metronome, pad_feedback, pad_setup))
+    }
+
+    /// a director that plays a script of messages timed on the virtual clock, for tests
+    #[cfg(test)]
+    pub fn scripted(config: ConfigFile, radio: Radio, script: Vec<(Instant, DirectorMessage)>) -> Director {
+        let show_end = config.show_end.as_ref().and_then(|e| e.deadline().unwrap());
+        let (_, pad_feedback, pad_setup) = Self::midi_out_users(&config, Some(Arc::new(MidiSender::Mock(Mutex::new(vec![]))))).unwrap();
+        let midi_filter = MidiFilter::new(config.midi_filter.as_ref()).unwrap();
+        let (reports, reports_rx) = crossbeam_channel::unbounded();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (router, realtime_rx) = MidiRouter::new(tx.clone(), MidiFilter::new(config.midi_filter.as_ref()).unwrap());
+        let realtime_notes = Some(router.realtime_notes());
+        let mut lanes = Lanes::new(rx);
+        lanes.use_fast_lane(realtime_rx);
         Director {
+            arbiter: Arbiter::new(&config.arbitration),
             config,
             radio,
-            rx
+            rx: Inbox::Script { script: RefCell::new(script.into()), router, tx, lanes, reports: reports_rx },
+            session_log: SessionLog::open(&None).unwrap(),
+            metronome: None,
+            pad_feedback,
+            pad_setup,
+            status_led: None,
+            event_stream: None,
+            mqtt: None,
+            dashboard: None,
+            midi_filter,
+            realtime_notes,
+            reports,
+            started: Cell::new(false),
+            next_show: RefCell::new(None),
+            muted_groups: RefCell::new(vec![]),
+            tag_operations: RefCell::new(vec![]),
+            show_end: Cell::new(show_end),
+            exit_requested: Cell::new(false),
+            sleep_at: Cell::new(None),
+            wake_at: Cell::new(None),
+            channel_stats: ChannelStats::new()
+        }
+    }
+
+    /// take realtime notes from the router's fast lane ahead of everything else
+    pub fn use_fast_lane(self: &mut Self, router: &MidiRouter, realtime_rx: Receiver<FastLaneMessage>) {
+        match &mut self.rx {
+            Inbox::Channel { lanes, .. } => lanes.use_fast_lane(realtime_rx),
+            #[cfg(test)]
+            Inbox::Script { .. } => return
         }
+        self.realtime_notes = Some(router.realtime_notes());
+    }
+
+    /// publish what the show does over MQTT
+    pub fn publish_to_mqtt(self: &mut Self, mqtt: MqttLink) {
+        self.mqtt = Some(mqtt);
+    }
+
+    /// draw the dashboard on the terminal while the show runs
+    pub fn show_dashboard(self: &mut Self) {
+        self.radio.track_sends();
+        self.dashboard = Some(Dashboard::start());
+    }
+
+    #[cfg(test)]
+    pub fn radio(self: &Self) -> &Radio {
+        &self.radio
+    }
+
+    /// the midi sent back (pad setup and feedback share the output), for tests
+    #[cfg(test)]
+    pub fn take_midi_sent(self: &Self) -> Vec<(Instant, Vec<u8>)> {
+        let output = self.pad_feedback.as_ref().map(|f| f.output()).or(self.pad_setup.as_ref().map(|s| s.output()));
+        output.map_or(vec![], |o| o.take_sent())
     }
 
     pub fn run_show(self: &mut Self) -> anyhow::Result<()> {
         let show_path = PathBuf::from(&self.config.show_file);
         debug!("Show path is: {:?}", show_path);
         'outer: loop {
-            match self.load_and_run(&show_path) {
+            let result = self.load_and_run(&show_path);
+            // the show's tempo goes away with it, and its cues
+            if let Some(metronome) = &self.metronome {
+                metronome.tick(None);
+            }
+            if let Some(pad_feedback) = &self.pad_feedback {
+                pad_feedback.clear();
+            }
+            if let Some(event_stream) = &self.event_stream {
+                event_stream.restart();
+            }
+            if let Some(mqtt) = &self.mqtt {
+                mqtt.restart();
+            }
+            match result {
                 Ok(false) => break 'outer,
                 Err(e) => {
-                    error!("Error loading/running show, waiting for reload command. Error: {:?}", e);
-                    loop { match self.rx.recv()? {
-                            DirectorMessage::Shutdown => break 'outer,
-                            DirectorMessage::Reload => break,
-                            _ => {}
+                    self.report_error(&e);
+                    let category = ChsError::categorize(&e);
+                    match self.config.recovery(category) {
+                        Recovery::Exit => {
+                            error!("Error loading/running show ({}), exiting. Error: {:?}", category, e);
+                            self.exit_requested.set(true);
+                            break 'outer
+                        },
+                        Recovery::Retry => {
+                            error!("Error loading/running show ({}), loading it again. Error: {:?}", category, e);
+                            // wait out the restart delay, unless told to shut down meanwhile
+                            match self.rx.recv_timeout(self.config.show_restart_delay()) {
+                                Ok(DirectorMessage::Shutdown) | Err(RecvTimeoutError::Disconnected) => break 'outer,
+                                _ => {}
+                            }
+                        },
+                        Recovery::SafeMode => {
+                            error!("Error loading/running show ({}), waiting for reload command. Error: {:?}", category, e);
+                            self.set_health(Health::Error);
+                            loop { match self.rx.recv()? {
+                                    DirectorMessage::Shutdown => break 'outer,
+                                    DirectorMessage::Reload => break,
+                                    DirectorMessage::LoadShow(show) => {
+                                        self.next_show.replace(Some(*show));
+                                        break
+                                    },
+                                    _ => {}
+                                }
+                            }
                         }
                     }
                 },
@@ -68,35 +535,103 @@ impl Director {
         Ok(())
     }
 
+    fn set_health(self: &Self, health: Health) {
+        if let Some(status_led) = &self.status_led {
+            status_led.set_health(health);
+        }
+    }
+
+    /// work out when the receivers next sleep and wake. after a scheduled wake, a
+    /// schedule without a sleep time is done (it only sleeps as the show loads)
+    fn schedule_sleep(self: &Self, after_wake: bool) -> anyhow::Result<()> {
+        let (sleep_at, wake_at) = match &self.config.sleep {
+            Some(schedule) if !(after_wake && schedule.at.is_none()) => {
+                let (sleep_at, wake_at) = schedule.deadlines().map_err(ChsError::Config)?;
+                (Some(sleep_at), Some(wake_at))
+            },
+            _ => (None, None)
+        };
+        self.sleep_at.set(sleep_at);
+        self.wake_at.set(wake_at);
+        Ok(())
+    }
+
+    /// did the show end on schedule, with the transmitter configured to exit afterwards
+    pub fn exit_requested(self: &Self) -> bool {
+        self.exit_requested.get()
+    }
+
+    /// throw away midi that queued up while the show wasn't running, so a restarted
+    /// show doesn't replay stale cues. returns false if a shutdown was requested meanwhile
+    pub fn drain_stale_messages(self: &Self) -> bool {
+        let mut keep_running = true;
+        for message in self.rx.drain() {
+            if let DirectorMessage::Shutdown = message {
+                keep_running = false;
+            }
+        }
+        keep_running
+    }
+
     fn load_and_run(self: &Self, show_path: &PathBuf) -> anyhow::Result<bool> {
-        let file = File::open(&show_path).context("Could not open file")?;
-        let show: ShowDefinition = serde_json::from_reader(StripComments::new(file)).context("Could not parse file")?;
+        let show = match self.next_show.take() {
+            Some(show) => show,
+            None => showfile::load(&show_path)?
+        };
         let state = ShowState::new(&show, &self.radio, &self.config).context("Could not validate show structure")?;
+        check::check_show(&show, &self.config, show.simulate_clips.unwrap_or(false))?;
         let mut mutable_state = state.create_mutable_state().context("Could not validate show structure")?;
+        if let Some(realtime_notes) = &self.realtime_notes {
+            *realtime_notes.write().unwrap() = state.realtime_notes(&mutable_state);
+        }
+        state.report_radio_to(self.reports.clone(), &mut mutable_state);
         state.initialize()?;
+        if let Some(pad_setup) = &self.pad_setup {
+            pad_setup.configure(&state.assigned_pads(&mutable_state).context("Could not assign pads")?);
+        }
+        self.set_health(Health::Loaded);
+        if let Err(e) = state.set_muted_groups(&self.muted_groups.borrow(), &mut mutable_state) {
+            error!("Could not restore muted groups, unmuting. Error: {}", e);
+            self.muted_groups.borrow_mut().clear();
+        }
+        self.tag_operations.borrow_mut().retain(|(tag, operation)| {
+            let result = state.apply_tag_operation(tag, operation, &mut mutable_state);
+            if let Err(e) = &result {
+                warn!("Could not restore change to cues tagged: {}, dropping it. Error: {}", tag, e);
+            }
+            result.is_ok()
+        });
+        if !self.started.replace(true) {
+            state.play_startup_clip();
+        }
+        self.schedule_sleep(false)?;
 
         info!("reset receivers and show state");
+        let result = self.perform(&state, &mut mutable_state);
+        info!("realtime cue latency: {}", mutable_state.realtime_latency);
+        info!("standard cue latency: {}", mutable_state.standard_latency);
+        info!("midi channel: {}", self.channel_stats);
+        result
+    }
+
+    /// the show loop, returns whether the show should be reloaded
+    fn perform(self: &Self, state: &ShowState, mutable_state: &mut MutableShowState) -> anyhow::Result<bool> {
         let mut timeout = Duration::ZERO;
+        // a message read ahead while gathering a chord
+        let mut next: Option<DirectorMessage> = None;
         loop {
-            match self.rx.recv_timeout(timeout) {
+            let received = match next.take() {
+                Some(message) => Ok(message),
+                None => self.rx.recv_timeout(timeout)
+            };
+            match received {
                 Ok(message) => {
-                    match message {
-                        DirectorMessage::Reload => return Ok(true),
-                        DirectorMessage::Shutdown => return Ok(false),
-                        DirectorMessage::MidiMessage { ts: _, buf } => {
-                            let midi_event = midly::live::LiveEvent::parse(&buf)?;
-                            if let LiveEvent::Midi{ channel, message } = midi_event {
-                                if channel == self.config.midi_control_channel {
-                                    if let MidiMessage::Controller { controller, value } = message {
-                                        if controller == RESET_CONTROLLER && value == 127 {
-                                            info!("midi reset received");
-                                            return Ok(true)
-                                        }
-                                    }
-                                }
-                            }
-                            state.process_midi(&midi_event, &mut mutable_state)?;
-                        }
+                    let (depth, capacity) = self.rx.depth();
+                    self.channel_stats.record(depth, capacity);
+                    match self.handle_message(message, state, mutable_state, &mut next) {
+                        Ok(Some(reload)) => return Ok(reload),
+                        Ok(None) => {},
+                        Err(e) => self.recover(e)?
                     }
                 }
                 Err(e) => match e {
@@ -107,8 +642,1568 @@ impl Director {
                     }
                 }
             };
-            timeout = state.tick(&mut mutable_state)?;
+            if let Some(end) = self.show_end.get() {
+                if clock::now() >= end {
+                    return self.end_show(state, mutable_state)
+                }
+            }
+            if self.sleep_at.get().is_some_and(|at| clock::now() >= at) {
+                self.sleep_at.set(None);
+                let wake_on_packet = self.config.sleep.as_ref().and_then(|s| s.wake_on_packet).unwrap_or(true);
+                state.sleep(wake_on_packet, mutable_state)?;
+                self.session_log.record("process", "sleep", "scheduled");
+            }
+            if self.wake_at.get().is_some_and(|at| clock::now() >= at) {
+                state.wake(mutable_state)?;
+                self.session_log.record("process", "wake", "scheduled");
+                self.schedule_sleep(true)?;
+            }
+            timeout = match state.tick(mutable_state) {
+                Ok(timeout) => timeout,
+                Err(e) => {
+                    self.recover(e)?;
+                    FAILED_TICK_BACKOFF
+                }
+            };
+            if let Some(pad_feedback) = &self.pad_feedback {
+                pad_feedback.update(state.lit_pads(mutable_state));
+            }
+            if self.event_stream.is_some() || self.mqtt.is_some() {
+                let snapshot = state.snapshot(mutable_state);
+                if let Some(mqtt) = &self.mqtt {
+                    mqtt.publish(&snapshot);
+                }
+                if let Some(event_stream) = &self.event_stream {
+                    event_stream.publish(snapshot);
+                }
+            }
+            if let Some(dashboard) = &self.dashboard {
+                let redraw_at = dashboard.refresh(state, mutable_state);
+                timeout = timeout.min(redraw_at.saturating_duration_since(clock::now()));
+            }
+            if let Some(metronome) = &self.metronome {
+                if let Some(pulse_at) = metronome.tick(state.master_tempo()) {
+                    timeout = timeout.min(pulse_at.saturating_duration_since(clock::now()));
+                }
+            }
+            for due in [self.show_end.get(), self.sleep_at.get(), self.wake_at.get()].into_iter().flatten() {
+                timeout = timeout.min(due.saturating_duration_since(clock::now()));
+            }
+        }
+    }
+
+    /// decide by its category whether the show carries on after an error. errors to
+    /// retry are logged and dropped, anything else ends the show loop
+    fn recover(self: &Self, error: anyhow::Error) -> anyhow::Result<()> {
+        let category = ChsError::categorize(&error);
+        match self.config.recovery(category) {
+            Recovery::Retry => {
+                error!("Carrying on with the show after {} error: {:#}", category, error);
+                self.report_error(&error);
+                Ok(())
+            },
+            _ => Err(error)
         }
     }
 
-}
\ No newline at end of file
+    /// tell websocket clients and the MQTT broker about an error the show hit
+    fn report_error(self: &Self, error: &anyhow::Error) {
+        let error = format!("{:#}", error);
+        if let Some(event_stream) = &self.event_stream {
+            event_stream.error(&error);
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.error(&error);
+        }
+    }
+
+    /// act on one message. once the show loop should end, returns whether the show
+    /// should be reloaded
+    fn handle_message(self: &Self, message: DirectorMessage, state: &ShowState, mutable_state: &mut MutableShowState,
+        next: &mut Option<DirectorMessage>) -> anyhow::Result<Option<bool>> {
+        match message {
+            DirectorMessage::Reload => return Ok(Some(true)),
+            DirectorMessage::LoadShow(show) => {
+                self.next_show.replace(Some(*show));
+                return Ok(Some(true))
+            },
+            DirectorMessage::Shutdown => return Ok(Some(false)),
+            DirectorMessage::Replay { window } => state.replay(window, mutable_state)?,
+            DirectorMessage::Mute { source, groups } => {
+                match state.set_muted_groups(&groups, mutable_state) {
+                    Ok(()) => {
+                        self.session_log.record(&source.to_string(), "mute", &groups.join(" "));
+                        self.muted_groups.replace(groups);
+                    },
+                    Err(e) => error!("Could not mute groups, error: {}", e)
+                }
+            },
+            DirectorMessage::SetVariable { source, name, value } => {
+                match state.set_variable(&name, &value, mutable_state) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "set variable", &format!("{}={}", name, value)),
+                    Err(e) => error!("Could not set variable, error: {}", e)
+                }
+            },
+            DirectorMessage::Sleep { source, wake_on_packet } => {
+                match state.sleep(wake_on_packet, mutable_state) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "sleep",
+                        if wake_on_packet { "wake on packet" } else { "until reset" }),
+                    Err(e) => error!("Could not put receivers to sleep, error: {}", e)
+                }
+            },
+            DirectorMessage::Wake { source } => {
+                match state.wake(mutable_state) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "wake", ""),
+                    Err(e) => error!("Could not wake receivers, error: {}", e)
+                }
+            },
+            DirectorMessage::Crossfade { source, scene } => {
+                match state.crossfade(&scene, mutable_state) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "crossfade", &scene),
+                    Err(e) => error!("Could not crossfade, error: {}", e)
+                }
+            },
+            DirectorMessage::CueList { source, action } => {
+                let result = if !self.arbiter.permit(source) {
+                    Err(anyhow::anyhow!("{} is locked out", source))
+                } else {
+                    state.move_cue_list(action, mutable_state)
+                };
+                match result {
+                    Ok(()) => self.session_log.record(&source.to_string(), "cue list", &format!("{:?}", action)),
+                    Err(e) => error!("Could not move through the cue list, error: {}", e)
+                }
+            },
+            DirectorMessage::Tag { source, tag, operation } => {
+                match state.apply_tag_operation(&tag, &operation, mutable_state) {
+                    Ok(()) => {
+                        self.session_log.record(&source.to_string(), "tag", &format!("{} {:?}", tag, operation));
+                        self.tag_operations.borrow_mut().push((tag, operation));
+                    },
+                    Err(e) => error!("Could not change tagged cues, error: {}", e)
+                }
+            },
+            DirectorMessage::Cue { source, cue, on, reply } => {
+                let result = if on && !self.arbiter.permit(source) {
+                    Err(anyhow::anyhow!("{} is locked out", source))
+                } else {
+                    state.fire_cue(&cue, on, mutable_state)
+                };
+                match &result {
+                    Ok(()) => self.session_log.record(&source.to_string(), if on { "cue" } else { "release" }, &cue),
+                    Err(e) => {
+                        self.session_log.record(&source.to_string(), if on { "failed cue" } else { "failed release" }, &cue);
+                        error!("Could not {} cue: {}, error: {}", if on { "fire" } else { "release" }, cue, e);
+                    }
+                }
+                if let Some(reply) = reply {
+                    let _ = reply.send(result);
+                }
+            },
+            DirectorMessage::Clip { source, clip, start, reply } => {
+                let result = if start && !self.arbiter.permit(source) {
+                    Err(anyhow::anyhow!("{} is locked out", source))
+                } else if start {
+                    state.play_clip(&clip, mutable_state)
+                } else {
+                    state.stop_clip(&clip, mutable_state)
+                };
+                match &result {
+                    Ok(()) => self.session_log.record(&source.to_string(), if start { "start clip" } else { "stop clip" }, &clip),
+                    Err(e) => {
+                        self.session_log.record(&source.to_string(), if start { "failed start clip" } else { "failed stop clip" }, &clip);
+                        error!("Could not {} clip: {}, error: {}", if start { "start" } else { "stop" }, clip, e);
+                    }
+                }
+                if let Some(reply) = reply {
+                    let _ = reply.send(result);
+                }
+            },
+            DirectorMessage::GrandMaster { source, percent } => {
+                state.set_grand_master(percent, mutable_state);
+                self.session_log.record(&source.to_string(), "grand master", &format!("{}%", percent));
+            },
+            DirectorMessage::Blackout { source } => {
+                match state.blackout(mutable_state) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "blackout", ""),
+                    Err(e) => error!("Could not black out, error: {}", e)
+                }
+            },
+            DirectorMessage::Record { source, path: Some(path) } => {
+                match self.radio.start_recording(&path) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "record", &path),
+                    Err(e) => error!("Could not start recording, error: {:#}", e)
+                }
+            },
+            DirectorMessage::Record { source, path: None } => {
+                if self.radio.stop_recording() {
+                    info!("Stopped recording packets");
+                    self.session_log.record(&source.to_string(), "stop recording", "");
+                }
+            },
+            DirectorMessage::ResetPacketIds { source } => {
+                self.radio.reset_packet_ids();
+                info!("Reset packet ids");
+                self.session_log.record(&source.to_string(), "reset packet ids", "");
+            },
+            DirectorMessage::Dmx { universe, levels } => {
+                if let Err(e) = state.process_dmx(universe, &levels, mutable_state) {
+                    error!("Could not send DMX universe: {}, error: {}", universe, e);
+                }
+            },
+            DirectorMessage::Radio(report) => state.take_report(report, mutable_state)?,
+            DirectorMessage::QueryStatus { source, reply } => {
+                match state.query_all_status(mutable_state, &reply) {
+                    Ok(()) => self.session_log.record(&source.to_string(), "query status", ""),
+                    Err(e) => {
+                        error!("Could not query receiver status, error: {}", e);
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            },
+            DirectorMessage::Status { reply } => {
+                let mut status = state.status(mutable_state);
+                status.muted_groups = self.muted_groups.borrow().clone();
+                let _ = reply.send(status);
+            },
+            DirectorMessage::Cues { reply } => {
+                let _ = reply.send(state.cues(mutable_state));
+            },
+            DirectorMessage::MidiMessage { ts: _, buf, received } => {
+                let Some(buf) = self.midi_filter.apply(&buf) else {
+                    return Ok(None)
+                };
+                // records for the session log wait until the cues have gone out, to keep them out of the latency
+                let mut records: Vec<(String,String)> = vec![];
+                let reset = match self.config.chord_window_millis.filter(|_| is_note_on(&buf)) {
+                    Some(window) => {
+                        let mut chord = vec![(buf, received)];
+                        let deadline = received + Duration::from_millis(window);
+                        while let Ok(message) = self.rx.recv_timeout(deadline.saturating_duration_since(clock::now())) {
+                            match &message {
+                                DirectorMessage::MidiMessage { ts: _, buf, received } => match self.midi_filter.apply(buf) {
+                                    Some(filtered) if is_note_on(&filtered) => chord.push((filtered, *received)),
+                                    None => {},
+                                    // filtered again when it's handled
+                                    Some(_) => {
+                                        *next = Some(message);
+                                        break
+                                    }
+                                },
+                                _ => {
+                                    *next = Some(message);
+                                    break
+                                }
+                            }
+                        }
+                        // a fixed order by channel then note, whatever order the notes arrived in
+                        chord.sort_by_key(|(buf, _)| (buf[0] & 0x0F, buf[1]));
+                        self.radio.start_burst();
+                        let mut reset = Ok(false);
+                        for (buf, received) in chord.iter() {
+                            reset = self.handle_midi(buf, *received, state, mutable_state, &mut records);
+                            if !matches!(reset, Ok(false)) {
+                                break
+                            }
+                        }
+                        self.radio.finish_burst(self.config.merge_identical_packets())?;
+                        reset?
+                    },
+                    None => self.handle_midi(&buf, received, state, mutable_state, &mut records)?
+                };
+                for (action, detail) in records.iter() {
+                    self.session_log.record("midi", action, detail);
+                    if let Some(dashboard) = &self.dashboard {
+                        dashboard.midi(action, detail);
+                    }
+                }
+                if reset {
+                    return Ok(Some(true))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// act on a midi message, adding what happened to the session log records.
+    /// returns whether the show should be reset
+    fn handle_midi(self: &Self, buf: &[u8], received: Instant, state: &ShowState, mutable_state: &mut MutableShowState,
+        records: &mut Vec<(String,String)>) -> anyhow::Result<bool> {
+        if let Some(status_led) = &self.status_led {
+            status_led.midi_activity();
+        }
+        let midi_event = midly::live::LiveEvent::parse(buf)
+            .map_err(|e| ChsError::Midi(anyhow::anyhow!("Could not parse midi message {:02x?}: {}", buf, e)))?;
+        if let LiveEvent::Midi{ channel, message: MidiMessage::Controller { controller, value } } = midi_event {
+            if channel == self.config.midi_control_channel && controller == RESET_CONTROLLER && value == 127 {
+                info!("midi reset received");
+                records.push(("reset".to_string(), String::new()));
+                return Ok(true)
+            }
+        }
+        if is_trigger(&midi_event) && !self.arbiter.permit(ControlSource::Midi) {
+            if let LiveEvent::Midi{ channel, message } = midi_event {
+                let (action, detail) = describe_midi(channel.as_int(), &message);
+                records.push((format!("locked out {}", action), detail));
+            }
+        } else {
+            state.process_midi(&midi_event, received, mutable_state)?;
+            if let LiveEvent::Midi{ channel, message } = midi_event {
+                records.push(describe_midi(channel.as_int(), &message));
+            }
+            for cue in state.take_fired_cues(mutable_state) {
+                records.push(("cue".to_string(), cue));
+            }
+        }
+        Ok(false)
+    }
+
+    /// end the show on schedule, then exit or idle until told to reload.
+    /// returns whether the show should be reloaded
+    fn end_show(self: &Self, state: &ShowState, mutable_state: &mut MutableShowState) -> anyhow::Result<bool> {
+        self.show_end.set(None);
+        let exit = self.config.show_end.as_ref().and_then(|e| e.exit).unwrap_or(false);
+        info!("scheduled end of show, turning everything off and {}", if exit { "exiting" } else { "idling" });
+        state.end_show(mutable_state)?;
+        if let Some(metronome) = &self.metronome {
+            metronome.tick(None);
+        }
+        if let Some(pad_feedback) = &self.pad_feedback {
+            pad_feedback.clear();
+        }
+        self.session_log.record("process", "show end", if exit { "exit" } else { "idle" });
+        self.set_health(Health::Idle);
+        if exit {
+            self.exit_requested.set(true);
+            return Ok(false)
+        }
+        loop {
+            match self.rx.recv()? {
+                DirectorMessage::Reload => return Ok(true),
+                DirectorMessage::LoadShow(show) => {
+                    self.next_show.replace(Some(*show));
+                    return Ok(true)
+                },
+                DirectorMessage::Shutdown => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+}
+
+/// describe a channel message for the session log as an action and its details
+fn describe_midi(channel: u8, message: &MidiMessage) -> (String, String) {
+    match message {
+        MidiMessage::NoteOn { key, vel } => ("note on".to_string(), format!("ch {} key {} vel {}", channel, key, vel)),
+        MidiMessage::NoteOff { key, vel } => ("note off".to_string(), format!("ch {} key {} vel {}", channel, key, vel)),
+        MidiMessage::Aftertouch { key, vel } => ("aftertouch".to_string(), format!("ch {} key {} vel {}", channel, key, vel)),
+        MidiMessage::Controller { controller, value } => ("cc".to_string(), format!("ch {} cc {} value {}", channel, controller, value)),
+        MidiMessage::ProgramChange { program } => ("program change".to_string(), format!("ch {} program {}", channel, program)),
+        MidiMessage::ChannelAftertouch { vel } => ("channel aftertouch".to_string(), format!("ch {} vel {}", channel, vel)),
+        MidiMessage::PitchBend { bend } => ("pitch bend".to_string(), format!("ch {} bend {}", channel, bend.as_int()))
+    }
+}
+
+/// is this a note on (as opposed to a note off sent as a note on with no velocity)
+fn is_note_on(buf: &[u8]) -> bool {
+    matches!(LiveEvent::parse(buf), Ok(LiveEvent::Midi { message: MidiMessage::NoteOn { vel, .. }, .. }) if vel > 0)
+}
+
+/// is this a message that can fire a cue (as opposed to releasing one), and so subject to arbitration
+fn is_trigger(event: &LiveEvent) -> bool {
+    match event {
+        LiveEvent::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => *vel > 0,
+        LiveEvent::Midi { message: MidiMessage::Controller { value, .. }, .. } => *value > 0,
+        _ => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scenario::Scenario;
+
+    const SHOW: &str = r#"{
+        "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+        "receivers": [
+            { "id": 1, "name": "left", "group_name": "front", "led_count": 30 },
+            { "id": 2, "name": "right", "group_name": "front", "led_count": 30 },
+            { "id": 3, "name": "tower", "group_name": "back", "led_count": 30,
+              "presets": [ { "Flame": { "min_flicker": 4, "max_flicker": 16 }} ] }
+        ],
+        "mappings": [
+            {
+                "cue": "left pop",
+                "midi": { "Note": { "channel": 0, "note": "C4" }},
+                "light": { "Effect": "Pop" },
+                "color": "red",
+                "targets": [ "left" ]
+            },
+            {
+                "cue": "right pop",
+                "midi": { "Note": { "channel": 0, "note": "E4" }},
+                "light": { "Effect": "Pop" },
+                "color": "red",
+                "targets": [ "right" ]
+            },
+            {
+                "cue": "everybody pop",
+                "midi": { "Note": { "channel": 0, "note": "D4" }},
+                "light": { "Effect": "Pop" },
+                "color": "red",
+                "one_shot": true
+            },
+            {
+                "cue": "focus pop",
+                "midi": { "Note": { "channel": 0, "note": "F4" }},
+                "light": { "Effect": "Pop" },
+                "color": "red",
+                "targets": [ "$focus" ]
+            },
+            {
+                "cue": "count",
+                "midi": { "Note": { "channel": 0, "note": "G4" }},
+                "light": { "Clip": "counting" },
+                "color": "red"
+            }
+        ],
+        "clips": {
+            "counting": [
+                { "MappingOn": {
+                    "cue": "count in", "light": { "Effect": "Pop" }, "color": "red", "targets": [ "left" ], "one_shot": true } },
+                { "WaitUntil": { "bar": 2, "beat": 3 } },
+                { "MappingOn": {
+                    "cue": "on the three", "light": { "Effect": "Pop" }, "color": "red", "targets": [ "right" ], "one_shot": true } },
+                "End"
+            ]
+        },
+        "variables": {
+            "focus": { "values": [ "left", "right" ], "cc": 20 }
+        },
+        "scenes": [
+            { "name": "right side", "cues": [ "right pop" ] }
+        ]
+    }"#;
+
+    #[test]
+    fn sustain_buffers_note_offs() {
+        Scenario::run(SHOW, "
+            at 0.5s note_on C4 ch0
+            expect 0.5s pop to 1
+            at 1s cc 64 127 ch15
+            at 1.5s note_off C4 ch0
+            expect nothing 0.6s..2.5s
+            at 2.5s cc 64 0 ch15
+            expect 2.5s off to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn lights_out_after_quiet_period() {
+        Scenario::run(SHOW, "
+            at 1s note_on C4 ch0
+            at 1.2s note_off C4 ch0
+            expect 1s pop to 1
+            expect 1.2s off to 1
+            expect nothing 1.3s..7s
+            expect 7.2s off to all
+            expect 9.2s off to all
+        ").unwrap();
+    }
+
+    #[test]
+    fn shows_handed_over_load_without_reading_the_file() {
+        // the show handed over sends the first cue to the other receiver; the file still has it
+        let mut other: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        other["mappings"][0]["targets"] = serde_json::json!([ "right" ]);
+        let other_path = std::env::temp_dir().join(format!("chs-handed-over-{}.json", std::process::id()));
+        std::fs::write(&other_path, other.to_string()).unwrap();
+        let result = Scenario::run(SHOW, &format!("
+            at 1s note_on C4 ch0
+            expect 1s pop to 1
+            at 1.2s note_off C4 ch0
+            at 2s load_show {}
+            expect 2s reset to all
+            at 3s note_on C4 ch0
+            expect 3s pop to 2
+        ", other_path.display()));
+        std::fs::remove_file(&other_path).unwrap();
+        result.unwrap();
+    }
+
+    #[test]
+    fn reload_reinitializes_receivers() {
+        Scenario::run(SHOW, "
+            expect 0s reset to all
+            at 0.5s note_on C4 ch0
+            expect 0.5s pop to 1
+            at 2s sighup
+            expect 2s reset to all
+            expect 2s setgroup to 1
+            expect 2s setgroup to 2
+            at 3s note_on C4 ch0
+            expect 3s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn muted_groups_are_left_out() {
+        Scenario::run(SHOW, "
+            at 1s mute front
+            at 2s note_on C4 ch0
+            at 3s note_on D4 ch0
+            expect 3s pop to 3
+            at 4s sighup
+            at 5s note_on D4 ch0
+            expect 5s pop to 3
+            at 6s unmute
+            at 7s note_on D4 ch0
+            expect 7s pop to all
+            expect nothing 1.5s..2.5s
+        ").unwrap();
+    }
+
+    #[test]
+    fn show_ends_on_schedule_then_idles() {
+        Scenario::run(SHOW, r#"
+            set show_end { "after_minutes": 1 }
+            set lights_out_window_open 100
+            at 1s note_on C4 ch0
+            expect 1s pop to 1
+            expect 60s off to all
+            at 61s note_on C4 ch0
+            expect nothing 60.5s..62s
+            at 62s sighup
+            expect 62s reset to all
+            at 63s note_on D4 ch0
+            expect 63s pop to all
+        "#).unwrap();
+    }
+
+    #[test]
+    fn chords_go_out_together() {
+        Scenario::run(SHOW, "
+            set chord_window_millis 10
+            set merge_identical_packets true
+            at 1000ms note_on E4 ch0
+            at 1004ms note_on C4 ch0
+            expect nothing 0.5s..1009ms
+            expect 1010ms pop to 1,2
+        ").unwrap();
+    }
+
+    #[test]
+    fn buffered_offs_are_merged() {
+        Scenario::run(SHOW, "
+            set merge_identical_packets true
+            at 0.5s note_on C4 ch0
+            at 0.6s note_on E4 ch0
+            at 1s cc 64 127 ch15
+            at 1.5s note_off C4 ch0
+            at 1.6s note_off E4 ch0
+            at 2s cc 64 0 ch15
+            expect 2s off to 1,2
+            expect nothing 2.1s..3s
+        ").unwrap();
+        // unless asked to, each receiver gets its own
+        Scenario::run(SHOW, "
+            at 0.5s note_on C4 ch0
+            at 0.6s note_on E4 ch0
+            at 1s cc 64 127 ch15
+            at 1.5s note_off C4 ch0
+            at 1.6s note_off E4 ch0
+            at 2s cc 64 0 ch15
+            expect 2s off to 1
+            expect 2s off to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn presets_are_sent_at_configure_time() {
+        Scenario::run(SHOW, "
+            expect 0s preset to 3
+            at 1s sighup
+            expect 1s preset to 3
+        ").unwrap();
+    }
+
+    #[test]
+    fn variables_take_effect_at_next_activation() {
+        Scenario::run(SHOW, "
+            at 0.5s note_on F4 ch0
+            expect 0.5s pop to 1
+            at 1s cc 20 100 ch15
+            at 1.5s note_off F4 ch0
+            expect 1.5s off to 1
+            at 2s note_on F4 ch0
+            expect 2s pop to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn crossfade_fades_out_the_old_look() {
+        Scenario::run(SHOW, "
+            at 0.5s note_on C4 ch0
+            expect 0.5s pop to 1
+            at 1s cc 110 0 ch15
+            expect 1s pop to 1
+            expect 1s off to 1
+            expect 1s pop to 2
+            at 1.5s note_off C4 ch0
+            expect nothing 1.1s..3s
+        ").unwrap();
+    }
+
+    #[test]
+    fn preview_redirects_cues_to_the_preview_group() {
+        Scenario::run(SHOW, "
+            set preview_group \"back\"
+            at 0.5s cc 111 127 ch15
+            at 1s note_on C4 ch0
+            expect 1s pop to 11
+            at 1.5s note_off C4 ch0
+            expect 1.5s off to 11
+            at 2s cc 111 0 ch15
+            expect 2s off to 11
+            at 2.5s note_on C4 ch0
+            expect 2.5s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn clips_wait_for_bar_and_beat() {
+        // at 120 bpm bar 2 beat 3 is six beats, three seconds, in
+        Scenario::run(SHOW, "
+            at 1s note_on G4 ch0
+            expect 1s pop to 1
+            expect nothing 1.1s..3.9s
+            expect 4s pop to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn clips_follow_midi_clock() {
+        // at the clock's 60 bpm, rather than the show's 120, bar 2 beat 3 is six seconds in
+        Scenario::run(SHOW, "
+            set midi_clock_sync true
+            at 0s clock 60 for 1.5s
+            at 2s note_on G4 ch0
+            expect 2s pop to 1
+            expect nothing 2.1s..7.9s
+            expect 8s pop to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn clips_wait_for_bar_and_beat_of_the_song() {
+        // started two beats into the song at 60 bpm, bar 2 beat 3 is four seconds later
+        Scenario::run(SHOW, "
+            set midi_clock_sync true
+            at 0s start
+            at 0s clock 60 for 8s
+            at 2s note_on G4 ch0
+            expect 2s pop to 1
+            expect nothing 2.1s..5.9s
+            expect 6s pop to 2
+        ").unwrap();
+        // located a beat in, the song reaches bar 2 beat 3 three beats after the clip starts
+        Scenario::run(SHOW, "
+            set midi_clock_sync true
+            at 0s song_position 4
+            at 0s continue
+            at 0s clock 60 for 8s
+            at 2s note_on G4 ch0
+            expect 2s pop to 1
+            expect nothing 2.1s..4.9s
+            expect 5s pop to 2
+        ").unwrap();
+        // a stopped song holds the wait until it carries on
+        Scenario::run(SHOW, "
+            set midi_clock_sync true
+            at 0s start
+            at 0s clock 60 for 3s
+            at 2s note_on G4 ch0
+            expect 2s pop to 1
+            at 3s stop
+            at 6s continue
+            at 6s clock 60 for 5s
+            expect nothing 2.1s..8.9s
+            expect 9s pop to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn cues_fire_at_timecode() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "hit", "midi": { "Timecode": { "hh": 0, "mm": 0, "ss": 12, "ff": 15 }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [1] },
+                { "cue": "skipped", "midi": { "Timecode": { "hh": 0, "mm": 0, "ss": 50, "ff": 0 }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] }
+            ],
+            "clips": {}
+        });
+        // the code passes 00:00:12:15 when the position spelled after it arrives, 2.525s in
+        Scenario::run(&show.to_string(), "
+            at 1s timecode 00:00:10:00 for 3s
+            expect 3.525s pop to 1
+            at 4.5s timecode 00:01:00:00
+            expect nothing 3.6s..5.5s
+        ").unwrap();
+    }
+
+    #[test]
+    fn velocity_scales_brightness() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 254 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "dynamic", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red",
+                  "targets": [1], "velocity_to_brightness": true },
+                { "cue": "flat", "midi": { "Note": { "channel": 0, "note": "D4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] }
+            ],
+            "clips": {}
+        });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0 vel 127
+            at 2s note_on C4 ch0 vel 32
+            at 3s note_on D4 ch0 vel 32
+            expect 1s pop to 1 color 0,255,254
+            expect 2s pop to 1 color 0,255,64
+            expect 3s pop to 2 color 0,255,254
+        ").unwrap();
+    }
+
+    #[test]
+    fn pitch_bend_shifts_hue() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "bent", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [1] },
+                { "cue": "straight", "midi": { "Note": { "channel": 1, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] }
+            ],
+            "clips": {}
+        });
+        // a quarter bend is a quarter of the default 32 step range; the channel 1 cue doesn't move.
+        // a sweep sends once per 50ms interval, the latest bend once it's up
+        Scenario::run(&show.to_string(), r#"
+            set pitch_bend_hue {"channels":[0]}
+            at 1s note_on C4 ch0
+            at 1s note_on C4 ch1
+            expect 1s pop to 1 color 0,255,255
+            expect 1s pop to 2 color 0,255,255
+            at 2s bend 2048 ch0
+            expect 2s pop to 1 color 8,255,255
+            at 3s bend 2048 ch0
+            expect nothing 2.5s..3.5s
+            at 4s bend -4096 ch0
+            expect 4s pop to 1 color 240,255,255
+            at 4.5s bend 0 ch0
+            expect 4.5s pop to 1 color 0,255,255
+            at 4.51s bend 2048 ch0
+            at 4.52s bend 4096 ch0
+            expect nothing 4.505s..4.545s
+            expect 4.55s pop to 1 color 16,255,255
+            at 5s note_off C4 ch0
+            at 6s bend 0 ch0
+            expect nothing 5.5s..7s
+        "#).unwrap();
+    }
+
+    #[test]
+    fn aftertouch_modulates_held_cues() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 127 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }, { "id": 3, "led_count": 30 }],
+            "mappings": [
+                { "cue": "swell", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red",
+                  "targets": [1], "modulation_target": "brightness" },
+                { "cue": "chase", "midi": { "Note": { "channel": 0, "note": "D4" }}, "light": { "Effect": { "Chase": { "chase_length": 4, "reverse": false }}}, "color": "red",
+                  "targets": [2], "tempo": 100.0, "modulation": 50, "modulation_target": "tempo" },
+                { "cue": "steady", "midi": { "Note": { "channel": 0, "note": "E4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [3] }
+            ],
+            "clips": {}
+        });
+        // the steady cue has no modulation target, so isn't sent again. quick changes of
+        // pressure send once per 50ms interval, the latest pressure once it's up
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            at 1s note_on D4 ch0
+            at 1s note_on E4 ch0
+            expect 1s pop to 1 color 0,255,127
+            expect 1s chase to 2 tempo 100
+            expect 1s pop to 3
+            at 2s pressure 127 ch0
+            expect 2s pop to 1 color 0,255,255
+            expect 2s chase to 2 tempo 150
+            at 3s pressure 127 ch0
+            expect nothing 2.5s..3.5s
+            at 3.5s pressure 0 ch0
+            expect 3.5s pop to 1 color 0,255,127
+            expect 3.5s chase to 2 tempo 100
+            at 3.51s pressure 64 ch0
+            at 3.52s pressure 127 ch0
+            expect nothing 3.505s..3.545s
+            expect 3.55s pop to 1 color 0,255,255
+            expect 3.55s chase to 2 tempo 150
+            at 4s note_off C4 ch0
+            at 4s note_off D4 ch0
+            at 4s note_off E4 ch0
+            at 5s pressure 0 ch0
+            expect nothing 4.5s..6s
+        ").unwrap();
+    }
+
+    #[test]
+    fn program_change_switches_song_banks() {
+        // the same note means a different cue in each segment of the game
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 }, "blue": { "h": 170, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "songs": [{ "name": "pregame", "number": 1 }, { "name": "halftime", "number": 2 }],
+            "mappings": [
+                { "cue": "pregame hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red",
+                  "targets": [1], "song": "pregame" },
+                { "cue": "halftime hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "blue",
+                  "targets": [2], "song": "halftime" }
+            ],
+            "clips": {}
+        });
+        // a program change on any other channel is left alone
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            at 1.5s note_off C4 ch0
+            expect 1s pop to 1 color 0,255,255
+            at 2s program 2 ch15
+            at 3s note_on C4 ch0
+            at 3.5s note_off C4 ch0
+            expect 3s pop to 2 color 170,255,255
+            at 4s program 1 ch3
+            at 5s note_on C4 ch0
+            expect 5s pop to 2 color 170,255,255
+        ").unwrap();
+        // a cue held through the switch still goes off with its note, and the new
+        // segment's cue on the same note doesn't fire
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            expect 1s pop to 1 color 0,255,255
+            at 2s program 2 ch15
+            at 3s note_off C4 ch0
+            expect 3s off to 1
+            expect nothing 1.1s..2.9s
+            expect nothing 3.1s..5s
+        ").unwrap();
+    }
+
+    #[test]
+    fn realtime_notes_keep_their_place_behind_song_changes_and_sustain() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 }, "blue": { "h": 170, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "songs": [{ "name": "pregame", "number": 1 }, { "name": "halftime", "number": 2 }],
+            "mappings": [
+                { "cue": "pregame hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red",
+                  "targets": [1], "song": "pregame", "realtime": true },
+                { "cue": "halftime hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "blue",
+                  "targets": [2], "song": "halftime", "realtime": true }
+            ],
+            "clips": {}
+        });
+        // the program change and the note arrive in one backlog; the note still
+        // waits for the song it was played in
+        Scenario::run(&show.to_string(), "
+            at 1s program 2 ch15
+            at 1s note_on C4 ch0
+            expect 1s pop to 2 color 170,255,255
+            expect nothing 1.1s..2s
+        ").unwrap();
+        // and a note off sent after the sustain pedal went down is held by it
+        Scenario::run(&show.to_string(), "
+            at 0.5s note_on C4 ch0
+            expect 0.5s pop to 1 color 0,255,255
+            at 1s cc 64 127 ch15
+            at 1s note_off C4 ch0
+            expect nothing 0.6s..1.9s
+            at 2s cc 64 0 ch15
+            expect 2s off to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn pads_light_while_their_cues_show() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [1] },
+                { "cue": "wash", "midi": { "Controller": { "channel": 1, "cc": 20 }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] }
+            ],
+            "clips": {}
+        });
+        // C4 is note 0x3c; the pads go out when the cues end, and not before
+        Scenario::run(&show.to_string(), r#"
+            set pad_feedback {"on_value":100}
+            at 1s note_on C4 ch0
+            expect 1s midi 90,3c,64
+            at 2s cc 20 127 ch1
+            expect 2s midi b1,14,64
+            at 3s note_off C4 ch0
+            expect 3s midi 90,3c,00
+            at 4s cc 20 0 ch1
+            expect 4s midi b1,14,00
+        "#).unwrap();
+    }
+
+    #[test]
+    fn pads_are_set_up_from_the_show() {
+        let show = serde_json::json!({
+            "colors": { "blue": { "h": 170, "s": 255, "v": 255 }, "white": { "h": 0, "s": 0, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }],
+            "mappings": [
+                { "cue": "hit", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "blue",
+                  "targets": [1], "pad": { "id": 112 } },
+                { "cue": "wash", "midi": { "Controller": { "channel": 1, "cc": 20 }}, "light": { "Effect": "Pop" }, "color": "white",
+                  "targets": [1], "pad": { "id": 113, "toggle": true } }
+            ],
+            "clips": {}
+        });
+        // each setting is prefix, setting, pad, value: pad 0x70 sends note 0x3c on channel 0
+        // held, in blue; pad 0x71 toggles controller 20 on channel 1, in white
+        Scenario::run(&show.to_string(), r#"
+            set pad_setup {}
+            expect 0s midi f0,00,20,6b,7f,42,02,00,01,70,09,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,02,70,00,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,03,70,3c,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,06,70,01,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,10,70,10,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,01,71,08,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,02,71,01,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,03,71,14,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,06,71,00,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,10,71,7f,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,04,71,00,f7
+            expect 0s midi f0,00,20,6b,7f,42,02,00,05,71,7f,f7
+        "#).unwrap();
+    }
+
+    #[test]
+    fn midi_is_filtered_before_the_show() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "one", "midi": { "Note": { "channel": 0, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [1] },
+                { "cue": "two", "midi": { "Note": { "channel": 1, "note": "C4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] }
+            ],
+            "clips": {}
+        });
+        // the keyboard plays a B3 on channel 3, which arrives as the show's C4 on channel 0;
+        // channel 1 is dropped, and a note transposed out of range goes nowhere
+        Scenario::run(&show.to_string(), r#"
+            set midi_filter {"drop_channels":[1],"remap_channels":{"3":0},"transpose":1}
+            at 1s note_on B3 ch3
+            at 1.5s note_off B3 ch3
+            expect 1s pop to 1
+            expect 1.5s off to 1
+            at 2s note_on B3 ch1
+            at 3s note_on G9 ch3
+            expect nothing 1.6s..4s
+        "#).unwrap();
+    }
+
+    #[test]
+    fn note_ranges_run_across_their_targets() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }, { "id": 3, "led_count": 30 }],
+            "mappings": [
+                { "cue": "run", "midi": { "NoteRange": { "channel": 0, "low": "C4", "high": "D4" }}, "light": { "Effect": "Pop" },
+                  "color": "red", "targets": [1, 2, 3] }
+            ],
+            "clips": {}
+        });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on Db4 ch0
+            at 1.5s note_off Db4 ch0
+            expect 1s pop to 2
+            at 2s note_on D4 ch0
+            at 2.5s note_off D4 ch0
+            expect 2s pop to 3
+            at 3s note_on B3 ch0
+            expect nothing 3s..4s
+        ").unwrap();
+    }
+
+    #[test]
+    fn controls_set_parameters_continuously() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [
+                { "cue": "chase", "midi": { "Note": { "channel": 0, "note": "C4" }},
+                  "light": { "Effect": { "Chase": { "chase_length": 4, "reverse": false }}}, "color": "red", "targets": [1] }
+            ],
+            "controls": [
+                { "cc": 7, "parameter": "Brightness", "interval_millis": 100 },
+                { "cc": 8, "parameter": { "Param1": { "cue": "chase" }}, "min": 1, "max": 16 }
+            ],
+            "clips": {}
+        });
+        // a move within the interval is held back until it's up, not lost
+        Scenario::run(&show.to_string(), "
+            at 1s cc 7 127 ch15
+            expect 1s brightness to all
+            at 1.02s cc 7 64 ch15
+            expect nothing 1.01s..1.09s
+            expect 1.1s brightness to all
+            at 2s note_on C4 ch0
+            expect 2s chase to 1
+            at 3s cc 8 127 ch15
+            expect 3s chase to 1
+            at 4s note_off C4 ch0
+            at 5s cc 8 0 ch15
+            expect nothing 4.5s..5.5s
+        ").unwrap();
+    }
+
+    #[test]
+    fn the_tempo_knob_sends_the_tempo_at_its_interval() {
+        let show = serde_json::json!({
+            "colors": {},
+            "receivers": [{ "id": 1, "led_count": 30 }],
+            "mappings": [],
+            "controls": [{ "cc": 7, "parameter": "Brightness" }],
+            "clips": {}
+        });
+        Scenario::run(&show.to_string(), r#"
+            set tempo_control { "cc": 20, "min_bpm": 60, "max_bpm": 180, "interval_millis": 100 }
+            at 1s cc 20 127 ch15
+            expect 1s tempo to all
+            at 1.02s cc 20 0 ch15
+            expect nothing 1.01s..1.09s
+            expect 1.1s tempo to all
+        "#).unwrap();
+        // nor can it share a controller with anything else: the show doesn't load, so
+        // the receivers are never configured
+        let script = r#"
+            set tempo_control { "cc": 7, "min_bpm": 60, "max_bpm": 180 }
+            expect 0s ledcount to 1
+        "#;
+        assert!(Scenario::run(&show.to_string(), &script.replace("\"cc\": 7", "\"cc\": 21")).is_ok());
+        assert!(Scenario::run(&show.to_string(), script).is_err());
+    }
+
+    #[test]
+    fn dmx_drives_patched_receivers() {
+        let show = serde_json::json!({
+            "colors": {},
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }],
+            "mappings": [],
+            "dmx": [
+                { "target": 1, "universe": 1, "address": 1 },
+                { "target": 2, "universe": 1, "address": 5, "color": "hsv" }
+            ],
+            "clips": {}
+        });
+        // unchanged levels send nothing, and a receiver held lit keeps lights out away
+        Scenario::run(&show.to_string(), "
+            at 1s dmx 1 255,255,0,0,128,170,255,255
+            expect 1s pop to 1 color 0,255,255
+            expect 1s pop to 2 color 170,255,128
+            at 2s dmx 1 255,255,0,0,128,170,255,255
+            expect nothing 1.5s..2.5s
+            at 3s dmx 1 255,0,255,0
+            expect 3s pop to 1 color 85,255,255
+            expect 3s off to 2
+            at 4s dmx 2 255,255,255,255
+            expect nothing 3.5s..10s
+        ").unwrap();
+    }
+
+    #[test]
+    fn long_recipient_lists_are_split() {
+        // sixty receivers won't fit in one packet: fifty go in the first, the rest in a second
+        let receivers: Vec<serde_json::Value> = (80..140).map(|id| serde_json::json!({ "id": id, "led_count": 30 })).collect();
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": receivers,
+            "mappings": [{
+                "cue": "most of the field",
+                "midi": { "Note": { "channel": 0, "note": "C4" }},
+                "light": { "Effect": "Pop" },
+                "color": "red",
+                "targets": (80..140).collect::<Vec<u8>>()
+            }],
+            "clips": {}
+        });
+        let first: Vec<String> = (80..130).map(|id: u8| id.to_string()).collect();
+        let second: Vec<String> = (130..140).map(|id: u8| id.to_string()).collect();
+        Scenario::run(&show.to_string(), &format!("
+            at 1s note_on C4 ch0
+            expect 1s pop to {}
+            expect 1s pop to {}
+        ", first.join(","), second.join(","))).unwrap();
+    }
+
+    #[test]
+    fn notes_can_be_spelled_any_way() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30 }, { "id": 2, "led_count": 30 }, { "id": 3, "led_count": 30 }],
+            "mappings": [
+                { "cue": "flat", "midi": { "Note": { "channel": 0, "note": "Db4" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [1] },
+                { "cue": "german", "midi": { "Note": { "channel": 0, "note": "H3" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [2] },
+                { "cue": "number", "midi": { "Note": { "channel": 0, "note": "64" }}, "light": { "Effect": "Pop" }, "color": "red", "targets": [3] }
+            ],
+            "clips": {}
+        });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on 61 ch0
+            at 2s note_on B3 ch0
+            at 3s note_on E4 ch0
+            expect 1s pop to 1
+            expect 2s pop to 2
+            expect 3s pop to 3
+        ").unwrap();
+    }
+
+    #[test]
+    fn synth_fades_step_up_to_the_effect() {
+        let show = serde_json::json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [{ "id": 1, "led_count": 30, "firmware": 1 }],
+            "mappings": [{
+                "cue": "slow strobe",
+                "midi": { "Note": { "channel": 0, "note": "C4" }},
+                "light": { "Effect": { "Strobe": { "division": 1 }}},
+                "color": "red",
+                "attack": 200,
+                "synth_fade": true
+            }],
+            "clips": {}
+        });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            at 2s note_off C4 ch0
+            expect nothing 0.9s..1.04s
+            expect 1.05s pop
+            expect 1.1s pop
+            expect 1.15s pop
+            expect 1.2s strobe
+            expect 2s off
+        ").unwrap();
+    }
+
+    #[test]
+    fn sleeping_receivers_are_left_alone_until_woken() {
+        Scenario::run(SHOW, "
+            at 1s sleep
+            expect 1s sleep to all
+            expect nothing 1.1s..9.9s
+            at 10s wake
+            expect 10s reset to all
+            expect 10s setgroup to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn cues_fire_by_name() {
+        Scenario::run(SHOW, "
+            at 1s fire right pop
+            expect 1s pop to 2
+            at 1.5s release right pop
+            expect 1.5s off to 2
+            at 2s fire no such cue
+            expect nothing 1.6s..3s
+        ").unwrap();
+    }
+
+    #[test]
+    fn clips_and_brightness_set_by_name() {
+        Scenario::run(SHOW, "
+            at 1s start_clip counting
+            expect 1s pop to 1
+            at 2s stop_clip counting
+            expect nothing 1.1s..5s
+            at 5s master 50
+            at 6s fire right pop
+            expect 6s pop to 2 color 0,255,127
+            at 7s start_clip no such clip
+            expect nothing 6.1s..8s
+        ").unwrap();
+    }
+
+    #[test]
+    fn effect_presets_fill_in_mappings() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["colors"]["blue"] = serde_json::json!({ "h": 160, "s": 255, "v": 255 });
+        show["effects"] = serde_json::json!({
+            "sweep": { "effect": { "Chase": { "chase_length": 8, "reverse": false }}, "color": "blue", "tempo": 90 }
+        });
+        show["mappings"][0]["light"] = serde_json::json!({ "Preset": { "name": "sweep", "overrides": { "reverse": true }}});
+        show["mappings"][0].as_object_mut().unwrap().remove("color");
+        show["mappings"][1]["light"] = serde_json::json!({ "Preset": { "name": "sweep" }});
+        show["mappings"][1]["tempo"] = serde_json::json!(120);
+        show["clips"]["counting"][0]["MappingOn"]["light"] = serde_json::json!({ "Preset": { "name": "sweep" }});
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            expect 1s chase to 1 color 160,255,255 tempo 90
+            at 2s note_on E4 ch0
+            expect 2s chase to 2 color 0,255,255 tempo 120
+            at 3s note_on G4 ch0
+            expect 3s chase to 1 color 0,255,255
+        ").unwrap();
+    }
+
+    #[test]
+    fn colors_can_be_rgb_hex_or_named() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["colors"]["blue"] = serde_json::json!("#0000FF");
+        show["colors"]["amber"] = serde_json::json!("Orange");
+        show["colors"]["green"] = serde_json::json!({ "r": 0, "g": 255, "b": 0 });
+        show["mappings"][0]["color"] = serde_json::json!("blue");
+        show["mappings"][1]["color"] = serde_json::json!("amber");
+        show["mappings"][2]["color"] = serde_json::json!("green");
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            expect 1s pop to 1 color 171,255,255
+            at 2s note_on E4 ch0
+            expect 2s pop to 2 color 28,255,255
+            at 3s note_on D4 ch0
+            expect 3s pop to all color 85,255,255
+        ").unwrap();
+    }
+
+    #[test]
+    fn cue_list_steps_through_cues() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["cue_list"] = serde_json::json!({
+            "steps": [ { "name": "opener", "cues": [ "left pop" ] }, { "cues": [ "right pop" ] } ],
+            "go": { "Note": { "channel": 0, "note": "A4" }},
+            "back": { "Note": { "channel": 0, "note": "B4" }},
+            "jump_cc": 30
+        });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on A4 ch0
+            expect 1s pop to 1
+            at 2s note_on A4 ch0
+            expect 2s off to 1
+            expect 2s pop to 2
+            at 3s note_on A4 ch0
+            expect nothing 2.5s..3.5s
+            at 4s note_on B4 ch0
+            expect 4s off to 2
+            expect 4s pop to 1
+            at 5s cc 30 1 ch15
+            expect 5s off to 1
+            expect 5s pop to 2
+        ").unwrap();
+    }
+
+    #[test]
+    fn the_cue_list_jump_controller_is_its_own() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["cue_list"] = serde_json::json!({
+            "steps": [ { "cues": [ "left pop" ] } ], "go": { "Note": { "channel": 0, "note": "A4" }}, "jump_cc": 30
+        });
+        show["controls"] = serde_json::json!([ { "cc": 31, "parameter": "Brightness" } ]);
+        show["mappings"].as_array_mut().unwrap().push(serde_json::json!({
+            "cue": "knob", "midi": { "Controller": { "channel": 15, "cc": 33 }}, "light": { "Effect": "Pop" }, "color": "red"
+        }));
+        // the show doesn't load with the jump on a controller something else answers to,
+        // so the receivers are never configured
+        let script = r#"
+            set tempo_control { "cc": 34, "min_bpm": 60, "max_bpm": 180 }
+            expect 0s ledcount to 1
+        "#;
+        Scenario::run(&show.to_string(), script).unwrap();
+        // a control, the show's variable, a controller mapping, the tempo knob, sustain
+        for cc in [31, 20, 33, 34, 64] {
+            show["cue_list"]["jump_cc"] = serde_json::json!(cc);
+            assert!(Scenario::run(&show.to_string(), script).is_err(), "jump_cc: {}", cc);
+        }
+    }
+
+    #[test]
+    fn higher_priority_mappings_hold_their_receivers() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["mappings"].as_array_mut().unwrap().push(serde_json::json!({
+            "cue": "both sides", "midi": { "Note": { "channel": 0, "note": "A4" }},
+            "light": { "Effect": "Pop" }, "color": "red", "targets": [ "left", "right" ], "priority": 1
+        }));
+        Scenario::run(&show.to_string(), "
+            at 1s note_on A4 ch0
+            expect 1s pop to 1,2
+            at 2s note_on C4 ch0
+            expect nothing 1.5s..2.5s
+            at 3s note_off A4 ch0
+            expect 3s off to 2
+            expect 3s pop to 1
+            at 4s note_off C4 ch0
+            expect 4s off to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn blackout_stops_everything() {
+        Scenario::run(SHOW, "
+            at 0.5s note_on C4 ch0
+            expect 0.5s pop to 1
+            at 1s note_on G4 ch0
+            expect 1s pop to 1
+            at 1.5s blackout
+            expect 1.5s off to all
+            at 2s note_off C4 ch0
+            expect nothing 1.6s..4s
+        ").unwrap();
+    }
+
+    #[test]
+    fn random_subsets_leave_out_muted_receivers() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["mappings"][2]["random_subset"] = serde_json::json!({ "Count": 2 });
+        Scenario::run(&show.to_string(), "
+            at 0.5s mute back
+            at 1s note_on D4 ch0
+            expect 1s pop to 1,2
+        ").unwrap();
+    }
+
+    #[test]
+    fn random_subsets_are_picked_by_weight() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["mappings"][2]["random_subset"] = serde_json::json!({ "Count": 2 });
+        show["receivers"][0]["random_weight"] = serde_json::json!(0);
+        show["receivers"][1]["random_weight"] = serde_json::json!(1000000);
+        // one weighted out of the picking, so only two to pick from
+        Scenario::run(&show.to_string(), "
+            at 1s note_on D4 ch0
+            expect 1s pop to 2,3
+            at 2s note_on D4 ch0
+            expect 2s pop to 2,3
+        ").unwrap();
+        // the heavy one is all but certain to be the one picked
+        show["mappings"][2]["random_subset"] = serde_json::json!({ "Count": 1 });
+        Scenario::run(&show.to_string(), "
+            at 1s note_on D4 ch0
+            expect 1s pop to 2
+        ").unwrap();
+        // a show with a weight below nothing doesn't load
+        show["receivers"][2]["random_weight"] = serde_json::json!(-1);
+        assert!(Scenario::run(&show.to_string(), "expect 0s ledcount to 1").is_err());
+    }
+
+    #[test]
+    fn chases_are_split_for_reversed_receivers() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["receivers"][1]["reversed"] = serde_json::json!(true);
+        show["mappings"][0]["light"] = serde_json::json!({ "Effect": { "Chase": { "chase_length": 4, "reverse": false }}});
+        show["mappings"][0]["targets"] = serde_json::json!([ "front" ]);
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            expect 1s chase to 1 param2 0
+            expect 1s chase to 2 param2 1
+        ").unwrap();
+        // a reversed chase runs forwards on the flipped receiver
+        show["mappings"][0]["light"] = serde_json::json!({ "Effect": { "Chase": { "chase_length": 4, "reverse": true }}});
+        Scenario::run(&show.to_string(), "
+            at 1s note_on C4 ch0
+            expect 1s chase to 1 param2 1
+            expect 1s chase to 2 param2 0
+        ").unwrap();
+    }
+
+    #[test]
+    fn unacknowledged_configuration_is_resent() {
+        // the mock radio's receivers never acknowledge anything
+        Scenario::run(SHOW, "
+            set header_mode \"ReliableDatagram\"
+            set ack_retries 2
+            expect 0s ledcount to 1
+            expect 0s ledcount to 1
+            expect 0s ledcount to 1
+            expect 0s preset to 3
+            expect 0s preset to 3
+            expect 0s preset to 3
+        ").unwrap();
+    }
+
+    #[test]
+    fn tagged_cues_can_be_muted() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["mappings"][0]["tags"] = serde_json::json!([ "strobe" ]);
+        Scenario::run(&show.to_string(), "
+            at 1s tag strobe mute
+            at 1.5s note_on C4 ch0
+            at 1.6s note_off C4 ch0
+            expect nothing 1.1s..1.9s
+            at 2s tag strobe unmute
+            at 2.5s note_on C4 ch0
+            expect 2.5s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn show_packets_are_repeated() {
+        Scenario::run(SHOW, "
+            set show_packet_repeats 2
+            at 1s note_on C4 ch0
+            expect 1s pop to 1
+            expect 1s pop to 1
+            expect 1s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn receivers_are_polled_in_turn() {
+        Scenario::run(SHOW, "
+            set link_poll_period 1
+            expect 1s poll to 1
+            expect 2s poll to 2
+            expect 3s poll to 3
+            expect 4s poll to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn the_fast_lane_is_taken_from_first() {
+        use super::{DirectorMessage, Inbox};
+        use crossbeam_channel::RecvTimeoutError;
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (realtime_tx, realtime_rx) = crossbeam_channel::unbounded();
+        let mut lanes = crate::fastlane::Lanes::new(rx);
+        lanes.use_fast_lane(realtime_rx);
+        let inbox = Inbox::Channel { lanes, reports: crossbeam_channel::never() };
+        tx.send(DirectorMessage::Reload).unwrap();
+        realtime_tx.send((0, DirectorMessage::Shutdown)).unwrap();
+        assert_eq!(inbox.depth().0, 2);
+        assert!(matches!(inbox.recv_timeout(std::time::Duration::ZERO), Ok(DirectorMessage::Shutdown)));
+        assert!(matches!(inbox.recv(), Ok(DirectorMessage::Reload)));
+        assert!(matches!(inbox.recv_timeout(std::time::Duration::from_millis(1)), Err(RecvTimeoutError::Timeout)));
+    }
+
+    #[test]
+    fn critical_cues_are_repeated_to_receivers_with_poor_links() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["mappings"][2]["critical"] = serde_json::json!(true);
+        // 1 and 3 don't answer their polls, 2 hears us well
+        Scenario::run(&show.to_string(), "
+            set link_poll_period 1
+            set adaptive_repeats 2
+            set adaptive_repeat_budget 3
+            at 1.5s pollreply 2 rssi 40
+            at 3.5s note_on D4 ch0
+            expect 3.5s pop to all
+            expect 3.5s pop to 1
+            expect 3.5s pop to 3
+            expect 3.5s pop to 1
+            expect nothing 3.4s..3.6s to 2
+            # the budget for this second is spent
+            at 3.7s note_on D4 ch0
+            expect 3.7s pop to all
+            expect nothing 3.6s..3.8s to 1
+            expect nothing 3.6s..3.8s to 3
+            at 4.6s note_on D4 ch0
+            expect 4.6s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn late_receivers_are_configured_alone() {
+        Scenario::run(SHOW, "
+            set announcement_listen_period 0.5
+            at 2.1s announce 3
+            expect 2.5s setgroup to 3
+            expect 2.5s ledcount to 3
+            expect 2.5s preset to 3
+            expect nothing 2.6s..3s
+        ").unwrap();
+    }
+
+    #[test]
+    fn receivers_are_queried_for_status_in_turn() {
+        Scenario::run(SHOW, "
+            set telemetry_poll_period 1
+            expect 1s querystatus to 1
+            expect 2s querystatus to 2
+            expect 3s querystatus to 3
+        ").unwrap();
+    }
+
+    #[test]
+    fn a_status_query_from_the_console_asks_everyone_at_once() {
+        Scenario::run(SHOW, "
+            at 1s query_status
+            expect 1s querystatus to 1
+            expect 1s querystatus to 2
+            expect 1s querystatus to 3
+            at 1s note_on C4 ch0
+            expect 1s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn bad_midi_is_skipped() {
+        Scenario::run(SHOW, "
+            at 1s cc 200 1 ch0
+            at 2s note_on C4 ch0
+            expect 2s pop to 1
+        ").unwrap();
+    }
+
+    #[test]
+    fn bad_midi_stops_the_show_in_safe_mode() {
+        Scenario::run(SHOW, "
+            set recovery { \"midi\": \"safe_mode\" }
+            at 1s cc 200 1 ch0
+            at 2s note_on C4 ch0
+            expect nothing 1s..3s
+        ").unwrap();
+    }
+
+    #[test]
+    fn clips_play_counted_loops_then_carry_on() {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        let pop = |cue: &str, target: &str| serde_json::json!({ "MappingOn": {
+            "cue": cue, "light": { "Effect": "Pop" }, "color": "red", "targets": [ target ], "one_shot": true }});
+        // three times round, leaving before the second pop the last time
+        show["clips"]["counting"] = serde_json::json!([
+            pop("left", "left"), { "WaitBeats": 1 }, "BreakLoop",
+            pop("right", "right"), { "WaitBeats": 1 }, { "LoopN": { "index": 0, "times": 3 }}, "End"
+        ]);
+        Scenario::run(&show.to_string(), "
+            at 1s note_on G4 ch0
+            expect 1s pop to 1
+            expect 1.5s pop to 2
+            expect 2s pop to 1
+            expect 2.5s pop to 2
+            expect 3s pop to 1
+            expect nothing 3.1s..6s
+            at 6s note_on G4 ch0
+            expect 6s pop to 1
+            expect 6.5s pop to 2
+        ").unwrap();
+    }
+
+    /// the test show with assertions added
+    fn show_asserting(assertions: serde_json::Value) -> String {
+        let mut show: serde_json::Value = serde_json::from_str(SHOW).unwrap();
+        show["assertions"] = assertions;
+        show["simulate_clips"] = serde_json::json!(true);
+        show.to_string()
+    }
+
+    #[test]
+    fn shows_failing_assertions_are_not_loaded() {
+        let passing = show_asserting(serde_json::json!([
+            "EveryReceiverTargeted", "UniqueTriggers", { "ClipMaxDuration": { "clip": "counting", "max_seconds": 3.5 }}]));
+        Scenario::run(&passing, "
+            expect 0s reset to all
+        ").unwrap();
+        let failing = show_asserting(serde_json::json!([{ "ClipMaxDuration": { "clip": "counting", "max_seconds": 2 }}]));
+        Scenario::run(&failing, "
+            expect nothing 0s..1s
+        ").unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use std::fmt::{Display,Formatter};
+use serde::Deserialize;
+
+use crate::radio::RadioError;
+
+///
+/// Failures are sorted into categories so the config can say how the transmitter
+/// recovers from each: carry on with the show, go dark and wait for a reload, or
+/// exit (and let systemd start it again). The categories apply to errors that reach
+/// the director's show loop, loading or running a show: anything there that isn't
+/// tagged as a ChsError (or a RadioError) came from the show, so only what comes from
+/// the config or the midi input is tagged. Errors setting the transmitter up before
+/// the show starts end it whatever they are, except for midi inputs, which main checks
+/// the midi recovery for itself
+///
+
+#[derive(Debug,Deserialize,Clone,Copy,PartialEq,Eq,Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// the config asks for something that can't be done
+    Config,
+    /// the show couldn't be loaded, or failed while running
+    Show,
+    /// the radio failed to send one packet, but is still usable
+    RadioTransient,
+    /// the radio (or the bus it's on) has stopped working
+    RadioFatal,
+    /// midi that couldn't be opened or understood
+    Midi
+}
+
+#[derive(Debug,Deserialize,Clone,Copy,PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Recovery {
+    /// drop whatever failed and carry on with the show. a show that failed to load
+    /// is loaded again after the restart delay
+    Retry,
+    /// stop the show and wait for a reload
+    SafeMode,
+    /// shut the transmitter down
+    Exit
+}
+
+impl ErrorCategory {
+    /// how to recover when the config doesn't say
+    pub fn default_recovery(self: &Self) -> Recovery {
+        match self {
+            ErrorCategory::Config => Recovery::Exit,
+            ErrorCategory::Show => Recovery::SafeMode,
+            ErrorCategory::RadioTransient => Recovery::Retry,
+            ErrorCategory::RadioFatal => Recovery::SafeMode,
+            ErrorCategory::Midi => Recovery::Retry
+        }
+    }
+}
+
+impl Display for ErrorCategory {
+    fn fmt(self: &Self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorCategory::Config => "config",
+            ErrorCategory::Show => "show",
+            ErrorCategory::RadioTransient => "radio (transient)",
+            ErrorCategory::RadioFatal => "radio (fatal)",
+            ErrorCategory::Midi => "midi"
+        })
+    }
+}
+
+/// an error tagged with its category
+#[derive(Debug)]
+pub enum ChsError {
+    Config(anyhow::Error),
+    Show(anyhow::Error),
+    RadioTransient(RadioError),
+    RadioFatal(RadioError),
+    Midi(anyhow::Error)
+}
+
+impl ChsError {
+    pub fn category(self: &Self) -> ErrorCategory {
+        match self {
+            ChsError::Config(_) => ErrorCategory::Config,
+            ChsError::Show(_) => ErrorCategory::Show,
+            ChsError::RadioTransient(_) => ErrorCategory::RadioTransient,
+            ChsError::RadioFatal(_) => ErrorCategory::RadioFatal,
+            ChsError::Midi(_) => ErrorCategory::Midi
+        }
+    }
+
+    /// the category of any error: the first tagged or radio error in its chain decides,
+    /// anything else happened running the show
+    pub fn categorize(error: &anyhow::Error) -> ErrorCategory {
+        for cause in error.chain() {
+            if let Some(chs) = cause.downcast_ref::<ChsError>() {
+                return chs.category()
+            }
+            if let Some(radio) = cause.downcast_ref::<RadioError>() {
+                return if radio.is_transient() { ErrorCategory::RadioTransient } else { ErrorCategory::RadioFatal }
+            }
+        }
+        ErrorCategory::Show
+    }
+}
+
+impl From<RadioError> for ChsError {
+    fn from(err: RadioError) -> ChsError {
+        if err.is_transient() { ChsError::RadioTransient(err) } else { ChsError::RadioFatal(err) }
+    }
+}
+
+impl Display for ChsError {
+    fn fmt(self: &Self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChsError::Config(e) | ChsError::Show(e) | ChsError::Midi(e) => write!(f, "{:#}", e),
+            ChsError::RadioTransient(e) | ChsError::RadioFatal(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for ChsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn errors_are_categorized_by_the_first_tag_in_their_chain() {
+        let tagged: anyhow::Error = ChsError::Config(anyhow!("Preview group: side is not a group in the show")).into();
+        assert_eq!(ChsError::categorize(&tagged.context("Could not validate show structure")), ErrorCategory::Config);
+        let radio: anyhow::Error = RadioError::IllegalPower.into();
+        assert_eq!(ChsError::categorize(&radio), ErrorCategory::RadioFatal);
+        let radio: anyhow::Error = ChsError::from(RadioError::Sx127xError(crate::lora::Sx127xError::Timeout)).into();
+        assert_eq!(ChsError::categorize(&radio), ErrorCategory::RadioTransient);
+        let untagged = Err::<(), _>(anyhow!("Cue list has no steps")).context("Could not validate show structure").unwrap_err();
+        assert_eq!(ChsError::categorize(&untagged), ErrorCategory::Show);
+    }
+}
@@ -0,0 +1,97 @@
+use std::thread::sleep;
+use std::time::Duration;
+use anyhow::Result;
+
+use crate::packet::{Packet, PacketPayload, ShowPacket};
+use crate::radio::Radio;
+use crate::show::{Color, Effect};
+
+///
+/// Cycles a single receiver through every effect with representative
+/// parameters, as an acceptance test for newly assembled receivers
+///
+
+const SECONDS_PER_EFFECT: u64 = 4;
+const EXERCISE_TEMPO: u8 = 120;
+
+/// every effect by name, with parameters that show it off
+pub fn representative_effects() -> Vec<(&'static str, Effect)> {
+    vec![
+        ("Pop", Effect::Pop),
+        ("Firecrackers", Effect::Firecrackers { delay_quantization: 4, delay_multiplier: 8 }),
+        ("Chase", Effect::Chase { chase_length: 8, reverse: false }),
+        ("Strobe", Effect::Strobe { division: 2 }),
+        ("BidiChase", Effect::BidiChase { chase_length: 8 }),
+        ("OneShotChase", Effect::OneShotChase { chase_length: 8, reverse: false, beat_denominator: 1 }),
+        ("BidiOneShotChase", Effect::BidiOneShotChase { chase_length: 8 }),
+        ("Sparkle", Effect::Sparkle { stride: 3, tempo_division: 2 }),
+        ("Wave", Effect::Wave { alternate_hue: 128, alternate_brightness: 128, colorspace_phase: 0, colorspace_range: 64 }),
+        ("PiezoTrigger", Effect::PiezoTrigger { flash_decay: 50, threshold: 100 }),
+        ("Flame", Effect::Flame { min_flicker: 4, max_flicker: 16 }),
+        ("Flame2", Effect::Flame2 { min_flicker: 4, max_flicker: 16 }),
+        ("Grass", Effect::Grass { base_height: 8, blade_top: 16 }),
+        ("CircularChase", Effect::CircularChase { chase_length: 8, reverse: false }),
+        ("BatteryTest", Effect::BatteryTest),
+        ("Rainbow", Effect::Rainbow { secondary_hue: 128 }),
+        ("Twinkle", Effect::Twinkle { twinkle_brightness: 255, twinkle_factor: 0.25 }),
+        ("DigitalPin", Effect::DigitalPin { pin: 1 }),
+        ("PinAndSpin", Effect::PinAndSpin { pin: 1, rpm: 30 }),
+        ("PopAndSpin", Effect::PopAndSpin { rpm: 30 })
+    ]
+}
+
+/// look up an effect (with representative parameters) by name, ignoring case
+pub fn effect_by_name(name: &str) -> Option<Effect> {
+    representative_effects().into_iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, effect)| effect)
+}
+
+/// run every effect on the receiver in turn, labelling each on the console
+pub fn exercise(radio: &Radio, receiver: u8) -> Result<()> {
+    let recipients = vec![receiver];
+    let effects = representative_effects();
+    for (n, (_, effect)) in effects.iter().enumerate() {
+        println!("[{}/{}] receiver {}: {:?}", n + 1, effects.len(), receiver, effect);
+        let mut show_packet = ShowPacket {
+            effect: effect.to_effect_id(),
+            // step around the color wheel so consecutive effects are easy to tell apart
+            color: Color { h: (n * 256 / effects.len()) as u8, s: 255, v: 255 },
+            attack: 0,
+            sustain: 255,
+            release: 0,
+            param1: 0,
+            param2: 0,
+            tempo: EXERCISE_TEMPO
+        };
+        effect.populate_effect_params(&mut show_packet);
+        radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(show_packet) })?;
+        sleep(Duration::from_secs(SECONDS_PER_EFFECT));
+        radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(ShowPacket::OFF_PACKET) })?;
+    }
+    println!("Exercised {} effects on receiver {}", effects.len(), receiver);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::EffectId;
+
+    #[test]
+    fn effects_are_found_by_name_ignoring_case() {
+        assert!(matches!(effect_by_name("pop"), Some(Effect::Pop)));
+        assert!(matches!(effect_by_name("ONESHOTCHASE"), Some(Effect::OneShotChase { .. })));
+        assert!(matches!(effect_by_name("Flame2"), Some(Effect::Flame2 { .. })));
+        assert!(effect_by_name("Flame3").is_none());
+        assert!(effect_by_name("").is_none());
+    }
+
+    #[test]
+    fn every_effect_has_a_name() {
+        let mut ids: Vec<u8> = representative_effects().iter().map(|(_, effect)| effect.to_effect_id() as u8).collect();
+        ids.dedup();
+        // everything but off
+        assert_eq!(ids, (EffectId::Pop as u8..=EffectId::PopAndSpin as u8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,215 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crossbeam_channel::{Receiver, SendError, Sender};
+
+use crate::clock;
+use crate::director::DirectorMessage;
+use crate::midifilter::MidiFilter;
+
+///
+/// Notes that trigger realtime mappings jump the queue. The midi input threads sort
+/// them onto a channel of their own, which the director always takes from first, so
+/// a percussive hit never waits behind a backlog of controller sweeps, clock or console
+/// requests. A realtime note's off goes the same way, so it can't overtake its on. The
+/// director keeps the set of realtime notes up to date as shows load.
+///
+/// A note mustn't overtake the messages that change what it means, though: a program
+/// change, song select or song position switching songs, or the sustain pedal holding
+/// offs. The router counts those as it sends them down the other lane and stamps each
+/// realtime note with the count, and the director holds a note back until it has taken
+/// that many of them
+///
+
+/// the (channel, note) of each realtime mapping, as the show has them
+pub type RealtimeNotes = Arc<RwLock<HashSet<(u8,u8)>>>;
+
+/// a realtime note, with how many messages it mustn't overtake were sent before it
+pub type FastLaneMessage = (u64, DirectorMessage);
+
+const SUSTAIN_CONTROLLER: u8 = 64;
+
+/// whether realtime notes have to wait for a message: one that switches songs, or the
+/// sustain pedal, on any channel, whatever the filter makes of it
+pub fn keeps_order(buf: &[u8]) -> bool {
+    match buf {
+        [0xF2 | 0xF3, ..] => true,
+        [status, ..] if status & 0xF0 == 0xC0 => true,
+        [status, SUSTAIN_CONTROLLER, ..] if status & 0xF0 == 0xB0 => true,
+        _ => false
+    }
+}
+
+/// sorts midi from the input threads onto the director's channels
+#[derive(Clone)]
+pub struct MidiRouter {
+    tx: Sender<DirectorMessage>,
+    realtime_tx: Sender<FastLaneMessage>,
+    realtime_notes: RealtimeNotes,
+    /// the messages realtime notes wait for, sent so far
+    ordered: Arc<AtomicU64>,
+    /// the director's own filter, so notes are sorted as the show will see them
+    filter: Arc<MidiFilter>
+}
+
+impl MidiRouter {
+
+    /// a router feeding the director's channel, and the fast lane it takes from first
+    pub fn new(tx: Sender<DirectorMessage>, filter: MidiFilter) -> (MidiRouter, Receiver<FastLaneMessage>) {
+        let (realtime_tx, realtime_rx) = crossbeam_channel::unbounded();
+        let router = MidiRouter { tx, realtime_tx, realtime_notes: Arc::new(RwLock::new(HashSet::new())),
+            ordered: Arc::new(AtomicU64::new(0)), filter: Arc::new(filter) };
+        (router, realtime_rx)
+    }
+
+    /// the set the director updates as shows load
+    pub fn realtime_notes(self: &Self) -> RealtimeNotes {
+        self.realtime_notes.clone()
+    }
+
+    /// pass a midi message on to the director, down the fast lane if it's a realtime note
+    pub fn route(self: &Self, ts: u64, buf: &[u8]) -> Result<(), SendError<DirectorMessage>> {
+        let message = DirectorMessage::MidiMessage { ts, buf: buf.to_owned(), received: clock::now() };
+        if self.is_realtime(buf) {
+            self.realtime_tx.send((self.ordered.load(Ordering::SeqCst), message)).map_err(|SendError((_, message))| SendError(message))
+        } else {
+            if keeps_order(buf) {
+                self.ordered.fetch_add(1, Ordering::SeqCst);
+            }
+            self.tx.send(message)
+        }
+    }
+
+    /// whether a message is a note on or off for a realtime mapping. the director filters
+    /// the message itself, this is only to sort it
+    fn is_realtime(self: &Self, buf: &[u8]) -> bool {
+        match self.filter.apply(buf).as_deref() {
+            Some(&[status, note, _]) if status & 0xE0 == 0x80 =>
+                self.realtime_notes.read().unwrap().contains(&(status & 0x0F, note)),
+            _ => false
+        }
+    }
+}
+
+/// the director's end of the two lanes: realtime notes are taken first, unless one has
+/// to wait for messages still on the other lane
+pub struct Lanes {
+    rx: Receiver<DirectorMessage>,
+    realtime: Receiver<FastLaneMessage>,
+    /// a realtime note taken off the fast lane that's waiting for messages before it
+    held: RefCell<Option<FastLaneMessage>>,
+    /// the messages realtime notes wait for, taken so far
+    ordered: Cell<u64>
+}
+
+impl Lanes {
+
+    /// the director's channel, without a fast lane until one is used
+    pub fn new(rx: Receiver<DirectorMessage>) -> Lanes {
+        Lanes { rx, realtime: crossbeam_channel::never(), held: RefCell::new(None), ordered: Cell::new(0) }
+    }
+
+    pub fn use_fast_lane(self: &mut Self, realtime: Receiver<FastLaneMessage>) {
+        self.realtime = realtime;
+    }
+
+    /// a realtime note already waiting that nothing before it holds up
+    pub fn realtime_waiting(self: &Self) -> Option<DirectorMessage> {
+        let mut held = self.held.borrow_mut();
+        if held.is_none() {
+            *held = self.realtime.try_recv().ok();
+        }
+        match *held {
+            Some((after, _)) if after <= self.ordered.get() => held.take().map(|(_, message)| message),
+            _ => None
+        }
+    }
+
+    /// the fast lane to wait on, which goes quiet while a note is held back
+    pub fn realtime(self: &Self) -> Receiver<FastLaneMessage> {
+        if self.held.borrow().is_some() { crossbeam_channel::never() } else { self.realtime.clone() }
+    }
+
+    pub fn rx(self: &Self) -> &Receiver<DirectorMessage> {
+        &self.rx
+    }
+
+    /// a note off the fast lane, unless it has to wait
+    pub fn arrived(self: &Self, message: FastLaneMessage) -> Option<DirectorMessage> {
+        self.held.replace(Some(message));
+        self.realtime_waiting()
+    }
+
+    /// a message off the other lane, counted if realtime notes wait for it
+    pub fn taken(self: &Self, message: DirectorMessage) -> DirectorMessage {
+        if matches!(&message, DirectorMessage::MidiMessage { buf, .. } if keeps_order(buf)) {
+            self.ordered.set(self.ordered.get() + 1);
+        }
+        message
+    }
+
+    /// whatever is next without waiting, realtime notes first
+    pub fn try_recv(self: &Self) -> Option<DirectorMessage> {
+        self.realtime_waiting().or_else(|| self.rx.try_recv().ok().map(|message| self.taken(message)))
+    }
+
+    pub fn len(self: &Self) -> usize {
+        self.rx.len() + self.realtime.len() + self.held.borrow().iter().count()
+    }
+
+    pub fn capacity(self: &Self) -> Option<usize> {
+        self.rx.capacity()
+    }
+
+    /// everything waiting on both lanes, realtime notes first
+    pub fn drain(self: &Self) -> Vec<DirectorMessage> {
+        let realtime = self.held.take().into_iter().chain(self.realtime.try_iter()).map(|(_, message)| message);
+        let rest: Vec<DirectorMessage> = self.rx.try_iter().map(|message| self.taken(message)).collect();
+        realtime.chain(rest).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midifilter::MidiFilterConfig;
+
+    #[test]
+    fn realtime_notes_take_the_fast_lane_as_the_show_sees_them() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let config = MidiFilterConfig { transpose: Some(12), ..Default::default() };
+        let (router, realtime_rx) = MidiRouter::new(tx, MidiFilter::new(Some(&config)).unwrap());
+        router.realtime_notes().write().unwrap().insert((0, 60));
+        // played an octave down, as the filter moves it up to the show's note
+        for buf in [[0x90, 48, 100], [0x80, 48, 0], [0x91, 48, 100], [0x90, 50, 100], [0xB0, 48, 127]] {
+            router.route(0, &buf).unwrap();
+        }
+        // passed on unfiltered, as the director filters them itself
+        assert_eq!(realtime_rx.try_iter().map(|(_, m)| buf(m)).collect::<Vec<_>>(), vec![vec![0x90, 48, 100], vec![0x80, 48, 0]]);
+        assert_eq!(rx.try_iter().map(buf).collect::<Vec<_>>(), vec![vec![0x91, 48, 100], vec![0x90, 50, 100], vec![0xB0, 48, 127]]);
+    }
+
+    #[test]
+    fn realtime_notes_wait_for_song_changes_and_sustain_sent_before_them() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let (router, realtime_rx) = MidiRouter::new(tx, MidiFilter::new(None).unwrap());
+        router.realtime_notes().write().unwrap().insert((0, 60));
+        let backlog: [&[u8]; 7] = [&[0x90, 60, 100], &[0x91, 61, 100], &[0xCF, 2], &[0x90, 60, 100], &[0xBF, 64, 127], &[0xBF, 7, 100], &[0x80, 60, 0]];
+        for message in backlog {
+            router.route(0, message).unwrap();
+        }
+        let mut lanes = Lanes::new(rx);
+        lanes.use_fast_lane(realtime_rx);
+        let taken: Vec<Vec<u8>> = std::iter::from_fn(|| lanes.try_recv()).map(buf).collect();
+        assert_eq!(taken, vec![vec![0x90, 60, 100], vec![0x91, 61, 100], vec![0xCF, 2], vec![0x90, 60, 100],
+            vec![0xBF, 64, 127], vec![0x80, 60, 0], vec![0xBF, 7, 100]]);
+    }
+
+    fn buf(message: DirectorMessage) -> Vec<u8> {
+        match message {
+            DirectorMessage::MidiMessage { buf, .. } => buf,
+            _ => unreachable!()
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use log::{debug,error};
+use serde::Deserialize;
+use crate::midi::MidiSender;
+
+///
+/// Pad feedback lights the pads of a MIDI pad controller while the cues they trigger
+/// are showing, and puts them out again when the cues end, by sending each pad's own
+/// note (or controller) back to the controller on the MIDI output. Pads are compared
+/// against what was last sent, so only changes go out
+///
+
+const DEFAULT_ON_VALUE: u8 = 127;
+const DEFAULT_OFF_VALUE: u8 = 0;
+
+#[derive(Debug,Deserialize)]
+pub struct PadFeedbackConfig {
+    /// the velocity (or controller value) that lights a pad, defaults to 127. many pad
+    /// controllers pick the pad's color from it
+    pub on_value: Option<u8>,
+
+    /// the velocity (or controller value) that puts a pad out, defaults to 0
+    pub off_value: Option<u8>
+}
+
+/// a pad, by the midi message that triggers its cues
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Pad {
+    Note { channel: u8, note: u8 },
+    Controller { channel: u8, controller: u8 }
+}
+
+pub struct PadFeedback {
+    output: Arc<MidiSender>,
+    on_value: u8,
+    off_value: u8,
+    /// the pads last lit
+    lit: RefCell<BTreeSet<Pad>>
+}
+
+impl PadFeedback {
+
+    pub fn new(config: &PadFeedbackConfig, output: Arc<MidiSender>) -> PadFeedback {
+        PadFeedback {
+            output,
+            on_value: config.on_value.unwrap_or(DEFAULT_ON_VALUE) & 0x7F,
+            off_value: config.off_value.unwrap_or(DEFAULT_OFF_VALUE) & 0x7F,
+            lit: RefCell::new(BTreeSet::new())
+        }
+    }
+
+    /// light the pads newly lit, and put out those no longer lit
+    pub fn update(self: &Self, lit: BTreeSet<Pad>) {
+        let mut was = self.lit.borrow_mut();
+        for pad in was.difference(&lit) {
+            self.send(pad, self.off_value);
+        }
+        for pad in lit.difference(&was) {
+            self.send(pad, self.on_value);
+        }
+        *was = lit;
+    }
+
+    /// put every pad out, eg when the show ends
+    pub fn clear(self: &Self) {
+        self.update(BTreeSet::new());
+    }
+
+    fn send(self: &Self, pad: &Pad, value: u8) {
+        debug!("pad feedback: {:?} {}", pad, value);
+        let message = match pad {
+            Pad::Note { channel, note } => [0x90 | channel, *note, value],
+            Pad::Controller { channel, controller } => [0xB0 | channel, *controller, value]
+        };
+        if let Err(e) = self.output.send(&message) {
+            error!("Could not send pad feedback: {}", e);
+        }
+    }
+
+    /// the midi output, for tests
+    #[cfg(test)]
+    pub fn output(self: &Self) -> &Arc<MidiSender> {
+        &self.output
+    }
+}
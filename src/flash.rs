@@ -0,0 +1,96 @@
+use anyhow::{anyhow,Result};
+
+use crate::show::{ClipStep, Effect, FlashPattern, FlashText, LightMapping, LightMappingType, ShowDefinition};
+
+///
+/// This module turns a short text message into a clip of Pop flashes on a
+/// mapping's targets, either as morse code or as counted pulses, for
+/// scoreboard-style gags without new receiver firmware
+///
+
+const DEFAULT_UNIT_MILLIS: u32 = 150;
+
+fn morse(c: char) -> Option<&'static str> {
+    match c.to_ascii_uppercase() {
+        'A' => Some(".-"), 'B' => Some("-..."), 'C' => Some("-.-."), 'D' => Some("-.."),
+        'E' => Some("."), 'F' => Some("..-."), 'G' => Some("--."), 'H' => Some("...."),
+        'I' => Some(".."), 'J' => Some(".---"), 'K' => Some("-.-"), 'L' => Some(".-.."),
+        'M' => Some("--"), 'N' => Some("-."), 'O' => Some("---"), 'P' => Some(".--."),
+        'Q' => Some("--.-"), 'R' => Some(".-."), 'S' => Some("..."), 'T' => Some("-"),
+        'U' => Some("..-"), 'V' => Some("...-"), 'W' => Some(".--"), 'X' => Some("-..-"),
+        'Y' => Some("-.--"), 'Z' => Some("--.."),
+        '0' => Some("-----"), '1' => Some(".----"), '2' => Some("..---"), '3' => Some("...--"),
+        '4' => Some("....-"), '5' => Some("....."), '6' => Some("-...."), '7' => Some("--..."),
+        '8' => Some("---.."), '9' => Some("----."),
+        _ => None
+    }
+}
+
+/// the number of pulses for a character in the pulse pattern
+fn pulse_count(c: char) -> Option<u32> {
+    match c.to_ascii_uppercase() {
+        '0' => Some(10),
+        d @ '1'..='9' => d.to_digit(10),
+        l @ 'A'..='Z' => Some(l as u32 - 'A' as u32 + 1),
+        _ => None
+    }
+}
+
+/// convert text to a sequence of (on, off) durations in units. a word gap
+/// is folded into the off time of the flash before it
+fn timings(flash: &FlashText) -> Result<Vec<(u32, u32)>> {
+    let mut result: Vec<(u32, u32)> = vec![];
+    for c in flash.text.chars() {
+        if c == ' ' {
+            if let Some(last) = result.last_mut() {
+                last.1 = 7;
+            }
+            continue;
+        }
+        let flashes: Vec<u32> = match flash.pattern {
+            FlashPattern::Morse => morse(c)
+                .ok_or_else(|| anyhow!("Character: '{}' has no morse code", c))?
+                .chars().map(|e| if e == '-' { 3 } else { 1 }).collect(),
+            FlashPattern::Pulses => vec![1; pulse_count(c)
+                .ok_or_else(|| anyhow!("Character: '{}' has no pulse count", c))? as usize]
+        };
+        for on in flashes {
+            result.push((on, 1));
+        }
+        // gap between characters
+        if let Some(last) = result.last_mut() {
+            last.1 = 3;
+        }
+    }
+    Ok(result)
+}
+
+fn build_clip(mapping: &LightMapping, flash: &FlashText) -> Result<Vec<ClipStep>> {
+    let unit = flash.unit_millis.unwrap_or(DEFAULT_UNIT_MILLIS);
+    let mut steps = vec![];
+    for (n, (on, off)) in timings(flash)?.into_iter().enumerate() {
+        steps.push(ClipStep::MappingOn(LightMapping {
+            id: None,
+            cue: format!("{} flash {}", mapping.cue, n),
+            midi: None,
+            light: LightMappingType::Effect(Effect::Pop),
+            one_shot: None,
+            random_subset: None,
+            exec: None, critical: None, tags: None, synth_fade: None, velocity_to_brightness: None, modulation_target: None, pad: None,
+            ..mapping.clone()
+        }));
+        steps.push(ClipStep::WaitMillis(on * unit));
+        steps.push(ClipStep::MappingOff(steps.len() - 2));
+        steps.push(ClipStep::WaitMillis(off * unit));
+    }
+    steps.push(ClipStep::End);
+    Ok(steps)
+}
+
+/// replace every flashed text mapping in the show with a reference to a synthesized clip
+pub fn expand_flash_text(show: &mut ShowDefinition) -> Result<()> {
+    show.synthesize_clips("flash", |mapping, _| match &mapping.light {
+        LightMappingType::FlashText(flash) => Ok(Some(build_clip(mapping, flash)?)),
+        _ => Ok(None)
+    })
+}
@@ -0,0 +1,202 @@
+use std::collections::{BTreeSet, HashMap};
+use anyhow::Result;
+use clap::ValueEnum;
+
+use crate::show::{ClipStep, LightMapping, LightMappingType, MidiMappingType, ShowDefinition, substitute_targets};
+use crate::showstate::{build_target_lookup, resolve_targets};
+use crate::matrix;
+
+///
+/// This module renders the reference structure of a show as a diagram:
+/// mappings to the clips they start, clips to the mappings embedded in them,
+/// and mappings to the groups and receivers they target. Handy for seeing what
+/// a given pad ultimately touches in a large show file
+///
+
+#[derive(Debug,Clone,Copy,ValueEnum)]
+pub enum GraphFormat {
+    /// graphviz dot
+    Dot,
+    /// d2 (https://d2lang.com)
+    D2
+}
+
+#[derive(PartialEq,Eq,PartialOrd,Ord,Hash,Clone)]
+enum Node {
+    /// top level mappings by index, since cue names needn't be unique
+    Mapping(usize),
+    ClipMapping(String, usize),
+    Clip(String),
+    Group(u8),
+    Receiver(u8),
+    AllReceivers
+}
+
+#[derive(PartialEq,Eq,PartialOrd,Ord)]
+struct Edge {
+    from: Node,
+    to: Node,
+    /// a dashed edge is a clip stopping another clip rather than using it
+    dashed: bool
+}
+
+struct Graph {
+    nodes: BTreeSet<Node>,
+    edges: BTreeSet<Edge>,
+    labels: HashMap<Node,String>
+}
+
+impl Node {
+    /// a quoted identifier, which both formats accept
+    fn id(self: &Self) -> String {
+        let id = match self {
+            Node::Mapping(index) => format!("mapping {}", index),
+            Node::ClipMapping(clip, step) => format!("clip {} step {}", clip, step),
+            Node::Clip(clip) => format!("clip {}", clip),
+            Node::Group(id) => format!("group {}", id),
+            Node::Receiver(id) => format!("receiver {}", id),
+            Node::AllReceivers => "all receivers".to_string()
+        };
+        format!("\"{}\"", escape(&id))
+    }
+
+    fn shape(self: &Self) -> &'static str {
+        match self {
+            Node::Mapping(_) | Node::ClipMapping(_, _) => "box",
+            Node::Clip(_) => "hexagon",
+            Node::Group(_) | Node::AllReceivers => "ellipse",
+            Node::Receiver(_) => "circle"
+        }
+    }
+}
+
+fn describe_trigger(mapping: &LightMapping) -> String {
+    match &mapping.midi {
+        Some(MidiMappingType::Note { channel, note }) => format!("\\nch {} {}", channel, note),
+        Some(MidiMappingType::Controller { channel, cc }) => format!("\\nch {} cc {}", channel, cc),
+        Some(MidiMappingType::Timecode { hh, mm, ss, ff }) => format!("\\n@ {:02}:{:02}:{:02}:{:02}", hh, mm, ss, ff),
+        Some(MidiMappingType::NoteRange { channel, low, high }) => format!("\\nch {} {}-{}", channel, low, high),
+        None => String::new()
+    }
+}
+
+impl Graph {
+
+    fn add(self: &mut Self, node: Node, label: String) {
+        self.labels.insert(node.clone(), label);
+        self.nodes.insert(node);
+    }
+
+    fn build(show: &ShowDefinition) -> Result<Graph> {
+        let mut graph = Graph { nodes: BTreeSet::new(), edges: BTreeSet::new(), labels: HashMap::new() };
+        let (target_lookup, group_members) = build_target_lookup(show);
+        let matrix_targets = matrix::matrix_targets(show)?;
+        let group_names: HashMap<u8,&String> = target_lookup.iter()
+            .filter(|(name, id)| group_members.contains_key(id) && name.parse::<u8>().is_err())
+            .map(|(name, id)| (*id, name))
+            .collect();
+        let receiver_names: HashMap<u8,&String> = show.receivers.iter()
+            .filter_map(|r| r.name.as_ref().map(|name| (r.id, name)))
+            .collect();
+
+        let add_mapping = |graph: &mut Graph, node: Node, mapping: &LightMapping| -> Result<()> {
+            graph.add(node.clone(), format!("{}{}", mapping.cue, describe_trigger(mapping)));
+            match &mapping.light {
+                LightMappingType::Clip(clip) => {
+                    graph.edges.insert(Edge { from: node, to: Node::Clip(clip.clone()), dashed: false });
+                },
+                _ => {
+                    // a variable target could be any of the variable's values
+                    let all_values = |name: &str| show.variables.as_ref()
+                        .and_then(|v| v.get(name)).map_or(vec![], |v| v.values.clone());
+                    let targets = resolve_targets(&substitute_targets(&mapping.targets, all_values), &target_lookup, &matrix_targets)?;
+                    if targets.is_empty() {
+                        graph.add(Node::AllReceivers, "all receivers".to_string());
+                        graph.edges.insert(Edge { from: node.clone(), to: Node::AllReceivers, dashed: false });
+                    }
+                    for target in targets {
+                        let target_node = match group_members.get(&target) {
+                            Some(members) => {
+                                let group = Node::Group(target);
+                                graph.add(group.clone(), format!("group {}", group_names.get(&target).map_or(target.to_string(), |n| n.to_string())));
+                                for member in members {
+                                    graph.edges.insert(Edge { from: group.clone(), to: Node::Receiver(*member), dashed: false });
+                                }
+                                group
+                            },
+                            None => Node::Receiver(target)
+                        };
+                        graph.edges.insert(Edge { from: node.clone(), to: target_node, dashed: false });
+                    }
+                }
+            }
+            Ok(())
+        };
+
+        for (index, mapping) in show.mappings.iter().enumerate() {
+            add_mapping(&mut graph, Node::Mapping(index), mapping)?;
+        }
+        for (name, steps) in show.clips.iter() {
+            let clip = Node::Clip(name.clone());
+            graph.add(clip.clone(), format!("clip {}", name));
+            for (index, step) in steps.iter().enumerate() {
+                match step {
+                    ClipStep::MappingOn(mapping) => {
+                        let node = Node::ClipMapping(name.clone(), index);
+                        add_mapping(&mut graph, node.clone(), mapping)?;
+                        graph.edges.insert(Edge { from: clip.clone(), to: node, dashed: false });
+                    },
+                    ClipStep::StopOther(other) => {
+                        graph.edges.insert(Edge { from: clip.clone(), to: Node::Clip(other.clone()), dashed: true });
+                    },
+                    _ => {}
+                }
+            }
+        }
+        // only draw receivers something actually reaches
+        let reached: Vec<u8> = graph.edges.iter()
+            .filter_map(|e| if let Node::Receiver(id) = e.to { Some(id) } else { None })
+            .collect();
+        for id in reached {
+            graph.add(Node::Receiver(id), receiver_names.get(&id).map_or(id.to_string(), |n| format!("{} ({})", n, id)));
+        }
+        Ok(graph)
+    }
+
+    fn render_dot(self: &Self) -> String {
+        let mut out = String::from("digraph show {\n    rankdir=LR;\n");
+        for node in self.nodes.iter() {
+            out.push_str(&format!("    {} [label=\"{}\", shape={}];\n", node.id(), escape(&self.labels[node]), node.shape()));
+        }
+        for edge in self.edges.iter() {
+            out.push_str(&format!("    {} -> {}{};\n", edge.from.id(), edge.to.id(), if edge.dashed { " [style=dashed, label=\"stops\"]" } else { "" }));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn render_d2(self: &Self) -> String {
+        let mut out = String::from("direction: right\n");
+        for node in self.nodes.iter() {
+            let shape = match node.shape() { "box" => "rectangle", "ellipse" => "oval", other => other };
+            out.push_str(&format!("{}: \"{}\" {{ shape: {} }}\n", node.id(), escape(&self.labels[node]), shape));
+        }
+        for edge in self.edges.iter() {
+            out.push_str(&format!("{} -> {}{}\n", edge.from.id(), edge.to.id(), if edge.dashed { ": stops { style.stroke-dash: 3 }" } else { "" }));
+        }
+        out
+    }
+}
+
+fn escape(label: &str) -> String {
+    label.replace('"', "\\\"")
+}
+
+/// render the reference graph of a show in the given format
+pub fn render(show: &ShowDefinition, format: GraphFormat) -> Result<String> {
+    let graph = Graph::build(show)?;
+    Ok(match format {
+        GraphFormat::Dot => graph.render_dot(),
+        GraphFormat::D2 => graph.render_d2()
+    })
+}
@@ -0,0 +1,167 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::show::LightMapping;
+use crate::clock;
+
+///
+/// Hooks let a mapping run an external command when it's turned on or off, for
+/// integrations the transmitter doesn't know about (scoreboard calls, pyro interlocks).
+/// They only run if the config turns them on, and then in a bare environment: no shell,
+/// no inherited variables beyond a fixed PATH, no stdin, a fixed working directory and
+/// a time limit. Commands run on their own thread so a slow script can't hold up a cue
+///
+
+const DEFAULT_MIN_INTERVAL_MILLIS: u64 = 1000;
+const DEFAULT_MAX_RUNNING: usize = 4;
+const DEFAULT_TIMEOUT_MILLIS: u64 = 10_000;
+const HOOK_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const POLL_PERIOD: Duration = Duration::from_millis(50);
+
+#[derive(Debug,Deserialize)]
+pub struct ExecHookConfig {
+    /// the least time between runs of one mapping's hook for the same event, further
+    /// triggers in between are skipped. an off is never held back by its on.
+    /// defaults to one second
+    pub min_interval_millis: Option<u64>,
+
+    /// how many hooks may run at once, further triggers are skipped. defaults to 4
+    pub max_running: Option<usize>,
+
+    /// how long a hook may run before it's killed. defaults to ten seconds
+    pub timeout_millis: Option<u64>,
+
+    /// the directory hooks run in, defaults to /
+    pub working_dir: Option<String>
+}
+
+pub struct HookRunner {
+    min_interval: Duration,
+    max_running: usize,
+    timeout: Duration,
+    working_dir: PathBuf,
+    /// when each mapping's hook last ran, by mapping id and whether it was for an on
+    last_run: RefCell<HashMap<(usize, bool), Instant>>,
+    /// hooks currently running, counted down by their threads
+    running: Arc<AtomicUsize>
+}
+
+impl HookRunner {
+
+    pub fn new(config: &ExecHookConfig) -> HookRunner {
+        HookRunner {
+            min_interval: Duration::from_millis(config.min_interval_millis.unwrap_or(DEFAULT_MIN_INTERVAL_MILLIS)),
+            max_running: config.max_running.unwrap_or(DEFAULT_MAX_RUNNING),
+            timeout: Duration::from_millis(config.timeout_millis.unwrap_or(DEFAULT_TIMEOUT_MILLIS)),
+            working_dir: PathBuf::from(config.working_dir.as_deref().unwrap_or("/")),
+            last_run: RefCell::new(HashMap::new()),
+            running: Arc::new(AtomicUsize::new(0))
+        }
+    }
+
+    /// run the mapping's hook, if it has one and isn't being rate limited, telling it
+    /// about the cue through CHS_* environment variables
+    pub fn run(self: &Self, mapping: &LightMapping, on: bool, receivers: &[u8]) {
+        let Some(command_line) = &mapping.exec else { return };
+        let now = clock::now();
+        let key = (mapping.get_id(), on);
+        if self.last_run.borrow().get(&key).is_some_and(|at| now - *at < self.min_interval) {
+            debug!("skipping hook for cue: {}, it ran too recently", mapping.cue);
+            return
+        }
+        if self.running.load(Ordering::SeqCst) >= self.max_running {
+            warn!("skipping hook for cue: {}, {} hooks are already running", mapping.cue, self.max_running);
+            return
+        }
+        self.last_run.borrow_mut().insert(key, now);
+
+        // arguments are split on whitespace, there's no shell to interpret quotes
+        let mut words = command_line.split_whitespace();
+        let Some(program) = words.next() else { return };
+        let mut command = Command::new(program);
+        command.args(words)
+            .env_clear()
+            .env("PATH", HOOK_PATH)
+            .env("CHS_CUE", &mapping.cue)
+            .env("CHS_EVENT", if on { "on" } else { "off" })
+            .env("CHS_COLOR", &mapping.color)
+            .env("CHS_SONG", mapping.song.as_deref().unwrap_or(""))
+            .env("CHS_RECEIVERS", receivers.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(","))
+            .current_dir(&self.working_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+
+        let cue = mapping.cue.clone();
+        let timeout = self.timeout;
+        let running = self.running.clone();
+        running.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            supervise(&mut command, &cue, timeout);
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+/// run a hook to completion, killing it if it takes too long
+fn supervise(command: &mut Command, cue: &str, timeout: Duration) {
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Could not run hook for cue: {}, error: {}", cue, e);
+            return
+        }
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => {
+                debug!("hook for cue: {} finished", cue);
+                return
+            },
+            Ok(Some(status)) => {
+                warn!("hook for cue: {} failed with {}", cue, status);
+                return
+            },
+            Ok(None) if Instant::now() >= deadline => {
+                info!("hook for cue: {} took too long, killing it", cue);
+                let _ = child.kill();
+                let _ = child.wait();
+                return
+            },
+            Ok(None) => thread::sleep(POLL_PERIOD),
+            Err(e) => {
+                error!("Could not wait for hook for cue: {}, error: {}", cue, e);
+                return
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_off_is_not_held_back_by_its_on() {
+        let hooks = HookRunner::new(&ExecHookConfig { min_interval_millis: None, max_running: None,
+            timeout_millis: None, working_dir: None });
+        let mapping: LightMapping = serde_json::from_str(r#"{ "cue": "pyro", "light": { "Effect": "Pop" },
+            "color": "red", "exec": "true" }"#).unwrap();
+        hooks.run(&mapping, true, &[1]);
+        let on_ran = hooks.last_run.borrow()[&(mapping.get_id(), true)];
+        hooks.run(&mapping, false, &[1]);
+        assert!(hooks.last_run.borrow().contains_key(&(mapping.get_id(), false)));
+        // but a second on so soon still is
+        hooks.run(&mapping, true, &[1]);
+        assert_eq!(hooks.last_run.borrow()[&(mapping.get_id(), true)], on_ran);
+    }
+}
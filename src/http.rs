@@ -0,0 +1,306 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use anyhow::{Context, Result, anyhow, bail};
+use crossbeam_channel::Sender;
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::arbitration::ControlSource;
+use crate::control::{self, SocketCommand, SocketReply};
+use crate::director::DirectorMessage;
+use crate::osc::percent_decode;
+use crate::session::SessionLog;
+use crate::showstate::ShowStatus;
+
+///
+/// The HTTP API, so the booth can get at the show from a phone browser when the midi
+/// controller won't: GET / is a page of every cue with buttons to fire and kill it, over
+/// the endpoints below. Names in paths are percent-encoded, eg /cues/big%20hit/fire.
+///
+///   GET  /cues                      every cue, its songs and whether it's showing
+///   GET  /status                    what the show is doing, as the control socket reports it
+///   POST /cues/<name>/fire|kill     fire or release a cue
+///   POST /clips/<name>/start|stop   start or stop a clip
+///   POST /brightness                set the grand master, eg {"percent": 50}
+///   POST /reload                    reload the show
+///
+/// Commands are answered with the control socket's replies, with 422 if the show
+/// couldn't carry one out. One request is served per connection
+///
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_BIND: &str = "127.0.0.1";
+/// the longest request body accepted
+const MAX_BODY: usize = 4096;
+
+const INDEX_PAGE: &str = r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width, initial-scale=1"><title>lights-xmit</title>
+<style>body{font-family:sans-serif} td{padding:4px} .active{font-weight:bold;color:#c00} button{padding:8px 14px}</style>
+</head><body>
+<p><button onclick="post('/reload')">Reload</button>
+Brightness <input id="brightness" type="range" min="0" max="100" value="100" onchange="post('/brightness', {percent: +this.value})"></p>
+<table id="cues"></table>
+<script>
+async function post(path, body) {
+  const reply = await (await fetch(path, {method: 'POST', body: body && JSON.stringify(body)})).json();
+  if (!reply.ok) alert(reply.error);
+  load();
+}
+async function load() {
+  const table = document.getElementById('cues');
+  table.innerHTML = '';
+  for (const c of await (await fetch('/cues')).json()) {
+    const row = table.insertRow();
+    const name = row.insertCell();
+    name.textContent = c.cue + (c.songs.length ? ' (' + c.songs.join(', ') + ')' : '');
+    if (c.active) name.className = 'active';
+    for (const action of ['fire', 'kill']) {
+      const button = document.createElement('button');
+      button.textContent = action;
+      button.onclick = () => post('/cues/' + encodeURIComponent(c.cue) + '/' + action);
+      row.insertCell().appendChild(button);
+    }
+  }
+}
+load();
+</script></body></html>
+"#;
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct HttpConfig {
+    /// the TCP port to listen on, defaults to 8080
+    pub port: Option<u16>,
+    /// the local address to listen on, defaults to 127.0.0.1 so only this machine can
+    /// reach it. "0.0.0.0" opens it to every interface, for phones in the booth
+    pub bind: Option<String>
+}
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String
+}
+
+impl Response {
+    fn json(status: u16, body: String) -> Response {
+        Response { status, content_type: "application/json", body }
+    }
+
+    /// the control socket's reply to a command
+    fn reply(result: Result<Option<ShowStatus>>) -> Response {
+        let status = if result.is_ok() { 200 } else { 422 };
+        Response::json(status, serde_json::to_string(&SocketReply::from(result)).unwrap())
+    }
+
+    fn error(status: u16, error: String) -> Response {
+        Response::json(status, serde_json::to_string(&SocketReply::from(Err(anyhow!(error)))).unwrap())
+    }
+}
+
+#[derive(Deserialize)]
+struct BrightnessBody {
+    percent: u8
+}
+
+/// listen for HTTP requests, passing the commands in them to the director
+pub fn start(config: &HttpConfig, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<()> {
+    let bind = config.bind.as_deref().unwrap_or(DEFAULT_BIND);
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+    let listener = TcpListener::bind((bind, port)).with_context(|| format!("Could not listen for HTTP on {}:{}", bind, port))?;
+    info!("Listening for HTTP on {}:{}", bind, port);
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(connection) => {
+                    let tx = tx.clone();
+                    let session_log = session_log.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = serve(connection, &tx, &session_log) {
+                            warn!("HTTP connection closed with error: {}", e);
+                        }
+                    });
+                },
+                Err(e) => error!("Could not accept HTTP connection: {}", e)
+            }
+        }
+    });
+    Ok(())
+}
+
+/// answer the connection's one request
+fn serve(mut connection: TcpStream, tx: &Sender<DirectorMessage>, session_log: &SessionLog) -> Result<()> {
+    let response = match read_request(&mut BufReader::new(connection.try_clone()?)) {
+        Ok(request) => {
+            debug!("HTTP request: {} {}", request.method, request.path);
+            route(&request, tx, session_log)
+        },
+        Err(e) => Response::error(400, e.to_string())
+    };
+    write!(connection, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status, reason(response.status), response.content_type, response.body.len(), response.body)?;
+    Ok(())
+}
+
+fn read_request(reader: &mut impl BufRead) -> Result<Request> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("bad request line: {}", request_line.trim_end())
+    };
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("connection closed in the headers")
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| anyhow!("bad content length: {}", value.trim()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        bail!("request body longer than {} bytes", MAX_BODY)
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    // the query string, if any, is ignored
+    let path = target.split('?').next().unwrap_or_default();
+    Ok(Request { method: method.to_string(), path: path.to_string(), body })
+}
+
+fn route(request: &Request, tx: &Sender<DirectorMessage>, session_log: &SessionLog) -> Response {
+    let execute = |command: Result<SocketCommand>| Response::reply(
+        command.and_then(|command| control::execute(command, ControlSource::Http, tx, session_log)));
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", [""]) => Response { status: 200, content_type: "text/html; charset=utf-8", body: INDEX_PAGE.to_string() },
+        ("GET", ["cues"]) => match control::cues(tx) {
+            Ok(cues) => Response::json(200, serde_json::to_string(&cues).unwrap()),
+            Err(e) => Response::error(503, e.to_string())
+        },
+        ("GET", ["status"]) => execute(Ok(SocketCommand::Status)),
+        ("POST", ["cues", name, "fire"]) => execute(percent_decode(name).map(|cue| SocketCommand::Fire { cue })),
+        ("POST", ["cues", name, "kill"]) => execute(percent_decode(name).map(|cue| SocketCommand::Release { cue })),
+        ("POST", ["clips", name, "start"]) => execute(percent_decode(name).map(|clip| SocketCommand::StartClip { clip })),
+        ("POST", ["clips", name, "stop"]) => execute(percent_decode(name).map(|clip| SocketCommand::StopClip { clip })),
+        ("POST", ["brightness"]) => match serde_json::from_slice::<BrightnessBody>(&request.body) {
+            Ok(body) => execute(Ok(SocketCommand::Brightness { percent: body.percent })),
+            Err(e) => Response::error(400, format!("Could not parse brightness: {}", e))
+        },
+        ("POST", ["reload"]) => execute(Ok(SocketCommand::Reload)),
+        (_, [""] | ["cues"] | ["status"] | ["brightness"] | ["reload"] | ["cues", _, "fire" | "kill"] | ["clips", _, "start" | "stop"]) =>
+            Response::error(405, format!("{} not allowed on: {}", request.method, request.path)),
+        _ => Response::error(404, format!("Nothing at: {}", request.path))
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        422 => "Unprocessable Entity",
+        _ => "Service Unavailable"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::*;
+
+    fn request(text: &str) -> Result<Request> {
+        read_request(&mut Cursor::new(text.as_bytes()))
+    }
+
+    /// route a request to a stand-in director, which knows one cue, returning the
+    /// response and the cues it was asked to fire or release
+    fn route_to_director(method: &str, path: &str, body: &str) -> (Response, Vec<(String, bool)>) {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let director = thread::spawn(move || rx.iter().filter_map(|message| match message {
+            DirectorMessage::Cue { cue, on, reply, .. } => {
+                let result = if cue == "big hit" { Ok(()) } else { Err(anyhow!("No cue named: {}", cue)) };
+                reply.unwrap().send(result).unwrap();
+                Some((cue, on))
+            },
+            _ => None
+        }).collect::<Vec<_>>());
+        let request = Request { method: method.to_string(), path: path.to_string(), body: body.as_bytes().to_vec() };
+        let response = route(&request, &tx, &SessionLog::open(&None).unwrap());
+        drop(tx);
+        (response, director.join().unwrap())
+    }
+
+    #[test]
+    fn requests_are_read_to_their_method_path_and_body() {
+        let r = request("POST /brightness?from=phone HTTP/1.1\r\nHost: x\r\ncontent-LENGTH: 15\r\n\r\n{\"percent\": 50}").unwrap();
+        assert_eq!((r.method.as_str(), r.path.as_str(), r.body.as_slice()), ("POST", "/brightness", &b"{\"percent\": 50}"[..]));
+        let r = request("GET /cues HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((r.method.as_str(), r.path.as_str(), r.body.len()), ("GET", "/cues", 0));
+    }
+
+    #[test]
+    fn bad_requests_are_refused() {
+        assert!(request("\r\n\r\n").is_err());
+        assert!(request("GET /cues HTTP/1.1\r\nHost: x\r\n").is_err());
+        assert!(request("POST /reload HTTP/1.1\r\nContent-Length: lots\r\n\r\n").is_err());
+        assert!(request(&format!("POST /reload HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1)).is_err());
+        // a body shorter than its length
+        assert!(request("POST /reload HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}").is_err());
+    }
+
+    #[test]
+    fn cues_are_fired_and_killed_by_their_percent_decoded_names() {
+        let (response, cues) = route_to_director("POST", "/cues/big%20hit/fire", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(cues, vec![("big hit".to_string(), true)]);
+        let (response, cues) = route_to_director("POST", "/cues/big%20hit/kill/", "");
+        assert_eq!(response.status, 200);
+        assert_eq!(cues, vec![("big hit".to_string(), false)]);
+        // a name that doesn't decode never reaches the director
+        let (response, cues) = route_to_director("POST", "/cues/big%2hit/fire", "");
+        assert_eq!(response.status, 422);
+        assert!(cues.is_empty());
+    }
+
+    #[test]
+    fn what_the_show_cannot_do_is_unprocessable() {
+        let (response, cues) = route_to_director("POST", "/cues/small%20hit/fire", "");
+        assert_eq!(response.status, 422);
+        assert!(response.body.contains("No cue named: small hit"), "{}", response.body);
+        assert_eq!(cues, vec![("small hit".to_string(), true)]);
+        let (response, _) = route_to_director("POST", "/brightness", r#"{"percent": 101}"#);
+        assert_eq!(response.status, 422);
+        let (response, _) = route_to_director("POST", "/brightness", r#"{"level": 50}"#);
+        assert_eq!(response.status, 400);
+    }
+
+    #[test]
+    fn wrong_methods_and_unknown_paths_are_refused() {
+        for (method, path) in [("GET", "/cues/big%20hit/fire"), ("POST", "/cues"), ("DELETE", "/"), ("GET", "/reload"), ("PUT", "/clips/intro/start")] {
+            let (response, cues) = route_to_director(method, path, "");
+            assert_eq!(response.status, 405, "{} {}", method, path);
+            assert!(cues.is_empty());
+        }
+        for (method, path) in [("GET", "/cue/big%20hit/fire"), ("POST", "/cues/big%20hit/toggle"), ("POST", "/cues/fire"), ("GET", "/status/now")] {
+            let (response, _) = route_to_director(method, path, "");
+            assert_eq!(response.status, 404, "{} {}", method, path);
+        }
+        let (response, _) = route_to_director("GET", "/", "");
+        assert_eq!((response.status, response.content_type), (200, "text/html; charset=utf-8"));
+    }
+}
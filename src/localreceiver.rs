@@ -0,0 +1,298 @@
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use crossbeam_channel::{Sender, Receiver, bounded, RecvTimeoutError};
+use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+use linux_embedded_hal::Spidev;
+use log::{debug,info,error,warn};
+use serde::Deserialize;
+
+use crate::packet::{Command, DecodedPacket, EffectId, HeaderMode, PacketPayload, ShowPacket, GROUP_ID_RANGE};
+use crate::show::Color;
+
+///
+/// A local receiver is an LED strip attached to the Pi itself (eg podium indicator
+/// lights) that behaves like a receiver on the field: it hears every packet the radio
+/// sends, keeps the ones addressed to it (its group is assigned by the show like any
+/// other receiver), and renders them with a transmitter-side approximation of the
+/// receiver effects. Doubles as a live confidence monitor of what was just sent.
+/// The strip is WS2812, driven from SPI with each data bit stretched to three SPI bits.
+/// The ws2812-spi crate does the same encoding, but its drivers need either the
+/// non-blocking FullDuplex spi of embedded-hal 0.2 (ws2812-spi 0.4) or the SpiBus of
+/// embedded-hal 1.0 (0.5). The Spidev of linux-embedded-hal 0.3, which the radios use,
+/// only has the blocking 0.2 traits, and moving to linux-embedded-hal 0.4 would take
+/// the rfm69 driver (on embedded-hal 0.2) with it, so the frames are encoded here and
+/// written straight to the device
+///
+
+const SPI_SPEED_HZ: u32 = 2_400_000;
+const FRAME_PERIOD: Duration = Duration::from_millis(20);
+/// more than the 50us of low the strip needs to latch a frame
+const RESET_BYTES: usize = 24;
+const DEFAULT_LED_COUNT: u16 = 30;
+const DEFAULT_TEMPO: f32 = 120.0;
+/// packets waiting for the local receiver thread, beyond which they're dropped
+const QUEUE_DEPTH: usize = 64;
+
+#[derive(Debug,Deserialize)]
+pub struct LocalReceiverConfig {
+    /// the receiver id, which the show uses to address (and assign a group to) this strip
+    pub id: u8,
+
+    /// the spi device the strip's data line hangs off, eg /dev/spidev0.1
+    pub spi_device: String,
+
+    /// the number of leds on the strip, until the show sets it. defaults to 30
+    pub led_count: Option<u16>
+}
+
+/// the radio's handle on a local receiver thread
+pub struct LocalReceiver {
+    tx: Sender<Vec<u8>>
+}
+
+impl LocalReceiver {
+
+    pub fn start(config: &LocalReceiverConfig, header_mode: HeaderMode) -> Result<LocalReceiver> {
+        let mut spi = Spidev::open(&config.spi_device)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(SPI_SPEED_HZ)
+            .mode(SpiModeFlags::SPI_MODE_0)
+            .build();
+        spi.configure(&options)?;
+        info!("Local receiver {} on {}", config.id, config.spi_device);
+
+        let (tx, rx) = bounded(QUEUE_DEPTH);
+        let mut strip = Strip {
+            id: config.id,
+            group: None,
+            led_count: config.led_count.unwrap_or(DEFAULT_LED_COUNT),
+            brightness: 255,
+            current: None
+        };
+        thread::spawn(move || strip.run(spi, rx, header_mode));
+        Ok(LocalReceiver { tx })
+    }
+
+    /// offer a packet that was just sent over the air
+    pub fn deliver(self: &Self, marshalled: &[u8]) {
+        if self.tx.try_send(marshalled.to_vec()).is_err() {
+            debug!("local receiver queue full, dropping packet");
+        }
+    }
+}
+
+/// the effect a strip is currently showing
+struct Playing {
+    packet: ShowPacket,
+    started: Instant,
+    /// when an off arrived, so the release can start early
+    released: Option<Instant>
+}
+
+struct Strip {
+    id: u8,
+    group: Option<u8>,
+    led_count: u16,
+    brightness: u8,
+    current: Option<Playing>
+}
+
+impl Strip {
+
+    fn run(self: &mut Self, mut spi: Spidev, rx: Receiver<Vec<u8>>, header_mode: HeaderMode) {
+        let mut next_frame = Instant::now();
+        loop {
+            match rx.recv_timeout(next_frame.saturating_duration_since(Instant::now())) {
+                Ok(buf) => match DecodedPacket::unmarshal(&buf, header_mode) {
+                    Ok(packet) => self.receive(packet),
+                    Err(e) => warn!("local receiver could not decode packet: {}", e)
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    let frame = encode(&self.render(Instant::now()));
+                    if let Err(e) = spi.write_all(&frame) {
+                        error!("Could not write to local receiver {}, giving up on it. Error: {}", self.id, e);
+                        return
+                    }
+                    next_frame = next_frame + FRAME_PERIOD;
+                },
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        }
+    }
+
+    fn addressed_to_me(self: &Self, packet: &DecodedPacket) -> bool {
+        let me = |target: u8| target == self.id || (GROUP_ID_RANGE.contains(&target) && Some(target) == self.group);
+        if packet.to == 0xFF {
+            packet.recipients.is_empty() || packet.recipients.iter().any(|r| me(*r))
+        } else {
+            me(packet.to)
+        }
+    }
+
+    fn receive(self: &mut Self, packet: DecodedPacket) {
+        if !self.addressed_to_me(&packet) {
+            return
+        }
+        let now = Instant::now();
+        match packet.payload {
+            PacketPayload::Show(ShowPacket { effect: EffectId::Off, .. }) => {
+                if let Some(playing) = &mut self.current {
+                    playing.released.get_or_insert(now);
+                }
+            },
+            PacketPayload::Show(show) => {
+                self.current = Some(Playing { packet: show, started: now, released: None });
+            },
+            PacketPayload::Control(Command::SetGroup { group_id }) => self.group = Some(group_id),
+            PacketPayload::Control(Command::SetLedCount { led_count }) => self.led_count = led_count,
+            PacketPayload::Control(Command::NewBrightness { brightness }) => self.brightness = brightness,
+            PacketPayload::Control(Command::Reset) => {
+                self.current = None;
+                self.brightness = 255;
+            },
+            PacketPayload::Control(_) => {}
+        }
+    }
+
+    /// the strip's colors at the given moment
+    fn render(self: &mut Self, now: Instant) -> Vec<(u8,u8,u8)> {
+        let count = self.led_count as usize;
+        let mut leds = vec![(0,0,0); count];
+        let Some(playing) = self.current.as_ref().filter(|_| count > 0) else { return leds };
+        let level = playing.level(now);
+        if level <= 0.0 {
+            self.current = None;
+            return leds
+        }
+        let packet = &playing.packet;
+        let tempo = if packet.tempo == 0 { DEFAULT_TEMPO } else { packet.tempo as f32 };
+        let beats = (now - playing.started).as_secs_f32() * tempo / 60.0;
+        // the fraction of the way through the current beat, and the lit position of a chase
+        let phase = beats.fract();
+        let position = (phase * count as f32) as usize;
+        let scale = level * self.brightness as f32 / 255.0;
+        let color = |c: Color| hsv_to_rgb(Color { v: (c.v as f32 * scale) as u8, ..c });
+
+        for (i, led) in leds.iter_mut().enumerate() {
+            let lit = match packet.effect {
+                EffectId::Strobe => (beats * packet.param1.max(1) as f32).fract() < 0.5,
+                EffectId::Chase | EffectId::CircularChase | EffectId::OneShotChase => {
+                    let head = if packet.param2 != 0 { count - 1 - position } else { position };
+                    (i + count - head) % count < packet.param1.max(1) as usize
+                },
+                EffectId::BidiChase | EffectId::BidiOneShotChase => {
+                    // out and back each beat
+                    let head = ((1.0 - (phase * 2.0 - 1.0).abs()) * (count - 1) as f32) as usize;
+                    i.abs_diff(head) < packet.param1.max(1) as usize
+                },
+                EffectId::Sparkle | EffectId::Twinkle => (i + beats as usize) % (packet.param1.max(2) as usize) == 0,
+                EffectId::BatteryTest => i < count / 2,
+                EffectId::DigitalPin | EffectId::PinAndSpin => false,
+                _ => true
+            };
+            if lit {
+                *led = match packet.effect {
+                    EffectId::Rainbow => {
+                        // sweep from the color's hue to the secondary hue along the strip, rotating each beat
+                        let span = packet.param1.wrapping_sub(packet.color.h) as f32;
+                        let offset = ((i as f32 / count as f32 + phase) % 1.0) * span;
+                        color(Color { h: packet.color.h.wrapping_add(offset as u8), ..packet.color })
+                    },
+                    _ => color(packet.color)
+                };
+            }
+        }
+        leds
+    }
+}
+
+/// attack and release are sent in hundredths of a second, or tenths if the high bit is set
+fn adr_duration(adr: u8) -> Duration {
+    if adr & 0x80 == 0 { Duration::from_millis(adr as u64 * 10) } else { Duration::from_millis((adr & 0x7F) as u64 * 100) }
+}
+
+/// sustain is sent in tenths of a second, or whole seconds if the high bit is set. 255 is forever
+fn sustain_duration(sustain: u8) -> Option<Duration> {
+    match sustain {
+        255 => None,
+        s if s & 0x80 == 0 => Some(Duration::from_millis(s as u64 * 100)),
+        s => Some(Duration::from_secs((s & 0x7F) as u64))
+    }
+}
+
+impl Playing {
+    /// the envelope level, 0.0 to 1.0, at the given moment
+    fn level(self: &Self, now: Instant) -> f32 {
+        let elapsed = now - self.started;
+        let attack = adr_duration(self.packet.attack);
+        let release = adr_duration(self.packet.release);
+        // release starts at the end of the sustain, or when an off arrives, whichever is first
+        let release_start = [sustain_duration(self.packet.sustain).map(|s| attack + s),
+            self.released.map(|at| at - self.started)].into_iter().flatten().min();
+        match release_start {
+            Some(start) if elapsed >= start => {
+                if release.is_zero() { 0.0 } else { 1.0 - ((elapsed - start).as_secs_f32() / release.as_secs_f32()).min(1.0) }
+            },
+            _ if elapsed < attack => elapsed.as_secs_f32() / attack.as_secs_f32(),
+            _ => 1.0
+        }
+    }
+}
+
+fn hsv_to_rgb(color: Color) -> (u8,u8,u8) {
+    let h = color.h as f32 * 6.0 / 256.0;
+    let s = color.s as f32 / 255.0;
+    let v = color.v as f32;
+    let f = h.fract();
+    let p = v * (1.0 - s);
+    let q = v * (1.0 - s * f);
+    let t = v * (1.0 - s * (1.0 - f));
+    let (r, g, b) = match h as u8 {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q)
+    };
+    (r as u8, g as u8, b as u8)
+}
+
+/// the spi bytes for a frame: green, red, blue for each led, each bit
+/// stretched to three spi bits (1 -> 110, 0 -> 100), then a latching gap
+fn encode(leds: &[(u8,u8,u8)]) -> Vec<u8> {
+    let mut bits: Vec<bool> = Vec::with_capacity(leds.len() * 72);
+    for (r, g, b) in leds {
+        for byte in [g, r, b] {
+            for bit in (0..8).rev() {
+                bits.extend([true, byte & (1 << bit) != 0, false]);
+            }
+        }
+    }
+    let mut buf: Vec<u8> = bits.chunks(8)
+        .map(|chunk| chunk.iter().fold(0u8, |acc, bit| (acc << 1) | *bit as u8))
+        .collect();
+    buf.extend([0u8; RESET_BYTES]);
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_are_green_red_blue_three_spi_bits_a_bit_then_a_latch() {
+        let frame = encode(&[(0xFF, 0x00, 0x80)]);
+        assert_eq!(frame.len(), 9 + RESET_BYTES);
+        // green 0x00: 100 eight times
+        assert_eq!(frame[0..3], [0b1001_0010, 0b0100_1001, 0b0010_0100]);
+        // red 0xFF: 110 eight times
+        assert_eq!(frame[3..6], [0b1101_1011, 0b0110_1101, 0b1011_0110]);
+        // blue 0x80: 110 then 100 seven times
+        assert_eq!(frame[6..9], [0b1101_0010, 0b0100_1001, 0b0010_0100]);
+        assert!(frame[9..].iter().all(|b| *b == 0));
+    }
+}
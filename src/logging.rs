@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, RwLock};
+use anyhow::{Result, anyhow};
+use log::{info, Level, LevelFilter, Log, Metadata, Record};
+
+///
+/// Logging that can be made more (or less) verbose for one module at a time while the
+/// transmitter runs, since turning on debug everywhere during a live show floods the
+/// journal with per-packet dumps. RUST_LOG sets the baseline as usual; a module given
+/// its own level here (eg "radio" at debug) ignores the baseline until it's cleared
+///
+
+/// the modules a level can be set for, as they're named in the source
+pub const MODULES: [&str; 4] = ["radio", "showstate", "clip", "director"];
+
+struct ModuleLogger {
+    /// RUST_LOG's filtering, used for any module without its own level
+    baseline: env_logger::Logger,
+    /// formats and writes whatever passes the filtering
+    writer: env_logger::Logger,
+    /// module path (eg "lights_xmit::radio") to the level it's been given
+    levels: RwLock<HashMap<String,LevelFilter>>,
+    /// error records kept while capturing, whatever the levels, for reports like a shadow run's
+    captured: Mutex<Option<Vec<String>>>
+}
+
+static LOGGER: OnceLock<ModuleLogger> = OnceLock::new();
+
+impl ModuleLogger {
+    /// the level set for the target's module or the nearest module containing it
+    fn level_for(self: &Self, target: &str) -> Option<LevelFilter> {
+        self.levels.read().unwrap().iter()
+            .filter(|(module, _)| target == module.as_str() || target.starts_with(&format!("{}::", module)))
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+    }
+}
+
+impl Log for ModuleLogger {
+    fn enabled(self: &Self, metadata: &Metadata) -> bool {
+        match self.level_for(metadata.target()) {
+            Some(level) => metadata.level() <= level,
+            None => self.baseline.enabled(metadata)
+        }
+    }
+
+    fn log(self: &Self, record: &Record) {
+        if record.level() == Level::Error {
+            if let Some(captured) = self.captured.lock().unwrap().as_mut() {
+                captured.push(record.args().to_string());
+            }
+        }
+        if self.enabled(record.metadata()) {
+            self.writer.log(record);
+        }
+    }
+
+    fn flush(self: &Self) {
+        self.writer.flush();
+    }
+}
+
+/// install the logger, in place of env_logger::init
+pub fn init() {
+    let logger = LOGGER.get_or_init(|| ModuleLogger {
+        baseline: env_logger::Builder::from_default_env().build(),
+        writer: env_logger::Builder::new().filter_level(LevelFilter::Trace).build(),
+        levels: RwLock::new(HashMap::new()),
+        captured: Mutex::new(None)
+    });
+    if log::set_logger(logger).is_ok() {
+        // every record has to reach the logger, in case a module's level is raised later
+        log::set_max_level(LevelFilter::Trace);
+    }
+}
+
+/// give a module its own log level, or with None return it to the baseline
+pub fn set_module_level(module: &str, level: Option<LevelFilter>) -> Result<()> {
+    if !MODULES.contains(&module) {
+        return Err(anyhow!("No module named: {}, expected one of: {}", module, MODULES.join(", ")))
+    }
+    let logger = LOGGER.get().ok_or_else(|| anyhow!("Logging is not initialized"))?;
+    let path = format!("{}::{}", env!("CARGO_CRATE_NAME"), module);
+    match level {
+        Some(level) => {
+            info!("log level for {} set to: {}", module, level);
+            logger.levels.write().unwrap().insert(path, level);
+        },
+        None => {
+            info!("log level for {} returned to the baseline", module);
+            logger.levels.write().unwrap().remove(&path);
+        }
+    }
+    Ok(())
+}
+
+/// parse a set of module levels given by name (eg from the config), checking the
+/// modules and levels are ones we know
+pub fn parse_levels(levels: &HashMap<String,String>) -> Result<Vec<(String,LevelFilter)>> {
+    levels.iter().map(|(module, level)| {
+        if !MODULES.contains(&module.as_str()) {
+            return Err(anyhow!("No module named: {}, expected one of: {}", module, MODULES.join(", ")))
+        }
+        let level = LevelFilter::from_str(level).map_err(|_| anyhow!("Unknown log level: {} for module: {}", level, module))?;
+        Ok((module.clone(), level))
+    }).collect()
+}
+
+/// give each of the modules its level, or with on false return them to the baseline
+pub fn apply_levels(levels: &[(String,LevelFilter)], on: bool) -> Result<()> {
+    levels.iter().try_for_each(|(module, level)| set_module_level(module, if on { Some(*level) } else { None }))
+}
+
+/// start keeping the messages of errors logged from now on
+pub fn capture_errors() {
+    if let Some(logger) = LOGGER.get() {
+        logger.captured.lock().unwrap().replace(vec![]);
+    }
+}
+
+/// stop capturing, returning the error messages logged since capture_errors
+pub fn take_errors() -> Vec<String> {
+    LOGGER.get().and_then(|logger| logger.captured.lock().unwrap().take()).unwrap_or_default()
+}
@@ -0,0 +1,319 @@
+use log::{debug,info};
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use linux_embedded_hal::spidev::SpidevTransfer;
+use linux_embedded_hal::Spidev;
+use linux_embedded_hal::gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+
+use crate::config::ConfigFile;
+use crate::packet::MAX_PACKET_LEN;
+use crate::radio::{RadioBackend, RadioError, open_spi};
+
+///
+/// A backend for receivers built on SX1276/7/8/9 (eg RFM95) LoRa modules. Packets are
+/// the same as over the rfm69, except the length byte isn't sent: LoRa's explicit
+/// header carries the length, so what goes on the air is what RadioHead's RH_RF95
+/// driver expects. The chip is driven a register at a time over spi, see the SX1276
+/// datasheet (https://www.semtech.com/products/wireless-rf/lora-connect/sx1276)
+///
+
+const DEFAULT_SETTLE_TIME: u64 = 10;
+const DEFAULT_SPREADING_FACTOR: u8 = 7;
+/// the chip's spreading factor 6 only works with implicit headers, where the receivers
+/// are set up for one payload length ahead of time. packets vary in length and the
+/// receivers read it from the explicit header, so 6 can't be used
+const SPREADING_FACTORS: RangeInclusive<u8> = 7..=12;
+const DEFAULT_BANDWIDTH: u32 = 125_000;
+const DEFAULT_CODING_RATE: u8 = 5;
+const PREAMBLE_LENGTH: u16 = 8;
+// radiohead's (private network) sync word
+const SYNC_WORD: u8 = 0x12;
+const CHIP_VERSION: u8 = 0x12;
+const CRYSTAL_HZ: u64 = 32_000_000;
+// at high spreading factors a full packet takes seconds on the air
+const SEND_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_PERIOD: Duration = Duration::from_millis(1);
+
+// registers
+const REG_FIFO: u8 = 0x00;
+const REG_OP_MODE: u8 = 0x01;
+const REG_FRF_MSB: u8 = 0x06;
+const REG_PA_CONFIG: u8 = 0x09;
+const REG_OCP: u8 = 0x0B;
+const REG_FIFO_ADDR_PTR: u8 = 0x0D;
+const REG_FIFO_TX_BASE_ADDR: u8 = 0x0E;
+const REG_FIFO_RX_BASE_ADDR: u8 = 0x0F;
+const REG_FIFO_RX_CURRENT_ADDR: u8 = 0x10;
+const REG_IRQ_FLAGS: u8 = 0x12;
+const REG_RX_NB_BYTES: u8 = 0x13;
+const REG_PKT_RSSI_VALUE: u8 = 0x1A;
+const REG_MODEM_CONFIG_1: u8 = 0x1D;
+const REG_MODEM_CONFIG_2: u8 = 0x1E;
+const REG_PREAMBLE_MSB: u8 = 0x20;
+const REG_PAYLOAD_LENGTH: u8 = 0x22;
+const REG_MODEM_CONFIG_3: u8 = 0x26;
+const REG_SYNC_WORD: u8 = 0x39;
+const REG_VERSION: u8 = 0x42;
+const REG_PA_DAC: u8 = 0x4D;
+
+// operating modes, with the long range (lora) bit set
+const MODE_LONG_RANGE: u8 = 0x80;
+const MODE_SLEEP: u8 = 0x00;
+const MODE_STANDBY: u8 = 0x01;
+const MODE_TX: u8 = 0x03;
+const MODE_RX_CONTINUOUS: u8 = 0x05;
+
+const IRQ_TX_DONE: u8 = 0x08;
+const IRQ_PAYLOAD_CRC_ERROR: u8 = 0x20;
+const IRQ_RX_DONE: u8 = 0x40;
+
+/// the LoRa modem settings, which must match the receivers' build
+#[derive(Debug,Deserialize,Clone,Copy,Default)]
+pub struct LoraConfig {
+    /// 7 to 12, higher reaches further but takes longer on the air. defaults to 7
+    pub spreading_factor: Option<u8>,
+    /// the bandwidth in Hz, one of the chip's steps from 7800 to 500000. defaults to 125000
+    pub bandwidth: Option<u32>,
+    /// the denominator of the 4/n coding rate, 5 to 8. defaults to 5
+    pub coding_rate: Option<u8>
+}
+
+impl LoraConfig {
+    /// check the spreading factor, as the chip (and the receivers) can't use 6
+    pub fn validate(self: &Self) -> anyhow::Result<()> {
+        if let Some(spreading_factor) = self.spreading_factor.filter(|sf| !SPREADING_FACTORS.contains(sf)) {
+            anyhow::bail!("LoRa spreading_factor {} is not from {} to {}{}", spreading_factor,
+                SPREADING_FACTORS.start(), SPREADING_FACTORS.end(),
+                if spreading_factor == 6 { " (6 needs implicit headers, which packets of varying length can't use)" } else { "" })
+        }
+        Ok(())
+    }
+}
+
+/// the chip's bandwidth steps, in the order of their register values
+const BANDWIDTHS: [u32; 10] = [7_800, 10_400, 15_600, 20_800, 31_250, 41_700, 62_500, 125_000, 250_000, 500_000];
+
+pub struct Sx127xBackend {
+    spi: Spidev,
+    /// chip select, if it's on a gpio line rather than the spi controller's
+    cs: Option<LineHandle>,
+    /// packet rssi is offset differently on the high and low frequency ports
+    rssi_offset: i16,
+    last_rssi: Option<f32>
+}
+
+impl RadioBackend for Sx127xBackend {
+    fn init(config: &ConfigFile) -> Result<Sx127xBackend, RadioError> {
+        if config.aes_key.is_some() {
+            return Err(RadioError::Sx127xError(Sx127xError::AesUnsupported))
+        }
+        let lora = config.lora.unwrap_or_default();
+        let spreading_factor = lora.spreading_factor.unwrap_or(DEFAULT_SPREADING_FACTOR);
+        if !SPREADING_FACTORS.contains(&spreading_factor) {
+            return Err(RadioError::Sx127xError(Sx127xError::SpreadingFactor))
+        }
+        let bandwidth = lora.bandwidth.unwrap_or(DEFAULT_BANDWIDTH);
+        let bandwidth_step = BANDWIDTHS.iter().position(|bw| *bw == bandwidth)
+            .ok_or(RadioError::Sx127xError(Sx127xError::Bandwidth))? as u8;
+        let coding_rate = lora.coding_rate.unwrap_or(DEFAULT_CODING_RATE);
+        if !(5..=8).contains(&coding_rate) {
+            return Err(RadioError::Sx127xError(Sx127xError::CodingRate))
+        }
+
+        // unlike the rfm69, the sx127x is held in reset by pulling reset low
+        let mut gpio_dev = Chip::new(&config.gpio_device)?;
+        let reset_line = gpio_dev.get_line(config.reset_line)?;
+        let reset_handle = reset_line.request(LineRequestFlags::OUTPUT, 0, "chs-lights")?;
+        let settle_time = Duration::from_millis(config.settle_time_millis.unwrap_or(DEFAULT_SETTLE_TIME));
+        sleep(settle_time);
+        reset_handle.set_value(1)?;
+        sleep(settle_time);
+
+        let spi = open_spi(config)?;
+        // chip select is active low, so it idles high
+        let cs = match config.cs_line {
+            Some(cs_line) => Some(gpio_dev.get_line(cs_line)?.request(LineRequestFlags::OUTPUT, 1, "chs-lights")?),
+            None => None
+        };
+
+        let mut radio = Sx127xBackend { spi, cs, rssi_offset: if config.frequency > 779_000_000 { -157 } else { -164 }, last_rssi: None };
+        let version = radio.read(REG_VERSION)?;
+        if version != CHIP_VERSION {
+            return Err(RadioError::Sx127xError(Sx127xError::Version(version)))
+        }
+        // the long range bit can only be changed while asleep
+        radio.write(REG_OP_MODE, MODE_SLEEP)?;
+        radio.write(REG_OP_MODE, MODE_LONG_RANGE | MODE_SLEEP)?;
+        radio.write(REG_FIFO_TX_BASE_ADDR, 0)?;
+        radio.write(REG_FIFO_RX_BASE_ADDR, 0)?;
+        radio.set_mode(MODE_STANDBY)?;
+
+        let frf = ((config.frequency as u64) << 19) / CRYSTAL_HZ;
+        radio.write_burst(REG_FRF_MSB, &[(frf >> 16) as u8, (frf >> 8) as u8, frf as u8])?;
+        // bandwidth, coding rate and the explicit header
+        radio.write(REG_MODEM_CONFIG_1, (bandwidth_step << 4) | ((coding_rate - 4) << 1))?;
+        // spreading factor, and crcs on
+        radio.write(REG_MODEM_CONFIG_2, (spreading_factor << 4) | 0x04)?;
+        // the chip needs help with long symbols (over 16ms), and the lna gain is left to the agc
+        let symbol_micros = (1u64 << spreading_factor) * 1_000_000 / bandwidth as u64;
+        radio.write(REG_MODEM_CONFIG_3, if symbol_micros > 16_000 { 0x0C } else { 0x04 })?;
+        radio.write_burst(REG_PREAMBLE_MSB, &[(PREAMBLE_LENGTH >> 8) as u8, PREAMBLE_LENGTH as u8])?;
+        radio.write(REG_SYNC_WORD, SYNC_WORD)?;
+        radio.set_power(config.transmitter_power)?;
+        info!("Using LoRa with spreading factor: {}, bandwidth: {}Hz, coding rate: 4/{}", spreading_factor, bandwidth, coding_rate);
+
+        for reg in 0x01..=0x26u8 {
+            debug!("Register 0x{:02x} = 0x{:02x}", reg, radio.read(reg)?);
+        }
+        Ok(radio)
+    }
+
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError> {
+        // the explicit header carries the length, so the length byte is left off
+        let body = &marshalled[1..];
+        self.set_mode(MODE_STANDBY)?;
+        self.write(REG_FIFO_ADDR_PTR, 0)?;
+        self.write_burst(REG_FIFO, body)?;
+        self.write(REG_PAYLOAD_LENGTH, body.len() as u8)?;
+        self.set_mode(MODE_TX)?;
+        let deadline = Instant::now() + SEND_TIMEOUT;
+        while self.read(REG_IRQ_FLAGS)? & IRQ_TX_DONE == 0 {
+            if Instant::now() >= deadline {
+                self.set_mode(MODE_STANDBY)?;
+                return Err(RadioError::Sx127xError(Sx127xError::Timeout))
+            }
+            sleep(POLL_PERIOD);
+        }
+        self.write(REG_IRQ_FLAGS, 0xFF)?;
+        Ok(())
+    }
+
+    /// PA_BOOST output, as on the RFM95 modules and LoRa bonnets
+    fn set_power(&mut self, power: i8) -> Result<(), RadioError> {
+        match power {
+            2..=17 => {
+                self.write(REG_PA_DAC, 0x84)?;
+                self.write(REG_PA_CONFIG, 0x80 | (power - 2) as u8)?;
+            },
+            // the top of the range needs the high power dac, and more current
+            18..=20 => {
+                self.write(REG_PA_DAC, 0x87)?;
+                self.write(REG_OCP, 0x3B)?;
+                self.write(REG_PA_CONFIG, 0x80 | (power - 5) as u8)?;
+            },
+            _ => return Err(RadioError::IllegalPower)
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        self.write(REG_FIFO_ADDR_PTR, 0)?;
+        self.set_mode(MODE_RX_CONTINUOUS)?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let flags = self.read(REG_IRQ_FLAGS)?;
+            if flags & IRQ_RX_DONE != 0 {
+                self.write(REG_IRQ_FLAGS, 0xFF)?;
+                if flags & IRQ_PAYLOAD_CRC_ERROR != 0 {
+                    debug!("Dropping received packet with a bad crc");
+                    continue
+                }
+                let len = (self.read(REG_RX_NB_BYTES)? as usize).min(MAX_PACKET_LEN);
+                let start = self.read(REG_FIFO_RX_CURRENT_ADDR)?;
+                self.write(REG_FIFO_ADDR_PTR, start)?;
+                // the rest of the transmitter expects the length byte at the front
+                let mut buf = vec![0u8; len + 1];
+                buf[0] = len as u8;
+                self.read_burst(REG_FIFO, &mut buf[1..])?;
+                self.last_rssi = Some((self.rssi_offset + self.read(REG_PKT_RSSI_VALUE)? as i16) as f32);
+                self.set_mode(MODE_STANDBY)?;
+                return Ok(Some(buf))
+            }
+            if Instant::now() >= deadline {
+                self.set_mode(MODE_STANDBY)?;
+                return Ok(None)
+            }
+            sleep(POLL_PERIOD);
+        }
+    }
+
+    fn rssi(&mut self) -> Option<f32> {
+        self.last_rssi
+    }
+}
+
+impl Sx127xBackend {
+    fn set_mode(self: &mut Self, mode: u8) -> Result<(), RadioError> {
+        self.write(REG_OP_MODE, MODE_LONG_RANGE | mode)
+    }
+
+    fn read(self: &mut Self, reg: u8) -> Result<u8, RadioError> {
+        let mut value = [0u8];
+        self.read_burst(reg, &mut value)?;
+        Ok(value[0])
+    }
+
+    fn write(self: &mut Self, reg: u8, value: u8) -> Result<(), RadioError> {
+        self.write_burst(reg, &[value])
+    }
+
+    /// the top bit of the address marks a write, consecutive bytes go to consecutive
+    /// registers (or, for the fifo, into the fifo)
+    fn write_burst(self: &mut Self, reg: u8, values: &[u8]) -> Result<(), RadioError> {
+        let mut tx = Vec::with_capacity(values.len() + 1);
+        tx.push(reg | 0x80);
+        tx.extend_from_slice(values);
+        self.transfer(&mut SpidevTransfer::write(&tx))
+    }
+
+    fn read_burst(self: &mut Self, reg: u8, values: &mut [u8]) -> Result<(), RadioError> {
+        let mut tx = vec![0u8; values.len() + 1];
+        tx[0] = reg & 0x7F;
+        let mut rx = vec![0u8; tx.len()];
+        self.transfer(&mut SpidevTransfer::read_write(&tx, &mut rx))?;
+        values.copy_from_slice(&rx[1..]);
+        Ok(())
+    }
+
+    fn transfer(self: &mut Self, transfer: &mut SpidevTransfer) -> Result<(), RadioError> {
+        if let Some(cs) = &self.cs {
+            cs.set_value(0)?;
+        }
+        let result = self.spi.transfer(transfer);
+        if let Some(cs) = &self.cs {
+            cs.set_value(1)?;
+        }
+        Ok(result?)
+    }
+}
+
+/// failures particular to the sx127x
+#[derive(Debug)]
+pub enum Sx127xError {
+    /// a packet took too long to send
+    Timeout,
+    /// the chip didn't identify itself as an sx127x
+    Version(u8),
+    SpreadingFactor,
+    Bandwidth,
+    CodingRate,
+    /// the chip has no hardware aes to encrypt with
+    AesUnsupported
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreading_factor_6_is_refused() {
+        let lora = |spreading_factor| LoraConfig { spreading_factor, ..Default::default() };
+        assert!(lora(None).validate().is_ok());
+        assert!(lora(Some(7)).validate().is_ok());
+        assert!(lora(Some(12)).validate().is_ok());
+        assert!(lora(Some(6)).validate().is_err());
+        assert!(lora(Some(13)).validate().is_err());
+    }
+}
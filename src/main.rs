@@ -1,48 +1,104 @@
 use std::path::PathBuf;
 use std::fs::File;
-use std::io;
-use clap::{Parser, command};
-use midir::MidiInputConnection;
-use packet::{Packet,PacketPayload,ShowPacket,EffectId};
+use clap::{Parser, Subcommand, command};
+use midir::{MidiInputConnection,MidiOutputConnection};
+use packet::{Packet,PacketPayload,ShowPacket,DecodedPacket,HeaderMode,parse_hex};
 use log::{debug,info,warn,error};
 use crossbeam_channel::bounded;
 use anyhow::{anyhow,Result,Context};
 use std::thread;
-use signal_hook::consts::{SIGINT,SIGTERM,SIGHUP};
+use std::panic::{self,AssertUnwindSafe};
+use std::time::Duration;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::AtomicBool;
+use signal_hook::consts::{SIGINT,SIGTERM,SIGHUP,SIGUSR2};
 use signal_hook::iterator::SignalsInfo;
 use signal_hook::iterator::exfiltrator::WithOrigin;
+use signal_hook::low_level::raise;
 use json_comments::StripComments;
 
 use crate::radio::Radio;
 use crate::director::{Director,DirectorMessage};
 use crate::show::Color;
+use crate::session::SessionLog;
+use crate::midi::MidiSender;
+use crate::fastlane::MidiRouter;
+use crate::statusled::StatusLed;
+use crate::error::{ChsError,ErrorCategory,Recovery};
 
 pub mod config;
 pub mod radio;
+pub mod lora;
+pub mod udp;
 pub mod midi;
+pub mod rtpmidi;
+pub mod osc;
+pub mod websocket;
+pub mod http;
+pub mod mqtt;
+pub mod sacn;
+pub mod midifilter;
+pub mod fastlane;
 pub mod packet;
 pub mod show;
 pub mod director;
 pub mod showstate;
 pub mod clip;
+pub mod matrix;
+pub mod flash;
+pub mod preset;
+pub mod showfile;
+pub mod session;
+pub mod metronome;
+pub mod feedback;
+pub mod padsetup;
+pub mod graph;
+pub mod dashboard;
+pub mod arbitration;
+pub mod exercise;
+pub mod clock;
+pub mod statusled;
+pub mod localreceiver;
+pub mod artnet;
+pub mod logging;
+pub mod check;
+pub mod validate;
+pub mod watch;
+pub mod control;
+pub mod ctl;
+pub mod hooks;
+pub mod error;
+pub mod shadow;
+pub mod recording;
+pub mod packetlog;
+pub mod midiclock;
+pub mod timecode;
+pub mod txqueue;
+#[cfg(test)]
+mod scenario;
 
 // note - the pad controller impersonates an Arturia Minilab 
 // and uses sysex messages like
 // F0 00 20 6B 7F 42 02 00 00 63 00 F7 
 // to manipulate settings. in this case, setting 0 on pad 63 with value 0
 //
-// this can be used to configure the controller based on the show JSON
+// padsetup uses these to configure the controller based on the show JSON
 // this much of the sysex message is prefix: F0 00 20 6B 7F 42 02 00
 
 const DEFAULT_BUFFER_SIZE: usize = 10;
+const DEFAULT_MAX_SHOW_RESTARTS: u32 = 3;
 
 #[derive(Parser, Debug)]
 #[command(author, version)]
 #[command(about = "CHS Band Lights Transmitter")]
+#[command(subcommand_negates_reqs = true)]
 struct Cli {
 
-    #[arg(short, long, value_name = "FILE")]
-    config: PathBuf,
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[arg(short, long, value_name = "FILE", required_unless_present = "decode")]
+    config: Option<PathBuf>,
 
     #[arg(short, long)]
     debug: bool,
@@ -50,30 +106,188 @@ struct Cli {
     #[arg(short, long)]
     enumerate_midi: bool,
 
-    /// if true, just send an "all on white" packet
-    /// and exit, for troubleshooting purposes
+    /// if true, just send one show packet (Pop in white to everybody, on until
+    /// turned off, unless the options below say otherwise) and exit, for
+    /// troubleshooting purposes
     #[arg(short, long)]
-    all_on: bool
+    all_on: bool,
+
+    /// the color for --all-on as h,s,v (defaults to white)
+    #[arg(long, value_name = "H,S,V", requires = "all_on", value_parser = parse_color)]
+    color: Option<Color>,
+
+    /// the effect for --all-on, by name (defaults to Pop)
+    #[arg(long, value_name = "NAME", requires = "all_on")]
+    effect: Option<String>,
+
+    /// the sustain for --all-on in milliseconds (defaults to on until turned off)
+    #[arg(long, value_name = "MS", requires = "all_on")]
+    sustain: Option<u32>,
+
+    /// the targets for --all-on as a comma separated list of receiver or group
+    /// ids or names (names are looked up in the show), defaults to everybody
+    #[arg(long, value_name = "LIST", requires = "all_on", value_delimiter = ',')]
+    targets: Option<Vec<String>>,
+
+    /// decode a marshalled packet given as hex bytes (eg from receiver
+    /// serial logs or a sniffer), print it in human-readable form and exit.
+    /// if a config is given, its header mode is used to interpret the packet
+    #[arg(long, value_name = "HEX")]
+    decode: Option<String>,
+
+    /// resolve and validate the configured show, write it to a compact binary
+    /// cue file that loads without JSON parsing, and exit
+    #[arg(long, value_name = "FILE")]
+    compile: Option<PathBuf>,
+
+    /// print a diagram of the configured show (mappings, the clips they start,
+    /// and the groups and receivers they target) and exit
+    #[arg(long, value_name = "FORMAT")]
+    graph: Option<graph::GraphFormat>,
+
+    /// the same as --validate text
+    #[arg(long)]
+    check: bool,
+
+    /// load the configured show and play every cue of every song through to its
+    /// end against a mock radio, faster than real time, report any problems and exit
+    #[arg(long)]
+    shadow_run: bool,
+
+    /// load the configured show as the transmitter would, without a radio, and print
+    /// a report of the problems found at each stage (as text, or json), then exit
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text")]
+    validate: Option<validate::ReportFormat>,
+
+    /// cycle a receiver (by id, or by name from the show) through every
+    /// effect, a few seconds each, as an acceptance test, and exit
+    #[arg(long, value_name = "RECEIVER")]
+    exercise: Option<String>,
+
+    /// play back a performance recorded with "ctl record", over and over until
+    /// interrupted, eg as an attract mode. no show or midi is needed
+    #[arg(long, value_name = "FILE", group = "playback")]
+    attract: Option<PathBuf>,
+
+    /// send the packets of a --packet-log again, once, with their original timing,
+    /// to reproduce a performance's radio traffic without the band or the midi rig
+    #[arg(long, value_name = "FILE", group = "playback")]
+    replay: Option<PathBuf>,
+
+    /// how many times faster than it was recorded to play --attract or --replay back
+    #[arg(long, value_name = "FACTOR", requires = "playback", default_value_t = 1.0)]
+    speed: f32,
+
+    /// append every packet sent (with a timestamp, its bytes, its recipients and what
+    /// it decodes to) to the file as a line of JSON, for working out afterwards what
+    /// the lights were told to do
+    #[arg(long, value_name = "FILE")]
+    packet_log: Option<PathBuf>,
+
+    /// show a live dashboard of the receivers, clips and midi on the terminal while
+    /// the show runs. logging still goes to stderr, so redirect it, eg 2>xmit.log
+    #[arg(long)]
+    tui: bool
 
 }
 
-fn load_config(cli: &Cli) -> Result<config::ConfigFile, io::Error> {
-    let file = File::open(&cli.config)?;
-    Ok(serde_json::from_reader(StripComments::new(file))?)
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// control a running transmitter through its control socket
+    Ctl(ctl::CtlArgs)
+}
+
+fn load_config(cli: &Cli) -> anyhow::Result<config::ConfigFile> {
+    let file = File::open(cli.config.as_ref().unwrap())?;
+    let config: config::ConfigFile = serde_json::from_reader(StripComments::new(file))?;
+    config.validate()?;
+    Ok(config)
 }
 
 fn main() -> anyhow::Result<()> {
-    env_logger::init();
+    logging::init();
 
     let cli = Cli::parse();
     debug!("Command line arguments: {:?}", cli);
 
+    // talking to a running transmitter only needs to know where its socket is
+    if let Some(Commands::Ctl(args)) = &cli.command {
+        let socket = match (&args.socket, &cli.config) {
+            (Some(socket), _) => socket.clone(),
+            (None, Some(_)) => load_config(&cli).context("Error parsing configuration")?.control_socket
+                .map(PathBuf::from)
+                .ok_or_else(|| anyhow!("No control_socket in the configuration"))?,
+            (None, None) => return Err(anyhow!("Either --socket or a configuration with a control_socket is needed"))
+        };
+        return ctl::run(args, &socket);
+    }
+
+    // decoding doesn't need a radio, and only needs a config to know the header mode
+    if let Some(hex) = &cli.decode {
+        let header_mode = match &cli.config {
+            Some(_) => load_config(&cli).context("Error parsing configuration")?.header_mode.unwrap_or_default(),
+            None => HeaderMode::default()
+        };
+        return decode(hex, header_mode);
+    }
+
     let config = load_config(&cli)
         .context("Error parsing configuration")?;
     info!("Loaded configuration: {:?}", config);
 
+    if let Some(out_path) = &cli.compile {
+        return showfile::compile(&PathBuf::from(&config.show_file), out_path);
+    }
+
+    if let Some(format) = cli.graph {
+        let show = showfile::load(&PathBuf::from(&config.show_file))?;
+        print!("{}", graph::render(&show, format)?);
+        return Ok(())
+    }
+
+    if let Some(format) = cli.validate.or(cli.check.then_some(validate::ReportFormat::Text)) {
+        return validate::run(&config, format);
+    }
+
+    if cli.shadow_run {
+        let show = showfile::load(&PathBuf::from(&config.show_file))?;
+        shadow::shadow_run(&show, &config)?;
+        println!("Show {} passed its shadow run", config.show_file);
+        return Ok(())
+    }
+
     info!("Initializing radio...");
-    let mut radio = Radio::init(&config)?;
+    let mut radio = Radio::init(&config).map_err(ChsError::from)?;
+    if let Some(path) = &cli.packet_log {
+        radio.log_packets(&path.to_string_lossy())?;
+    }
+
+    if let Some(receiver) = &cli.exercise {
+        let receiver_id = match receiver.parse::<u8>() {
+            Ok(id) => id,
+            Err(_) => showfile::load(&PathBuf::from(&config.show_file))?.receivers.iter()
+                .find(|r| r.name.as_ref() == Some(receiver))
+                .map(|r| r.id)
+                .ok_or_else(|| anyhow!("No receiver named: {} in the show", receiver))?
+        };
+        return exercise::exercise(&radio, receiver_id);
+    }
+
+    if let Some(path) = &cli.attract {
+        let packets = recording::load(path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGINT, stop.clone())?;
+        signal_hook::flag::register(SIGTERM, stop.clone())?;
+        return recording::play(&radio, &packets, cli.speed, true, &stop);
+    }
+
+    if let Some(path) = &cli.replay {
+        let packets = packetlog::load(path)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(SIGINT, stop.clone())?;
+        signal_hook::flag::register(SIGTERM, stop.clone())?;
+        return recording::play(&radio, &packets, cli.speed, false, &stop);
+    }
 
     // handle some command line options that do some work and then terminate early
     match cli {
@@ -83,8 +297,7 @@ fn main() -> anyhow::Result<()> {
             return Ok(())
         },
         Cli { all_on: true, ..} => {
-            all_on(&mut radio);
-            return Ok(())
+            return all_on(&cli, &config, &mut radio);
         }
         _ => {}
     }
@@ -94,32 +307,110 @@ fn main() -> anyhow::Result<()> {
     let (tx, rx) = 
         bounded(config.channel_buf_depth.unwrap_or(DEFAULT_BUFFER_SIZE));
 
-    let midi_tx = tx.clone();
+    // realtime notes take a fast lane to the director, ahead of everything else
+    let midi_filter = midifilter::MidiFilter::new(config.midi_filter.as_ref()).context("Invalid midi filter").map_err(ChsError::Config)?;
+    let (router, realtime_rx) = MidiRouter::new(tx.clone(), midi_filter);
     
     let mut midi_in_connection: Option<MidiInputConnection<()>> = None;
+    let mut midi_out_connection: Option<MidiOutputConnection> = None;
     // if midi is configured, open the midi device and forward data to the midi channel
     if let Some(port) = &config.midi_port {
         info!("Initializing MIDI...");
-        let (midi_in, midi_out) = midi::midi_init(&config)?;
-
-        if let Some(ports) = midi::find_ports(&midi_in, &midi_out, &port) {
-            midi_in_connection = Some(midi_in.connect(&ports.0, "chs-lights-in", 
-                        move | ts, midi_bytes, _ | 
-                            { midi_tx.send(DirectorMessage::MidiMessage { ts, buf: midi_bytes.to_owned() }).unwrap(); }, ()).unwrap());
-        } else {
-            return Err(anyhow!("No MIDI port matches prefix: {:?}", config.midi_port))
+        match connect_midi(&config, port, router.clone()) {
+            Ok((midi_in, midi_out)) => {
+                midi_in_connection = Some(midi_in);
+                midi_out_connection = midi_out;
+            },
+            Err(e) if config.recovery(ErrorCategory::Midi) != Recovery::Exit =>
+                error!("Could not open MIDI, continuing without it. Error: {:#}", e),
+            Err(e) => return Err(e)
+        }
+    }
+    if let Some(rtp_midi) = &config.rtp_midi {
+        match rtpmidi::start(rtp_midi, &config.midi_client_name, router.clone()) {
+            Ok(()) => {},
+            Err(e) if config.recovery(ErrorCategory::Midi) != Recovery::Exit =>
+                error!("Could not listen for RTP-MIDI, continuing without it. Error: {:#}", e),
+            Err(e) => return Err(ChsError::Midi(e).into())
+        }
+    }
+    let mut virtual_in_connection: Option<MidiInputConnection<()>> = None;
+    if config.midi_virtual_port.unwrap_or(false) {
+        info!("Creating virtual MIDI port: {}", config.midi_client_name);
+        match create_virtual_midi(&config, router.clone()) {
+            Ok(connection) => virtual_in_connection = Some(connection),
+            Err(e) if config.recovery(ErrorCategory::Midi) != Recovery::Exit =>
+                error!("Could not create virtual MIDI port, continuing without it. Error: {:#}", e),
+            Err(e) => return Err(e)
         }
     }
     
+    let midi_out = midi_out_connection.map(|connection| Arc::new(MidiSender::Connection(Mutex::new(connection))));
+
+    let session_log = SessionLog::open(&config.session_log)?;
+    session_log.record("process", "start", &config.show_file);
+
+    let debug_log_levels = logging::parse_levels(&config.debug_log_levels.clone().unwrap_or_default())
+        .context("Error in debug_log_levels")?;
+
+    let max_restarts = config.max_show_restarts.unwrap_or(DEFAULT_MAX_SHOW_RESTARTS);
+    let restart_delay = config.show_restart_delay();
+
     // create a director and give it the receive channel, the config, and the radio
     // note the director takes ownership of the config, radio, and receiver
-    let mut director = Director::new(config, radio, rx);
+    let status_led = match &config.status_led {
+        Some(led_config) => match StatusLed::start(led_config, &config.gpio_device) {
+            Ok(status_led) => Some(status_led),
+            Err(e) => {
+                warn!("Could not set up the status LED, continuing without it. Error: {}", e);
+                None
+            }
+        },
+        None => None
+    };
+
+    if let Some(path) = &config.control_socket {
+        control::start(path, tx.clone(), session_log.clone())?;
+    }
+    if let Some(osc) = &config.osc {
+        match osc::start(osc, tx.clone()) {
+            Ok(()) => {},
+            Err(e) if config.recovery(ErrorCategory::Midi) != Recovery::Exit =>
+                error!("Could not listen for OSC, continuing without it. Error: {:#}", e),
+            Err(e) => return Err(ChsError::Midi(e).into())
+        }
+    }
+    if let Some(sacn) = &config.sacn {
+        sacn::start(sacn, tx.clone())?;
+    }
+    if let Some(http) = &config.http {
+        http::start(http, tx.clone(), session_log.clone())?;
+    }
+    let event_stream = config.websocket.as_ref()
+        .map(|websocket| websocket::start(websocket, tx.clone(), session_log.clone()))
+        .transpose()?;
+    let mqtt = config.mqtt.as_ref()
+        .map(|mqtt| mqtt::start(mqtt, &config.midi_client_name, tx.clone(), session_log.clone()));
+    if config.watch_show.unwrap_or(false) {
+        // the director takes the config, so the watcher validates changes against its own
+        let watch_config = load_config(&cli).context("Error parsing configuration")?;
+        if let Err(e) = watch::start(watch_config, tx.clone(), session_log.clone()) {
+            warn!("Could not watch the show file, continuing without reloading it on changes. Error: {:#}", e);
+        }
+    }
+
+    let mut director = Director::new(config, radio, rx, session_log.clone(), midi_out, status_led, event_stream)?;
+    director.use_fast_lane(&router, realtime_rx);
+    if let Some(mqtt) = mqtt {
+        director.publish_to_mqtt(mqtt);
+    }
+    if cli.tui {
+        director.show_dashboard();
+    }
 
-    // launch the show in its own thread
+    // launch the show in its own thread, under supervision
     let join_handle = thread::spawn(move || { 
-        if let Err(e) = director.run_show() { 
-            error!("Show terminated early with error: {}", e);
-        } 
+        supervise(&mut director, max_restarts, restart_delay);
     });
         
     // listen for signals and forward them to the director
@@ -129,19 +420,43 @@ fn main() -> anyhow::Result<()> {
         SIGTERM,
         // reload show from JSON
         SIGHUP,
+        // toggle the configured per-module log levels
+        SIGUSR2,
     ];
+    let mut debug_logging = false;
     
     let mut signals = SignalsInfo::<WithOrigin>::new(&sigs)?;
 
     if !join_handle.is_finished() {
         for info in &mut signals {
             debug!("In signal handling loop");
+            let sender = match &info.process {
+                Some(process) => format!("pid {}", process.pid),
+                None => "unknown sender".to_string()
+            };
             match info.signal {
                 SIGINT | SIGTERM => {
-                    tx.send(DirectorMessage::Shutdown)?;
+                    session_log.record("signal", "shutdown", &format!("{} from {}", signal_name(info.signal), sender));
+                    // the show thread may already be gone if it gave up restarting
+                    let _ = tx.send(DirectorMessage::Shutdown);
                     break;
                 },
-                SIGHUP => { tx.send(DirectorMessage::Reload)?; },
+                SIGHUP => {
+                    session_log.record("signal", "reload", &format!("SIGHUP from {}", sender));
+                    tx.send(DirectorMessage::Reload)?;
+                },
+                SIGUSR2 => {
+                    if debug_log_levels.is_empty() {
+                        warn!("SIGUSR2 received but no debug_log_levels are configured");
+                        continue;
+                    }
+                    debug_logging = !debug_logging;
+                    session_log.record("signal", "debug logging", &format!("{} by SIGUSR2 from {}",
+                        if debug_logging { "on" } else { "off" }, sender));
+                    if let Err(e) = logging::apply_levels(&debug_log_levels, debug_logging) {
+                        error!("Could not change log levels, error: {}", e);
+                    }
+                },
                 x => { warn!("Unexpected signal: {}", x); }
             }
         }
@@ -152,27 +467,148 @@ fn main() -> anyhow::Result<()> {
     // otherwise midirs will close the connection. The explicit drop
     // prevents midi_connection from being dropped prematurely
     drop(midi_in_connection);
+    drop(virtual_in_connection);
 
     // join the show thread before shutdown
     let _ = join_handle.join();
     Ok(())
 }
 
-fn all_on(radio: &mut Radio) {
-    let all_on = Packet {
-        recipients: &vec![],
-        payload: PacketPayload::Show(
-            ShowPacket {
-                effect: EffectId::Pop,
-                color: Color { h: 0, s: 0, v: 255 },
-                attack: 0,
-                sustain: 255,
-                release: 0,
-                param1: 0,
-                param2: 0,
-                tempo: 0
-            })
+/// connect to the configured midi port, forwarding what arrives to the director, and
+/// to its output too if the metronome or the pad controller needs it
+fn connect_midi(config: &config::ConfigFile, port: &str, router: MidiRouter)
+    -> Result<(MidiInputConnection<()>, Option<MidiOutputConnection>)> {
+    let (midi_in, midi_out) = midi::midi_init(config).map_err(|e| ChsError::Midi(e.into()))?;
+    let ports = midi::find_ports(&midi_in, &midi_out, port)
+        .ok_or_else(|| ChsError::Midi(anyhow!("No MIDI port matches prefix: {}", port)))?;
+    let midi_in_connection = midi_in.connect(&ports.0, "chs-lights-in", 
+                move | ts, midi_bytes, _ | 
+                    { router.route(ts, midi_bytes).unwrap(); }, ())
+        .map_err(|e| ChsError::Midi(anyhow!("Could not open MIDI input: {}", e)))?;
+    let midi_out_connection = if config.metronome.is_some() || config.pad_feedback.is_some() || config.pad_setup.is_some() {
+        Some(midi_out.connect(&ports.1, "chs-lights-out")
+            .map_err(|e| ChsError::Midi(anyhow!("Could not open MIDI output: {}", e)))?)
+    } else {
+        None
+    };
+    Ok((midi_in_connection, midi_out_connection))
+}
+
+/// create a virtual midi input port named for the midi client, forwarding what arrives
+/// to the director just as the configured port does
+#[cfg(unix)]
+fn create_virtual_midi(config: &config::ConfigFile, router: MidiRouter)
+    -> Result<MidiInputConnection<()>> {
+    use midir::os::unix::VirtualInput;
+    let (midi_in, _) = midi::midi_init(config).map_err(|e| ChsError::Midi(e.into()))?;
+    let connection = midi_in.create_virtual(&config.midi_client_name,
+                move | ts, midi_bytes, _ |
+                    { router.route(ts, midi_bytes).unwrap(); }, ())
+        .map_err(|e| ChsError::Midi(anyhow!("Could not create virtual MIDI port: {}", e)))?;
+    Ok(connection)
+}
+
+#[cfg(not(unix))]
+fn create_virtual_midi(_config: &config::ConfigFile, _router: MidiRouter)
+    -> Result<MidiInputConnection<()>> {
+    Err(ChsError::Midi(anyhow!("Virtual MIDI ports are not supported on this platform")).into())
+}
+
+/// run the show, restarting it after an error or panic up to max_restarts times. the
+/// director (and so the radio and the channel the midi connection feeds) survives a
+/// restart, so midi stays connected throughout
+fn supervise(director: &mut Director, max_restarts: u32, restart_delay: Duration) {
+    let mut restarts = 0;
+    loop {
+        match panic::catch_unwind(AssertUnwindSafe(|| director.run_show())) {
+            Ok(Ok(())) => {
+                if director.exit_requested() {
+                    // the show ended on schedule, wake up the signal handling loop so the process exits
+                    let _ = raise(SIGTERM);
+                }
+                return
+            },
+            Ok(Err(e)) => error!("Show terminated early with error: {}", e),
+            Err(_) => error!("Show thread panicked")
+        }
+        if restarts >= max_restarts {
+            error!("Show failed {} times, giving up", restarts + 1);
+            // wake up the signal handling loop so the process exits
+            let _ = raise(SIGTERM);
+            return
+        }
+        restarts = restarts + 1;
+        thread::sleep(restart_delay);
+        if !director.drain_stale_messages() {
+            info!("Shutdown requested while waiting to restart show");
+            return
+        }
+        warn!("Restarting show, attempt {} of {}", restarts, max_restarts);
+    }
+}
+
+fn signal_name(signal: i32) -> &'static str {
+    match signal {
+        SIGINT => "SIGINT",
+        SIGTERM => "SIGTERM",
+        SIGHUP => "SIGHUP",
+        SIGUSR2 => "SIGUSR2",
+        _ => "signal"
+    }
+}
+
+/// send a single show packet built from the --all-on options
+fn all_on(cli: &Cli, config: &config::ConfigFile, radio: &mut Radio) -> Result<()> {
+    let effect = match &cli.effect {
+        Some(name) => exercise::effect_by_name(name).ok_or_else(|| anyhow!("Unknown effect: {}", name))?,
+        None => show::Effect::Pop
+    };
+    let recipients = match &cli.targets {
+        Some(targets) => {
+            let targets: Vec<serde_json::Value> = targets.iter()
+                .map(|t| t.parse::<u8>().map_or_else(|_| serde_json::Value::from(t.as_str()), serde_json::Value::from))
+                .collect();
+            // names need the show to look up, plain ids don't
+            if targets.iter().all(|t| t.is_number()) {
+                targets.iter().map(|t| t.as_u64().unwrap() as u8).collect()
+            } else {
+                let show = showfile::load(&PathBuf::from(&config.show_file))?;
+                let (target_lookup, _) = showstate::build_target_lookup(&show);
+                showstate::resolve_targets(&Some(targets), &target_lookup, &matrix::matrix_targets(&show)?)?
+            }
+        },
+        None => vec![]
+    };
+    let mut show_packet = ShowPacket {
+        effect: effect.to_effect_id(),
+        color: cli.color.unwrap_or(Color { h: 0, s: 0, v: 255 }),
+        attack: 0,
+        sustain: cli.sustain.map_or(255, showstate::convert_millis_sustain),
+        release: 0,
+        param1: 0,
+        param2: 0,
+        tempo: 0
     };
+    effect.populate_effect_params(&mut show_packet);
+    radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(show_packet) })?;
+    Ok(())
+}
 
-    radio.send(&all_on).unwrap();
+/// parse a color given as h,s,v
+fn parse_color(hsv: &str) -> Result<Color> {
+    let parts = hsv.split(',').map(|p| p.trim().parse::<u8>()).collect::<Result<Vec<u8>,_>>()
+        .with_context(|| format!("Color components must be 0-255: {}", hsv))?;
+    match parts.as_slice() {
+        [h, s, v] => Ok(Color { h: *h, s: *s, v: *v }),
+        _ => Err(anyhow!("Color must be given as h,s,v: {}", hsv))
+    }
+}
+
+/// parse a string of hex bytes, tolerating whitespace, commas,
+/// colons and 0x prefixes between them
+fn decode(hex: &str, header_mode: HeaderMode) -> Result<()> {
+    let buf = parse_hex(hex)?;
+    let packet = DecodedPacket::unmarshal(&buf, header_mode)?;
+    println!("{:#?}", packet);
+    Ok(())
 }
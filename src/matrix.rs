@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use anyhow::{anyhow,Result};
+
+use crate::show::{ClipStep, Effect, LightMapping, LightMappingType, MatrixDefinition, MatrixEffect, ShowDefinition};
+
+///
+/// This module supports receivers arranged in a grid (eg backdrop panels).
+/// Rows and columns of a matrix become addressable targets, and matrix-wide
+/// meta-effects are decomposed into clips of ordinary Pop packets so that
+/// no receiver firmware changes are needed
+///
+
+const DEFAULT_BEATS_PER_STEP: f32 = 0.25;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+
+/// a 3x5 pixel font, one byte per row from the top, low three bits are the columns (msb leftmost)
+fn glyph(c: char) -> [u8; GLYPH_HEIGHT] {
+    match c.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        _ => [0; GLYPH_HEIGHT]
+    }
+}
+
+/// render text to a list of pixel columns, each a bitmask of lit rows (bit 0 is the top row)
+fn render_columns(text: &str) -> Vec<u8> {
+    let mut columns = vec![];
+    for c in text.chars() {
+        let rows = glyph(c);
+        for x in 0..GLYPH_WIDTH {
+            let mask = (GLYPH_WIDTH - 1) - x;
+            columns.push((0..GLYPH_HEIGHT).fold(0u8, |col, y| col | (((rows[y] >> mask) & 1) << y)));
+        }
+        // spacing between characters
+        columns.push(0);
+    }
+    columns
+}
+
+/// look up the receiver ids making up the cells of a matrix, in row-major order
+fn resolve_cells(show: &ShowDefinition, name: &str, matrix: &MatrixDefinition) -> Result<Vec<u8>> {
+    if matrix.receivers.len() != matrix.width * matrix.height {
+        return Err(anyhow!("Matrix: {} is {}x{} but lists {} receivers", name, matrix.width, matrix.height, matrix.receivers.len()));
+    }
+    matrix.receivers.iter().map(|r| {
+        let receiver = match r {
+            serde_json::Value::Number(n) => show.receivers.iter().find(|rc| n.as_u64() == Some(rc.id as u64)),
+            serde_json::Value::String(s) => show.receivers.iter().find(|rc| rc.name.as_ref() == Some(s)),
+            _ => None
+        };
+        receiver.map(|rc| rc.id).ok_or_else(|| anyhow!("Matrix: {} lists unknown receiver: {}", name, r))
+    }).collect()
+}
+
+fn lookup<'a>(show: &'a ShowDefinition, name: &str) -> Result<&'a MatrixDefinition> {
+    show.matrices.as_ref().and_then(|m| m.get(name))
+        .ok_or_else(|| anyhow!("Unknown matrix: {}", name))
+}
+
+/// build the extra named targets for every matrix: the whole matrix by name,
+/// plus each row and column as "name:row:N" and "name:col:N" (zero based)
+pub fn matrix_targets(show: &ShowDefinition) -> Result<HashMap<String,Vec<u8>>> {
+    let mut targets = HashMap::new();
+    for (name, matrix) in show.matrices.iter().flatten() {
+        let cells = resolve_cells(show, name, matrix)?;
+        for row in 0..matrix.height {
+            targets.insert(format!("{}:row:{}", name, row), cells[row * matrix.width..(row + 1) * matrix.width].to_vec());
+        }
+        for col in 0..matrix.width {
+            targets.insert(format!("{}:col:{}", name, col), cells.iter().skip(col).step_by(matrix.width).copied().collect());
+        }
+        targets.insert(name.clone(), cells);
+    }
+    Ok(targets)
+}
+
+/// compute the lit cells of each step of a matrix effect
+fn frames(show: &ShowDefinition, effect: &MatrixEffect) -> Result<Vec<Vec<u8>>> {
+    match effect {
+        MatrixEffect::ColumnSweep { matrix, reverse, .. } => {
+            let def = lookup(show, matrix)?;
+            let cells = resolve_cells(show, matrix, def)?;
+            let mut frames: Vec<Vec<u8>> = (0..def.width)
+                .map(|col| cells.iter().skip(col).step_by(def.width).copied().collect())
+                .collect();
+            if reverse.unwrap_or(false) { frames.reverse(); }
+            Ok(frames)
+        },
+        MatrixEffect::RowSweep { matrix, reverse, .. } => {
+            let def = lookup(show, matrix)?;
+            let cells = resolve_cells(show, matrix, def)?;
+            let mut frames: Vec<Vec<u8>> = cells.chunks(def.width).map(|row| row.to_vec()).collect();
+            if reverse.unwrap_or(false) { frames.reverse(); }
+            Ok(frames)
+        },
+        MatrixEffect::ScrollText { matrix, text, .. } => {
+            let def = lookup(show, matrix)?;
+            let cells = resolve_cells(show, matrix, def)?;
+            // pad with a screenful of blank columns on either side so the text
+            // scrolls in from the right edge and all the way off the left edge
+            let mut columns = vec![0u8; def.width];
+            columns.extend(render_columns(text));
+            columns.extend(vec![0u8; def.width]);
+            // center the font vertically on matrices taller than it
+            let top = def.height.saturating_sub(GLYPH_HEIGHT) / 2;
+            Ok(columns.windows(def.width).map(|window| {
+                let mut lit = vec![];
+                for y in 0..def.height.min(GLYPH_HEIGHT) {
+                    for (x, column) in window.iter().enumerate() {
+                        if (column >> y) & 1 == 1 {
+                            lit.push(cells[(top + y) * def.width + x]);
+                        }
+                    }
+                }
+                lit
+            }).collect())
+        }
+    }
+}
+
+/// turn a mapping to a matrix effect into a clip that lights each frame in turn. each frame is
+/// lit before the previous one is turned off, so cells lit in consecutive frames don't flicker
+fn build_clip(mapping: &LightMapping, effect: &MatrixEffect, frames: Vec<Vec<u8>>) -> Vec<ClipStep> {
+    let beats_per_step = match effect {
+        MatrixEffect::ColumnSweep { beats_per_step, .. } |
+        MatrixEffect::RowSweep { beats_per_step, .. } |
+        MatrixEffect::ScrollText { beats_per_step, .. } => beats_per_step.unwrap_or(DEFAULT_BEATS_PER_STEP)
+    };
+    let mut steps = vec![];
+    let mut previous: Option<usize> = None;
+    for (n, frame) in frames.into_iter().enumerate() {
+        // an empty target list would mean "everybody", so blank frames just turn off the previous one
+        let current = if frame.is_empty() { None } else {
+            steps.push(ClipStep::MappingOn(LightMapping {
+                id: None,
+                cue: format!("{} frame {}", mapping.cue, n),
+                midi: None,
+                light: LightMappingType::Effect(Effect::Pop),
+                one_shot: None,
+                random_subset: None,
+                exec: None, critical: None, tags: None, synth_fade: None, velocity_to_brightness: None, modulation_target: None, pad: None,
+                targets: Some(frame.into_iter().map(serde_json::Value::from).collect()),
+                ..mapping.clone()
+            }));
+            Some(steps.len() - 1)
+        };
+        if let Some(index) = previous {
+            steps.push(ClipStep::MappingOff(index));
+        }
+        previous = current;
+        steps.push(ClipStep::WaitBeats(beats_per_step));
+    }
+    if let Some(index) = previous {
+        steps.push(ClipStep::MappingOff(index));
+    }
+    steps.push(ClipStep::End);
+    steps
+}
+
+/// replace every matrix effect in the show (top level or embedded in a clip)
+/// with a reference to a synthesized clip that performs it
+pub fn expand_matrix_effects(show: &mut ShowDefinition) -> Result<()> {
+    show.synthesize_clips("matrix", |mapping, show| match &mapping.light {
+        LightMappingType::Matrix(effect) => Ok(Some(build_clip(mapping, effect, frames(show, effect)?))),
+        _ => Ok(None)
+    })
+}
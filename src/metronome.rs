@@ -0,0 +1,121 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use log::{debug,error};
+use serde::Deserialize;
+use crate::clock;
+use crate::midi::MidiSender;
+
+///
+/// The metronome echoes the tempo the lights are running at back out on the
+/// MIDI output, as MIDI clock and/or a click note, so that other gear (eg the
+/// drumline monitor rig) can sync to automated segments of the show
+///
+
+const PULSES_PER_BEAT: u32 = 24;
+const DEFAULT_CLICK_CHANNEL: u8 = 9;
+const DEFAULT_CLICK_VELOCITY: u8 = 100;
+
+const CLOCK: u8 = 0xF8;
+const START: u8 = 0xFA;
+const STOP: u8 = 0xFC;
+
+#[derive(Debug,Deserialize)]
+pub struct MetronomeConfig {
+    /// whether to send MIDI clock (with start/stop), defaults to true
+    pub clock: Option<bool>,
+
+    /// if populated, the note to send on every beat as a click
+    pub click_note: Option<u8>,
+
+    /// the channel to send the click on, defaults to 9 (GM drums)
+    pub click_channel: Option<u8>,
+
+    /// the velocity of the click, will use a default value if not supplied
+    pub click_velocity: Option<u8>
+}
+
+struct MetronomeState {
+    running: bool,
+    pulse: u32,
+    next_pulse: Instant
+}
+
+pub struct Metronome {
+    output: Arc<MidiSender>,
+    clock: bool,
+    click_note: Option<u8>,
+    click_channel: u8,
+    click_velocity: u8,
+    state: RefCell<MetronomeState>
+}
+
+impl Metronome {
+
+    pub fn new(config: &MetronomeConfig, output: Arc<MidiSender>) -> Metronome {
+        Metronome {
+            output,
+            clock: config.clock.unwrap_or(true),
+            click_note: config.click_note,
+            click_channel: config.click_channel.unwrap_or(DEFAULT_CLICK_CHANNEL) & 0x0F,
+            click_velocity: config.click_velocity.unwrap_or(DEFAULT_CLICK_VELOCITY),
+            state: RefCell::new(MetronomeState { running: false, pulse: 0, next_pulse: clock::now() })
+        }
+    }
+
+    fn send(self: &Self, message: &[u8]) {
+        if let Err(e) = self.output.send(message) {
+            error!("Could not send metronome message: {}", e);
+        }
+    }
+
+    fn click_off(self: &Self) {
+        if let Some(note) = self.click_note {
+            self.send(&[0x80 | self.click_channel, note, 0]);
+        }
+    }
+
+    /// send any clock pulses and clicks that are due at the given tempo, starting or
+    /// stopping the clock as the tempo comes and goes. returns when to call again
+    pub fn tick(self: &Self, tempo: Option<f32>) -> Option<Instant> {
+        let mut state = self.state.borrow_mut();
+        let now = clock::now();
+        let tempo = match tempo {
+            Some(tempo) if tempo > 0.0 => tempo,
+            _ => {
+                if state.running {
+                    debug!("Stopping metronome");
+                    if self.clock { self.send(&[STOP]); }
+                    self.click_off();
+                    state.running = false;
+                }
+                return None
+            }
+        };
+        let interval = Duration::from_secs_f32(60.0 / (tempo * PULSES_PER_BEAT as f32));
+        if !state.running {
+            debug!("Starting metronome at {} bpm", tempo);
+            if self.clock { self.send(&[START]); }
+            state.running = true;
+            state.pulse = 0;
+            state.next_pulse = now;
+        }
+        // if we've fallen well behind, resync rather than sending a burst of pulses
+        if now > state.next_pulse + interval {
+            state.next_pulse = now;
+        }
+        while state.next_pulse <= now {
+            if self.clock { self.send(&[CLOCK]); }
+            if let Some(note) = self.click_note {
+                match state.pulse {
+                    0 => self.send(&[0x90 | self.click_channel, note, self.click_velocity]),
+                    1 => self.click_off(),
+                    _ => {}
+                }
+            }
+            state.pulse = (state.pulse + 1) % PULSES_PER_BEAT;
+            state.next_pulse += interval;
+        }
+        Some(state.next_pulse)
+    }
+}
@@ -1,6 +1,38 @@
-use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputPort};
+use std::sync::Mutex;
+use midir::{MidiInput, MidiInputPort, MidiOutput, MidiOutputConnection, MidiOutputPort, SendError};
 use crate::config::ConfigFile;
 
+/// the midi output, shared by everything that sends on it (the metronome, pad feedback)
+pub enum MidiSender {
+    Connection(Mutex<MidiOutputConnection>),
+    /// records what would have been sent, for tests
+    #[cfg(test)]
+    Mock(Mutex<Vec<(std::time::Instant, Vec<u8>)>>)
+}
+
+impl MidiSender {
+
+    pub fn send(self: &Self, message: &[u8]) -> Result<(), SendError> {
+        match self {
+            MidiSender::Connection(connection) => connection.lock().unwrap().send(message),
+            #[cfg(test)]
+            MidiSender::Mock(sent) => {
+                sent.lock().unwrap().push((crate::clock::now(), message.to_vec()));
+                Ok(())
+            }
+        }
+    }
+
+    /// what a mock has recorded since this was last called
+    #[cfg(test)]
+    pub fn take_sent(self: &Self) -> Vec<(std::time::Instant, Vec<u8>)> {
+        match self {
+            MidiSender::Mock(sent) => std::mem::take(&mut *sent.lock().unwrap()),
+            MidiSender::Connection(_) => vec![]
+        }
+    }
+}
+
 pub fn midi_init(config: &ConfigFile) -> Result<(MidiInput, MidiOutput), midir::InitError> {
     Ok((MidiInput::new(&config.midi_client_name)?, MidiOutput::new(&config.midi_client_name)?))
 }
@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+///
+/// MIDI clock is 24 timing messages to the quarter note, sent by whatever is keeping
+/// time (eg the drumline's click or a DAW). Following it lets chases and clips run at
+/// the tempo actually being played rather than the one written in the show. The tempo
+/// is measured over the last beat, so one late or early tick doesn't jolt it. While the
+/// clock is running (after a start or continue) the ticks also count out the song's
+/// position, from its start or the last song position pointer
+///
+
+/// timing messages per quarter note
+const TICKS_PER_BEAT: usize = 24;
+/// tempos outside this range are taken for a clock starting or stopping, and ignored
+const MIN_TEMPO: f32 = 20.0;
+const MAX_TEMPO: f32 = 300.0;
+/// tempo changes smaller than this, in bpm, are put down to jitter and not passed on
+const TEMPO_HYSTERESIS: f32 = 0.5;
+/// song position pointers count sixteenths, six timing messages each
+const TICKS_PER_SIXTEENTH: u32 = 6;
+
+/// how far into the song the clock has got, in beats from its start, and when it got there
+#[derive(Debug,Clone,Copy)]
+pub struct SongPosition {
+    pub beats: f64,
+    pub at: Instant
+}
+
+#[derive(Default)]
+pub struct MidiClock {
+    /// when the ticks of the last beat or so arrived
+    ticks: VecDeque<Instant>,
+    /// ticks since the tempo was last measured
+    since_measured: usize,
+    /// the tempo last passed on
+    tempo: Option<f32>,
+    /// the song's position at the next tick, in ticks
+    position: u32,
+    /// whether the song is playing, since a start or continue
+    running: bool,
+    /// the position of the last tick of the running song, and when it arrived
+    last_tick: Option<(u32, Instant)>
+}
+
+impl MidiClock {
+
+    /// a timing message arrived. once a beat, returns the measured tempo if it has changed
+    pub fn tick(self: &mut Self, at: Instant) -> Option<f32> {
+        if self.running {
+            self.last_tick = Some((self.position, at));
+            self.position += 1;
+        }
+        self.ticks.push_back(at);
+        if self.ticks.len() > TICKS_PER_BEAT + 1 {
+            self.ticks.pop_front();
+        }
+        self.since_measured += 1;
+        if self.ticks.len() <= TICKS_PER_BEAT || self.since_measured < TICKS_PER_BEAT {
+            return None
+        }
+        self.since_measured = 0;
+        let beat = *self.ticks.back().unwrap() - *self.ticks.front().unwrap();
+        let tempo = 60.0 / beat.as_secs_f32();
+        if !(MIN_TEMPO..=MAX_TEMPO).contains(&tempo) {
+            return None
+        }
+        if self.tempo.map_or(true, |t| (t - tempo).abs() >= TEMPO_HYSTERESIS) {
+            self.tempo = Some(tempo);
+            return self.tempo
+        }
+        None
+    }
+
+    /// the clock started, or stopped: the ticks from before don't count towards the tempo.
+    /// the tempo last measured stands until the next is
+    pub fn restart(self: &mut Self) {
+        self.ticks.clear();
+        self.since_measured = 0;
+    }
+
+    /// the song started from the top: the next tick is its first beat
+    pub fn start(self: &mut Self) {
+        self.position = 0;
+        self.resume();
+    }
+
+    /// the song carries on from where it stopped, or was located to
+    pub fn resume(self: &mut Self) {
+        self.restart();
+        self.running = true;
+        self.last_tick = None;
+    }
+
+    /// the song stopped, so has no position until it plays again
+    pub fn stop(self: &mut Self) {
+        self.restart();
+        self.running = false;
+        self.last_tick = None;
+    }
+
+    /// a song position pointer, in sixteenths from the start of the song: where the next
+    /// tick is
+    pub fn locate(self: &mut Self, sixteenths: u16) {
+        self.position = sixteenths as u32 * TICKS_PER_SIXTEENTH;
+    }
+
+    /// where the running song has got to, once it has ticked since starting
+    pub fn song_position(self: &Self) -> Option<SongPosition> {
+        self.last_tick.map(|(position, at)| SongPosition { beats: position as f64 / TICKS_PER_BEAT as f64, at })
+    }
+
+    /// the tempo last measured, if the clock has been heard
+    pub fn tempo(self: &Self) -> Option<f32> {
+        self.tempo
+    }
+}
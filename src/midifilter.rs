@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use anyhow::{Result, bail};
+use log::debug;
+use serde::Deserialize;
+
+///
+/// The midi filter fixes up midi before the show sees it, so a show authored for one
+/// set of instruments can be played from another (eg a rented keyboard that only
+/// transmits on channel 3): messages on some channels are dropped, channels are moved
+/// onto others, and notes can be transposed. Only channel messages are touched, clock,
+/// time code and the like pass straight through
+///
+
+#[derive(Debug,Deserialize,Default)]
+pub struct MidiFilterConfig {
+    /// channels (0-15, as in the show file) whose messages are dropped
+    pub drop_channels: Option<Vec<u8>>,
+
+    /// channels to move onto others, eg {"2": 0} plays channel 2 as channel 0. applied
+    /// after dropping, so a channel can be dropped and another moved onto it
+    pub remap_channels: Option<HashMap<u8,u8>>,
+
+    /// semitones to move notes (and their polyphonic aftertouch) by, after remapping.
+    /// notes moved out of range are dropped
+    pub transpose: Option<i8>
+}
+
+pub struct MidiFilter {
+    dropped: [bool; 16],
+    remapped: [u8; 16],
+    transpose: i8
+}
+
+impl MidiFilter {
+
+    pub fn new(config: Option<&MidiFilterConfig>) -> Result<MidiFilter> {
+        let mut filter = MidiFilter { dropped: [false; 16], remapped: std::array::from_fn(|c| c as u8), transpose: 0 };
+        let Some(config) = config else {
+            return Ok(filter)
+        };
+        for channel in config.drop_channels.iter().flatten() {
+            if *channel > 15 {
+                bail!("Channel {} out of range in drop_channels", channel);
+            }
+            filter.dropped[*channel as usize] = true;
+        }
+        for (from, to) in config.remap_channels.iter().flatten() {
+            if *from > 15 || *to > 15 {
+                bail!("Channel out of range in remap_channels: {} to {}", from, to);
+            }
+            filter.remapped[*from as usize] = *to;
+        }
+        filter.transpose = config.transpose.unwrap_or(0);
+        Ok(filter)
+    }
+
+    /// the message as the show is to see it, or none if it's dropped
+    pub fn apply(self: &Self, buf: &[u8]) -> Option<Vec<u8>> {
+        let status = *buf.first()?;
+        if !(0x80..0xF0).contains(&status) {
+            return Some(buf.to_vec())
+        }
+        let channel = (status & 0x0F) as usize;
+        if self.dropped[channel] {
+            debug!("dropping midi on channel: {}", channel);
+            return None
+        }
+        let mut filtered = buf.to_vec();
+        filtered[0] = (status & 0xF0) | self.remapped[channel];
+        // note off, note on and polyphonic aftertouch carry a note
+        if self.transpose != 0 && status < 0xB0 {
+            let note = *filtered.get(1)? as i16 + self.transpose as i16;
+            if !(0..=127).contains(&note) {
+                debug!("dropping note transposed out of range: {}", note);
+                return None
+            }
+            filtered[1] = note as u8;
+        }
+        Some(filtered)
+    }
+}
@@ -0,0 +1,364 @@
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+use std::time::Duration;
+use anyhow::{Context, Result, anyhow, bail};
+use crossbeam_channel::{Sender, Receiver, TryRecvError, bounded, select};
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::arbitration::ControlSource;
+use crate::control::{self, SocketCommand, SocketReply};
+use crate::director::DirectorMessage;
+use crate::session::SessionLog;
+use crate::showstate::{ShowEvent, ShowSnapshot};
+
+///
+/// An MQTT (3.1.1) client, for show control networks that run over a broker. What the
+/// show does is published to <prefix>/events as the websocket sends it: cues activated
+/// and deactivated, clips started and stopped, receivers changing cue, lights-out
+/// packets and errors, with the whole state when a show loads. The control socket's
+/// commands (eg {"command": "blackout"}) are taken from <prefix>/command, and their
+/// replies published to <prefix>/reply. Everything goes at QoS 0, and a lost connection
+/// to the broker is retried every few seconds, dropping what the show did meanwhile
+///
+
+const DEFAULT_PREFIX: &str = "lights-xmit";
+const DEFAULT_KEEP_ALIVE_SECS: u16 = 30;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// messages waiting for the broker, beyond which they're dropped
+const QUEUE_DEPTH: usize = 256;
+
+/// packet types, in the top four bits of the first byte
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+/// subscribe has to have the reserved bits 0010
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+const PROTOCOL_LEVEL: u8 = 4;
+const CLEAN_SESSION: u8 = 0x02;
+const PASSWORD_FLAG: u8 = 0x40;
+const USERNAME_FLAG: u8 = 0x80;
+
+#[derive(Debug,Deserialize,Clone)]
+pub struct MqttConfig {
+    /// the broker's address, eg "192.168.1.5:1883"
+    pub broker: String,
+    /// the client id to connect as, defaults to the midi client name
+    pub client_id: Option<String>,
+    /// what topics start with, defaults to "lights-xmit"
+    pub topic_prefix: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// how often to let the broker know we're alive, defaults to 30 seconds
+    pub keep_alive_secs: Option<u16>
+}
+
+impl MqttConfig {
+
+    pub fn validate(self: &Self) -> Result<()> {
+        // MQTT 3.1.1 has no password without a username, and brokers hang up on one
+        if self.password.is_some() && self.username.is_none() {
+            bail!("MQTT password given without a username")
+        }
+        Ok(())
+    }
+}
+
+/// a message on its way to the broker
+enum Outgoing {
+    Publish { topic: String, payload: String }
+}
+
+/// the director's handle on the client
+pub struct MqttLink {
+    tx: Sender<Outgoing>,
+    events_topic: String,
+    /// the snapshot last published
+    last: RefCell<Option<ShowSnapshot>>
+}
+
+impl MqttLink {
+
+    /// publish what changed since the last snapshot, if anything did
+    pub fn publish(self: &Self, snapshot: &ShowSnapshot) {
+        let events = match &*self.last.borrow() {
+            Some(last) if last == snapshot => return,
+            Some(last) => last.changes_to(snapshot),
+            None => vec![snapshot.state_event()]
+        };
+        for event in events.iter() {
+            self.send(event);
+        }
+        self.last.replace(Some(snapshot.clone()));
+    }
+
+    /// publish an error the show hit
+    pub fn error(self: &Self, error: &str) {
+        self.send(&ShowEvent::Error { error: error.to_string() });
+    }
+
+    /// a new show is loading, so its whole state is published once it's running
+    pub fn restart(self: &Self) {
+        self.last.replace(None);
+    }
+
+    fn send(self: &Self, event: &ShowEvent) {
+        let payload = serde_json::to_string(event).unwrap();
+        if self.tx.try_send(Outgoing::Publish { topic: self.events_topic.clone(), payload }).is_err() {
+            debug!("MQTT queue full, dropping event");
+        }
+    }
+}
+
+/// connect to the broker (and keep connecting), passing commands to the director. the
+/// link returned is for the director to publish what the show is doing
+pub fn start(config: &MqttConfig, client_name: &str, tx: Sender<DirectorMessage>, session_log: SessionLog) -> MqttLink {
+    let prefix = config.topic_prefix.clone().unwrap_or_else(|| DEFAULT_PREFIX.to_string());
+    let (out_tx, out_rx) = bounded(QUEUE_DEPTH);
+    let client = Client {
+        config: config.clone(),
+        client_id: config.client_id.clone().unwrap_or_else(|| client_name.to_string()),
+        command_topic: format!("{}/command", prefix),
+        reply_topic: format!("{}/reply", prefix),
+        tx,
+        session_log
+    };
+    thread::spawn(move || loop {
+        match client.run(&out_rx) {
+            Ok(()) => return,
+            Err(e) => {
+                warn!("MQTT connection to {} lost, retrying in {}s. Error: {:#}", client.config.broker, RECONNECT_DELAY.as_secs(), e);
+                thread::sleep(RECONNECT_DELAY);
+                // what queued up meanwhile is stale
+                loop {
+                    match out_rx.try_recv() {
+                        Ok(_) => continue,
+                        Err(TryRecvError::Empty) => break,
+                        Err(TryRecvError::Disconnected) => return
+                    }
+                }
+            }
+        }
+    });
+    MqttLink { tx: out_tx, events_topic: format!("{}/events", prefix), last: RefCell::new(None) }
+}
+
+struct Client {
+    config: MqttConfig,
+    client_id: String,
+    command_topic: String,
+    reply_topic: String,
+    tx: Sender<DirectorMessage>,
+    session_log: SessionLog
+}
+
+impl Client {
+
+    /// connect, subscribe, then send what's queued (and pings) until the connection
+    /// fails, or the director goes away. the reading thread has a queue of its own for
+    /// replies, so the director's queue closes when the director drops its link
+    fn run(self: &Self, out_rx: &Receiver<Outgoing>) -> Result<()> {
+        let mut connection = TcpStream::connect(&self.config.broker).with_context(|| format!("Could not connect to {}", self.config.broker))?;
+        let keep_alive = self.config.keep_alive_secs.unwrap_or(DEFAULT_KEEP_ALIVE_SECS);
+        connection.write_all(&connect_packet(&self.config, &self.client_id, keep_alive))?;
+        let (packet_type, body) = read_packet(&mut connection)?;
+        if packet_type & 0xF0 != CONNACK || body.len() != 2 {
+            bail!("broker did not acknowledge the connection")
+        }
+        if body[1] != 0 {
+            bail!("broker refused the connection, return code: {}", body[1])
+        }
+        let mut subscribe = vec![0, 1];
+        push_string(&mut subscribe, &self.command_topic);
+        subscribe.push(0);
+        connection.write_all(&packet(SUBSCRIBE, &subscribe))?;
+        info!("Connected to MQTT broker at {}, taking commands on {}", self.config.broker, self.command_topic);
+
+        let reader = connection.try_clone()?;
+        let (reply_tx, reply_rx) = bounded(QUEUE_DEPTH);
+        let (tx, session_log, reply_topic) = (self.tx.clone(), self.session_log.clone(), self.reply_topic.clone());
+        // the reader's queue closing is word the broker hung up
+        thread::spawn(move || {
+            if let Err(e) = read_commands(reader, &reply_tx, &tx, &session_log, &reply_topic) {
+                debug!("MQTT reader stopped: {:#}", e);
+            }
+        });
+
+        // half the keep alive leaves the ping plenty of time to arrive
+        let ping_period = Duration::from_secs(keep_alive.max(2) as u64 / 2);
+        let result = loop {
+            let written = select! {
+                recv(out_rx) -> outgoing => match outgoing {
+                    Ok(outgoing) => write_publish(&mut connection, outgoing),
+                    Err(_) => break Ok(())
+                },
+                recv(reply_rx) -> reply => match reply {
+                    Ok(reply) => write_publish(&mut connection, reply),
+                    Err(_) => break Err(anyhow!("broker closed the connection"))
+                },
+                default(ping_period) => connection.write_all(&[PINGREQ, 0])
+            };
+            if let Err(e) = written {
+                break Err(e.into())
+            }
+        };
+        let _ = connection.shutdown(Shutdown::Both);
+        result
+    }
+}
+
+fn connect_packet(config: &MqttConfig, client_id: &str, keep_alive: u16) -> Vec<u8> {
+    let mut body = vec![];
+    push_string(&mut body, "MQTT");
+    body.push(PROTOCOL_LEVEL);
+    body.push(CLEAN_SESSION
+        | if config.username.is_some() { USERNAME_FLAG } else { 0 }
+        | if config.password.is_some() { PASSWORD_FLAG } else { 0 });
+    body.extend(keep_alive.to_be_bytes());
+    push_string(&mut body, client_id);
+    for credential in [&config.username, &config.password].into_iter().flatten() {
+        push_string(&mut body, credential);
+    }
+    packet(CONNECT, &body)
+}
+
+fn write_publish(connection: &mut TcpStream, outgoing: Outgoing) -> std::io::Result<()> {
+    let Outgoing::Publish { topic, payload } = outgoing;
+    let mut body = vec![];
+    push_string(&mut body, &topic);
+    body.extend(payload.as_bytes());
+    connection.write_all(&packet(PUBLISH, &body))
+}
+
+/// act on commands published to the command topic until the connection closes
+fn read_commands(mut connection: TcpStream, reply_tx: &Sender<Outgoing>, tx: &Sender<DirectorMessage>,
+    session_log: &SessionLog, reply_topic: &str) -> Result<()> {
+    loop {
+        let (packet_type, body) = read_packet(&mut connection)?;
+        match packet_type & 0xF0 {
+            PUBLISH => {
+                let text = String::from_utf8_lossy(publish_payload(packet_type, &body)?);
+                debug!("MQTT command: {}", text);
+                let result = serde_json::from_str::<SocketCommand>(&text)
+                    .map_err(|e| anyhow!("Could not parse command: {}", e))
+                    .and_then(|command| control::execute(command, ControlSource::Mqtt, tx, session_log));
+                if let Err(e) = &result {
+                    error!("MQTT command failed: {}", e);
+                }
+                let payload = serde_json::to_string(&SocketReply::from(result))?;
+                let _ = reply_tx.try_send(Outgoing::Publish { topic: reply_topic.to_string(), payload });
+            },
+            SUBACK if body.last() == Some(&0x80) => bail!("broker refused the command subscription"),
+            SUBACK | PINGRESP => {},
+            other => debug!("ignoring MQTT packet type: {:#04x}", other)
+        }
+    }
+}
+
+/// the payload of a publish packet, past its topic (and packet id)
+fn publish_payload(packet_type: u8, body: &[u8]) -> Result<&[u8]> {
+    let [high, low, rest @ ..] = body else { bail!("short publish") };
+    let topic_len = u16::from_be_bytes([*high, *low]) as usize;
+    // at QoS 1 or 2, a packet id follows the topic
+    let skip = topic_len + if packet_type & 0x06 != 0 { 2 } else { 0 };
+    rest.get(skip..).ok_or_else(|| anyhow!("short publish"))
+}
+
+/// a packet: its type and flags, the remaining length, then the body
+fn packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![packet_type];
+    // the remaining length takes seven bits a byte, low first
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break
+        }
+    }
+    packet.extend(body);
+    packet
+}
+
+fn read_packet<R: Read>(connection: &mut R) -> Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    connection.read_exact(&mut byte)?;
+    let packet_type = byte[0];
+    let mut len = 0usize;
+    for shift in [0, 7, 14, 21] {
+        connection.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; len];
+            connection.read_exact(&mut body)?;
+            return Ok((packet_type, body))
+        }
+    }
+    bail!("bad remaining length")
+}
+
+fn push_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(username: Option<&str>, password: Option<&str>) -> MqttConfig {
+        MqttConfig {
+            broker: "localhost:1883".to_string(),
+            client_id: None,
+            topic_prefix: None,
+            username: username.map(str::to_string),
+            password: password.map(str::to_string),
+            keep_alive_secs: None
+        }
+    }
+
+    #[test]
+    fn remaining_length_takes_seven_bits_a_byte() {
+        for (len, encoded) in [(0, vec![0x00]), (127, vec![0x7F]), (128, vec![0x80, 0x01]),
+            (16383, vec![0xFF, 0x7F]), (16384, vec![0x80, 0x80, 0x01])] {
+            let body = vec![0xAB; len];
+            let packet = packet(PUBLISH, &body);
+            assert_eq!(&packet[1..1 + encoded.len()], &encoded[..], "length {}", len);
+            assert_eq!(read_packet(&mut &packet[..]).unwrap(), (PUBLISH, body));
+        }
+        assert!(read_packet(&mut &[PUBLISH, 0x80, 0x80, 0x80, 0x80, 0x01][..]).is_err());
+    }
+
+    #[test]
+    fn connect_flags_follow_the_credentials() {
+        // the flags come after the protocol name and level
+        let flags = |config: &MqttConfig| connect_packet(config, "xmit", 30)[9];
+        assert_eq!(flags(&config(None, None)), CLEAN_SESSION);
+        assert_eq!(flags(&config(Some("band"), None)), CLEAN_SESSION | USERNAME_FLAG);
+        assert_eq!(flags(&config(Some("band"), Some("drum"))), CLEAN_SESSION | USERNAME_FLAG | PASSWORD_FLAG);
+
+        let packet = connect_packet(&config(Some("band"), Some("drum")), "xmit", 30);
+        assert_eq!(&packet[2..10], b"\x00\x04MQTT\x04\xC2");
+        assert_eq!(&packet[10..], b"\x00\x1E\x00\x04xmit\x00\x04band\x00\x04drum");
+
+        assert!(config(None, Some("drum")).validate().is_err());
+        assert!(config(Some("band"), None).validate().is_ok());
+    }
+
+    #[test]
+    fn publish_payloads_skip_the_packet_id_above_qos_0() {
+        let body = b"\x00\x03a/b{}";
+        assert_eq!(publish_payload(PUBLISH, body).unwrap(), b"{}");
+        let body = b"\x00\x03a/b\x00\x07{}";
+        assert_eq!(publish_payload(PUBLISH | 0x02, body).unwrap(), b"{}");
+        assert_eq!(publish_payload(PUBLISH | 0x04, body).unwrap(), b"{}");
+        assert!(publish_payload(PUBLISH, b"\x00\x09a/b").is_err());
+        assert!(publish_payload(PUBLISH, b"\x00").is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::net::UdpSocket;
+use std::thread;
+use anyhow::{Context, Result, anyhow, bail};
+use crossbeam_channel::Sender;
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::arbitration::ControlSource;
+use crate::director::DirectorMessage;
+
+///
+/// An OSC server, so front of house software that speaks OSC can fire and release cues
+/// by name: /cue/<name>/on and /cue/<name>/off, as a cue's midi trigger would. Names
+/// with spaces (or slashes) are sent with them percent-encoded, eg /cue/big%20hit/on.
+/// Arguments are ignored, other addresses are logged and dropped, and bundles are
+/// unpacked and their messages acted on straight away, whatever their time tag
+///
+
+const DEFAULT_PORT: u16 = 9000;
+const DEFAULT_BIND: &str = "0.0.0.0";
+const MAX_DATAGRAM: usize = 1500;
+const BUNDLE_TAG: &[u8] = b"#bundle\0";
+/// the bundle tag and the time tag after it
+const BUNDLE_HEADER_LEN: usize = 16;
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct OscConfig {
+    /// the UDP port to listen on, defaults to 9000
+    pub port: Option<u16>,
+    /// the local address to listen on, defaults to every interface
+    pub bind: Option<String>
+}
+
+/// listen for OSC on the configured port, passing cues to the director
+pub fn start(config: &OscConfig, tx: Sender<DirectorMessage>) -> Result<()> {
+    let bind = config.bind.as_deref().unwrap_or(DEFAULT_BIND);
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+    let socket = UdpSocket::bind((bind, port)).with_context(|| format!("Could not listen for OSC on {}:{}", bind, port))?;
+    info!("Listening for OSC on {}:{}", bind, port);
+    thread::spawn(move || {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) => if let Err(e) = handle_packet(&buf[..len], &tx) {
+                    warn!("Bad OSC packet from {}: {}", from, e);
+                },
+                Err(e) => {
+                    error!("Could not receive OSC, closing the port. Error: {}", e);
+                    return
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// act on a message, or on each of the messages in a bundle
+fn handle_packet(packet: &[u8], tx: &Sender<DirectorMessage>) -> Result<()> {
+    if !packet.starts_with(BUNDLE_TAG) {
+        return handle_message(packet, tx)
+    }
+    let mut elements = packet.get(BUNDLE_HEADER_LEN..).ok_or_else(|| anyhow!("short bundle"))?;
+    while let [a, b, c, d, rest @ ..] = elements {
+        let len = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+        let element = rest.get(..len).ok_or_else(|| anyhow!("bundle element longer than the bundle"))?;
+        handle_packet(element, tx)?;
+        elements = &rest[len..];
+    }
+    Ok(())
+}
+
+fn handle_message(message: &[u8], tx: &Sender<DirectorMessage>) -> Result<()> {
+    let end = message.iter().position(|b| *b == 0).ok_or_else(|| anyhow!("unterminated address"))?;
+    let address = std::str::from_utf8(&message[..end]).context("address is not text")?;
+    debug!("OSC message: {}", address);
+    let Some((cue, on)) = parse_address(address)? else {
+        debug!("ignoring OSC address: {}", address);
+        return Ok(())
+    };
+    tx.send(DirectorMessage::Cue { source: ControlSource::Osc, cue, on, reply: None })?;
+    Ok(())
+}
+
+/// the cue an address names, and whether it's turned on or off
+fn parse_address(address: &str) -> Result<Option<(String, bool)>> {
+    let Some(rest) = address.strip_prefix("/cue/") else {
+        return Ok(None)
+    };
+    let (name, on) = match rest.rsplit_once('/') {
+        Some((name, "on")) => (name, true),
+        Some((name, "off")) => (name, false),
+        _ => return Ok(None)
+    };
+    if name.is_empty() {
+        bail!("no cue named in: {}", address)
+    }
+    Ok(Some((percent_decode(name)?, on)))
+}
+
+/// undo the percent-encoding of a cue name, as the HTTP API needs too
+pub fn percent_decode(name: &str) -> Result<String> {
+    let mut bytes = vec![];
+    let mut rest = name.as_bytes();
+    while let [first, tail @ ..] = rest {
+        if *first == b'%' {
+            let hex = tail.get(..2).filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|h| u8::from_str_radix(std::str::from_utf8(h).ok()?, 16).ok())
+                .ok_or_else(|| anyhow!("bad percent-encoding in: {}", name))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(*first);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| anyhow!("cue name is not text: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_addresses_name_the_cue_and_whether_it_goes_on() {
+        assert_eq!(parse_address("/cue/hit/on").unwrap(), Some(("hit".to_string(), true)));
+        assert_eq!(parse_address("/cue/hit/off").unwrap(), Some(("hit".to_string(), false)));
+        assert_eq!(parse_address("/cue/big%20hit/on").unwrap(), Some(("big hit".to_string(), true)));
+        // an encoded slash stays in the name, an unencoded one too
+        assert_eq!(parse_address("/cue/a%2Fb/on").unwrap(), Some(("a/b".to_string(), true)));
+        assert_eq!(parse_address("/cue/a/b/off").unwrap(), Some(("a/b".to_string(), false)));
+    }
+
+    #[test]
+    fn other_addresses_are_ignored_and_bad_ones_refused() {
+        for address in ["/cue/hit", "/cue/hit/toggle", "/cues/hit/on", "/cue", "cue/hit/on"] {
+            assert_eq!(parse_address(address).unwrap(), None, "{}", address);
+        }
+        assert!(parse_address("/cue//on").is_err());
+        assert!(parse_address("/cue/big%2hit/on").is_err());
+    }
+
+    #[test]
+    fn percent_decoding_takes_two_hex_digits_to_a_byte() {
+        assert_eq!(percent_decode("plain").unwrap(), "plain");
+        assert_eq!(percent_decode("%41%62c").unwrap(), "Abc");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+        assert_eq!(percent_decode("100%25").unwrap(), "100%");
+        // but the '+' of a form is not a space here
+        assert_eq!(percent_decode("a+b").unwrap(), "a+b");
+        for bad in ["%", "%4", "%zz", "%+1", "%-1", "a%4"] {
+            assert!(percent_decode(bad).is_err(), "{}", bad);
+        }
+        // bytes that aren't utf-8
+        assert!(percent_decode("%C3").is_err());
+        assert!(percent_decode("%FF%FE").is_err());
+    }
+
+    #[test]
+    fn bundles_are_unpacked_into_their_messages() {
+        let message = |address: &str| {
+            let mut buf = address.as_bytes().to_vec();
+            buf.resize((buf.len() / 4 + 1) * 4, 0);
+            buf.extend(b",\0\0\0");
+            buf
+        };
+        let mut inner = BUNDLE_TAG.to_vec();
+        inner.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [message("/cue/b/off"), message("/other")] {
+            inner.extend((element.len() as u32).to_be_bytes());
+            inner.extend(element);
+        }
+        let mut bundle = BUNDLE_TAG.to_vec();
+        bundle.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for element in [message("/cue/a/on"), inner] {
+            bundle.extend((element.len() as u32).to_be_bytes());
+            bundle.extend(element);
+        }
+        let (tx, rx) = crossbeam_channel::unbounded();
+        handle_packet(&bundle, &tx).unwrap();
+        let cues = rx.try_iter().map(|m| match m {
+            DirectorMessage::Cue { cue, on, .. } => (cue, on),
+            _ => unreachable!()
+        }).collect::<Vec<_>>();
+        assert_eq!(cues, vec![("a".to_string(), true), ("b".to_string(), false)]);
+
+        // an element running past the end of the bundle
+        bundle.truncate(bundle.len() - 1);
+        assert!(handle_packet(&bundle, &tx).is_err());
+    }
+}
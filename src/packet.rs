@@ -1,4 +1,6 @@
 use std::ops::Range;
+use anyhow::{anyhow,Result};
+use serde::Deserialize;
 use crate::show::Color;
 use crate::show::Effect;
 
@@ -7,12 +9,15 @@ pub const TRANSMITTER_ID_RANGE: Range<u8> = 0u8..10u8;
 pub const GROUP_ID_RANGE: Range<u8> = 10u8..80u8;
 pub const RECEIVER_ID_RANGE: Range<u8> = 80u8..255u8;
 
+/// the longest marshalled packet (including the length byte) the radio can send
+pub const MAX_PACKET_LEN: usize = 65;
+
 ///
 /// this module concerns itself with building packet buffers from a given
 /// mapping
 /// 
 #[repr(u8)]
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub enum EffectId {
     Off = 0,
     Pop = 1,
@@ -37,7 +42,70 @@ pub enum EffectId {
     PopAndSpin = 20,
 }
 
+/// receiver firmware generations, numbered in the order effects (and commands) were added
+/// to the receivers. receivers not marked with a firmware version are assumed to be current
+pub const CURRENT_FIRMWARE: u8 = 8;
+
+/// the first firmware generation that accepts parameter presets
+pub const PRESET_FIRMWARE: u8 = 4;
+
+/// the first firmware generation with a low power sleep
+pub const SLEEP_FIRMWARE: u8 = 5;
+
+/// the first firmware generation that answers link quality polls
+pub const POLL_FIRMWARE: u8 = 6;
+
+/// the first firmware generation that reports its battery and uptime
+pub const STATUS_FIRMWARE: u8 = 7;
+
+/// the first firmware generation that announces itself when it powers up
+pub const ANNOUNCE_FIRMWARE: u8 = 8;
+
+impl EffectId {
+
+    /// the first receiver firmware generation that supports this effect
+    pub fn min_firmware(self: &Self) -> u8 {
+        match self {
+            EffectId::BatteryTest | EffectId::Rainbow | EffectId::Twinkle => 2,
+            EffectId::DigitalPin | EffectId::PinAndSpin | EffectId::PopAndSpin => 3,
+            _ => 1
+        }
+    }
+
+    pub fn from_u8(id: u8) -> Option<EffectId> {
+        match id {
+            0 => Some(EffectId::Off),
+            1 => Some(EffectId::Pop),
+            2 => Some(EffectId::Firecrackers),
+            3 => Some(EffectId::Chase),
+            4 => Some(EffectId::Strobe),
+            5 => Some(EffectId::BidiChase),
+            6 => Some(EffectId::OneShotChase),
+            7 => Some(EffectId::BidiOneShotChase),
+            8 => Some(EffectId::Sparkle),
+            9 => Some(EffectId::Wave),
+            10 => Some(EffectId::PiezoTrigger),
+            11 => Some(EffectId::Flame),
+            12 => Some(EffectId::Flame2),
+            13 => Some(EffectId::Grass),
+            14 => Some(EffectId::CircularChase),
+            15 => Some(EffectId::BatteryTest),
+            16 => Some(EffectId::Rainbow),
+            17 => Some(EffectId::Twinkle),
+            18 => Some(EffectId::DigitalPin),
+            19 => Some(EffectId::PinAndSpin),
+            20 => Some(EffectId::PopAndSpin),
+            _ => None
+        }
+    }
+}
+
 impl Effect {
+    /// does the effect run in a direction along the strip, that reverse flips
+    pub fn is_directional(self: &Self) -> bool {
+        matches!(self, Effect::Chase { .. } | Effect::OneShotChase { .. } | Effect::CircularChase { .. })
+    }
+
     pub fn to_effect_id(self: &Self) -> EffectId {
         match &self {
             Effect::Pop => EffectId::Pop,
@@ -148,6 +216,28 @@ pub enum Command {
     SetLedCount { led_count: u16 },
     NewBrightness { brightness: u8 },
     NewTempo { tempo: u8 },
+    /// "transmitter alive" beacon, period is the number of seconds until the next
+    /// beacon so receivers can fall back to a local idle animation if they stop hearing it
+    Heartbeat { period: u8 },
+    /// params for the receiver to use for an effect when a cue leaves them at zero,
+    /// so per-unit calibration (eg flame flicker, piezo threshold) lives in the roster
+    SetPreset { effect: EffectId, param1: u8, param2: u8 },
+    /// go into low power idle (radio listening, leds off) until woken by a reset, or
+    /// by any packet addressed to the receiver if wake_on_packet is set
+    Sleep { wake_on_packet: bool },
+    /// ask the addressed receiver to answer with a PollReply, to measure the link
+    Poll,
+    /// a receiver's answer to a poll, with the strength it heard the poll at, in -dBm
+    PollReply { rssi: u8 },
+    /// ask the addressed receiver to answer with a StatusReply
+    QueryStatus,
+    /// a receiver's answer to a status query: its battery voltage in units of 20 mV,
+    /// and how long it has been up in minutes (stopping at the maximum)
+    StatusReply { battery: u8, uptime_minutes: u16 },
+    /// broadcast by a receiver when it powers up, with its id and firmware generation,
+    /// so it can be configured without reconfiguring everybody. the transmitter only
+    /// listens now and then, so receivers repeat it until they're sent their led count
+    Announce { receiver: u8, firmware: u8 },
     Reset
 }
 
@@ -158,6 +248,14 @@ impl Command {
             Command::SetLedCount {..} => CommandId::SetLedCount,
             Command::NewBrightness {..} => CommandId::NewBrightness,
             Command::NewTempo {..} => CommandId::NewTempo,
+            Command::Heartbeat {..} => CommandId::Heartbeat,
+            Command::SetPreset {..} => CommandId::SetPreset,
+            Command::Sleep {..} => CommandId::Sleep,
+            Command::Poll => CommandId::Poll,
+            Command::PollReply {..} => CommandId::PollReply,
+            Command::QueryStatus => CommandId::QueryStatus,
+            Command::StatusReply {..} => CommandId::StatusReply,
+            Command::Announce {..} => CommandId::Announce,
             Command::Reset => CommandId::Reset
         }
     }
@@ -168,6 +266,36 @@ impl Command {
         self.populate_params(buf);
     }
 
+    /// the inverse of marshal, buf starts at the command id (just past the command marker)
+    pub fn unmarshal(buf: &[u8]) -> Result<Command> {
+        if buf.len() < 4 {
+            return Err(anyhow!("Command payload too short: {} bytes", buf.len()))
+        }
+        let params = &buf[1..4];
+        match buf[0] {
+            x if x == CommandId::SetGroup as u8 => Ok(Command::SetGroup { group_id: params[0] }),
+            x if x == CommandId::SetLedCount as u8 => 
+                Ok(Command::SetLedCount { led_count: ((params[0] as u16) << 8) | params[1] as u16 }),
+            x if x == CommandId::Heartbeat as u8 => Ok(Command::Heartbeat { period: params[0] }),
+            x if x == CommandId::SetPreset as u8 => Ok(Command::SetPreset {
+                effect: EffectId::from_u8(params[0]).ok_or_else(|| anyhow!("Unknown effect id in preset: {}", params[0]))?,
+                param1: params[1],
+                param2: params[2] }),
+            x if x == CommandId::Sleep as u8 => Ok(Command::Sleep { wake_on_packet: params[0] != 0 }),
+            x if x == CommandId::Poll as u8 => Ok(Command::Poll),
+            x if x == CommandId::PollReply as u8 => Ok(Command::PollReply { rssi: params[0] }),
+            x if x == CommandId::QueryStatus as u8 => Ok(Command::QueryStatus),
+            x if x == CommandId::StatusReply as u8 => Ok(Command::StatusReply {
+                battery: params[0],
+                uptime_minutes: ((params[1] as u16) << 8) | params[2] as u16 }),
+            x if x == CommandId::Announce as u8 => Ok(Command::Announce { receiver: params[0], firmware: params[1] }),
+            x if x == CommandId::NewBrightness as u8 => Ok(Command::NewBrightness { brightness: params[0] }),
+            x if x == CommandId::NewTempo as u8 => Ok(Command::NewTempo { tempo: params[0] }),
+            x if x == CommandId::Reset as u8 => Ok(Command::Reset),
+            x => Err(anyhow!("Unknown command id: {}", x))
+        }
+    }
+
     pub fn populate_params(self: &Self, buf: &mut Vec<u8>) {
         match self {
             Command::SetGroup { group_id} => {
@@ -190,7 +318,37 @@ impl Command {
                 buf.push(0);
                 buf.push(0);
             },
-            Command::Reset => {
+            Command::Heartbeat { period } => {
+                buf.push(*period);
+                buf.push(0);
+                buf.push(0);
+            },
+            Command::SetPreset { effect, param1, param2 } => {
+                buf.push(*effect as u8);
+                buf.push(*param1);
+                buf.push(*param2);
+            },
+            Command::Sleep { wake_on_packet } => {
+                buf.push(*wake_on_packet as u8);
+                buf.push(0);
+                buf.push(0);
+            },
+            Command::PollReply { rssi } => {
+                buf.push(*rssi);
+                buf.push(0);
+                buf.push(0);
+            },
+            Command::StatusReply { battery, uptime_minutes } => {
+                buf.push(*battery);
+                buf.push((uptime_minutes >> 8) as u8);
+                buf.push((uptime_minutes & 0xFF) as u8);
+            },
+            Command::Announce { receiver, firmware } => {
+                buf.push(*receiver);
+                buf.push(*firmware);
+                buf.push(0);
+            },
+            Command::Poll | Command::QueryStatus | Command::Reset => {
                 buf.extend_from_slice(&[0;3]);
             }
         }
@@ -202,11 +360,44 @@ impl Command {
 pub enum CommandId {
     SetGroup = 109,
     SetLedCount = 110,
+    Heartbeat = 111,
+    SetPreset = 112,
+    Sleep = 113,
+    Poll = 114,
+    PollReply = 115,
+    QueryStatus = 116,
+    StatusReply = 117,
+    Announce = 118,
     NewBrightness = 127,
     NewTempo = 128,
     Reset = 255
 }
 
+/// which header bytes follow the length and recipient address. receiver
+/// firmware has to agree on this, so it is selected in the config
+#[derive(Debug,Copy,Clone,PartialEq,Default,Deserialize)]
+pub enum HeaderMode {
+    /// from, packet id and flags, as the RadioHead RH_RF69 driver expects
+    #[default]
+    RadioHead,
+    /// the same bytes as RadioHead, but packet ids follow RHReliableDatagram
+    /// conventions (id 0 is never used, since a freshly booted RHReliableDatagram
+    /// receiver considers it already seen and drops it as a duplicate)
+    ReliableDatagram,
+    /// no RadioHead bytes at all, the payload immediately follows the recipient address
+    Raw
+}
+
+impl HeaderMode {
+    /// the number of bytes before the payload, including the length byte
+    pub fn header_len(self: &Self) -> usize {
+        match self {
+            HeaderMode::Raw => 2,
+            _ => 5
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Packet<'a> {
     pub recipients: &'a Vec<u8>,
@@ -219,6 +410,157 @@ pub enum PacketPayload {
     Show(ShowPacket)
 }
 
+impl PacketPayload {
+
+    /// scale the intensity of the payload (the value of a show packet's color, or a
+    /// brightness command) by master percent, leaving everything else alone
+    pub fn dimmed(self: &Self, master: u8) -> PacketPayload {
+        let scale = |v: u8| (v as u16 * master.min(100) as u16 / 100) as u8;
+        match *self {
+            PacketPayload::Show(mut p) => {
+                p.color.v = scale(p.color.v);
+                PacketPayload::Show(p)
+            },
+            PacketPayload::Control(Command::NewBrightness { brightness }) =>
+                PacketPayload::Control(Command::NewBrightness { brightness: scale(brightness) }),
+            other => other
+        }
+    }
+}
+
+/// an owned, fully unpacked view of a marshalled packet including
+/// the RadioHead-compatible header bytes (absent in raw mode), used for debugging
+#[derive(Debug)]
+pub struct DecodedPacket {
+    pub to: u8,
+    pub from: Option<u8>,
+    pub packet_id: Option<u8>,
+    pub flags: Option<u8>,
+    pub payload: PacketPayload,
+    pub recipients: Vec<u8>
+}
+
+impl DecodedPacket {
+
+    /// the inverse of Packet::marshal. buf must include the leading length byte
+    pub fn unmarshal(buf: &[u8], header_mode: HeaderMode) -> Result<DecodedPacket> {
+        if buf.is_empty() || buf[0] as usize != buf.len() - 1 {
+            return Err(anyhow!("Length byte does not match buffer of {} bytes", buf.len()))
+        }
+        let header_len = header_mode.header_len();
+        if buf.len() <= header_len {
+            return Err(anyhow!("Packet too short to contain a header and payload: {} bytes", buf.len()))
+        }
+        let header_byte = |i: usize| if header_mode == HeaderMode::Raw { None } else { Some(buf[i]) };
+        let body = &buf[header_len..];
+        let (payload, payload_len) = if body[0] == 0xFF {
+            (PacketPayload::Control(Command::unmarshal(&body[1..])?), 5)
+        } else {
+            (PacketPayload::Show(ShowPacket::unmarshal(body)?), 10)
+        };
+        if body.len() < payload_len {
+            return Err(anyhow!("Payload truncated: {} of {} bytes", body.len(), payload_len))
+        }
+        Ok(DecodedPacket {
+            to: buf[1],
+            from: header_byte(2),
+            packet_id: header_byte(3),
+            flags: header_byte(4),
+            payload,
+            recipients: body[payload_len..].to_vec()
+        })
+    }
+}
+
+/// could two target lists reach the same receiver. groups are assumed to
+/// overlap anything, since membership isn't known at this level
+pub fn targets_overlap(a: &[u8], b: &[u8]) -> bool {
+    a.is_empty() || b.is_empty() || a.iter().any(|x| b.iter().any(|y|
+        x == y || GROUP_ID_RANGE.contains(x) || GROUP_ID_RANGE.contains(y)))
+}
+
+/// a packet held for a burst, with its targets and show payload if it could be decoded
+struct Held {
+    marshalled: Vec<u8>,
+    decoded: Option<DecodedPacket>,
+    targets: Vec<u8>,
+    merged: bool
+}
+
+impl Held {
+    fn show(self: &Self) -> Option<ShowPacket> {
+        match self.decoded {
+            Some(DecodedPacket { payload: PacketPayload::Show(show), .. }) => Some(show),
+            _ => None
+        }
+    }
+}
+
+/// merge marshalled packets carrying identical show payloads into one packet with the
+/// union of their targets, where that can't change what any receiver ends up doing
+/// (no packet in between reaches the same receivers) and the result still fits
+pub fn merge_identical(packets: Vec<Vec<u8>>, header_mode: HeaderMode) -> Vec<Vec<u8>> {
+    let mut held: Vec<Held> = Vec::with_capacity(packets.len());
+    for marshalled in packets {
+        let decoded = DecodedPacket::unmarshal(&marshalled, header_mode).ok();
+        let targets = decoded.as_ref().map_or(vec![], |d| if d.to == 0xFF { d.recipients.clone() } else { vec![d.to] });
+        let packet = Held { marshalled, decoded, targets, merged: false };
+
+        // the latest earlier packet with the same payload, provided nothing since reaches these targets
+        let mut into: Option<usize> = None;
+        if let Some(show) = packet.show() {
+            for (i, earlier) in held.iter().enumerate().rev() {
+                if earlier.show() == Some(show) {
+                    into = Some(i);
+                    break
+                }
+                if earlier.decoded.is_none() || targets_overlap(&earlier.targets, &packet.targets) {
+                    break
+                }
+            }
+        }
+        match into {
+            Some(i) => {
+                let earlier = &mut held[i];
+                let union: Vec<u8> = if earlier.targets.is_empty() || packet.targets.is_empty() {
+                    vec![]
+                } else {
+                    earlier.targets.iter().chain(packet.targets.iter().filter(|t| !earlier.targets.contains(t))).copied().collect()
+                };
+                // the union has to fit in one packet along with the header and payload
+                if header_mode.header_len() + 10 + union.len() <= MAX_PACKET_LEN {
+                    earlier.targets = union;
+                    earlier.merged = true;
+                } else {
+                    held.push(packet);
+                }
+            },
+            None => held.push(packet)
+        }
+    }
+    held.into_iter().map(|h| match (h.merged, h.decoded) {
+        (true, Some(decoded)) => Packet { recipients: &h.targets, payload: decoded.payload }
+            .marshal(header_mode, decoded.from.unwrap_or(0), 0, decoded.flags.unwrap_or(0)),
+        (_, _) => h.marshalled
+    }).collect()
+}
+
+/// split a marshalled broadcast packet that's too long for the radio (because of a long
+/// recipients list) into packets with the same payload and a share of the recipients each
+pub fn split_oversized(marshalled: &[u8], header_mode: HeaderMode) -> Vec<Vec<u8>> {
+    if marshalled.len() <= MAX_PACKET_LEN {
+        return vec![marshalled.to_vec()]
+    }
+    let header_len = header_mode.header_len();
+    let payload_len = if marshalled[header_len] == 0xFF { 5 } else { 10 };
+    let (prefix, recipients) = marshalled.split_at(header_len + payload_len);
+    recipients.chunks(MAX_PACKET_LEN - prefix.len()).map(|chunk| {
+        let mut buf = [prefix, chunk].concat();
+        buf[0] = (buf.len() - 1) as u8;
+        buf
+    }).collect()
+}
+
 impl<'a> Packet<'a> {
 
     fn is_broadcast(self: &Self) -> bool {
@@ -227,15 +569,17 @@ impl<'a> Packet<'a> {
         self.recipients.len() == 0 || self.recipients.len() > 1 || GROUP_ID_RANGE.contains(&self.recipients[0])
     }
 
-    pub fn marshal(self: &Self, from_id: u8, packet_id: u8, flags: u8) -> Vec<u8> {
+    pub fn marshal(self: &Self, header_mode: HeaderMode, from_id: u8, packet_id: u8, flags: u8) -> Vec<u8> {
         let mut buf = Vec::with_capacity(64);
         buf.push(0); // we'll poke the length in here later
         // recipient address is next, this is either 255 for broadcast/multi or a group id or a single receiver id
         buf.push(if self.is_broadcast() { 0xFF } else { self.recipients[0] });
         // three bytes that are here for compatibility with RadioHead
-        buf.push(from_id);
-        buf.push(packet_id);
-        buf.push(flags);
+        if header_mode != HeaderMode::Raw {
+            buf.push(from_id);
+            buf.push(packet_id);
+            buf.push(flags);
+        }
         match &self.payload {
             PacketPayload::Control(p) => p.marshal(&mut buf),
             PacketPayload::Show(p) => p.marshal(&mut buf),
@@ -252,7 +596,7 @@ impl<'a> Packet<'a> {
     }
 }
 
-#[derive(Debug,Copy,Clone)]
+#[derive(Debug,Copy,Clone,PartialEq)]
 pub struct ShowPacket {
     // the effect to perform
     pub effect: EffectId,
@@ -293,6 +637,55 @@ impl ShowPacket {
         buf.push(self.tempo);
     }
 
+    /// substitute the nearest effect that receivers running the given firmware generation
+    /// support, adjusting params to suit, or None if there's no sensible substitute
+    pub fn downgrade(self: &Self, firmware: u8) -> Option<ShowPacket> {
+        let mut packet = *self;
+        while packet.effect.min_firmware() > firmware {
+            match packet.effect {
+                EffectId::Twinkle => {
+                    packet.effect = EffectId::Sparkle;
+                    packet.param1 = 3; // stride
+                    packet.param2 = 1; // tempo division
+                },
+                EffectId::Rainbow => {
+                    packet.effect = EffectId::Wave;
+                    packet.param1 = 0;
+                    packet.param2 = 0;
+                },
+                EffectId::BatteryTest | EffectId::PopAndSpin => {
+                    packet.effect = EffectId::Pop;
+                    packet.param1 = 0;
+                    packet.param2 = 0;
+                    packet.tempo = 0;
+                },
+                EffectId::PinAndSpin => {
+                    packet.effect = EffectId::DigitalPin;
+                    packet.tempo = 0;
+                },
+                // driving a pin has no lighting equivalent
+                _ => return None
+            }
+        }
+        Some(packet)
+    }
+
+    pub fn unmarshal(buf: &[u8]) -> Result<ShowPacket> {
+        if buf.len() < 10 {
+            return Err(anyhow!("Show payload too short: {} bytes", buf.len()))
+        }
+        Ok(ShowPacket {
+            effect: EffectId::from_u8(buf[0]).ok_or_else(|| anyhow!("Unknown effect id: {}", buf[0]))?,
+            color: Color { h: buf[1], s: buf[2], v: buf[3] },
+            attack: buf[4],
+            sustain: buf[5],
+            release: buf[6],
+            param1: buf[7],
+            param2: buf[8],
+            tempo: buf[9]
+        })
+    }
+
     pub const OFF_PACKET: ShowPacket = ShowPacket {
         effect: EffectId::Off,
         color: Color { h: 0, s: 0, v: 0 },
@@ -315,4 +708,41 @@ impl ShowPacket {
         tempo: 0
     };
 
-}
\ No newline at end of file
+}
+
+/// bytes given as hex digits, optionally separated by whitespace, commas or colons
+/// and prefixed with 0x, eg as receiver serial logs and sniffers print them
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let digits: Vec<char> = hex.split(|c: char| c.is_whitespace() || c == ',' || c == ':')
+        .flat_map(|b| b.trim_start_matches("0x").trim_start_matches("0X").chars())
+        .collect();
+    if let Some(c) = digits.iter().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid hex digit: {} in: {}", c, hex))
+    }
+    if digits.len() % 2 != 0 {
+        return Err(anyhow!("Odd number of hex digits in: {}", hex))
+    }
+    Ok(digits.chunks(2)
+        .map(|pair| (pair[0].to_digit(16).unwrap() * 16 + pair[1].to_digit(16).unwrap()) as u8)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_is_read_however_it_is_separated() {
+        assert_eq!(parse_hex("0a ff 10").unwrap(), vec![0x0A, 0xFF, 0x10]);
+        assert_eq!(parse_hex("0x0A,0XfF:10").unwrap(), vec![0x0A, 0xFF, 0x10]);
+        assert_eq!(parse_hex("0aff10\n").unwrap(), vec![0x0A, 0xFF, 0x10]);
+        assert!(parse_hex("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn anything_but_pairs_of_hex_digits_is_refused() {
+        for bad in ["0a f", "0g", "+f", "-1", "0a é1", "éé", "0a\u{1F4A1}"] {
+            assert!(parse_hex(bad).is_err(), "{}", bad);
+        }
+    }
+}
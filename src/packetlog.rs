@@ -0,0 +1,98 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{Context, Result, anyhow};
+use chrono::{DateTime, Local, SecondsFormat};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::packet::{DecodedPacket, HeaderMode, parse_hex};
+
+///
+/// The packet log is a capture of every packet handed to the radio, one JSON object
+/// per line with a wall clock timestamp, the bytes in hex, who it was for and what it
+/// decodes to. Lining it up with the session log answers "the lights did something
+/// weird at bar 37" after a rehearsal. It's appended to, a line at a time, so a crash
+/// loses nothing. --replay sends a packet log again with its original timing
+///
+
+pub struct PacketLog {
+    writer: BufWriter<File>,
+    header_mode: HeaderMode
+}
+
+#[derive(Serialize)]
+struct LoggedPacket<'a> {
+    timestamp: String,
+    hex: String,
+    /// the recipient address, 255 when the recipients list says who it's for
+    to: Option<u8>,
+    recipients: Vec<u8>,
+    packet_id: Option<u8>,
+    /// the payload as the transmitter understands it, or why it couldn't be decoded
+    decoded: &'a str
+}
+
+impl PacketLog {
+
+    /// open the packet log at the path, appending if it already exists
+    pub fn open(path: &str, header_mode: HeaderMode) -> Result<PacketLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Could not open packet log: {}", path))?;
+        Ok(PacketLog { writer: BufWriter::new(file), header_mode })
+    }
+
+    /// log a packet just handed to the radio. failures are logged rather than
+    /// returned, the packet log mustn't stop the show
+    pub fn log(self: &mut Self, marshalled: &[u8]) {
+        let decoded = DecodedPacket::unmarshal(marshalled, self.header_mode);
+        let description = match &decoded {
+            Ok(packet) => format!("{:?}", packet.payload),
+            Err(e) => format!("undecodable: {}", e)
+        };
+        let line = LoggedPacket {
+            timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+            hex: marshalled.iter().map(|b| format!("{:02x}", b)).collect(),
+            to: marshalled.get(1).copied(),
+            recipients: decoded.as_ref().map_or(vec![], |p| if p.to == 0xFF { p.recipients.clone() } else { vec![p.to] }),
+            packet_id: decoded.as_ref().ok().and_then(|p| p.packet_id),
+            decoded: &description
+        };
+        let written = serde_json::to_writer(&mut self.writer, &line).map_err(std::io::Error::from)
+            .and_then(|_| self.writer.write_all(b"\n"))
+            .and_then(|_| self.writer.flush());
+        if let Err(e) = written {
+            error!("Could not write to packet log: {}", e);
+        }
+    }
+}
+
+/// the parts of a logged packet replaying needs
+#[derive(Deserialize)]
+struct ReplayedPacket {
+    timestamp: String,
+    hex: String
+}
+
+/// read a packet log, as packets with their offsets from the first
+pub fn load(path: &Path) -> Result<Vec<(Duration, Vec<u8>)>> {
+    let file = File::open(path).with_context(|| format!("Could not open packet log: {}", path.display()))?;
+    let mut packets = vec![];
+    let mut first = None;
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue
+        }
+        let mut parse = || -> Result<(Duration, Vec<u8>)> {
+            let logged: ReplayedPacket = serde_json::from_str(&line)?;
+            let at = DateTime::parse_from_rfc3339(&logged.timestamp)?;
+            let first = *first.get_or_insert(at);
+            let offset = (at - first).to_std().map_err(|_| anyhow!("earlier than the first packet"))?;
+            Ok((offset, parse_hex(&logged.hex)?))
+        };
+        packets.push(parse().with_context(|| format!("Bad packet on line {} of packet log", n + 1))?);
+    }
+    Ok(packets)
+}
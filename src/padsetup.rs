@@ -0,0 +1,128 @@
+use std::sync::Arc;
+use anyhow::Result;
+use log::{debug,error,info};
+use serde::Deserialize;
+use crate::feedback::Pad;
+use crate::midi::MidiSender;
+use crate::packet::parse_hex;
+use crate::show::Color;
+
+///
+/// Pad setup configures the pad controller from the show when it loads, so the
+/// controller can't drift from the show file: each pad a mapping claims is set to send
+/// the mapping's note (or controller) on its channel, held or toggled, and lit in the
+/// nearest color the controller has to the mapping's. The controller impersonates an
+/// Arturia MiniLab, which takes its settings as sysex: prefix, setting, pad, value
+///
+
+/// F0 00 20 6B 7F 42 02 00: sysex start, Arturia's manufacturer id, then "set a setting"
+const DEFAULT_SYSEX_PREFIX: &str = "00206B7F420200";
+const SYSEX_START: u8 = 0xF0;
+const SYSEX_END: u8 = 0xF7;
+
+/// the settings of a pad
+const MODE_SETTING: u8 = 0x01;
+const CHANNEL_SETTING: u8 = 0x02;
+const NUMBER_SETTING: u8 = 0x03;
+const OFF_VALUE_SETTING: u8 = 0x04;
+const ON_VALUE_SETTING: u8 = 0x05;
+const BEHAVIOUR_SETTING: u8 = 0x06;
+const COLOR_SETTING: u8 = 0x10;
+
+/// values of the mode setting
+const CONTROLLER_MODE: u8 = 0x08;
+const NOTE_MODE: u8 = 0x09;
+
+/// values of the behaviour setting
+const TOGGLE: u8 = 0x00;
+const GATE: u8 = 0x01;
+
+/// the colors the pads can show
+const OFF: u8 = 0x00;
+const WHITE: u8 = 0x7F;
+/// around the color wheel from red, a sixth of the way apart
+const HUES: [u8; 6] = [0x01, 0x05, 0x04, 0x14, 0x10, 0x11];
+
+#[derive(Debug,Deserialize)]
+pub struct PadSetupConfig {
+    /// the start of each settings message after F0, in hex, defaults to the MiniLab's
+    pub sysex_prefix: Option<String>
+}
+
+/// what a pad is set up to do, from the mappings that claim it
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct AssignedPad {
+    /// the controller's id for the pad
+    pub id: u8,
+    pub trigger: Pad,
+    pub toggle: bool,
+    pub color: Color
+}
+
+pub struct PadSetup {
+    output: Arc<MidiSender>,
+    prefix: Vec<u8>
+}
+
+impl PadSetup {
+
+    pub fn new(config: &PadSetupConfig, output: Arc<MidiSender>) -> Result<PadSetup> {
+        let prefix = parse_hex(config.sysex_prefix.as_deref().unwrap_or(DEFAULT_SYSEX_PREFIX))?;
+        Ok(PadSetup { output, prefix })
+    }
+
+    /// set up every pad the show assigns. failures are logged, the show goes on
+    /// without the controller set up rather than not at all
+    pub fn configure(self: &Self, pads: &[AssignedPad]) {
+        info!("setting up {} pads on the pad controller", pads.len());
+        for pad in pads {
+            debug!("setting up pad: {} as {:?}", pad.id, pad);
+            let (mode, channel, number) = match pad.trigger {
+                Pad::Note { channel, note } => (NOTE_MODE, channel, note),
+                Pad::Controller { channel, controller } => (CONTROLLER_MODE, channel, controller)
+            };
+            let mut settings = vec![
+                (MODE_SETTING, mode),
+                (CHANNEL_SETTING, channel),
+                (NUMBER_SETTING, number),
+                (BEHAVIOUR_SETTING, if pad.toggle { TOGGLE } else { GATE }),
+                (COLOR_SETTING, nearest_color(pad.color))
+            ];
+            if mode == CONTROLLER_MODE {
+                settings.extend([(OFF_VALUE_SETTING, 0), (ON_VALUE_SETTING, 127)]);
+            }
+            for (setting, value) in settings {
+                if let Err(e) = self.send(setting, pad.id, value) {
+                    error!("Could not set up pad: {}, error: {}", pad.id, e);
+                    return
+                }
+            }
+        }
+    }
+
+    fn send(self: &Self, setting: u8, pad: u8, value: u8) -> Result<(), midir::SendError> {
+        let mut message = vec![SYSEX_START];
+        message.extend(&self.prefix);
+        message.extend([setting, pad, value & 0x7F, SYSEX_END]);
+        self.output.send(&message)
+    }
+
+    /// the midi output, for tests
+    #[cfg(test)]
+    pub fn output(self: &Self) -> &Arc<MidiSender> {
+        &self.output
+    }
+}
+
+/// the pad color closest to a show color: off when dark, white when washed out, or
+/// the nearest of the six hues the pads have
+fn nearest_color(color: Color) -> u8 {
+    if color.v == 0 {
+        OFF
+    } else if color.s < 64 {
+        WHITE
+    } else {
+        // the hues are 256 / 6 apart, starting at red
+        HUES[((color.h as usize * 6 + 128) / 256) % 6]
+    }
+}
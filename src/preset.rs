@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, Context, Result};
+
+use crate::show::{ClipStep, Effect, EffectPreset, LightMapping, LightMappingType, PresetReference, ShowDefinition};
+
+///
+/// This module resolves effect presets: effects the show defines once, with all their
+/// parameters (and the color, envelope and tempo to go with them), under a name in its
+/// effects. A mapping or clip step uses one as { "Preset": { "name": "big wave" } },
+/// with any of the effect's parameters overridden for that use in "overrides", and any
+/// color, attack, sustain, release or tempo of its own taking the place of the
+/// preset's. References are replaced with the effects they name when the show loads
+///
+
+/// replace every preset reference in the show with the effect it names
+pub fn expand_presets(show: &mut ShowDefinition) -> Result<()> {
+    let presets = show.effects.clone().unwrap_or_default();
+    let mut problems: Vec<String> = vec![];
+    let mut expand = |m: &mut LightMapping| {
+        if let Err(e) = expand_mapping(m, &presets) {
+            problems.push(format!("cue: {} {:#}", m.cue, e));
+        }
+    };
+    show.mappings.iter_mut().for_each(&mut expand);
+    for steps in show.clips.values_mut() {
+        for step in steps.iter_mut() {
+            if let ClipStep::MappingOn(m) = step {
+                expand(m);
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Show has bad effect presets:\n  {}", problems.join("\n  ")))
+    }
+}
+
+fn expand_mapping(m: &mut LightMapping, presets: &HashMap<String, EffectPreset>) -> Result<()> {
+    if let LightMappingType::Preset(reference) = &m.light {
+        let preset = presets.get(&reference.name).ok_or_else(|| anyhow!("uses unknown effect preset: {}", reference.name))?;
+        let effect = with_overrides(&preset.effect, reference)
+            .with_context(|| format!("could not override effect preset: {}", reference.name))?;
+        m.light = LightMappingType::Effect(effect);
+        if m.color.is_empty() {
+            m.color = preset.color.clone().unwrap_or_default();
+        }
+        m.attack = m.attack.or(preset.attack);
+        m.sustain = m.sustain.or(preset.sustain);
+        m.release = m.release.or(preset.release);
+        m.tempo = m.tempo.or(preset.tempo);
+    }
+    if m.color.is_empty() {
+        return Err(anyhow!("has no color"))
+    }
+    Ok(())
+}
+
+/// the preset's effect with the reference's overrides of its parameters
+fn with_overrides(effect: &Effect, reference: &PresetReference) -> Result<Effect> {
+    let Some(overrides) = reference.overrides.as_ref().filter(|o| !o.is_empty()) else {
+        return Ok(effect.clone())
+    };
+    // an effect with parameters is { "Name": { parameters } }, one without just "Name"
+    let mut value = serde_json::to_value(effect)?;
+    let Some(parameters) = value.as_object_mut().and_then(|o| o.values_mut().next()).and_then(|p| p.as_object_mut()) else {
+        return Err(anyhow!("the effect has no parameters"))
+    };
+    for (name, setting) in overrides {
+        match parameters.get_mut(name) {
+            Some(parameter) => *parameter = setting.clone(),
+            None => return Err(anyhow!("the effect has no parameter: {}", name))
+        }
+    }
+    Ok(serde_json::from_value::<Effect>(value)?)
+}
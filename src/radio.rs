@@ -1,18 +1,29 @@
-use log::debug;
-use std::{cell::{Cell, RefCell}, num::Wrapping, thread::sleep};
+use log::{debug,info,warn};
+use serde::{Deserialize, Serialize};
+use std::{cell::{Cell, RefCell}, collections::{HashMap, VecDeque}, ops::RangeInclusive, sync::{Arc, Mutex}, thread::sleep};
+use rand::Rng;
 use rfm69::{Rfm69, registers::{Registers, Modulation, ModulationShaping, 
     ModulationType, DataMode, PacketConfig, PacketFormat, 
     PacketDc, PacketFiltering, InterPacketRxDelay, RxBw, RxBwFsk,
-    Pa13dBm1, Pa13dBm2 }};
+    Pa13dBm1, Pa13dBm2, Mode }};
 use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
-use linux_embedded_hal::Spidev;
+use linux_embedded_hal::{CdevPin, Spidev};
 use linux_embedded_hal::gpio_cdev::{Chip, LineRequestFlags};
 
 use std::time::Duration;
 use std::fmt::{Display,Formatter};
 
 use crate::config::ConfigFile;
-use crate::packet::Packet;
+use crate::packet::{Command,DecodedPacket,HeaderMode,Packet,PacketPayload,MAX_PACKET_LEN,merge_identical,split_oversized};
+use crate::localreceiver::LocalReceiver;
+use crate::artnet::ArtNetMirror;
+use crate::lora::{Sx127xBackend, Sx127xError};
+use crate::recording::PacketRecorder;
+use crate::packetlog::PacketLog;
+use crate::txqueue::TxQueue;
+use crate::udp::UdpBackend;
+use crate::clock;
+use std::time::Instant;
 
 // reference links
 // radio datasheet: https://cdn.sparkfun.com/datasheets/Wireless/General/RFM69HCW-V1.1.pdf
@@ -24,18 +35,23 @@ use crate::packet::Packet;
 //  02,           03,   04,   05,   06,   19,   1a,   37
 // { CONFIG_GFSK, 0x00, 0x80, 0x10, 0x00, 0xe0, 0xe0, CONFIG_WHITE}, // GFSK_Rb250Fd250
 
-// rpi rf69 bonnet uses chip select CE1 (the ".1" suffix here)
-//const SPI_DEVICE: &str = "/dev/spidev0.1";
+// rpi rf69 bonnet uses chip select CE1 (the ".1" suffix here), so its spi_device
+// is "/dev/spidev0.1". boards with chip select elsewhere give a cs_line
+// rpi rf69 bonnet connects reset to GPIO25, so its reset_line is 25 (424 on a pi 5)
 
-// rpi rf69 bonnet connects reset to GPIO25
-//const RESET_PIN: u64 = 424; // pi 5
-//const RESET_PIN: u64 = 25;
-
-const BIT_RATE: u32 = 250_000; // 250 kbps
-const FREQ_DEVIATION: u32 = 250_000; // 250 kHz
-const PREAMBLE_LENGTH: u16 = 4;
 const SYNCWORD: &str = "CHS";
 const DEFAULT_SETTLE_TIME: u64 = 10;
+const DEFAULT_SPI_SPEED: u32 = 1_000_000;
+// length, recipient, from, then the packet id and flags
+const PACKET_ID_OFFSET: usize = 3;
+const FLAGS_OFFSET: usize = 4;
+// the rfm69's hardware aes takes a 128 bit key
+const AES_KEY_LEN: usize = 16;
+const DEFAULT_REPEAT_GAP: u64 = 5;
+// the flag RHReliableDatagram sets on acknowledgements
+const ACK_FLAG: u8 = 0x80;
+// how often to check the radio for a received packet
+const RECEIVE_POLL: Duration = Duration::from_millis(1);
 
 const MODULATION: Modulation = Modulation { 
     data_mode: DataMode::Packet, 
@@ -49,26 +65,189 @@ const PACKET_CONFIG: PacketConfig = PacketConfig {
     interpacket_rx_delay: InterPacketRxDelay::Delay1Bit,
     auto_rx_restart: true
 };
-const RX_BW: RxBw<RxBwFsk> = RxBw {
-    dcc_cutoff: rfm69::registers::DccCutoff::Percent0dot125,
-    rx_bw: RxBwFsk::Khz500dot0
-};
+
+/// a range of packet ids, first to last inclusive
+#[derive(Debug,Deserialize,Clone,Copy)]
+pub struct PacketIdRange {
+    pub first: u8,
+    pub last: u8
+}
+
+impl PacketIdRange {
+    /// a range has to run the right way and hold an id the header can use
+    pub fn validate(self: &Self, header_mode: HeaderMode) -> anyhow::Result<()> {
+        if self.first > self.last {
+            anyhow::bail!("packet_id_range first {} is above last {}", self.first, self.last)
+        }
+        if self.last < lowest_packet_id(header_mode) {
+            anyhow::bail!("packet_id_range {}-{} has no ids the {:?} header can use", self.first, self.last, header_mode)
+        }
+        Ok(())
+    }
+}
+
+/// which radio module the transmitter has
+#[derive(Debug,Deserialize,Clone,Copy,PartialEq,Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RadioType {
+    /// the rfm69 bonnet
+    #[default]
+    Rfm69,
+    /// an SX1276/7/8/9 LoRa module, for the LoRa receivers
+    Sx127x,
+    /// no radio at all, packets go out as UDP datagrams, eg to a receiver simulator
+    Udp
+}
+
+/// modem settings that must match the receivers' build
+#[derive(Debug,Deserialize,Clone,Copy,PartialEq,Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RadioProfile {
+    /// the standard receiver build, radiohead's GFSK_Rb250Fd250
+    #[default]
+    Standard,
+    /// the long range receiver build for routes beyond the reach of the standard
+    /// profile, a lower bit rate and narrower bandwidth like radiohead's GFSK_Rb55555Fd50
+    LongRange
+}
+
+struct ProfileSettings {
+    bit_rate: u32,
+    freq_deviation: u32,
+    preamble_length: u16,
+    rx_bw: RxBwFsk
+}
+
+impl RadioProfile {
+    fn settings(self: &Self) -> ProfileSettings {
+        match self {
+            RadioProfile::Standard => ProfileSettings {
+                bit_rate: 250_000, // 250 kbps
+                freq_deviation: 250_000, // 250 kHz
+                preamble_length: 4,
+                rx_bw: RxBwFsk::Khz500dot0
+            },
+            RadioProfile::LongRange => ProfileSettings {
+                bit_rate: 55_555, // 55.5 kbps
+                freq_deviation: 50_000, // 50 kHz
+                // a longer preamble gives receivers more time to lock on at the margins
+                preamble_length: 8,
+                rx_bw: RxBwFsk::Khz125dot0
+            }
+        }
+    }
+}
 
 type MyRfm = Rfm69<rfm69::NoCs, rfm69::SpiTransactional<Spidev>>;
+type GpioCsRfm = Rfm69<CdevPin, Spidev>;
 
-pub struct Radio {
-    // putting the radio in a refcell allows us to call mut methods on it without
-    // having a mutable radio, which otherwise percolates up the encapsulation stack
-    // and causes pain
-    radio: RefCell<MyRfm>,
-    my_address: u8,
+/// what marshalled packets go out through: the rfm69 on the bonnet, a LoRa module, udp, or (for tests
+/// and dry runs) a stand-in. Radio does the framing, packet ids and bursts on top
+pub trait RadioBackend: Send {
+    /// bring the device up as the config describes
+    fn init(config: &ConfigFile) -> Result<Self, RadioError> where Self: Sized;
+
+    /// put one marshalled packet on the air
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError>;
+
+    /// wait for the gap before the next packet goes out, eg between repeats
+    fn pause(&mut self, gap: Duration) {
+        sleep(gap);
+    }
+
+    /// change the transmitter power, in dBm
+    fn set_power(&mut self, _power: i8) -> Result<(), RadioError> {
+        Ok(())
+    }
+
+    /// listen for a packet for up to the timeout, returning it (from the length byte
+    /// on) if one arrived. backends that can't receive never hear anything
+    fn recv(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        Ok(None)
+    }
+
+    /// the signal strength the last packet received arrived at, in dBm, if known
+    fn rssi(&mut self) -> Option<f32> {
+        None
+    }
+
+    /// the packets recorded since last asked, with when they were sent, for
+    /// backends that record rather than transmit
+    fn take_sent(&mut self) -> Vec<(Instant, Vec<u8>)> {
+        vec![]
+    }
+
+    /// put a packet on the air (if there is one), then hand what's heard to the listener
+    /// for up to the timeout, or until it has heard what it listens for. backends driven
+    /// from a thread of their own do this there, so the caller doesn't wait for answers
+    fn exchange(&mut self, marshalled: Option<Vec<u8>>, timeout: Duration, listener: Box<dyn Listener>) -> Result<(), RadioError> {
+        exchange(self, marshalled, timeout, listener)
+    }
+}
+
+/// hears what comes back after a packet sent with RadioBackend::exchange
+pub trait Listener: Send {
+    /// a packet heard (from the length byte on), with the signal strength it arrived at
+    /// if known. returns whether it was the last one wanted
+    fn heard(&mut self, buf: &[u8], rssi: Option<f32>) -> bool;
+
+    /// the listening is over, whether or not what was wanted was heard
+    fn finish(self: Box<Self>);
+}
+
+/// the exchange every backend does, on whichever thread drives it. the listener
+/// finishes even if the packet couldn't be sent
+pub fn exchange<B>(backend: &mut B, marshalled: Option<Vec<u8>>, timeout: Duration, mut listener: Box<dyn Listener>) -> Result<(), RadioError>
+where B: RadioBackend + ?Sized {
+    let result = listen(backend, marshalled, timeout, listener.as_mut());
+    listener.finish();
+    result
+}
+
+fn listen<B>(backend: &mut B, marshalled: Option<Vec<u8>>, timeout: Duration, listener: &mut dyn Listener) -> Result<(), RadioError>
+where B: RadioBackend + ?Sized {
+    if let Some(marshalled) = marshalled {
+        backend.send(&marshalled)?;
+    }
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let Some(buf) = backend.recv(remaining)? else { return Ok(()) };
+        debug!("Received: {:?}", buf);
+        let rssi = backend.rssi();
+        if listener.heard(&buf, rssi) || remaining.is_zero() {
+            return Ok(())
+        }
+    }
+}
+
+/// the rfm69 radio bonnet, or any board with an rfm69 on spi
+pub struct Rfm69Backend {
+    rfm: Rfm,
     power: i8,
-    packet_id: Cell<Wrapping<u8>>
+    last_rssi: Option<f32>
 }
 
-impl Radio {
-    pub fn init(config: &ConfigFile) -> Result<Radio, RadioError>  {
+/// the rfm69 driver's type depends on how chip select is driven
+enum Rfm {
+    /// by the spi controller, as on the bonnet
+    SpiCs(MyRfm),
+    /// by a gpio line of its own
+    GpioCs(GpioCsRfm)
+}
+
+/// run the same code against the driver, whichever type it is
+macro_rules! with_rfm {
+    ($rfm:expr, $radio:ident => $body:expr) => {
+        match $rfm {
+            Rfm::SpiCs($radio) => $body,
+            Rfm::GpioCs($radio) => $body
+        }
+    }
+}
 
+impl RadioBackend for Rfm69Backend {
+    fn init(config: &ConfigFile) -> Result<Rfm69Backend, RadioError> {
         // the rfm69 bonnet pulls the reset pin high by
         // default, it needs to be pulled low to bring the radio
         // out of reset
@@ -83,86 +262,720 @@ impl Radio {
         // sleep briefly again before trying to configure the radio
         sleep(settle_time);
 
-        let mut spi = Spidev::open(&config.spi_device)?;
-        let options = SpidevOptions::new()
-            .bits_per_word(8)
-            .max_speed_hz(1_000_000)
-            .mode(SpiModeFlags::SPI_MODE_0)
-            .build();
-        spi.configure(&options)?;
-
-        let mut radio = Rfm69::new_without_cs(spi);
-        radio.modulation(Modulation { ..MODULATION })?;
-        radio.sync(SYNCWORD.as_bytes())?;
-        radio.frequency(config.frequency)?;
-        radio.bit_rate(BIT_RATE)?;
-        radio.packet(PACKET_CONFIG)?;
-        radio.fdev(FREQ_DEVIATION)?;
-        radio.rx_bw(RX_BW)?;
-        radio.rx_afc_bw(RX_BW)?;
-        radio.node_address(config.transmitter_id)?;
-        radio.preamble(PREAMBLE_LENGTH)?;
-        radio.broadcast_address(0xFF)?;
-        radio.fifo_mode(rfm69::registers::FifoMode::NotEmpty)?;
-
-        // rfm69 power is confusing, there are two power amps that can each be enabled/disabled
-        // (or combined) and a "high power" mode from 18-20 dBm requiring enabling/disabling as
-        // part of each write.
-        // good writeup at https://andrehessling.de/2015/02/07/figuring-out-the-power-level-settings-of-hoperfs-rfm69-hwhcw-modules/
-        // tldr: If you use RFM69HW modules, enable PA1 (and only PA1!) for output powers less than +13 dBm. Combine PA1 and PA2 for powers 
-        // between +13 dBm and +17 dBm. And only if you need more power, use PA1+PA2 with high power settings to get more than +17 dBm.
-        let power = config.transmitter_power;
-        let pa_level: u8 = match power {
-            -18..=13 => (power + 18) as u8 | 0x40, // 0x40 - PA1 only
-            14..=17 => (power + 14) as u8 | 0x60, // 0x60 - PA1 + PA2 
-            18..=20 => (power + 11) as u8 | 0x60, // PA1 + PA2 and enable "high power" on xmit
-            _ => return Result::Err(RadioError::IllegalPower)
+        let spi = open_spi(config)?;
+        let mut rfm = match config.cs_line {
+            Some(cs_line) => {
+                // chip select is active low, so it idles high
+                let cs = CdevPin::new(gpio_dev.get_line(cs_line)?.request(LineRequestFlags::OUTPUT, 1, "chs-lights")?)?;
+                info!("Driving chip select on gpio line {}", cs_line);
+                Rfm::GpioCs(Rfm69::new(spi, cs))
+            },
+            None => Rfm::SpiCs(Rfm69::new_without_cs(spi))
         };
-        radio.write(Registers::PaLevel, pa_level)?;
 
-        // now let's read back data from all the registers to confirm that the radio
-        // is in fact alive and took our settings
-        // Print content of all RFM registers
-        for (index, val) in radio.read_all_regs()?.iter().enumerate() {
-            debug!("Register 0x{:02x} = 0x{:02x}", index + 1, val);
-        }
-        Ok(Radio { radio: RefCell::new(radio), 
-            my_address: config.transmitter_id, 
-            power,
-            packet_id: Cell::new(Wrapping(0u8)) })
+        let profile = config.radio_profile.unwrap_or_default();
+        info!("Using radio profile: {:?}", profile);
+        let settings = profile.settings();
+        let rx_bw = RxBw { dcc_cutoff: rfm69::registers::DccCutoff::Percent0dot125, rx_bw: settings.rx_bw };
+
+        let power = config.transmitter_power;
+        with_rfm!(&mut rfm, radio => {
+            radio.modulation(Modulation { ..MODULATION })?;
+            radio.sync(SYNCWORD.as_bytes())?;
+            radio.frequency(config.frequency)?;
+            radio.bit_rate(settings.bit_rate)?;
+            radio.packet(PACKET_CONFIG)?;
+            radio.fdev(settings.freq_deviation)?;
+            radio.rx_bw(rx_bw)?;
+            radio.rx_afc_bw(rx_bw)?;
+            radio.node_address(config.transmitter_id)?;
+            radio.preamble(settings.preamble_length)?;
+            radio.broadcast_address(0xFF)?;
+            radio.fifo_mode(rfm69::registers::FifoMode::NotEmpty)?;
+            if let Some(key) = &config.aes_key {
+                radio.aes(&parse_aes_key(key)?)?;
+                info!("Encrypting packets with AES");
+            }
+
+            // rfm69 power is confusing, there are two power amps that can each be enabled/disabled
+            // (or combined) and a "high power" mode from 18-20 dBm requiring enabling/disabling as
+            // part of each write.
+            // good writeup at https://andrehessling.de/2015/02/07/figuring-out-the-power-level-settings-of-hoperfs-rfm69-hwhcw-modules/
+            // tldr: If you use RFM69HW modules, enable PA1 (and only PA1!) for output powers less than +13 dBm. Combine PA1 and PA2 for powers 
+            // between +13 dBm and +17 dBm. And only if you need more power, use PA1+PA2 with high power settings to get more than +17 dBm.
+            radio.write(Registers::PaLevel, pa_level(power)?)?;
+
+            // now let's read back data from all the registers to confirm that the radio
+            // is in fact alive and took our settings
+            // Print content of all RFM registers
+            for (index, val) in radio.read_all_regs()?.iter().enumerate() {
+                debug!("Register 0x{:02x} = 0x{:02x}", index + 1, val);
+            }
+        });
+        Ok(Rfm69Backend { rfm, power, last_rssi: None })
     }
 
-    pub fn send(self: &Self, packet: &Packet) -> Result<(),RadioError> {
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError> {
         self.pre_tx_hook()?;
-        let marshalled = packet.marshal(self.my_address, self.packet_id.get().0, 0);
-        debug!("Sending packet: {:?}, marshalled: {:?}", packet, marshalled);
-        let result = self.radio.borrow_mut().send(marshalled.as_slice());
+        let result = with_rfm!(&mut self.rfm, radio => radio.send(marshalled).map_err(From::from));
         self.post_tx_hook()?;
-        // increment the packet id for next time
-        self.packet_id.set(self.packet_id.get() + Wrapping(1u8));
-        result.map_err(From::from)
+        result
+    }
+
+    fn set_power(&mut self, power: i8) -> Result<(), RadioError> {
+        with_rfm!(&mut self.rfm, radio => radio.write(Registers::PaLevel, pa_level(power)?)?);
+        self.power = power;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        with_rfm!(&mut self.rfm, radio => {
+            radio.mode(Mode::Receiver)?;
+            let deadline = Instant::now() + timeout;
+            while !radio.is_packet_ready()? {
+                if Instant::now() >= deadline {
+                    radio.mode(Mode::Standby)?;
+                    return Ok(None)
+                }
+                sleep(RECEIVE_POLL);
+            }
+            // the fifo holds the length byte followed by the rest of the packet
+            let mut buf = vec![0u8; MAX_PACKET_LEN + 1];
+            let len = radio.recv_large(&mut buf)?;
+            buf.truncate(len);
+            self.last_rssi = Some(radio.rssi());
+            radio.mode(Mode::Standby)?;
+            Ok(Some(buf))
+        })
     }
 
-    fn pre_tx_hook(self: &Self) -> Result<(),RadioError> {
+    fn rssi(&mut self) -> Option<f32> {
+        self.last_rssi
+    }
+}
+
+impl Rfm69Backend {
+    fn pre_tx_hook(self: &mut Self) -> Result<(),RadioError> {
         if (18..=20).contains(&self.power) {
-            let mut rad = self.radio.borrow_mut();
-            rad.write(Registers::Ocp, 0x0F)?; // disables over-current protection
-            rad.pa13_dbm1(Pa13dBm1::High20dBm)?;
-            rad.pa13_dbm2(Pa13dBm2::High20dBm)?;
+            with_rfm!(&mut self.rfm, radio => {
+                radio.write(Registers::Ocp, 0x0F)?; // disables over-current protection
+                radio.pa13_dbm1(Pa13dBm1::High20dBm)?;
+                radio.pa13_dbm2(Pa13dBm2::High20dBm)?;
+            });
         }
         return Ok(())
     }
 
-    fn post_tx_hook(self: &Self) -> Result<(),RadioError> {
-        let mut rad = self.radio.borrow_mut();
+    fn post_tx_hook(self: &mut Self) -> Result<(),RadioError> {
         if (18..=20).contains(&self.power) {
-            rad.write(Registers::Ocp, 0x1A)?; // re-enables over-current protection
-            rad.pa13_dbm1(Pa13dBm1::Normal)?;
-            rad.pa13_dbm2(Pa13dBm2::Normal)?;
+            with_rfm!(&mut self.rfm, radio => {
+                radio.write(Registers::Ocp, 0x1A)?; // re-enables over-current protection
+                radio.pa13_dbm1(Pa13dBm1::Normal)?;
+                radio.pa13_dbm2(Pa13dBm2::Normal)?;
+            });
         }
         return Ok(())
     }
+}
+
+/// bring up the configured radio module
+pub fn open_backend(config: &ConfigFile) -> Result<Box<dyn RadioBackend>, RadioError> {
+    let radio_type = config.radio_type.unwrap_or_default();
+    info!("Using radio: {:?}", radio_type);
+    Ok(match radio_type {
+        RadioType::Rfm69 => Box::new(Rfm69Backend::init(config)?),
+        RadioType::Sx127x => Box::new(Sx127xBackend::init(config)?),
+        RadioType::Udp => Box::new(UdpBackend::init(config)?)
+    })
+}
+
+/// open and configure the radio's spi device
+pub fn open_spi(config: &ConfigFile) -> Result<Spidev, RadioError> {
+    let mut spi = Spidev::open(&config.spi_device)?;
+    let mut flags = SpiModeFlags::SPI_MODE_0;
+    // with chip select on a gpio line, the controller's own chip select is left alone
+    if config.cs_line.is_some() {
+        flags |= SpiModeFlags::SPI_NO_CS;
+    }
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(config.spi_speed_hz.unwrap_or(DEFAULT_SPI_SPEED))
+        .mode(flags)
+        .build();
+    spi.configure(&options)?;
+    Ok(spi)
+}
+
+/// how well a receiver and the transmitter hear each other, from a poll
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub struct LinkQuality {
+    /// the strength the receiver heard the poll at, in dBm
+    pub at_receiver: i16,
+    /// the strength the reply arrived at here, in dBm, if the radio can tell
+    pub at_transmitter: Option<f32>
+}
+
+/// what a receiver reports about itself when asked for its status
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+pub struct ReceiverTelemetry {
+    pub battery_millivolts: u16,
+    pub uptime_minutes: u16
+}
+
+impl LinkQuality {
+    /// the weaker direction of the link, in dBm
+    pub fn weakest(self: &Self) -> f32 {
+        self.at_transmitter.map_or(self.at_receiver as f32, |rssi| rssi.min(self.at_receiver as f32))
+    }
+}
+
+/// a radio that records packets in memory instead of sending them, so show logic
+/// can be exercised without a Pi and an rfm69
+#[derive(Default)]
+pub struct MockRadio {
+    sent: Vec<(Instant, Vec<u8>)>,
+    /// packets to hear, each from the time given on
+    heard: VecDeque<(Instant, Vec<u8>)>
+}
+
+impl MockRadio {
+    /// a mock that hears the packets given, in order, once their times come
+    pub fn hearing(heard: Vec<(Instant, Vec<u8>)>) -> MockRadio {
+        MockRadio { sent: vec![], heard: heard.into() }
+    }
+}
+
+impl RadioBackend for MockRadio {
+    fn init(_config: &ConfigFile) -> Result<MockRadio, RadioError> {
+        Ok(MockRadio::default())
+    }
+
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError> {
+        self.sent.push((clock::now(), marshalled.to_vec()));
+        Ok(())
+    }
+
+    fn recv(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        match self.heard.front() {
+            Some((at, _)) if *at <= clock::now() => Ok(self.heard.pop_front().map(|(_, buf)| buf)),
+            _ => Ok(None)
+        }
+    }
+
+    fn take_sent(&mut self) -> Vec<(Instant, Vec<u8>)> {
+        std::mem::take(&mut self.sent)
+    }
+}
+
+pub struct Radio {
+    // putting the backend in a refcell allows us to call mut methods on it without
+    // having a mutable radio, which otherwise percolates up the encapsulation stack
+    // and causes pain
+    radio: RefCell<Box<dyn RadioBackend>>,
+    my_address: u8,
+    header_mode: HeaderMode,
+    /// the id the next packet goes out with, whoever it's to: receivers discard duplicates
+    /// by the sender's last id, so every packet from this transmitter is one sequence.
+    /// None until the first packet picks a starting point
+    packet_id: Cell<Option<u8>>,
+    /// the ids this transmitter may use
+    packet_id_range: RangeInclusive<u8>,
+    /// the grand master, in percent, scaling the intensity of every packet sent
+    grand_master: Cell<u8>,
+    /// the brightness the schedule calls for at this time of day, in percent, on top of the grand master
+    scheduled_brightness: Cell<u8>,
+    /// led strips on the Pi itself that hear everything sent, as a receiver would
+    local_receivers: Vec<LocalReceiver>,
+    /// mirrors what every receiver is told onto Art-Net, for a visualizer
+    artnet: Option<ArtNetMirror>,
+    /// packets held back while a burst is assembled, to go out back to back
+    burst: RefCell<Option<Vec<Vec<u8>>>>,
+    /// bursts can nest, the outermost one sends the packets
+    burst_depth: Cell<u32>,
+    /// how many extra copies of each show packet, and each control packet, to send
+    show_repeats: Cell<u8>,
+    control_repeats: Cell<u8>,
+    repeat_gap: Duration,
+    /// if recording a performance, where each packet sent is recorded
+    recorder: RefCell<Option<PacketRecorder>>,
+    /// if capturing packets for debugging, where each packet sent is logged
+    packet_log: RefCell<Option<PacketLog>>,
+    /// receivers heard announcing themselves (with their firmware) and not yet asked about,
+    /// kept when heard while waiting for something else, here or on the radio's thread
+    announced: Arc<Mutex<Vec<(u8,u8)>>>,
+    /// if tracking them for the dashboard, when each address (or 0xFF, for
+    /// everybody) was last sent a packet
+    sent_at: RefCell<Option<HashMap<u8,Instant>>>
+}
+
+impl Radio {
+    /// bring up the configured radio module, sending from a thread of its own so the
+    /// show never waits on it, and any local receivers and Art-Net mirror
+    pub fn init(config: &ConfigFile) -> Result<Radio, RadioError>  {
+        let mut radio = Radio::new(config, Box::new(TxQueue::init(config)?));
+        for local_config in config.local_receivers.iter().flatten() {
+            match LocalReceiver::start(local_config, radio.header_mode) {
+                Ok(local) => radio.local_receivers.push(local),
+                Err(e) => warn!("Could not set up local receiver {}, continuing without it. Error: {}", local_config.id, e)
+            }
+        }
+        if let Some(artnet_config) = &config.artnet {
+            match ArtNetMirror::start(artnet_config, radio.header_mode) {
+                Ok(artnet) => radio.artnet = Some(artnet),
+                Err(e) => warn!("Could not set up the Art-Net mirror, continuing without it. Error: {:#}", e)
+            }
+        }
+        Ok(radio)
+    }
+
+    /// a radio sending through the given backend
+    pub fn new(config: &ConfigFile, backend: Box<dyn RadioBackend>) -> Radio {
+        let header_mode = config.header_mode.unwrap_or_default();
+        Radio { radio: RefCell::new(backend),
+            my_address: config.transmitter_id,
+            header_mode,
+            packet_id: Cell::new(None),
+            packet_id_range: packet_id_range(config, header_mode),
+            grand_master: Cell::new(100),
+            scheduled_brightness: Cell::new(100),
+            local_receivers: vec![],
+            artnet: None,
+            burst: RefCell::new(None),
+            burst_depth: Cell::new(0),
+            show_repeats: Cell::new(config.show_packet_repeats.unwrap_or(0)),
+            control_repeats: Cell::new(config.control_packet_repeats.unwrap_or(0)),
+            repeat_gap: Duration::from_millis(config.repeat_gap_millis.unwrap_or(DEFAULT_REPEAT_GAP)),
+            recorder: RefCell::new(None),
+            packet_log: RefCell::new(None),
+            announced: Arc::new(Mutex::new(vec![])),
+            sent_at: RefCell::new(None) }
+    }
+
+    /// a radio that records packets instead of sending them, for tests and dry runs
+    pub fn mock(config: &ConfigFile) -> Radio {
+        Radio::new(config, Box::new(MockRadio::default()))
+    }
+
+    /// change the transmitter power, in dBm
+    pub fn set_power(self: &Self, power: i8) -> Result<(),RadioError> {
+        self.radio.borrow_mut().set_power(power)
+    }
+
+    /// change how many extra copies of show and control packets are sent
+    pub fn set_repeats(self: &Self, show: u8, control: u8) {
+        self.show_repeats.set(show);
+        self.control_repeats.set(control);
+    }
+
+    /// record every packet sent from now on to the file at the path, replacing any earlier recording
+    pub fn start_recording(self: &Self, path: &str) -> anyhow::Result<()> {
+        self.recorder.replace(Some(PacketRecorder::create(path)?));
+        info!("Recording packets to: {}", path);
+        Ok(())
+    }
+
+    /// stop recording packets, returning whether a recording was running
+    pub fn stop_recording(self: &Self) -> bool {
+        self.recorder.take().is_some()
+    }
+
+    /// log every packet sent from now on to the file at the path, appending to what's there
+    pub fn log_packets(self: &Self, path: &str) -> anyhow::Result<()> {
+        self.packet_log.replace(Some(PacketLog::open(path, self.header_mode)?));
+        info!("Logging packets to: {}", path);
+        Ok(())
+    }
+
+    /// keep track from now on of when each address is sent a packet
+    pub fn track_sends(self: &Self) {
+        self.sent_at.replace(Some(HashMap::new()));
+    }
+
+    /// when a packet last went to any of the addresses (a receiver and its group, say),
+    /// or to everybody. None if sends aren't tracked or nothing has gone to them
+    pub fn last_sent_to(self: &Self, addresses: &[u8]) -> Option<Instant> {
+        let sent_at = self.sent_at.borrow();
+        let sent_at = sent_at.as_ref()?;
+        addresses.iter().chain([&0xFF]).filter_map(|a| sent_at.get(a)).max().copied()
+    }
+
+    /// the id the next packet will go out with, once a packet has been sent
+    pub fn packet_id(self: &Self) -> Option<u8> {
+        self.packet_id.get()
+    }
+
+    /// forget the packet id, starting again from a random point in the range
+    pub fn reset_packet_ids(self: &Self) {
+        self.packet_id.set(None);
+    }
+
+    /// the packets a mock radio recorded since last asked, with when they were sent
+    pub fn take_sent(self: &Self) -> Vec<(Instant, Vec<u8>)> {
+        self.radio.borrow_mut().take_sent()
+    }
+
+    /// marshal a packet for sending later with send_marshalled, applying the grand
+    /// master and the scheduled brightness
+    pub fn marshal(self: &Self, packet: &Packet) -> Vec<u8> {
+        let master = (self.grand_master.get() as u16 * self.scheduled_brightness.get() as u16 / 100) as u8;
+        if master < 100 {
+            let dimmed = Packet { recipients: packet.recipients, payload: packet.payload.dimmed(master) };
+            dimmed.marshal(self.header_mode, self.my_address, 0, 0)
+        } else {
+            packet.marshal(self.header_mode, self.my_address, 0, 0)
+        }
+    }
+
+    /// set the grand master percentage (0-100). note packets already
+    /// marshalled for send_marshalled need marshalling again
+    pub fn set_grand_master(self: &Self, percent: u8) {
+        self.grand_master.set(percent.min(100));
+    }
+
+    pub fn grand_master(self: &Self) -> u8 {
+        self.grand_master.get()
+    }
+
+    /// set the scheduled brightness percentage (0-100), as set_grand_master
+    pub fn set_scheduled_brightness(self: &Self, percent: u8) {
+        self.scheduled_brightness.set(percent.min(100));
+    }
+
+    pub fn scheduled_brightness(self: &Self) -> u8 {
+        self.scheduled_brightness.get()
+    }
+
+    pub fn send(self: &Self, packet: &Packet) -> Result<(),RadioError> {
+        let mut marshalled = self.marshal(packet);
+        debug!("Sending packet: {:?}", packet);
+        self.send_marshalled(&mut marshalled)
+    }
+
+    /// send a packet marshalled ahead of time, poking the current packet id into its header
+    pub fn send_marshalled(self: &Self, marshalled: &mut [u8]) -> Result<(),RadioError> {
+        if marshalled.len() > MAX_PACKET_LEN {
+            let chunks = split_oversized(marshalled, self.header_mode);
+            debug!("Packet of {} bytes is too long to send, splitting its recipients over {} packets", marshalled.len(), chunks.len());
+            for mut chunk in chunks {
+                self.send_marshalled(&mut chunk)?;
+            }
+            return Ok(())
+        }
+        if let Some(burst) = &mut *self.burst.borrow_mut() {
+            burst.push(marshalled.to_vec());
+            return Ok(())
+        }
+        self.transmit(marshalled)
+    }
+
+    /// hold packets sent from now on until finish_burst, so that the work of
+    /// deciding what to send doesn't spread them out
+    pub fn start_burst(self: &Self) {
+        if self.burst_depth.replace(self.burst_depth.get() + 1) == 0 {
+            self.burst.replace(Some(vec![]));
+        }
+    }
+
+    /// send the packets held since start_burst back to back, optionally merging
+    /// identical effects sent to different targets into one packet
+    pub fn finish_burst(self: &Self, merge: bool) -> Result<(),RadioError> {
+        self.burst_depth.set(self.burst_depth.get().saturating_sub(1));
+        if self.burst_depth.get() > 0 {
+            return Ok(())
+        }
+        let Some(held) = self.burst.take() else { return Ok(()) };
+        let mut held = if merge { merge_identical(held, self.header_mode) } else { held };
+        for marshalled in held.iter_mut() {
+            let id = self.next_packet_id();
+            self.transmit_as(marshalled, id)?;
+        }
+        // the copies go out after the whole burst, so they don't spread it out
+        self.repeat(&held.iter().map(|m| m.as_slice()).collect::<Vec<_>>())
+    }
+
+    /// send an addressed packet and wait for the recipient to acknowledge it, as
+    /// RHReliableDatagram receivers do. unacknowledged packets are sent again up to
+    /// retries times, with the same packet id so the receiver can discard duplicates.
+    /// returns whether the packet was acknowledged; only the reliable datagram header
+    /// gets acknowledgements, see expects_acks
+    pub fn send_acknowledged(self: &Self, packet: &Packet, retries: u32, timeout: Duration) -> Result<bool,RadioError> {
+        let mut marshalled = self.marshal(packet);
+        debug!("Sending packet expecting an ack: {:?}", packet);
+        let recipient = marshalled[1];
+        let id = self.next_packet_id();
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                debug!("No ack from receiver {}, retrying (attempt {} of {})", recipient, attempt, retries);
+            }
+            self.transmit_as(&mut marshalled, id)?;
+            if self.await_ack(recipient, id, timeout)? {
+                return Ok(true)
+            }
+        }
+        Ok(false)
+    }
+
+    /// whether receivers acknowledge addressed packets
+    pub fn expects_acks(self: &Self) -> bool {
+        self.header_mode == HeaderMode::ReliableDatagram
+    }
+
+    /// listen for a packet for up to the timeout
+    pub fn receive(self: &Self, timeout: Duration) -> Result<Option<Vec<u8>>,RadioError> {
+        let received = self.radio.borrow_mut().recv(timeout)?;
+        if let Some(buf) = &received {
+            debug!("Received: {:?}", buf);
+            if let Ok(DecodedPacket { to, payload: PacketPayload::Control(Command::Announce { receiver, firmware }), .. }) =
+                    DecodedPacket::unmarshal(buf, self.header_mode) {
+                if to == 0xFF || to == self.my_address {
+                    self.announced.lock().unwrap().push((receiver, firmware));
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    /// listen for receivers announcing themselves for the window, without waiting: done
+    /// hears every receiver that has announced itself since last asked (with the firmware
+    /// generation each announced), from the radio's thread
+    pub fn listen_for_announcements<D>(self: &Self, window: Duration, done: D) -> Result<(),RadioError>
+    where D: FnOnce(Vec<(u8,u8)>) + Send + 'static {
+        let listener = Announcements { my_address: self.my_address, header_mode: self.header_mode, announced: self.announced.clone(), done };
+        self.radio.borrow_mut().exchange(None, window, Box::new(listener))
+    }
+
+    /// ask a receiver how well it hears us, without waiting for its reply: done hears
+    /// how it answered within the timeout (or None if it didn't), from the radio's thread
+    pub fn poll<D>(self: &Self, receiver: u8, timeout: Duration, done: D) -> Result<(),RadioError>
+    where D: FnOnce(Option<LinkQuality>) + Send + 'static {
+        debug!("Polling receiver {}", receiver);
+        self.request(receiver, Command::Poll, timeout, |reply, rssi| match reply {
+            Command::PollReply { rssi: at_receiver } => Some(LinkQuality { at_receiver: -(*at_receiver as i16), at_transmitter: rssi }),
+            _ => None
+        }, done)
+    }
+
+    /// ask a receiver for its battery voltage and uptime, without waiting for its reply:
+    /// done hears what it said within the timeout (or None if it didn't answer)
+    pub fn query_status<D>(self: &Self, receiver: u8, timeout: Duration, done: D) -> Result<(),RadioError>
+    where D: FnOnce(Option<ReceiverTelemetry>) + Send + 'static {
+        debug!("Querying status of receiver {}", receiver);
+        self.request(receiver, Command::QueryStatus, timeout, |reply, _| match reply {
+            Command::StatusReply { battery, uptime_minutes } =>
+                Some(ReceiverTelemetry { battery_millivolts: *battery as u16 * 20, uptime_minutes: *uptime_minutes }),
+            _ => None
+        }, done)
+    }
+
+    /// send a command to a receiver, then have done hear its answer: the first command
+    /// from it addressed to us that accept takes (given the signal strength it arrived
+    /// at), or None if none came within the timeout
+    fn request<T, A, D>(self: &Self, receiver: u8, command: Command, timeout: Duration, accept: A, done: D) -> Result<(),RadioError>
+    where T: Send + 'static, A: Fn(&Command, Option<f32>) -> Option<T> + Send + 'static, D: FnOnce(Option<T>) + Send + 'static {
+        let recipients = vec![receiver];
+        let mut marshalled = self.marshal(&Packet { recipients: &recipients, payload: PacketPayload::Control(command) });
+        let id = self.next_packet_id();
+        self.set_packet_id(&mut marshalled, id);
+        debug!("Sending marshalled: {:?}", marshalled);
+        let answer = Answer { receiver, my_address: self.my_address, header_mode: self.header_mode,
+            announced: self.announced.clone(), accept, answer: None, done };
+        self.radio.borrow_mut().exchange(Some(marshalled.clone()), timeout, Box::new(answer))?;
+        self.mirror(&marshalled);
+        Ok(())
+    }
+
+    /// wait for the recipient's acknowledgement of the packet id, ignoring anything else heard
+    fn await_ack(self: &Self, recipient: u8, id: u8, timeout: Duration) -> Result<bool,RadioError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(buf) = self.receive(remaining)? else { return Ok(false) };
+            if buf.len() > FLAGS_OFFSET && buf[1] == self.my_address && buf[2] == recipient &&
+                buf[PACKET_ID_OFFSET] == id && buf[FLAGS_OFFSET] & ACK_FLAG != 0 {
+                return Ok(true)
+            }
+            if remaining.is_zero() {
+                return Ok(false)
+            }
+        }
+    }
 
+    fn transmit(self: &Self, marshalled: &mut [u8]) -> Result<(),RadioError> {
+        let id = self.next_packet_id();
+        self.transmit_as(marshalled, id)?;
+        self.repeat(&[marshalled])
+    }
+
+    /// send packets that have just gone out again, as many times as their kind calls
+    /// for, a gap apart. the copies keep their packet ids, so receivers that discard
+    /// duplicates only act on the first to arrive
+    fn repeat(self: &Self, sent: &[&[u8]]) -> Result<(),RadioError> {
+        let rounds = sent.iter().map(|m| self.repeats_for(m)).max().unwrap_or(0);
+        for round in 0..rounds {
+            self.radio.borrow_mut().pause(self.repeat_gap);
+            for marshalled in sent.iter().filter(|m| self.repeats_for(m) > round) {
+                debug!("Repeating marshalled: {:?}", marshalled);
+                self.radio.borrow_mut().send(marshalled)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn repeats_for(self: &Self, marshalled: &[u8]) -> u8 {
+        // control payloads start with the command marker
+        if marshalled.get(self.header_mode.header_len()) == Some(&0xFF) {
+            self.control_repeats.get()
+        } else {
+            self.show_repeats.get()
+        }
+    }
+
+    fn transmit_as(self: &Self, marshalled: &mut [u8], id: u8) -> Result<(),RadioError> {
+        self.set_packet_id(marshalled, id);
+        debug!("Sending marshalled: {:?}", marshalled);
+        self.radio.borrow_mut().send(marshalled)?;
+        self.mirror(marshalled);
+        Ok(())
+    }
+
+    fn set_packet_id(self: &Self, marshalled: &mut [u8], id: u8) {
+        if self.header_mode != HeaderMode::Raw {
+            marshalled[PACKET_ID_OFFSET] = id;
+        }
+    }
+
+    /// show a packet that went out to everything following what's sent
+    fn mirror(self: &Self, marshalled: &[u8]) {
+        self.local_receivers.iter().for_each(|local| local.deliver(marshalled));
+        if let Some(artnet) = &self.artnet {
+            artnet.deliver(marshalled);
+        }
+        if let Some(recorder) = self.recorder.borrow_mut().as_mut() {
+            recorder.record(marshalled);
+        }
+        if let Some(packet_log) = self.packet_log.borrow_mut().as_mut() {
+            packet_log.log(marshalled);
+        }
+        if let Some(sent_at) = self.sent_at.borrow_mut().as_mut() {
+            if let Ok(packet) = DecodedPacket::unmarshal(marshalled, self.header_mode) {
+                let now = clock::now();
+                match (packet.to, packet.recipients.is_empty()) {
+                    (0xFF, true) => { sent_at.insert(0xFF, now); },
+                    (0xFF, false) => packet.recipients.iter().for_each(|to| { sent_at.insert(*to, now); }),
+                    (to, _) => { sent_at.insert(to, now); }
+                }
+            }
+        }
+    }
+
+    /// the packet id to send with, moving the sequence on for next time. the sequence starts
+    /// from a random point in the range, so that after a restart receivers that remember
+    /// the last id they saw don't drop the first packet as a duplicate
+    fn next_packet_id(self: &Self) -> u8 {
+        let id = self.packet_id.get().unwrap_or_else(|| rand::thread_rng().gen_range(self.packet_id_range.clone()));
+        let next_id = if id >= *self.packet_id_range.end() { *self.packet_id_range.start() } else { id + 1 };
+        self.packet_id.set(Some(next_id));
+        id
+    }
+}
+
+/// listens for a receiver's answer to a request, see Radio::request. announcements
+/// heard meanwhile are kept for the next time they're asked about
+struct Answer<T, A, D> {
+    receiver: u8,
+    my_address: u8,
+    header_mode: HeaderMode,
+    announced: Arc<Mutex<Vec<(u8,u8)>>>,
+    accept: A,
+    answer: Option<T>,
+    done: D
+}
+
+impl<T, A, D> Listener for Answer<T, A, D>
+where T: Send, A: Fn(&Command, Option<f32>) -> Option<T> + Send, D: FnOnce(Option<T>) + Send {
+    fn heard(&mut self, buf: &[u8], rssi: Option<f32>) -> bool {
+        // without a from byte (the raw header) the reply can only be told apart by its timing
+        if let Ok(reply) = DecodedPacket::unmarshal(buf, self.header_mode) {
+            if let PacketPayload::Control(command) = reply.payload {
+                if let Command::Announce { receiver, firmware } = command {
+                    if reply.to == 0xFF || reply.to == self.my_address {
+                        self.announced.lock().unwrap().push((receiver, firmware));
+                    }
+                } else if reply.to == self.my_address && reply.from.map_or(true, |from| from == self.receiver) {
+                    self.answer = (self.accept)(&command, rssi);
+                }
+            }
+        }
+        self.answer.is_some()
+    }
+
+    fn finish(self: Box<Self>) {
+        let answer = *self;
+        (answer.done)(answer.answer)
+    }
+}
+
+/// keeps the announcements heard, see Radio::listen_for_announcements
+struct Announcements<D> {
+    my_address: u8,
+    header_mode: HeaderMode,
+    announced: Arc<Mutex<Vec<(u8,u8)>>>,
+    done: D
+}
+
+impl<D: FnOnce(Vec<(u8,u8)>) + Send> Listener for Announcements<D> {
+    fn heard(&mut self, buf: &[u8], _rssi: Option<f32>) -> bool {
+        if let Ok(DecodedPacket { to, payload: PacketPayload::Control(Command::Announce { receiver, firmware }), .. }) =
+                DecodedPacket::unmarshal(buf, self.header_mode) {
+            if to == 0xFF || to == self.my_address {
+                self.announced.lock().unwrap().push((receiver, firmware));
+            }
+        }
+        false
+    }
+
+    fn finish(self: Box<Self>) {
+        let announced = std::mem::take(&mut *self.announced.lock().unwrap());
+        (self.done)(announced)
+    }
+}
+
+/// the packet ids the config reserves for this transmitter (checked by PacketIdRange::validate)
+fn packet_id_range(config: &ConfigFile, header_mode: HeaderMode) -> RangeInclusive<u8> {
+    let lowest = lowest_packet_id(header_mode);
+    match config.packet_id_range {
+        Some(PacketIdRange { first, last }) => first.max(lowest)..=last,
+        None => lowest..=u8::MAX
+    }
+}
+
+/// the reliable datagram header leaves out 0 (RHReliableDatagram receivers take it as already seen)
+fn lowest_packet_id(header_mode: HeaderMode) -> u8 {
+    if header_mode == HeaderMode::ReliableDatagram { 1 } else { 0 }
+}
+
+/// the PaLevel register value for a power in dBm, see the notes in Rfm69Backend::init
+fn pa_level(power: i8) -> Result<u8, RadioError> {
+    match power {
+        -18..=13 => Ok((power + 18) as u8 | 0x40), // 0x40 - PA1 only
+        14..=17 => Ok((power + 14) as u8 | 0x60), // 0x60 - PA1 + PA2 
+        18..=20 => Ok((power + 11) as u8 | 0x60), // PA1 + PA2 and enable "high power" on xmit
+        _ => Err(RadioError::IllegalPower)
+    }
+}
+
+/// the aes key from the config, 16 bytes given as 32 hex digits
+fn parse_aes_key(key: &str) -> Result<[u8; AES_KEY_LEN], RadioError> {
+    let digits: Vec<char> = key.chars().filter(|c| !c.is_whitespace()).collect();
+    if digits.len() != AES_KEY_LEN * 2 {
+        return Err(RadioError::IllegalAesKey)
+    }
+    let mut parsed = [0u8; AES_KEY_LEN];
+    for (i, pair) in digits.chunks(2).enumerate() {
+        let byte: String = pair.iter().collect();
+        parsed[i] = u8::from_str_radix(&byte, 16).map_err(|_| RadioError::IllegalAesKey)?;
+    }
+    Ok(parsed)
 }
 
 /// our own error type to wrap the underlying errors, not 
@@ -172,8 +985,19 @@ pub enum RadioError {
     SysfsError(linux_embedded_hal::sysfs_gpio::Error),
     GpioError(linux_embedded_hal::gpio_cdev::Error),
     Rfm69Error(Rfm69Error),
+    Sx127xError(Sx127xError),
     SpiError(std::io::Error),
-    IllegalPower
+    IllegalPower,
+    IllegalAesKey
+}
+
+impl RadioError {
+    /// whether the radio is likely to work again for the next packet, as opposed
+    /// to the device or its bus having failed
+    pub fn is_transient(self: &Self) -> bool {
+        matches!(self, RadioError::Rfm69Error(Rfm69Error::Timeout | Rfm69Error::BufferTooSmall | Rfm69Error::PacketTooLarge) |
+            RadioError::Sx127xError(Sx127xError::Timeout))
+    }
 }
 
 /// our own non-generic Rfm69Error type that can be fromable
@@ -226,10 +1050,37 @@ impl Display for RadioError {
             RadioError::SysfsError(e) => write!(f, "SysfsError: {:?}", e),
             RadioError::GpioError(e) => write!(f, "GpioError: {:?}", e),
             RadioError::Rfm69Error(e) => write!(f, "Rfm69Error: {:?}", e),
+            RadioError::Sx127xError(e) => write!(f, "Sx127xError: {:?}", e),
             RadioError::SpiError(e) => write!(f, "SpiError: {:?}", e),
-            RadioError::IllegalPower => write!(f, "Unsupported power value specified")
+            RadioError::IllegalPower => write!(f, "Unsupported power value specified"),
+            RadioError::IllegalAesKey => write!(f, "The AES key must be {} bytes given as {} hex digits", AES_KEY_LEN, AES_KEY_LEN * 2)
         }
     }
 }
 
 impl std::error::Error for RadioError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_packet_takes_the_next_id_whoever_it_is_to() {
+        let config: ConfigFile = serde_json::from_value(serde_json::json!({
+            "spi_device": "/dev/null", "gpio_device": "/dev/null", "reset_line": 0, "frequency": 915,
+            "transmitter_id": 1, "transmitter_power": 0, "midi_client_name": "test", "midi_control_channel": 15,
+            "show_file": "", "lights_out_window_open": 5.0, "lights_out_window_close": 60.0, "lights_out_period": 2.0,
+            "packet_id_range": { "first": 250, "last": 252 }
+        })).unwrap();
+        let radio = Radio::mock(&config);
+        for to in [vec![1], vec![2], vec![1], vec![], vec![2]] {
+            radio.send(&Packet { recipients: &to, payload: PacketPayload::Control(Command::Reset) }).unwrap();
+        }
+        // a receiver hearing its own packets and the broadcasts never sees an id twice running
+        let ids: Vec<u8> = radio.take_sent().iter().map(|(_, buf)| buf[PACKET_ID_OFFSET]).collect();
+        for pair in ids.windows(2) {
+            assert_eq!(pair[1], if pair[0] == 252 { 250 } else { pair[0] + 1 }, "ids: {:?}", ids);
+        }
+        assert_eq!(radio.packet_id(), Some(if ids[4] == 252 { 250 } else { ids[4] + 1 }));
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, anyhow};
+use log::{error, info};
+
+use crate::clock;
+use crate::packet::{Packet, PacketPayload, ShowPacket, parse_hex};
+use crate::radio::Radio;
+
+///
+/// A performance recording is every packet the transmitter sent while recording, with
+/// when it went out, so the performance can be played back later without the show, the
+/// band or any midi (eg as an attract mode at the banquet). It's written as CSV, the
+/// packets in hex as --decode takes them, a record at a time so a crash loses nothing
+///
+
+/// the longest a playback sleeps at once, so it notices being stopped
+const MAX_SLEEP: Duration = Duration::from_millis(100);
+/// the pause between passes when playback loops
+const LOOP_GAP: Duration = Duration::from_secs(2);
+
+pub struct PacketRecorder {
+    writer: csv::Writer<File>,
+    started: Instant
+}
+
+impl PacketRecorder {
+
+    /// start a recording at the path, replacing anything already there
+    pub fn create(path: &str) -> Result<PacketRecorder> {
+        let mut writer = csv::Writer::from_path(path)
+            .with_context(|| format!("Could not create recording: {}", path))?;
+        writer.write_record(["millis", "packet"])?;
+        writer.flush()?;
+        Ok(PacketRecorder { writer, started: clock::now() })
+    }
+
+    /// record a packet that has just been sent. failures are logged rather than
+    /// returned, a recording mustn't stop the show
+    pub fn record(self: &mut Self, marshalled: &[u8]) {
+        let millis = (clock::now() - self.started).as_millis().to_string();
+        let hex: String = marshalled.iter().map(|b| format!("{:02x}", b)).collect();
+        if let Err(e) = self.writer.write_record([millis.as_str(), hex.as_str()])
+                .and_then(|_| Ok(self.writer.flush()?)) {
+            error!("Could not write to recording: {}", e);
+        }
+    }
+}
+
+/// read a recording, as packets with their offsets from the start
+pub fn load(path: &Path) -> Result<Vec<(Duration, Vec<u8>)>> {
+    let mut reader = csv::Reader::from_path(path)
+        .with_context(|| format!("Could not open recording: {}", path.display()))?;
+    let mut packets = vec![];
+    for (line, record) in reader.records().enumerate() {
+        let record = record?;
+        let parse = || -> Result<(Duration, Vec<u8>)> {
+            let millis: u64 = record.get(0).ok_or_else(|| anyhow!("missing time"))?.parse()?;
+            let packet = parse_hex(record.get(1).ok_or_else(|| anyhow!("missing packet"))?)?;
+            Ok((Duration::from_millis(millis), packet))
+        };
+        // the header is line 1
+        packets.push(parse().with_context(|| format!("Bad record on line {} of recording", line + 2))?);
+    }
+    Ok(packets)
+}
+
+/// play a recording back, speed times as fast as it was recorded, once or over and
+/// over until stopped. packets get fresh packet ids, so receivers don't take them
+/// for duplicates of what they heard when it was recorded
+pub fn play(radio: &Radio, packets: &[(Duration, Vec<u8>)], speed: f32, repeat: bool, stop: &AtomicBool) -> Result<()> {
+    if speed.is_nan() || speed <= 0.0 {
+        return Err(anyhow!("The playback speed must be more than zero"))
+    }
+    let length = packets.last().map_or(Duration::ZERO, |(at, _)| at.div_f32(speed));
+    info!("Playing {} packets over {:.0}s{}", packets.len(), length.as_secs_f32(), if repeat { ", looping" } else { "" });
+    'passes: loop {
+        let start = clock::now();
+        for (at, packet) in packets.iter() {
+            let due = start + at.div_f32(speed);
+            while clock::now() < due {
+                if stop.load(Ordering::SeqCst) {
+                    break 'passes
+                }
+                sleep(MAX_SLEEP.min(due - clock::now()));
+            }
+            radio.send_marshalled(&mut packet.clone())?;
+        }
+        if !repeat || stop.load(Ordering::SeqCst) {
+            break
+        }
+        sleep(LOOP_GAP);
+    }
+    // whatever was showing when playback stopped goes off
+    radio.send(&Packet { recipients: &vec![], payload: PacketPayload::Show(ShowPacket::OFF_PACKET) })?;
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::thread;
+use std::time::Instant;
+use anyhow::{Context, Result, anyhow, bail};
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::fastlane::MidiRouter;
+
+///
+/// RTP-MIDI (AppleMIDI) lets a DAW on a laptop send MIDI to the transmitter over the
+/// network instead of a cable. A session uses a pair of UDP ports: the control port
+/// for the session itself (invitations, goodbyes) and the one after it for the MIDI,
+/// carried in RTP packets, and for clock synchronization. Whoever initiates a session
+/// is accepted; the MIDI arriving goes to the director like MIDI from the midi port.
+/// Sessions aren't advertised over Bonjour, so add the transmitter to the DAW's
+/// network session by address and port. Recovery journals are ignored, so a lost
+/// packet's MIDI is lost
+///
+
+const DEFAULT_PORT: u16 = 5004;
+const DEFAULT_BIND: &str = "0.0.0.0";
+const PROTOCOL_VERSION: u32 = 2;
+const MAX_DATAGRAM: usize = 1500;
+
+/// the signature that starts every session packet
+const SIGNATURE: [u8; 2] = [0xFF, 0xFF];
+const INVITATION: &[u8; 2] = b"IN";
+const ACCEPT: &[u8; 2] = b"OK";
+const END_SESSION: &[u8; 2] = b"BY";
+const CLOCK_SYNC: &[u8; 2] = b"CK";
+const RECEIVER_FEEDBACK: &[u8; 2] = b"RS";
+
+/// the RTP payload type of MIDI
+const MIDI_PAYLOAD_TYPE: u8 = 0x61;
+const RTP_HEADER_LEN: usize = 12;
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct RtpMidiConfig {
+    /// the control port to listen on (below 65535), the MIDI arriving on the one after it. defaults to 5004
+    pub port: Option<u16>,
+    /// the local address to listen on, defaults to every interface
+    pub bind: Option<String>,
+    /// the session name shown in the DAW, defaults to the midi client name
+    pub name: Option<String>
+}
+
+impl RtpMidiConfig {
+    /// the MIDI port is the one after the control port, so there has to be one
+    pub fn validate(self: &Self) -> Result<()> {
+        if self.port == Some(u16::MAX) {
+            bail!("rtp_midi port can't be {}, the MIDI arrives on the port after it", u16::MAX)
+        }
+        Ok(())
+    }
+}
+
+/// what both ports need to answer the initiator
+struct Session {
+    name: String,
+    ssrc: u32,
+    started: Instant,
+    router: MidiRouter
+}
+
+/// listen for sessions on the configured ports, forwarding MIDI to the director
+pub fn start(config: &RtpMidiConfig, client_name: &str, router: MidiRouter) -> Result<()> {
+    let bind = config.bind.as_deref().unwrap_or(DEFAULT_BIND);
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+    let control = UdpSocket::bind((bind, port)).with_context(|| format!("Could not listen for RTP-MIDI on {}:{}", bind, port))?;
+    let data = UdpSocket::bind((bind, port + 1)).with_context(|| format!("Could not listen for RTP-MIDI on {}:{}", bind, port + 1))?;
+    let name = config.name.clone().unwrap_or_else(|| client_name.to_string());
+    info!("Listening for RTP-MIDI sessions as: {} on {}:{}", name, bind, port);
+    for (socket, is_data) in [(control, false), (data, true)] {
+        let session = Session { name: name.clone(), ssrc: rand::random(), started: Instant::now(), router: router.clone() };
+        thread::spawn(move || {
+            let mut buf = [0u8; MAX_DATAGRAM];
+            loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((len, from)) => if let Err(e) = session.handle(&socket, &buf[..len], from, is_data) {
+                        warn!("Bad RTP-MIDI packet from {}: {}", from, e);
+                    },
+                    Err(e) => {
+                        error!("Could not receive RTP-MIDI, closing the port. Error: {}", e);
+                        return
+                    }
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+impl Session {
+
+    fn handle(self: &Self, socket: &UdpSocket, packet: &[u8], from: SocketAddr, is_data: bool) -> Result<()> {
+        match packet {
+            [0xFF, 0xFF, a, b, body @ ..] => self.handle_session(socket, &[*a, *b], body, from),
+            _ if is_data => self.handle_midi(packet),
+            _ => bail!("not a session packet")
+        }
+    }
+
+    /// answer invitations and clock syncs, note goodbyes, ignore feedback
+    fn handle_session(self: &Self, socket: &UdpSocket, command: &[u8; 2], body: &[u8], from: SocketAddr) -> Result<()> {
+        match command {
+            INVITATION => {
+                let [_, _, _, _, t0, t1, t2, t3, _, _, _, _, name @ ..] = body else { bail!("short invitation") };
+                let initiator = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap_or_default()).to_string();
+                info!("accepting RTP-MIDI session from: {} at {}", initiator, from);
+                let mut reply = SIGNATURE.to_vec();
+                reply.extend(ACCEPT);
+                reply.extend(PROTOCOL_VERSION.to_be_bytes());
+                reply.extend([*t0, *t1, *t2, *t3]);
+                reply.extend(self.ssrc.to_be_bytes());
+                reply.extend(self.name.as_bytes());
+                reply.push(0);
+                socket.send_to(&reply, from)?;
+            },
+            CLOCK_SYNC => {
+                let [_, _, _, _, count, _, _, _, timestamps @ ..] = body else { bail!("short clock sync") };
+                if timestamps.len() < 24 {
+                    bail!("short clock sync")
+                }
+                // the initiator sends count 0, we answer with 1, and it finishes with 2
+                if *count == 0 {
+                    let mut reply = SIGNATURE.to_vec();
+                    reply.extend(CLOCK_SYNC);
+                    reply.extend(self.ssrc.to_be_bytes());
+                    reply.extend([1, 0, 0, 0]);
+                    reply.extend(&timestamps[..8]);
+                    reply.extend(self.now().to_be_bytes());
+                    reply.extend([0; 8]);
+                    socket.send_to(&reply, from)?;
+                }
+            },
+            END_SESSION => info!("RTP-MIDI session from {} ended", from),
+            RECEIVER_FEEDBACK => {},
+            _ => debug!("ignoring RTP-MIDI session command: {}", String::from_utf8_lossy(command))
+        }
+        Ok(())
+    }
+
+    /// pass on the MIDI in an RTP packet
+    fn handle_midi(self: &Self, packet: &[u8]) -> Result<()> {
+        if packet.len() < RTP_HEADER_LEN + 1 || packet[0] >> 6 != 2 || packet[1] & 0x7F != MIDI_PAYLOAD_TYPE {
+            bail!("not an RTP-MIDI packet")
+        }
+        let timestamp = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        for message in parse_command_section(&packet[RTP_HEADER_LEN..])? {
+            debug!("RTP-MIDI message: {:02x?}", message);
+            // timestamps count tenths of milliseconds, midir's microseconds
+            self.router.route(timestamp as u64 * 100, &message)?;
+        }
+        Ok(())
+    }
+
+    /// our clock for clock syncs, in tenths of milliseconds
+    fn now(self: &Self) -> u64 {
+        (self.started.elapsed().as_micros() / 100) as u64
+    }
+}
+
+/// the MIDI messages in an RTP-MIDI command section: a header giving its length, then
+/// the messages with delta times between them, which may use running status
+fn parse_command_section(section: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let flags = section[0];
+    let long_header = flags & 0x80 != 0;
+    let first_has_delta = flags & 0x20 != 0;
+    let (len, start) = if long_header {
+        let low = *section.get(1).ok_or_else(|| anyhow!("short command section"))?;
+        ((((flags & 0x0F) as usize) << 8) | low as usize, 2)
+    } else {
+        ((flags & 0x0F) as usize, 1)
+    };
+    let list = section.get(start..start + len).ok_or_else(|| anyhow!("command section longer than the packet"))?;
+
+    let mut messages = vec![];
+    let mut running_status: Option<u8> = None;
+    let mut i = 0;
+    let mut first = true;
+    while i < list.len() {
+        if !first || first_has_delta {
+            // a delta time of up to four bytes, the last without its top bit set
+            while i < list.len() && list[i] & 0x80 != 0 {
+                i += 1;
+            }
+            i += 1;
+            if i >= list.len() {
+                break
+            }
+        }
+        first = false;
+        let status = if list[i] & 0x80 != 0 {
+            i += 1;
+            list[i - 1]
+        } else {
+            running_status.ok_or_else(|| anyhow!("running status with no status"))?
+        };
+        let data_len = match status {
+            0x80..=0xBF | 0xE0..=0xEF | 0xF2 => 2,
+            0xC0..=0xDF | 0xF1 | 0xF3 => 1,
+            0xF0 => {
+                // only whole sysex messages are passed on, not segments of one
+                let end = list[i..].iter().position(|b| *b & 0x80 != 0).map(|p| i + p)
+                    .ok_or_else(|| anyhow!("unterminated sysex"))?;
+                if list[end] == 0xF7 {
+                    let mut message = vec![0xF0];
+                    message.extend(&list[i..=end]);
+                    messages.push(message);
+                } else {
+                    debug!("ignoring segmented sysex");
+                }
+                running_status = None;
+                i = end + 1;
+                continue
+            },
+            _ => 0
+        };
+        if status < 0xF0 {
+            running_status = Some(status);
+        } else if status < 0xF8 {
+            running_status = None;
+        }
+        let data = list.get(i..i + data_len).ok_or_else(|| anyhow!("truncated message"))?;
+        let mut message = vec![status];
+        message.extend(data);
+        messages.push(message);
+        i += data_len;
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_status_carries_over_to_later_messages() {
+        // no delta time before the first message, a zero one before the second
+        assert_eq!(parse_command_section(&[0x06, 0x90, 60, 100, 0x00, 62, 100]).unwrap(),
+            vec![vec![0x90, 60, 100], vec![0x90, 62, 100]]);
+        assert!(parse_command_section(&[0x02, 60, 100]).is_err());
+    }
+
+    #[test]
+    fn delta_times_are_skipped_whatever_their_length() {
+        // the Z flag puts a delta time before the first message too, this one two bytes long
+        assert_eq!(parse_command_section(&[0x28, 0x81, 0x00, 0xB0, 7, 127, 0x05, 0xC0, 5]).unwrap(),
+            vec![vec![0xB0, 7, 127], vec![0xC0, 5]]);
+        // the long header has a twelve bit length
+        assert_eq!(parse_command_section(&[0x80, 0x03, 0xC0, 5, 0x00]).unwrap(), vec![vec![0xC0, 5]]);
+    }
+
+    #[test]
+    fn whole_sysex_is_passed_on_and_segments_are_not() {
+        assert_eq!(parse_command_section(&[0x0A, 0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7, 0x00, 0x90, 60, 100]).unwrap(),
+            vec![vec![0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7], vec![0x90, 60, 100]]);
+        // the first segment of a sysex ends with F0 rather than F7
+        assert_eq!(parse_command_section(&[0x08, 0xF0, 0x01, 0x02, 0xF0, 0x00, 0xC0, 5, 0x00]).unwrap(),
+            vec![vec![0xC0, 5]]);
+        // and sysex cancels running status
+        assert!(parse_command_section(&[0x0A, 0x90, 60, 100, 0x00, 0xF0, 0x01, 0xF7, 0x00, 62, 100]).is_err());
+    }
+
+    #[test]
+    fn sections_longer_than_the_packet_are_refused() {
+        assert!(parse_command_section(&[0x06, 0x90, 60, 100]).is_err());
+        assert!(parse_command_section(&[0x80]).is_err());
+        assert!(parse_command_section(&[0x03, 0x90, 60]).is_err());
+    }
+
+    #[test]
+    fn the_control_port_needs_a_port_after_it() {
+        assert!(RtpMidiConfig { port: Some(5004), ..Default::default() }.validate().is_ok());
+        assert!(RtpMidiConfig { port: Some(u16::MAX), ..Default::default() }.validate().is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::thread;
+use anyhow::{Context, Result, bail};
+use crossbeam_channel::Sender;
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+
+use crate::director::DirectorMessage;
+
+///
+/// The sACN (E1.31) bridge lets a conventional lighting console run the receivers
+/// alongside its regular fixtures. DMX data for the universes arriving on the sACN port
+/// (multicast, for the universes joined, or unicast to the transmitter) goes to the
+/// director, which sends the receivers the show patches into them their color whenever
+/// it changes. Preview data and other start codes are ignored, as is the sending
+/// source's priority, so only one console should send each universe
+///
+
+const SACN_PORT: u16 = 5568;
+const DEFAULT_BIND: &str = "0.0.0.0";
+const MAX_DATAGRAM: usize = 638;
+
+/// the start of every E1.31 packet: preamble size, postamble size and ACN packet identifier
+const ACN_HEADER: &[u8; 16] = b"\x00\x10\x00\x00ASC-E1.17\x00\x00\x00";
+const ROOT_VECTOR_DATA: u32 = 0x04;
+const FRAMING_VECTOR_DATA: u32 = 0x02;
+const DMP_VECTOR_SET_PROPERTY: u8 = 0x02;
+/// options bits of the framing layer
+const PREVIEW_DATA: u8 = 0x80;
+const STREAM_TERMINATED: u8 = 0x40;
+/// the start code of plain DMX levels
+const DMX_START_CODE: u8 = 0x00;
+
+/// offsets into an E1.31 data packet
+const ROOT_VECTOR: usize = 18;
+const FRAMING_VECTOR: usize = 40;
+const OPTIONS: usize = 112;
+const UNIVERSE: usize = 113;
+const DMP_VECTOR: usize = 117;
+const PROPERTY_COUNT: usize = 123;
+const START_CODE: usize = 125;
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct SacnConfig {
+    /// universes to join the multicast groups of, unicast sACN arrives whatever
+    pub universes: Option<Vec<u16>>,
+    /// the local address to listen on, defaults to every interface
+    pub bind: Option<String>
+}
+
+/// listen for sACN, passing the levels of each universe to the director
+pub fn start(config: &SacnConfig, tx: Sender<DirectorMessage>) -> Result<()> {
+    let bind = config.bind.as_deref().unwrap_or(DEFAULT_BIND);
+    let socket = UdpSocket::bind((bind, SACN_PORT)).with_context(|| format!("Could not listen for sACN on {}:{}", bind, SACN_PORT))?;
+    for universe in config.universes.iter().flatten() {
+        // universe n is multicast on 239.255.(n high byte).(n low byte)
+        let [high, low] = universe.to_be_bytes();
+        socket.join_multicast_v4(&Ipv4Addr::new(239, 255, high, low), &Ipv4Addr::UNSPECIFIED)
+            .with_context(|| format!("Could not join the multicast group of sACN universe: {}", universe))?;
+    }
+    info!("Listening for sACN on {}:{}", bind, SACN_PORT);
+    thread::spawn(move || {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((len, from)) => match parse_data_packet(&buf[..len]) {
+                    Ok(Some((universe, levels))) => if tx.send(DirectorMessage::Dmx { universe, levels }).is_err() {
+                        return
+                    },
+                    Ok(None) => {},
+                    Err(e) => warn!("Bad sACN packet from {}: {}", from, e)
+                },
+                Err(e) => {
+                    error!("Could not receive sACN, closing the port. Error: {}", e);
+                    return
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// the universe and levels (channel 1 first) of a data packet, or none for data to
+/// ignore. a terminated stream has no levels, so its receivers go dark
+fn parse_data_packet(packet: &[u8]) -> Result<Option<(u16, Vec<u8>)>> {
+    if packet.len() < FRAMING_VECTOR + 4 || !packet.starts_with(ACN_HEADER) {
+        bail!("not an E1.31 packet")
+    }
+    let vector = |at: usize| u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]);
+    if vector(ROOT_VECTOR) != ROOT_VECTOR_DATA || vector(FRAMING_VECTOR) != FRAMING_VECTOR_DATA {
+        // sync and universe discovery packets, which are shorter than data
+        return Ok(None)
+    }
+    if packet.len() <= START_CODE {
+        bail!("E1.31 data packet too short: {} bytes", packet.len())
+    }
+    if packet[DMP_VECTOR] != DMP_VECTOR_SET_PROPERTY {
+        bail!("unexpected DMP vector: {}", packet[DMP_VECTOR])
+    }
+    let universe = u16::from_be_bytes([packet[UNIVERSE], packet[UNIVERSE + 1]]);
+    let options = packet[OPTIONS];
+    if options & STREAM_TERMINATED != 0 {
+        info!("sACN source stopped sending universe: {}", universe);
+        return Ok(Some((universe, vec![])))
+    }
+    if options & PREVIEW_DATA != 0 || packet[START_CODE] != DMX_START_CODE {
+        debug!("ignoring sACN preview or alternate start code data for universe: {}", universe);
+        return Ok(None)
+    }
+    // the property count includes the start code
+    let count = u16::from_be_bytes([packet[PROPERTY_COUNT], packet[PROPERTY_COUNT + 1]]) as usize;
+    let Some(levels) = packet.get(START_CODE + 1..START_CODE + count.max(1)) else {
+        bail!("property count: {} longer than the packet", count)
+    };
+    Ok(Some((universe, levels.to_vec())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_VECTOR_EXTENDED: u32 = 0x08;
+    const SOURCE_NAME: &[u8] = b"console";
+
+    /// the flags and length of a layer running from at to the end of the packet
+    fn poke_length(packet: &mut [u8], at: usize) {
+        let length = 0x7000 | (packet.len() - at) as u16;
+        packet[at..at + 2].copy_from_slice(&length.to_be_bytes());
+    }
+
+    /// an E1.31 data packet as a console sends it
+    fn data_packet(universe: u16, options: u8, start_code: u8, levels: &[u8]) -> Vec<u8> {
+        let mut packet = ACN_HEADER.to_vec();
+        packet.extend([0, 0]); // root flags and length
+        packet.extend(ROOT_VECTOR_DATA.to_be_bytes());
+        packet.extend([0xC1; 16]); // CID
+        packet.extend([0, 0]); // framing flags and length
+        packet.extend(FRAMING_VECTOR_DATA.to_be_bytes());
+        packet.extend(SOURCE_NAME);
+        packet.resize(108, 0);
+        packet.extend([100, 0, 0, 7, options]); // priority, sync address, sequence, options
+        packet.extend(universe.to_be_bytes());
+        packet.extend([0, 0]); // DMP flags and length
+        packet.extend([DMP_VECTOR_SET_PROPERTY, 0xA1, 0, 0, 0, 1]);
+        packet.extend((levels.len() as u16 + 1).to_be_bytes());
+        packet.push(start_code);
+        packet.extend(levels);
+        for at in [16, 38, 115] {
+            poke_length(&mut packet, at);
+        }
+        packet
+    }
+
+    /// an E1.31 extended packet: synchronization (vector 1) or universe discovery (vector 2)
+    fn extended_packet(framing_vector: u32) -> Vec<u8> {
+        let mut packet = ACN_HEADER.to_vec();
+        packet.extend([0, 0]);
+        packet.extend(ROOT_VECTOR_EXTENDED.to_be_bytes());
+        packet.extend([0xC1; 16]);
+        packet.extend([0, 0]);
+        packet.extend(framing_vector.to_be_bytes());
+        if framing_vector == 1 {
+            packet.extend([7, 0, 1, 0, 0]); // sequence, sync address, reserved
+        } else {
+            packet.extend(SOURCE_NAME);
+            packet.resize(112, 0);
+            packet.extend([0x70, 0x0A, 0, 0, 0, 1, 0, 0]); // discovery layer, page 0 of 0
+            packet.extend(1u16.to_be_bytes());
+        }
+        for at in [16, 38] {
+            poke_length(&mut packet, at);
+        }
+        packet
+    }
+
+    #[test]
+    fn data_packets_give_their_universe_and_levels() {
+        let packet = data_packet(3, 0, DMX_START_CODE, &[1, 2, 3]);
+        assert_eq!(parse_data_packet(&packet).unwrap(), Some((3, vec![1, 2, 3])));
+        let packet = data_packet(0x1234, 0, DMX_START_CODE, &[255; 512]);
+        assert_eq!(parse_data_packet(&packet).unwrap(), Some((0x1234, vec![255; 512])));
+        // a count of just the start code carries no levels
+        assert_eq!(parse_data_packet(&data_packet(3, 0, DMX_START_CODE, &[])).unwrap(), Some((3, vec![])));
+    }
+
+    #[test]
+    fn a_terminated_stream_has_no_levels() {
+        let packet = data_packet(3, STREAM_TERMINATED, DMX_START_CODE, &[1, 2, 3]);
+        assert_eq!(parse_data_packet(&packet).unwrap(), Some((3, vec![])));
+        // however it's marked otherwise
+        let packet = data_packet(3, STREAM_TERMINATED | PREVIEW_DATA, 0xDD, &[1, 2, 3]);
+        assert_eq!(parse_data_packet(&packet).unwrap(), Some((3, vec![])));
+    }
+
+    #[test]
+    fn preview_data_and_other_start_codes_are_ignored() {
+        assert_eq!(parse_data_packet(&data_packet(3, PREVIEW_DATA, DMX_START_CODE, &[1, 2, 3])).unwrap(), None);
+        // per-address priority
+        assert_eq!(parse_data_packet(&data_packet(3, 0, 0xDD, &[100; 3])).unwrap(), None);
+    }
+
+    #[test]
+    fn a_property_count_past_the_end_is_an_error() {
+        let mut packet = data_packet(3, 0, DMX_START_CODE, &[1, 2, 3]);
+        packet.truncate(packet.len() - 1);
+        let error = parse_data_packet(&packet).unwrap_err().to_string();
+        assert_eq!(error, "property count: 4 longer than the packet");
+        // nor do trailing bytes past the count become levels
+        let mut packet = data_packet(3, 0, DMX_START_CODE, &[1, 2, 3]);
+        packet.extend([4, 5]);
+        assert_eq!(parse_data_packet(&packet).unwrap(), Some((3, vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn sync_and_discovery_packets_are_ignored() {
+        let sync = extended_packet(1);
+        assert_eq!(sync.len(), 49);
+        assert_eq!(parse_data_packet(&sync).unwrap(), None);
+        assert_eq!(parse_data_packet(&extended_packet(2)).unwrap(), None);
+    }
+
+    #[test]
+    fn anything_else_is_an_error() {
+        assert!(parse_data_packet(b"Art-Net\0").is_err());
+        assert!(parse_data_packet(&ACN_HEADER[..]).is_err());
+        let packet = data_packet(3, 0, DMX_START_CODE, &[1, 2, 3]);
+        assert!(parse_data_packet(&packet[..START_CODE]).is_err());
+        let mut packet = packet;
+        packet[DMP_VECTOR] = 0x01;
+        assert!(parse_data_packet(&packet).is_err());
+    }
+}
@@ -0,0 +1,480 @@
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result, anyhow, bail};
+use serde_json::{Value, json};
+
+use crate::arbitration::ControlSource;
+use crate::clock;
+use crate::config::ConfigFile;
+use crate::director::{Director, DirectorMessage};
+use crate::packet::{Command, DecodedPacket, Packet, PacketPayload, CURRENT_FIRMWARE};
+use crate::radio::{MockRadio, Radio};
+use crate::show::{Color, ShowDefinition, parse_note};
+use crate::showfile;
+use crate::showstate::TagOperation;
+
+///
+/// A small scripting language for driving the director end to end in tests. A script
+/// feeds timed inputs to a real director running on the virtual clock with a mock
+/// radio, then checks the packets it sent. One statement per line, # starts a comment:
+///
+///     set lights_out_window_open 5         override a config field (value is json)
+///     at 0.5s note_on C4 ch0 vel 100       midi input (channels numbered 0-15, as in the show file)
+///     at 1s note_off C4 ch0
+///     at 1s cc 64 127 ch15                 controller change
+///     at 1s bend -4096 ch0                 pitch bend (-8192 to 8191)
+///     at 1s pressure 64 ch0                channel aftertouch
+///     at 1s program 2 ch15                 program change (on the control channel, selects a song)
+///     at 2s sighup                         reload the show (also: shutdown, replay [window])
+///     at 2s load_show other.json           hand the director the show in another file to load
+///     at 2s mute front,back                mute groups from the console (unmute to clear)
+///     at 2s sleep [until_reset]            put receivers to sleep from the console (wake to wake them)
+///     at 2s tag strobe mute                mute the cues tagged strobe (also: unmute, reset, release 2s)
+///     at 2s query_status                   ask every receiver for its status from the console
+///     at 2s announce 3                     the radio hears receiver 3 announce itself on power up
+///     at 2s pollreply 3 rssi 40            the radio hears receiver 3 answer a link poll, heard at -40 dBm
+///     at 2s dmx 1 255,0,128,0              sACN levels for universe 1, from channel 1
+///     at 0s clock 90 for 2s                midi clock at 90 bpm (24 timing messages a beat) for 2 seconds
+///     at 0s start                          midi clock start (also: continue, stop)
+///     at 0s song_position 16               song position pointer, in sixteenths
+///     at 1s timecode 00:01:00:00           a full frame time code message (a locate), at 30 fps
+///     at 1s timecode 00:01:00:00 for 2s    time code running from there, in quarter frames
+///     expect 0.5s pop to 1,2               a packet sent at that time, by effect or command name,
+///                                          optionally to exactly these recipients (or "all")
+///     expect 0.5s pop color 0,255,128      optionally with exactly this color (h,s,v)
+///     expect 0.5s chase tempo 150          optionally at exactly this tempo
+///     expect 0.5s chase param2 1           optionally with exactly this effect parameter (eg a chase's direction)
+///     expect 0.5s midi 90,3c,7f            midi sent back (pad setup or feedback), as hex bytes
+///     expect nothing 1s..4s                no packets at all in the window
+///     expect nothing 1s..4s to 2           no packets to exactly these recipients in the window
+///
+/// Times are seconds ("0.5s", "2") or milliseconds ("500ms") from the start of the show.
+/// The director is shut down a second after the last time the script mentions
+///
+
+/// how far from the expected time a packet may be sent and still match
+const TOLERANCE: Duration = Duration::from_millis(5);
+
+/// a config suitable for tests: no pacing of configuration packets, and a lights-out
+/// window that opens five seconds after the last effect
+fn base_config() -> Value {
+    json!({
+        "spi_device": "/dev/null",
+        "gpio_device": "/dev/null",
+        "reset_line": 0,
+        "frequency": 915,
+        "transmitter_id": 1,
+        "transmitter_power": 0,
+        "config_packet_spacing_millis": 0,
+        "config_chunk_pause_millis": 0,
+        "midi_client_name": "scenario",
+        "midi_control_channel": 15,
+        "show_file": "",
+        "lights_out_window_open": 5.0,
+        "lights_out_window_close": 60.0,
+        "lights_out_period": 2.0
+    })
+}
+
+enum Input {
+    Midi(Vec<u8>),
+    Reload,
+    /// a show loaded ahead of time, as the show file watcher hands it over
+    LoadShow(Box<ShowDefinition>),
+    Shutdown,
+    Replay(Option<Duration>),
+    Mute(Vec<String>),
+    Sleep(bool),
+    Wake,
+    Blackout,
+    /// mute, unmute or reset the cues with a tag
+    Tag(String, TagOperation),
+    /// a cue fired (or released) by name
+    Cue(String, bool),
+    /// a clip started (or stopped) by name
+    Clip(String, bool),
+    /// the grand master set, in percent
+    GrandMaster(u8),
+    /// the levels of a DMX universe, from channel 1
+    Dmx(u16, Vec<u8>),
+    /// every receiver asked for its status from the console
+    QueryStatus,
+    /// a receiver announcing itself, heard by the radio rather than sent to the director
+    Announce(u8),
+    /// a receiver answering a link poll, with how strongly it heard it (as -dBm), heard by the radio too
+    PollReply(u8, u8)
+}
+
+enum Expectation {
+    Packet { line: usize, at: Duration, name: String, to: Option<Vec<u8>>, color: Option<Color>, tempo: Option<u8>, param2: Option<u8> },
+    Midi { line: usize, at: Duration, bytes: Vec<u8> },
+    Nothing { line: usize, window: Range<Duration>, to: Option<Vec<u8>> }
+}
+
+pub struct Scenario {
+    config: Value,
+    inputs: Vec<(Duration, Input)>,
+    expectations: Vec<Expectation>
+}
+
+/// a packet the director sent, as the scenario sees it
+struct Sent {
+    at: Duration,
+    name: String,
+    to: Vec<u8>,
+    /// the color, tempo and second effect parameter of show packets
+    color: Option<Color>,
+    tempo: Option<u8>,
+    param2: Option<u8>
+}
+
+/// midi the director sent back, and when
+type SentMidi = (Duration, Vec<u8>);
+
+fn parse_time(s: &str) -> Result<Duration> {
+    let seconds = if let Some(millis) = s.strip_suffix("ms") {
+        millis.parse::<f64>()? / 1000.0
+    } else {
+        s.strip_suffix('s').unwrap_or(s).parse::<f64>()?
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_channel(s: &str) -> Result<u8> {
+    let channel: u8 = s.strip_prefix("ch").ok_or_else(|| anyhow!("Expected a channel like ch0, found {}", s))?.parse()?;
+    if channel > 15 {
+        bail!("Channel {} out of range", channel);
+    }
+    Ok(channel)
+}
+
+/// midi bytes as expectations write them
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<String>>().join(",")
+}
+
+/// the name expectations use for a packet: the effect, or the command
+fn packet_name(payload: &PacketPayload) -> String {
+    match payload {
+        PacketPayload::Show(show) => format!("{:?}", show.effect).to_lowercase(),
+        PacketPayload::Control(command) => match command {
+            Command::SetGroup { .. } => "setgroup",
+            Command::SetLedCount { .. } => "ledcount",
+            Command::NewBrightness { .. } => "brightness",
+            Command::NewTempo { .. } => "tempo",
+            Command::Heartbeat { .. } => "heartbeat",
+            Command::SetPreset { .. } => "preset",
+            Command::Sleep { .. } => "sleep",
+            Command::Poll => "poll",
+            Command::PollReply { .. } => "pollreply",
+            Command::QueryStatus => "querystatus",
+            Command::StatusReply { .. } => "statusreply",
+            Command::Announce { .. } => "announce",
+            Command::Reset => "reset"
+        }.to_string()
+    }
+}
+
+/// a list of recipients like 1,2, or "all" for none (a broadcast)
+fn parse_recipients(list: &str) -> Result<Vec<u8>> {
+    if list == "all" {
+        return Ok(vec![])
+    }
+    Ok(list.split(',').map(|r| r.parse::<u8>()).collect::<Result<Vec<u8>,_>>()?)
+}
+
+fn describe_to(to: &[u8]) -> String {
+    if to.is_empty() {
+        "all".to_string()
+    } else {
+        to.iter().map(|r| r.to_string()).collect::<Vec<_>>().join(",")
+    }
+}
+
+impl Scenario {
+
+    pub fn parse(script: &str) -> Result<Scenario> {
+        let mut scenario = Scenario { config: base_config(), inputs: vec![], expectations: vec![] };
+        for (index, line) in script.lines().enumerate() {
+            let line_number = index + 1;
+            let words: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            scenario.parse_statement(line_number, &words).with_context(|| format!("Line {}: {}", line_number, line.trim()))?;
+        }
+        Ok(scenario)
+    }
+
+    fn parse_statement(self: &mut Self, line: usize, words: &[&str]) -> Result<()> {
+        match words {
+            [] => {},
+            ["set", key, value @ ..] => {
+                self.config[*key] = serde_json::from_str(&value.join(" "))?;
+            },
+            ["at", time, "clock", bpm, "for", length] => {
+                let at = parse_time(time)?;
+                let tick = Duration::from_secs_f64(60.0 / bpm.parse::<f64>()? / 24.0);
+                let end = at + parse_time(length)?;
+                let mut tick_at = at;
+                while tick_at < end {
+                    self.inputs.push((tick_at, Input::Midi(vec![0xF8])));
+                    tick_at += tick;
+                }
+            },
+            ["at", time, "timecode", position, rest @ ..] => {
+                let at = parse_time(time)?;
+                let fields: Vec<u32> = position.split(':').map(|f| f.parse()).collect::<Result<_, _>>()?;
+                let [hh, mm, ss, ff] = fields[..] else { bail!("Expected a time code like 00:01:02:03") };
+                match rest {
+                    [] => self.inputs.push((at, Input::Midi(vec![0xF0, 0x7F, 0x7F, 0x01, 0x01, 0x60 | hh as u8, mm as u8, ss as u8, ff as u8, 0xF7]))),
+                    ["for", length] => {
+                        // eight quarter frames spell a position, and take two frames to send
+                        let start = ((hh * 60 + mm) * 60 + ss) * 30 + ff;
+                        let quarter_frame = Duration::from_secs_f64(1.0 / 120.0);
+                        let count = (parse_time(length)?.as_secs_f64() * 120.0) as u32;
+                        for i in 0..count {
+                            let frame = start + i / 8 * 2;
+                            let (hh, mm, ss, ff) = (frame / 108_000, frame / 1800 % 60, frame / 30 % 60, frame % 30);
+                            let byte = [ff, ff, ss, ss, mm, mm, hh | 0x60, hh | 0x60][(i % 8) as usize];
+                            let nibble = if i % 2 == 0 { byte & 0x0F } else { byte >> 4 };
+                            self.inputs.push((at + quarter_frame * i, Input::Midi(vec![0xF1, ((i % 8) << 4 | nibble) as u8])));
+                        }
+                    },
+                    _ => bail!("Unexpected {:?} after timecode", rest)
+                }
+            },
+            ["at", time, input @ ..] => {
+                let at = parse_time(time)?;
+                let input = match input {
+                    ["note_on", note, channel, rest @ ..] => {
+                        let velocity = match rest {
+                            [] => 100,
+                            ["vel", velocity] => velocity.parse()?,
+                            _ => bail!("Unexpected {:?} after note_on", rest)
+                        };
+                        Input::Midi(vec![0x90 | parse_channel(channel)?, parse_note(note)?, velocity])
+                    },
+                    ["note_off", note, channel] => Input::Midi(vec![0x80 | parse_channel(channel)?, parse_note(note)?, 0]),
+                    ["cc", cc, value, channel] => Input::Midi(vec![0xB0 | parse_channel(channel)?, cc.parse()?, value.parse()?]),
+                    ["bend", bend, channel] => {
+                        let bend = (bend.parse::<i16>()? + 8192) as u16;
+                        Input::Midi(vec![0xE0 | parse_channel(channel)?, (bend & 0x7F) as u8, (bend >> 7) as u8])
+                    },
+                    ["pressure", pressure, channel] => Input::Midi(vec![0xD0 | parse_channel(channel)?, pressure.parse()?]),
+                    ["program", program, channel] => Input::Midi(vec![0xC0 | parse_channel(channel)?, program.parse()?]),
+                    ["start"] => Input::Midi(vec![0xFA]),
+                    ["continue"] => Input::Midi(vec![0xFB]),
+                    ["stop"] => Input::Midi(vec![0xFC]),
+                    ["song_position", sixteenths] => {
+                        let sixteenths: u16 = sixteenths.parse()?;
+                        Input::Midi(vec![0xF2, (sixteenths & 0x7F) as u8, (sixteenths >> 7) as u8])
+                    },
+                    ["sighup"] => Input::Reload,
+                    ["load_show", path] => Input::LoadShow(Box::new(showfile::load(Path::new(path))?)),
+                    ["shutdown"] => Input::Shutdown,
+                    ["replay"] => Input::Replay(None),
+                    ["replay", window] => Input::Replay(Some(parse_time(window)?)),
+                    ["mute", groups] => Input::Mute(groups.split(',').map(|g| g.to_string()).collect()),
+                    ["unmute"] => Input::Mute(vec![]),
+                    ["sleep"] => Input::Sleep(true),
+                    ["sleep", "until_reset"] => Input::Sleep(false),
+                    ["wake"] => Input::Wake,
+                    ["blackout"] => Input::Blackout,
+                    ["tag", tag, "mute"] => Input::Tag(tag.to_string(), TagOperation::Mute),
+                    ["tag", tag, "unmute"] => Input::Tag(tag.to_string(), TagOperation::Unmute),
+                    ["tag", tag, "release", release] =>
+                        Input::Tag(tag.to_string(), TagOperation::Adjust { attack: None, sustain: None, release: Some(parse_time(release)?.as_millis() as u32) }),
+                    ["tag", tag, "reset"] => Input::Tag(tag.to_string(), TagOperation::Reset),
+                    ["fire", cue @ ..] if !cue.is_empty() => Input::Cue(cue.join(" "), true),
+                    ["release", cue @ ..] if !cue.is_empty() => Input::Cue(cue.join(" "), false),
+                    ["start_clip", clip @ ..] if !clip.is_empty() => Input::Clip(clip.join(" "), true),
+                    ["stop_clip", clip @ ..] if !clip.is_empty() => Input::Clip(clip.join(" "), false),
+                    ["master", percent] => Input::GrandMaster(percent.parse()?),
+                    ["query_status"] => Input::QueryStatus,
+                    ["announce", receiver] => Input::Announce(receiver.parse()?),
+                    ["pollreply", receiver, "rssi", rssi] => Input::PollReply(receiver.parse()?, rssi.parse()?),
+                    ["dmx", universe, levels] => Input::Dmx(universe.parse()?,
+                        levels.split(',').map(|l| l.parse::<u8>()).collect::<Result<_,_>>()?),
+                    _ => bail!("Unknown input")
+                };
+                self.inputs.push((at, input));
+            },
+            ["expect", "nothing", window, rest @ ..] => {
+                let (start, end) = window.split_once("..").ok_or_else(|| anyhow!("Expected a window like 1s..2s"))?;
+                let to = match rest {
+                    [] => None,
+                    ["to", list] => Some(parse_recipients(list)?),
+                    _ => bail!("Unexpected {:?} after window", rest)
+                };
+                self.expectations.push(Expectation::Nothing { line, window: parse_time(start)?..parse_time(end)?, to });
+            },
+            ["expect", time, "midi", bytes] => {
+                let bytes = bytes.split(',').map(|b| u8::from_str_radix(b, 16)).collect::<Result<Vec<u8>,_>>()?;
+                self.expectations.push(Expectation::Midi { line, at: parse_time(time)?, bytes });
+            },
+            ["expect", time, name, rest @ ..] => {
+                let (mut to, mut color, mut tempo, mut param2) = (None, None, None, None);
+                for clause in rest.chunks(2) {
+                    match clause {
+                        ["to", list] => to = Some(parse_recipients(list)?),
+                        ["color", hsv] => {
+                            let hsv: Vec<u8> = hsv.split(',').map(|c| c.parse::<u8>()).collect::<Result<_,_>>()?;
+                            let [h, s, v] = hsv[..] else { bail!("Expected a color like 0,255,128") };
+                            color = Some(Color { h, s, v });
+                        },
+                        ["tempo", bpm] => tempo = Some(bpm.parse()?),
+                        ["param2", value] => param2 = Some(value.parse()?),
+                        _ => bail!("Unexpected {:?} after packet name", rest)
+                    }
+                }
+                self.expectations.push(Expectation::Packet { line, at: parse_time(time)?, name: name.to_lowercase(), to, color, tempo, param2 });
+            },
+            _ => bail!("Unknown statement")
+        }
+        Ok(())
+    }
+
+    /// the last time the script mentions
+    fn end(self: &Self) -> Duration {
+        let inputs = self.inputs.iter().map(|(at, _)| *at);
+        let expectations = self.expectations.iter().map(|e| match e {
+            Expectation::Packet { at, .. } | Expectation::Midi { at, .. } => *at,
+            Expectation::Nothing { window, .. } => window.end
+        });
+        inputs.chain(expectations).max().unwrap_or(Duration::ZERO)
+    }
+
+    /// run the director over the script with the given show json, returning the packets
+    /// it sent and the midi it sent back
+    fn perform(self: &Self, show: &str) -> Result<(Vec<Sent>, Vec<SentMidi>)> {
+        static RUN: AtomicUsize = AtomicUsize::new(0);
+        let show_path: PathBuf = std::env::temp_dir().join(format!("chs-scenario-{}-{}.json",
+            std::process::id(), RUN.fetch_add(1, Ordering::Relaxed)));
+        fs::write(&show_path, show)?;
+
+        let mut config = self.config.clone();
+        config["show_file"] = json!(show_path.to_string_lossy());
+        let config: ConfigFile = serde_json::from_value(config)?;
+        let header_mode = config.header_mode.unwrap_or_default();
+
+        let start = clock::start_virtual();
+        let mut heard: Vec<(Instant, Vec<u8>)> = self.inputs.iter().filter_map(|(at, input)| match input {
+            Input::Announce(receiver) => Some((start + *at, Packet {
+                recipients: &vec![],
+                payload: PacketPayload::Control(Command::Announce { receiver: *receiver, firmware: CURRENT_FIRMWARE })
+            }.marshal(header_mode, *receiver, 0, 0))),
+            Input::PollReply(receiver, rssi) => Some((start + *at, Packet {
+                recipients: &vec![config.transmitter_id],
+                payload: PacketPayload::Control(Command::PollReply { rssi: *rssi })
+            }.marshal(header_mode, *receiver, 0, 0))),
+            _ => None
+        }).collect();
+        heard.sort_by_key(|(at, _)| *at);
+        let mut script: Vec<(Instant, DirectorMessage)> = self.inputs.iter().filter_map(|(at, input)| {
+            let at = start + *at;
+            Some((at, match input {
+                Input::Midi(buf) => DirectorMessage::MidiMessage { ts: 0, buf: buf.clone(), received: at },
+                Input::Reload => DirectorMessage::Reload,
+                Input::LoadShow(show) => DirectorMessage::LoadShow(show.clone()),
+                Input::Shutdown => DirectorMessage::Shutdown,
+                Input::Replay(window) => DirectorMessage::Replay { window: *window },
+                Input::Mute(groups) => DirectorMessage::Mute { source: ControlSource::Console, groups: groups.clone() },
+                Input::Sleep(wake_on_packet) => DirectorMessage::Sleep { source: ControlSource::Console, wake_on_packet: *wake_on_packet },
+                Input::Wake => DirectorMessage::Wake { source: ControlSource::Console },
+                Input::Blackout => DirectorMessage::Blackout { source: ControlSource::Console },
+                Input::Tag(tag, operation) => DirectorMessage::Tag { source: ControlSource::Console, tag: tag.clone(), operation: operation.clone() },
+                Input::Cue(cue, on) => DirectorMessage::Cue { source: ControlSource::Console, cue: cue.clone(), on: *on, reply: None },
+                Input::Clip(clip, start) => DirectorMessage::Clip { source: ControlSource::Console, clip: clip.clone(), start: *start, reply: None },
+                Input::GrandMaster(percent) => DirectorMessage::GrandMaster { source: ControlSource::Console, percent: *percent },
+                Input::Dmx(universe, levels) => DirectorMessage::Dmx { universe: *universe, levels: levels.clone() },
+                Input::QueryStatus => DirectorMessage::QueryStatus { source: ControlSource::Console, reply: crossbeam_channel::bounded(1).0 },
+                Input::Announce(_) | Input::PollReply(..) => return None
+            }))
+        }).collect();
+        script.sort_by_key(|(at, _)| *at);
+        script.push((start + self.end() + Duration::from_secs(1), DirectorMessage::Shutdown));
+
+        let radio = Radio::new(&config, Box::new(MockRadio::hearing(heard)));
+        let mut director = Director::scripted(config, radio, script);
+        let result = director.run_show();
+        clock::stop_virtual();
+        fs::remove_file(&show_path)?;
+        result?;
+
+        let midi = director.take_midi_sent().into_iter().map(|(at, bytes)| (at - start, bytes)).collect();
+        let sent = director.radio().take_sent().into_iter().map(|(at, buf)| {
+            let packet = DecodedPacket::unmarshal(&buf, header_mode)?;
+            Ok(Sent {
+                at: at - start,
+                name: packet_name(&packet.payload),
+                to: if packet.to == 0xFF { packet.recipients } else { vec![packet.to] },
+                color: match &packet.payload {
+                    PacketPayload::Show(show) => Some(show.color),
+                    PacketPayload::Control(_) => None
+                },
+                tempo: match &packet.payload {
+                    PacketPayload::Show(show) => Some(show.tempo),
+                    PacketPayload::Control(_) => None
+                },
+                param2: match &packet.payload {
+                    PacketPayload::Show(show) => Some(show.param2),
+                    PacketPayload::Control(_) => None
+                }
+            })
+        }).collect::<Result<Vec<Sent>>>()?;
+        Ok((sent, midi))
+    }
+
+    /// run the script against the show json, failing with every unmet expectation
+    /// and a listing of what was actually sent
+    pub fn run(show: &str, script: &str) -> Result<()> {
+        let scenario = Scenario::parse(script)?;
+        let (sent, midi) = scenario.perform(show)?;
+        let mut claimed = vec![false; sent.len()];
+        let mut midi_claimed = vec![false; midi.len()];
+        let mut failures: Vec<String> = vec![];
+        for expectation in scenario.expectations.iter() {
+            match expectation {
+                Expectation::Packet { line, at, name, to, color, tempo, param2 } => {
+                    let found = sent.iter().enumerate().find(|(i, s)| !claimed[*i] &&
+                        s.at + TOLERANCE >= *at && s.at <= *at + TOLERANCE &&
+                        s.name == *name && to.as_ref().map_or(true, |to| *to == s.to) &&
+                        color.map_or(true, |color| Some(color) == s.color) &&
+                        tempo.map_or(true, |tempo| Some(tempo) == s.tempo) &&
+                        param2.map_or(true, |param2| Some(param2) == s.param2));
+                    match found {
+                        Some((i, _)) => claimed[i] = true,
+                        None => failures.push(format!("line {}: no {} at {:?}{}{}{}{}", line, name, at,
+                            to.as_ref().map_or(String::new(), |to| format!(" to {}", describe_to(to))),
+                            color.map_or(String::new(), |c| format!(" color {},{},{}", c.h, c.s, c.v)),
+                            tempo.map_or(String::new(), |t| format!(" tempo {}", t)),
+                            param2.map_or(String::new(), |p| format!(" param2 {}", p))))
+                    }
+                },
+                Expectation::Midi { line, at, bytes } => {
+                    let found = midi.iter().enumerate().find(|(i, (sent_at, sent))| !midi_claimed[*i] &&
+                        *sent_at + TOLERANCE >= *at && *sent_at <= *at + TOLERANCE && sent == bytes);
+                    match found {
+                        Some((i, _)) => midi_claimed[i] = true,
+                        None => failures.push(format!("line {}: no midi {} at {:?}", line, hex(bytes), at))
+                    }
+                },
+                Expectation::Nothing { line, window, to } => {
+                    for s in sent.iter().filter(|s| window.contains(&s.at) && to.as_ref().map_or(true, |to| *to == s.to)) {
+                        failures.push(format!("line {}: unexpected {} at {:?} to {}", line, s.name, s.at, describe_to(&s.to)));
+                    }
+                }
+            }
+        }
+        if !failures.is_empty() {
+            let listing: Vec<String> = sent.iter().map(|s| format!("  {:?} {} to {}{}{}{}", s.at, s.name, describe_to(&s.to),
+                s.color.map_or(String::new(), |c| format!(" color {},{},{}", c.h, c.s, c.v)),
+                s.tempo.map_or(String::new(), |t| format!(" tempo {}", t)),
+                s.param2.map_or(String::new(), |p| format!(" param2 {}", p)))).collect();
+            let midi_listing: Vec<String> = midi.iter().map(|(at, bytes)| format!("  {:?} midi {}", at, hex(bytes))).collect();
+            bail!("{}\nsent:\n{}\n{}", failures.join("\n"), listing.join("\n"), midi_listing.join("\n"));
+        }
+        Ok(())
+    }
+}
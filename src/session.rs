@@ -0,0 +1,57 @@
+use std::fs::OpenOptions;
+use std::fs::File;
+use std::sync::{Arc,Mutex};
+use anyhow::{Context,Result};
+use chrono::{Local,SecondsFormat};
+use log::error;
+
+///
+/// The session log is a record of everything a human did to the transmitter during
+/// a show (cues triggered, signals sent, console and API commands), with wall clock
+/// timestamps, written as CSV so staff can reconstruct what the operator actually
+/// did when a cue was missed. It's kept separate from the debug log on purpose
+///
+
+/// a cheaply cloneable handle to the session log, so every front end
+/// (midi, signals, consoles) can record its own actions
+#[derive(Clone)]
+pub struct SessionLog {
+    writer: Option<Arc<Mutex<csv::Writer<File>>>>
+}
+
+impl SessionLog {
+
+    /// open the session log at the given path, appending if it already
+    /// exists. with no path, actions are silently discarded
+    pub fn open(path: &Option<String>) -> Result<SessionLog> {
+        let writer = match path {
+            Some(path) => {
+                let file = OpenOptions::new().create(true).append(true).open(path)
+                    .with_context(|| format!("Could not open session log: {}", path))?;
+                let is_new = file.metadata()?.len() == 0;
+                let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(file);
+                if is_new {
+                    writer.write_record(["timestamp", "source", "action", "detail"])?;
+                    writer.flush()?;
+                }
+                Some(Arc::new(Mutex::new(writer)))
+            },
+            None => None
+        };
+        Ok(SessionLog { writer })
+    }
+
+    /// record an operator action. failures are logged rather than returned,
+    /// the show must go on even if the session log can't be written
+    pub fn record(self: &Self, source: &str, action: &str, detail: &str) {
+        if let Some(writer) = &self.writer {
+            let timestamp = Local::now().to_rfc3339_opts(SecondsFormat::Millis, false);
+            let mut writer = writer.lock().unwrap();
+            // flush every record so nothing is lost if the process dies
+            if let Err(e) = writer.write_record([timestamp.as_str(), source, action, detail])
+                    .and_then(|_| Ok(writer.flush()?)) {
+                error!("Could not write to session log: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use anyhow::{Result, anyhow};
+use log::info;
+
+use crate::check::clip_duration;
+use crate::clock;
+use crate::config::ConfigFile;
+use crate::logging;
+use crate::radio::Radio;
+use crate::show::{LightMapping, LightMappingType, ShowDefinition};
+use crate::showstate::{MutableShowState, ShowState};
+
+///
+/// A shadow run performs the whole show against a mock radio on a virtual clock: every
+/// cue of every song is fired, held for as long as it would play (clips to their end)
+/// and released, with the show ticking just as it would live, only faster than real
+/// time. Anything that goes wrong on the way (bad indices, unknown clips, packets that
+/// won't marshal) is reported, so a show edit can be checked unattended, eg from cron
+/// after every sync
+///
+
+/// the longest a cue is held for, so a long (or looping) clip doesn't hold up the run
+const MAX_HOLD: Duration = Duration::from_secs(600);
+/// how long a cue without a sustain or a clip is held for
+const DEFAULT_HOLD: Duration = Duration::from_secs(1);
+/// how long the show is left to settle after a cue is released
+const SETTLE: Duration = Duration::from_secs(1);
+/// the least the virtual clock moves per tick, so a tick that wants to run again
+/// immediately can't stall the run
+const MIN_STEP: Duration = Duration::from_millis(1);
+
+/// shadow run the show, reporting every problem found
+pub fn shadow_run(show: &ShowDefinition, config: &ConfigFile) -> Result<()> {
+    let started = Instant::now();
+    let virtual_start = clock::start_virtual();
+    logging::capture_errors();
+    let mut problems: Vec<String> = vec![];
+    let result = perform(show, config, &mut problems);
+    let logged = logging::take_errors();
+    let virtual_elapsed = clock::now() - virtual_start;
+    clock::stop_virtual();
+    let (cues, packets) = result?;
+    problems.extend(logged.into_iter().map(|e| format!("logged: {}", e)));
+
+    info!("Shadow ran {} cues, {:.0}s of show in {:.1}s, {} packets would have been sent",
+        cues, virtual_elapsed.as_secs_f32(), started.elapsed().as_secs_f32(), packets);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Show failed its shadow run:\n  {}", problems.join("\n  ")))
+    }
+}
+
+/// fire every cue in every song, returning how many cues were fired and packets sent
+fn perform(show: &ShowDefinition, config: &ConfigFile, problems: &mut Vec<String>) -> Result<(usize, usize)> {
+    let radio = Radio::mock(config);
+    let mut state = ShowState::new(show, &radio, config)?;
+    state.disable_hooks();
+    let mut mutable_state = state.create_mutable_state()?;
+    state.initialize()?;
+
+    let songs: Vec<Option<&String>> = match &show.songs {
+        Some(songs) if !songs.is_empty() => songs.iter().map(|s| Some(&s.name)).collect(),
+        _ => vec![None]
+    };
+    let mut cues = 0;
+    for song in songs {
+        if let Some(song) = song {
+            state.switch_song(song, &mut mutable_state)?;
+        }
+        // mappings sharing a cue name fire together, so each name is fired once
+        let mut fired: HashSet<&str> = HashSet::new();
+        for mapping in show.mappings.iter().filter(|m| song.is_none() || m.song.is_none() || m.song.as_ref() == song) {
+            if !fired.insert(&mapping.cue) {
+                continue
+            }
+            cues += 1;
+            let context = match song {
+                Some(song) => format!("song: {} cue: {}", song, mapping.cue),
+                None => format!("cue: {}", mapping.cue)
+            };
+            if let Err(e) = state.fire_cue(&mapping.cue, true, &mut mutable_state) {
+                problems.push(format!("{} failed to fire: {}", context, e));
+                continue
+            }
+            if let Err(e) = run_for(&state, hold_time(show, mapping), &mut mutable_state) {
+                problems.push(format!("{} failed while playing: {}", context, e));
+            }
+            if let Err(e) = state.fire_cue(&mapping.cue, false, &mut mutable_state) {
+                problems.push(format!("{} failed to release: {}", context, e));
+            }
+            if let Err(e) = run_for(&state, SETTLE, &mut mutable_state) {
+                problems.push(format!("{} failed after release: {}", context, e));
+            }
+        }
+    }
+    Ok((cues, radio.take_sent().len()))
+}
+
+/// how long the cue would be held live: a clip to its end, an effect for its sustain
+fn hold_time(show: &ShowDefinition, mapping: &LightMapping) -> Duration {
+    let hold = match &mapping.light {
+        LightMappingType::Clip(clip) => show.clips.get(clip).map_or(DEFAULT_HOLD, clip_duration),
+        _ => mapping.sustain.map_or(DEFAULT_HOLD, |s| Duration::from_millis(s as u64))
+    };
+    hold.clamp(MIN_STEP, MAX_HOLD)
+}
+
+/// tick the show until the virtual clock has moved on by the duration
+fn run_for(state: &ShowState, duration: Duration, mutable_state: &mut MutableShowState) -> Result<()> {
+    let deadline = clock::now() + duration;
+    while clock::now() < deadline {
+        let wait = state.tick(mutable_state)?;
+        let now = clock::now();
+        clock::advance_to((now + wait.max(MIN_STEP)).min(deadline));
+    }
+    Ok(())
+}
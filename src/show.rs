@@ -1,5 +1,7 @@
-use serde::Deserialize;
+use serde::{Deserialize,Serialize};
 use std::collections::HashMap;
+use std::time::Duration;
+use log::info;
 
 ///
 /// This module holds all the structs and functions that
@@ -8,20 +10,304 @@ use std::collections::HashMap;
 /// 
 
 
-/// this struct maps directly to the show JSON
-#[derive(Debug,Deserialize,Clone)]
+/// this struct maps directly to the show JSON. any part of it can be left out of a
+/// file that's included by others
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
 pub struct ShowDefinition {
     /// listing of receivers and their groups and LED counts
+    #[serde(default)]
     pub receivers: Vec<ReceiverConfiguration>,
 
-    /// named colors that can be associated by name with effects and clip effects
+    /// named colors that can be associated by name with effects and clip effects, each
+    /// given as h, s and v, as r, g and b, as "#RRGGBB" or as a CSS color name
+    #[serde(default)]
     pub colors: HashMap<String,Color>,
 
     /// associations between MIDI signals and effects or clips
+    #[serde(default)]
     pub mappings: Vec<LightMapping>,
 
     /// clip definitions
-    pub clips: HashMap<String,Vec<ClipStep>>
+    #[serde(default)]
+    pub clips: HashMap<String,Vec<ClipStep>>,
+
+    /// named effects with all their parameters, which mappings and clip steps can use
+    /// (changing any of them) rather than repeating them
+    pub effects: Option<HashMap<String,EffectPreset>>,
+
+    /// named rectangular arrangements of receivers (eg backdrop panels)
+    pub matrices: Option<HashMap<String,MatrixDefinition>>,
+
+    /// songs that mappings can be grouped into, so the same midi triggers can mean
+    /// different cues in different tunes (or segments of a game, eg pre-game, halftime
+    /// and stands music), switched by program change without reloading the show. the
+    /// first song is active when the show loads
+    pub songs: Option<Vec<SongDefinition>>,
+
+    /// runtime variables, which mappings can use as "$name" in place of their color
+    /// or a target, so one cue's look can follow a choice the operator makes live
+    pub variables: Option<HashMap<String,VariableDefinition>>,
+
+    /// controllers whose position continuously sets the master brightness, the tempo or
+    /// a cue's effect parameter, rather than turning cues on and off at 0 and 127
+    pub controls: Option<Vec<ControlDefinition>>,
+
+    /// receivers patched like fixtures, for a lighting console to drive over sACN
+    /// (if the config enables it) alongside its regular fixtures
+    pub dmx: Option<Vec<DmxPatch>>,
+
+    /// looks made of several cues, which the show can crossfade between. these don't
+    /// switch which mappings the midi triggers: for named sets of mappings selected by
+    /// program change (eg one for each segment of a game), give the mappings a song
+    pub scenes: Option<Vec<SceneDefinition>>,
+
+    /// cues in order, for one operator to step through with a single GO trigger (eg a
+    /// footswitch) rather than a key per cue
+    pub cue_list: Option<CueList>,
+
+    /// properties the show promises to have, checked when it loads and by --check
+    pub assertions: Option<Vec<ShowAssertion>>,
+
+    /// dry run every clip (silently) when the show loads, to make sure every step
+    /// resolves. --check always does. defaults to false
+    pub simulate_clips: Option<bool>,
+
+    /// radio settings for this show, overriding the config's while it's loaded, eg
+    /// for an indoor show that needs far less power than the installed config's
+    pub radio: Option<ShowRadioSettings>,
+
+    /// other show files merged into this one when it loads (paths relative to this
+    /// file), eg a receiver inventory or a clip library shared between shows. once
+    /// loaded, the paths of every file included, however deeply
+    pub include: Option<Vec<String>>
+}
+
+/// radio settings a show can override. a show can turn things down from the
+/// config but not up: power and repeats are capped at the config's, and pacing can
+/// only be slower
+#[derive(Debug,Serialize,Deserialize,Clone,Default)]
+pub struct ShowRadioSettings {
+    /// the transmitter power to use in dBm
+    pub transmitter_power: Option<i8>,
+    /// how many extra times to send each show packet, and each control packet
+    pub show_packet_repeats: Option<u8>,
+    pub control_packet_repeats: Option<u8>,
+    /// the pause between the packets that configure receivers
+    pub config_packet_spacing_millis: Option<u64>,
+    /// how many receivers to configure at a time, and the pause between them
+    pub config_chunk_size: Option<usize>,
+    pub config_chunk_pause_millis: Option<u64>
+}
+
+/// a property of the show checked when it loads, to catch mistakes before a performance
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub enum ShowAssertion {
+    /// every receiver in the roster is targeted by at least one cue
+    EveryReceiverTargeted,
+    /// one pass through the clip (at its own tempo, or 120 bpm) takes no longer than this
+    ClipMaxDuration { clip: String, max_seconds: f32 },
+    /// no two cues in the same song are triggered by the same note or controller
+    UniqueTriggers
+}
+
+/// a look: cues lit together, which a crossfade brings up while fading out whatever was showing
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct SceneDefinition {
+    pub name: String,
+    /// the cues of the scene's mappings
+    pub cues: Vec<String>,
+    /// how long a crossfade to this scene takes, overriding the configured time
+    pub crossfade_millis: Option<u32>
+}
+
+/// cues stepped through in order: GO releases the cues of the step showing and fires
+/// those of the next
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct CueList {
+    pub steps: Vec<CueListStep>,
+    /// the note, or controller (at 127), that goes to the next step
+    pub go: MidiMappingType,
+    /// the note, or controller (at 127), that goes back a step
+    pub back: Option<MidiMappingType>,
+    /// a controller on the control channel whose value jumps straight to that step,
+    /// counting from 0
+    pub jump_cc: Option<u8>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct CueListStep {
+    /// a label for the step, for the log
+    pub name: Option<String>,
+    /// the cues of the mappings the step fires
+    pub cues: Vec<String>
+}
+
+/// a runtime variable, set from a controller (or the network) while the show runs
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct VariableDefinition {
+    /// the values the variable can take: color names, or target names, depending on
+    /// where it's used. the first is the value when the show loads
+    pub values: Vec<String>,
+    /// the controller that picks the value, its range divided evenly among the values.
+    /// the channel defaults to the control channel
+    pub channel: Option<u8>,
+    pub cc: Option<u8>
+}
+
+/// a controller that sets a parameter as it moves, its 0-127 scaled onto min to max
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct ControlDefinition {
+    /// the controller, the channel defaults to the control channel
+    pub channel: Option<u8>,
+    pub cc: u8,
+    pub parameter: ControlledParameter,
+    /// the parameter's value at the bottom and the top of the controller's travel,
+    /// defaults to 0-255. min above max reverses the controller
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    /// the least time between sends as the controller moves, defaults to 50. the
+    /// controller's last position is always sent, once the interval is up
+    pub interval_millis: Option<u64>,
+    /// the receivers brightness is sent to, if absent, all receivers
+    pub targets: Option<Vec<serde_json::Value>>
+}
+
+/// a receiver or group patched into a DMX universe: the intensity channel at the
+/// address, then three color channels, which the intensity scales the brightness of
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct DmxPatch {
+    /// a receiver or group, as in a mapping's targets
+    pub target: serde_json::Value,
+    pub universe: u16,
+    /// the intensity channel, 1-509
+    pub address: u16,
+    /// what the color channels are, defaults to rgb
+    pub color: Option<DmxColorMode>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DmxColorMode {
+    /// red, green and blue
+    #[default]
+    Rgb,
+    /// hue, saturation and value, as the show's colors are
+    Hsv
+}
+
+/// what a continuous control sets
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq)]
+pub enum ControlledParameter {
+    /// the master brightness of the receivers (tempo is the config's tempo_control knob)
+    Brightness,
+    /// the first effect parameter (eg a chase's length) of a cue's mappings, which are
+    /// sent again with it while they're showing
+    Param1 { cue: String }
+}
+
+const DEFAULT_CONTROL_INTERVAL: u64 = 50;
+
+impl ControlDefinition {
+
+    /// the parameter's value for a controller position from 0-127
+    pub fn scaled(self: &Self, value: u8) -> u8 {
+        let (min, max) = (self.min.unwrap_or(0.0), self.max.unwrap_or(255.0));
+        (min + (max - min) * value.min(127) as f32 / 127.0).round().clamp(0.0, 255.0) as u8
+    }
+
+    pub fn interval(self: &Self) -> Duration {
+        Duration::from_millis(self.interval_millis.unwrap_or(DEFAULT_CONTROL_INTERVAL))
+    }
+}
+
+/// the name of the variable a color or target refers to, if it's a "$name" reference
+pub fn variable_reference(s: &str) -> Option<&str> {
+    s.strip_prefix('$')
+}
+
+/// a target list with each variable reference replaced by the values value_of gives for it
+pub fn substitute_targets<F>(targets: &Option<Vec<serde_json::Value>>, value_of: F) -> Option<Vec<serde_json::Value>>
+where F: Fn(&str) -> Vec<String> {
+    targets.as_ref().map(|targets| targets.iter().flat_map(|t| {
+        match t.as_str().and_then(variable_reference) {
+            Some(name) => value_of(name).into_iter().map(serde_json::Value::from).collect(),
+            None => vec![t.clone()]
+        }
+    }).collect())
+}
+
+/// the note names notes are normalized to, sharps rather than flats
+const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// parse a note from a mapping into its midi note number. accepts a raw midi number
+/// (0-127) or a name with middle C as C4: sharps or flats (C#4, Db4, or with ♯/♭/x),
+/// doubled if need be, and H for B as in European charts. B is always B natural, never
+/// the German B flat
+pub fn parse_note(s: &str) -> anyhow::Result<u8> {
+    let invalid = || anyhow::anyhow!("Invalid note: {} (expected eg C4, Db4, C#4, H3 or a midi number 0-127)", s);
+    let trimmed = s.trim();
+    if let Ok(number) = trimmed.parse::<u8>() {
+        return if number < 128 { Ok(number) } else { Err(anyhow::anyhow!("Note: {} is out of the midi range 0-127", s)) };
+    }
+    let mut chars = trimmed.chars().peekable();
+    let mut pitch: i32 = match chars.next().ok_or_else(invalid)?.to_ascii_uppercase() {
+        'C' => 0, 'D' => 2, 'E' => 4, 'F' => 5, 'G' => 7, 'A' => 9, 'B' | 'H' => 11,
+        _ => return Err(invalid())
+    };
+    while let Some(c) = chars.peek() {
+        match c {
+            '#' | '♯' => pitch += 1,
+            'b' | '♭' => pitch -= 1,
+            'x' | '𝄪' => pitch += 2,
+            _ => break
+        }
+        chars.next();
+    }
+    let octave: i32 = chars.collect::<String>().parse().map_err(|_| invalid())?;
+    let midi = (octave + 1) * 12 + pitch;
+    u8::try_from(midi).ok().filter(|n| *n < 128)
+        .ok_or_else(|| anyhow::anyhow!("Note: {} is out of the midi range 0-127", s))
+}
+
+/// the canonical name of a midi note number, eg 61 is C#4
+pub fn note_name(midi: u8) -> String {
+    format!("{}{}", NOTE_NAMES[(midi % 12) as usize], (midi / 12) as i32 - 1)
+}
+
+/// a bank of mappings that's active while a song (or a segment of a game) is playing:
+/// the mappings naming it, plus those naming no song. the active song is switched by
+/// marker messages: a program change on the control channel or a song select carrying
+/// the song's number, or a song position pointer at or after the song's position. cues
+/// left on by the song before still go off with their notes
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct SongDefinition {
+    pub name: String,
+    /// the program change/song select number that selects this song
+    pub number: Option<u8>,
+    /// where this song starts on the sequencer timeline, in MIDI beats (sixteenth notes)
+    pub position: Option<u16>
+}
+
+/// a grid of receivers, each receiver being one cell of the matrix. rows and
+/// columns of a matrix can be used as targets as "name:row:N" and "name:col:N"
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct MatrixDefinition {
+    pub width: usize,
+    pub height: usize,
+    /// receiver ids or names, in row-major order starting at the top left
+    pub receivers: Vec<serde_json::Value>
+}
+
+/// transmitter-side effects spanning a matrix, which are decomposed into
+/// a clip of plain Pop packets to the appropriate cells when the show is loaded
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub enum MatrixEffect {
+    /// light one column at a time, left to right (or right to left if reversed)
+    ColumnSweep { matrix: String, reverse: Option<bool>, beats_per_step: Option<f32> },
+    /// light one row at a time, top to bottom (or bottom to top if reversed)
+    RowSweep { matrix: String, reverse: Option<bool>, beats_per_step: Option<f32> },
+    /// scroll text right to left across the matrix in a 3x5 pixel font
+    ScrollText { matrix: String, text: String, beats_per_step: Option<f32> }
 }
 
 ///
@@ -30,7 +316,7 @@ pub struct ShowDefinition {
 /// at the receiver level. Struct members code for the effect-specific
 /// params that will be sent as param1/param2
 /// 
-#[derive(Debug,Deserialize,Clone)]
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum Effect {
     Pop,
     /// delay quantization controls how many receivers will fire together
@@ -71,7 +357,7 @@ pub enum Effect {
 
 
 /// for a given receiver, what is its id, group name, and led count
-#[derive(Debug,Deserialize,Clone)]
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub struct ReceiverConfiguration {
     /// the id of the receiver
     pub id: u8,
@@ -81,32 +367,242 @@ pub struct ReceiverConfiguration {
     pub group_name: Option<String>,
     /// the number of LEDs in the string
     pub led_count: u16,
+    /// the firmware generation the receiver runs, if older than current. effects the
+    /// firmware doesn't support are substituted with the nearest supported one
+    pub firmware: Option<u8>,
+    /// calibration for this unit: effects whose params the receiver uses whenever a cue
+    /// leaves them at zero (eg flame flicker ranges, piezo threshold). sent at configure time
+    pub presets: Option<Vec<Effect>>,
+    /// if true, the strip runs the opposite way to the rest (eg sewn in the other
+    /// way round), so chases targeted at it have their direction flipped
+    pub reversed: Option<bool>,
+    /// how likely the receiver is to be among a mapping's random subset, relative to the
+    /// other receivers it could be picked from, defaults to 1. 0 leaves it out of them
+    pub random_weight: Option<f32>,
     
     pub comment: Option<String>
 }
 
-/// the source of a midi mapping whether it be a note or CC (continuous controller)
-#[derive(Debug,Deserialize,Clone)]
+/// the source of a midi mapping whether it be a note or CC (continuous controller),
+/// or a position in midi time code
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum MidiMappingType {
     Note { channel: u8, note: String },
-    Controller { channel: u8, cc: u8 }
+    Controller { channel: u8, cc: u8 },
+    /// fires (only on, never off) when the time code passes hours:minutes:seconds:frames
+    Timecode { hh: u8, mm: u8, ss: u8, ff: u8 },
+    /// a run of notes, low to high, each firing the cue on the next of the mapping's
+    /// targets in turn (eg a keyboard run across the field), rewritten into a mapping
+    /// per note at load time
+    NoteRange { channel: u8, low: String, high: String }
+}
+
+impl MidiMappingType {
+    /// the midi channel the trigger arrives on, if it's a channel message
+    pub fn channel(self: &Self) -> Option<u8> {
+        match self {
+            MidiMappingType::Note { channel, .. } | MidiMappingType::Controller { channel, .. } |
+                MidiMappingType::NoteRange { channel, .. } => Some(*channel),
+            MidiMappingType::Timecode { .. } => None
+        }
+    }
 }
 
 /// the target of a mapping, which can be either an effect or a name clip
-#[derive(Debug,Deserialize,Clone)]
+/// (matrix effects are rewritten into clips at load time, and presets into effects)
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum LightMappingType {
     Effect(Effect),
-    Clip(String)
+    Clip(String),
+    Matrix(MatrixEffect),
+    FlashText(FlashText),
+    Preset(PresetReference)
+}
+
+/// an effect defined once in the show's effects, with all its parameters and
+/// (optionally) the color, envelope and tempo that go with it
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct EffectPreset {
+    pub effect: Effect,
+    pub color: Option<String>,
+    pub attack: Option<u32>,
+    pub sustain: Option<u32>,
+    pub release: Option<u32>,
+    pub tempo: Option<f32>
+}
+
+/// a use of an effect preset. the mapping's own color, envelope and tempo (if any)
+/// take the place of the preset's
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct PresetReference {
+    pub name: String,
+    /// parameters of the preset's effect to change for this use, eg { "alternate_hue": 40 }
+    pub overrides: Option<serde_json::Map<String,serde_json::Value>>
 }
 
-#[derive(Debug,Clone,Copy,Deserialize)]
+/// flash a short text message on the mapping's targets with Pop packets,
+/// rewritten into a clip at load time
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct FlashText {
+    pub text: String,
+    pub pattern: FlashPattern,
+    /// the length of a morse dot or a single pulse, defaults if not supplied
+    pub unit_millis: Option<u32>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub enum FlashPattern {
+    /// international morse code: dot 1 unit, dash 3 units, gaps of 1/3/7 units
+    Morse,
+    /// each character flashes a count of pulses: digits their value (0 as 10),
+    /// letters their position in the alphabet
+    Pulses
+}
+
+/// a color as the receivers take it. shows can also give one as { "r", "g", "b" }, as
+/// "#RRGGBB", or as a CSS color name, which are converted when the show loads
+#[derive(Debug,Clone,Copy,PartialEq,Serialize)]
 pub struct Color { pub h: u8, pub s: u8, pub v: u8 }
 
-#[derive(Debug,Deserialize,Clone)]
+/// the CSS named colors, in order, as 0xRRGGBB
+const CSS_COLORS: &[(&str, u32)] = &[
+    ("aliceblue", 0xF0F8FF), ("antiquewhite", 0xFAEBD7), ("aqua", 0x00FFFF), ("aquamarine", 0x7FFFD4),
+    ("azure", 0xF0FFFF), ("beige", 0xF5F5DC), ("bisque", 0xFFE4C4), ("black", 0x000000),
+    ("blanchedalmond", 0xFFEBCD), ("blue", 0x0000FF), ("blueviolet", 0x8A2BE2), ("brown", 0xA52A2A),
+    ("burlywood", 0xDEB887), ("cadetblue", 0x5F9EA0), ("chartreuse", 0x7FFF00), ("chocolate", 0xD2691E),
+    ("coral", 0xFF7F50), ("cornflowerblue", 0x6495ED), ("cornsilk", 0xFFF8DC), ("crimson", 0xDC143C),
+    ("cyan", 0x00FFFF), ("darkblue", 0x00008B), ("darkcyan", 0x008B8B), ("darkgoldenrod", 0xB8860B),
+    ("darkgray", 0xA9A9A9), ("darkgreen", 0x006400), ("darkgrey", 0xA9A9A9), ("darkkhaki", 0xBDB76B),
+    ("darkmagenta", 0x8B008B), ("darkolivegreen", 0x556B2F), ("darkorange", 0xFF8C00), ("darkorchid", 0x9932CC),
+    ("darkred", 0x8B0000), ("darksalmon", 0xE9967A), ("darkseagreen", 0x8FBC8F), ("darkslateblue", 0x483D8B),
+    ("darkslategray", 0x2F4F4F), ("darkslategrey", 0x2F4F4F), ("darkturquoise", 0x00CED1), ("darkviolet", 0x9400D3),
+    ("deeppink", 0xFF1493), ("deepskyblue", 0x00BFFF), ("dimgray", 0x696969), ("dimgrey", 0x696969),
+    ("dodgerblue", 0x1E90FF), ("firebrick", 0xB22222), ("floralwhite", 0xFFFAF0), ("forestgreen", 0x228B22),
+    ("fuchsia", 0xFF00FF), ("gainsboro", 0xDCDCDC), ("ghostwhite", 0xF8F8FF), ("gold", 0xFFD700),
+    ("goldenrod", 0xDAA520), ("gray", 0x808080), ("green", 0x008000), ("greenyellow", 0xADFF2F), ("grey", 0x808080),
+    ("honeydew", 0xF0FFF0), ("hotpink", 0xFF69B4), ("indianred", 0xCD5C5C), ("indigo", 0x4B0082),
+    ("ivory", 0xFFFFF0), ("khaki", 0xF0E68C), ("lavender", 0xE6E6FA), ("lavenderblush", 0xFFF0F5),
+    ("lawngreen", 0x7CFC00), ("lemonchiffon", 0xFFFACD), ("lightblue", 0xADD8E6), ("lightcoral", 0xF08080),
+    ("lightcyan", 0xE0FFFF), ("lightgoldenrodyellow", 0xFAFAD2), ("lightgray", 0xD3D3D3), ("lightgreen", 0x90EE90),
+    ("lightgrey", 0xD3D3D3), ("lightpink", 0xFFB6C1), ("lightsalmon", 0xFFA07A), ("lightseagreen", 0x20B2AA),
+    ("lightskyblue", 0x87CEFA), ("lightslategray", 0x778899), ("lightslategrey", 0x778899),
+    ("lightsteelblue", 0xB0C4DE), ("lightyellow", 0xFFFFE0), ("lime", 0x00FF00), ("limegreen", 0x32CD32),
+    ("linen", 0xFAF0E6), ("magenta", 0xFF00FF), ("maroon", 0x800000), ("mediumaquamarine", 0x66CDAA),
+    ("mediumblue", 0x0000CD), ("mediumorchid", 0xBA55D3), ("mediumpurple", 0x9370DB), ("mediumseagreen", 0x3CB371),
+    ("mediumslateblue", 0x7B68EE), ("mediumspringgreen", 0x00FA9A), ("mediumturquoise", 0x48D1CC),
+    ("mediumvioletred", 0xC71585), ("midnightblue", 0x191970), ("mintcream", 0xF5FFFA), ("mistyrose", 0xFFE4E1),
+    ("moccasin", 0xFFE4B5), ("navajowhite", 0xFFDEAD), ("navy", 0x000080), ("oldlace", 0xFDF5E6),
+    ("olive", 0x808000), ("olivedrab", 0x6B8E23), ("orange", 0xFFA500), ("orangered", 0xFF4500),
+    ("orchid", 0xDA70D6), ("palegoldenrod", 0xEEE8AA), ("palegreen", 0x98FB98), ("paleturquoise", 0xAFEEEE),
+    ("palevioletred", 0xDB7093), ("papayawhip", 0xFFEFD5), ("peachpuff", 0xFFDAB9), ("peru", 0xCD853F),
+    ("pink", 0xFFC0CB), ("plum", 0xDDA0DD), ("powderblue", 0xB0E0E6), ("purple", 0x800080),
+    ("rebeccapurple", 0x663399), ("red", 0xFF0000), ("rosybrown", 0xBC8F8F), ("royalblue", 0x4169E1),
+    ("saddlebrown", 0x8B4513), ("salmon", 0xFA8072), ("sandybrown", 0xF4A460), ("seagreen", 0x2E8B57),
+    ("seashell", 0xFFF5EE), ("sienna", 0xA0522D), ("silver", 0xC0C0C0), ("skyblue", 0x87CEEB),
+    ("slateblue", 0x6A5ACD), ("slategray", 0x708090), ("slategrey", 0x708090), ("snow", 0xFFFAFA),
+    ("springgreen", 0x00FF7F), ("steelblue", 0x4682B4), ("tan", 0xD2B48C), ("teal", 0x008080), ("thistle", 0xD8BFD8),
+    ("tomato", 0xFF6347), ("turquoise", 0x40E0D0), ("violet", 0xEE82EE), ("wheat", 0xF5DEB3), ("white", 0xFFFFFF),
+    ("whitesmoke", 0xF5F5F5), ("yellow", 0xFFFF00), ("yellowgreen", 0x9ACD32)
+];
+
+impl Color {
+
+    /// the color of "#RRGGBB", or a CSS color name
+    pub fn parse(text: &str) -> anyhow::Result<Color> {
+        let rgb = match text.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => u32::from_str_radix(hex, 16).map_err(|_| anyhow::anyhow!("Bad hex color: {}", text))?,
+            Some(_) => return Err(anyhow::anyhow!("Hex color: {} is not #RRGGBB", text)),
+            None => {
+                let name = text.to_ascii_lowercase();
+                let index = CSS_COLORS.binary_search_by(|(n, _)| (*n).cmp(&name))
+                    .map_err(|_| anyhow::anyhow!("Unknown color name: {}", text))?;
+                CSS_COLORS[index].1
+            }
+        };
+        let [_, r, g, b] = rgb.to_be_bytes();
+        Ok(Color::from_rgb(r, g, b))
+    }
+
+    /// the color of red, green and blue levels, with hue around the wheel in 256ths
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> Color {
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = (max - min) as f32;
+        if delta == 0.0 {
+            return Color { h: 0, s: 0, v: max }
+        }
+        let (r, g, b) = (r as f32, g as f32, b as f32);
+        // sixths of the way round from red, yellow, green, cyan, blue and magenta
+        let sixths = if max as f32 == r {
+            ((g - b) / delta).rem_euclid(6.0)
+        } else if max as f32 == g {
+            (b - r) / delta + 2.0
+        } else {
+            (r - g) / delta + 4.0
+        };
+        Color {
+            h: (sixths * 256.0 / 6.0).round() as u32 as u8,
+            s: (delta * 255.0 / max as f32).round() as u8,
+            v: max
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Color, D::Error> {
+        deserializer.deserialize_any(ColorVisitor)
+    }
+}
+
+struct ColorVisitor;
+
+impl<'de> serde::de::Visitor<'de> for ColorVisitor {
+    type Value = Color;
+
+    fn expecting(self: &Self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a color as h, s and v, as r, g and b, as \"#RRGGBB\" or as a CSS color name")
+    }
+
+    fn visit_str<E: serde::de::Error>(self, text: &str) -> Result<Color, E> {
+        Color::parse(text).map_err(E::custom)
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(self, mut map: A) -> Result<Color, A::Error> {
+        let mut levels: HashMap<String,u8> = HashMap::new();
+        while let Some(key) = map.next_key::<String>()? {
+            if ["h", "s", "v", "r", "g", "b"].contains(&key.as_str()) {
+                levels.insert(key, map.next_value()?);
+            } else {
+                map.next_value::<serde::de::IgnoredAny>()?;
+            }
+        }
+        let level = |key: &'static str| levels.get(key).copied().ok_or_else(|| serde::de::Error::missing_field(key));
+        if ["r", "g", "b"].iter().any(|key| levels.contains_key(*key)) {
+            Ok(Color::from_rgb(level("r")?, level("g")?, level("b")?))
+        } else {
+            Ok(Color { h: level("h")?, s: level("s")?, v: level("v")? })
+        }
+    }
+
+    /// compiled shows hold colors as [h, s, v]
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<Color, A::Error> {
+        let mut level = |index| seq.next_element::<u8>()?.ok_or_else(|| serde::de::Error::invalid_length(index, &self));
+        Ok(Color { h: level(0)?, s: level(1)?, v: level(2)? })
+    }
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub struct LightMapping {
+    /// the mapping's id, which names it to the hooks, the session log and the dashboard.
+    /// give one to keep it across edits of the show; without one the mapping is numbered
+    /// by its place in the show when it loads (see ShowDefinition::assign_mapping_ids)
+    #[serde(default)]
+    pub id: Option<usize>,
     pub cue: String,
     pub midi: Option<MidiMappingType>,
     pub light: LightMappingType,
+    /// the name of the mapping's color, which can be left out if its effect preset has one
+    #[serde(default)]
     pub color: String,
     pub override_clip_color: Option<bool>,
     pub attack: Option<u32>,
@@ -114,20 +610,350 @@ pub struct LightMapping {
     pub release: Option<u32>,
     pub one_shot: Option<bool>,
     pub tempo: Option<f32>,
+    /// how far full pressure takes the modulation target, as a percentage, defaults to 100
     pub modulation: Option<u8>,
+    /// if populated, channel aftertouch (pressing into a held note) on the mapping's
+    /// channel modulates this, live
+    pub modulation_target: Option<ModulationTarget>,
     /// targets is optional, if absent, all receivers are targets
     pub targets: Option<Vec<serde_json::Value>>,
+    /// what to do when the mapping is triggered again while it is still active
+    pub retrigger: Option<RetriggerPolicy>,
+    /// the mapping's layer on its receivers, defaults to 0. a receiver shows the latest
+    /// mapping activated of the highest priority among those on it: lower ones activated
+    /// meanwhile wait beneath, and whatever is beneath shows again once it's released
+    pub priority: Option<u8>,
+    /// if populated, the mapping's midi trigger only works while this song is active.
+    /// mappings without a song work in every song
+    pub song: Option<String>,
+    /// if true, the effect's packet is marshalled ahead of time and sent with as little
+    /// work as possible when triggered live, for latency sensitive (eg percussive) hits
+    pub realtime: Option<bool>,
+    /// if populated, each activation goes to a random subset of the receivers
+    /// targeted, eg for twinkles that land somewhere different on every hit
+    pub random_subset: Option<RandomSubset>,
+    /// if populated, a command to run when the mapping is turned on or off, with
+    /// details of the cue in CHS_* environment variables. only runs if the config enables hooks
+    pub exec: Option<String>,
+    /// if true, receivers whose links have been poor get extra copies of the mapping's
+    /// packets, sent to them alone (if the config enables adaptive_repeats)
+    pub critical: Option<bool>,
+    /// free-form labels (eg "strobe", "ballad") that live operations can pick cues out by
+    pub tags: Option<Vec<String>>,
+    /// if true, the transmitter fades the effect in itself, stepping the brightness up
+    /// with a series of Pop packets over the attack and then sending the effect without
+    /// one, for receivers whose firmware can't manage a long attack smoothly
+    pub synth_fade: Option<bool>,
+    /// if true, a note's velocity scales the brightness of the mapping's color, so harder
+    /// hits give brighter pops
+    pub velocity_to_brightness: Option<bool>,
+    /// if populated, the pad on the pad controller that triggers the mapping, set up to
+    /// send the mapping's note or controller when the show loads (if the config enables
+    /// pad_setup)
+    pub pad: Option<PadAssignment>,
+}
+
+/// a pad on the pad controller claimed by a mapping
+#[derive(Debug,Serialize,Deserialize,Clone,Copy)]
+pub struct PadAssignment {
+    /// the controller's id for the pad, as its sysex messages address it (eg 0x70 to
+    /// 0x7F for the pads of a MiniLab)
+    pub id: u8,
+    /// if true, a press turns the cue on and the next turns it off, rather than it
+    /// showing while the pad is held. defaults to false
+    pub toggle: Option<bool>
+}
+
+/// what channel aftertouch modulates in a mapping
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ModulationTarget {
+    /// the brightness swells towards full
+    Brightness,
+    /// the tempo speeds up, eg for a chase
+    Tempo
+}
+
+/// how many of a mapping's receivers a randomized activation picks
+#[derive(Debug,Serialize,Deserialize,Clone,Copy)]
+pub enum RandomSubset {
+    /// this many receivers (or all of them, if there are fewer)
+    Count(u8),
+    /// this percentage of the receivers, rounded up
+    Percent(u8)
+}
+
+impl RandomSubset {
+    /// how many to pick out of the given number of receivers
+    pub fn count(self: &Self, receivers: usize) -> usize {
+        match self {
+            RandomSubset::Count(n) => (*n as usize).min(receivers),
+            RandomSubset::Percent(p) => (receivers * (*p).min(100) as usize).div_ceil(100)
+        }
+    }
+}
+
+/// how an effect mapping responds to being triggered while it is already active
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RetriggerPolicy {
+    /// resend the packet, restarting the envelope from the attack
+    #[default]
+    Restart,
+    /// do nothing, the effect carries on as it was
+    Ignore,
+    /// resend the packet without an attack, so the sustain is extended
+    /// without a visible re-attack flash (useful on rolls)
+    Extend
+}
+
+/// join an optional list, or map, with a later one
+fn merge_option<C: Default + Extend<T> + IntoIterator<Item = T>, T>(earlier: &mut Option<C>, later: Option<C>) {
+    if let Some(later) = later {
+        earlier.get_or_insert_with(C::default).extend(later);
+    }
+}
+
+impl ShowDefinition {
+
+    /// give each mapping without an id of its own its position: the top level ones in
+    /// order and then those of the clips, clip by clip in name order, so it gets the same
+    /// id every time the same show loads. that fallback isn't stable: adding, removing or
+    /// reordering mappings, or renaming a clip, renumbers those after it, so an id kept
+    /// across an edit of the show can name another mapping. mappings the show generates,
+    /// for note ranges, matrix effects and flashed text, are always numbered this way. two
+    /// mappings with the same id, whether given or numbered, are rejected
+    pub fn assign_mapping_ids(self: &mut Self) -> anyhow::Result<()> {
+        let mut clips: Vec<(&String, &mut Vec<ClipStep>)> = self.clips.iter_mut().collect();
+        clips.sort_by(|a, b| a.0.cmp(b.0));
+        let clip_mappings = clips.into_iter().flat_map(|(_, steps)| steps.iter_mut()).filter_map(|step|
+            if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+        let mut cues: HashMap<usize, String> = HashMap::new();
+        let mut problems = vec![];
+        for (position, m) in self.mappings.iter_mut().chain(clip_mappings).enumerate() {
+            let id = *m.id.get_or_insert(position);
+            if let Some(other) = cues.insert(id, m.cue.clone()) {
+                problems.push(format!("cue: {}: id {} is already cue: {}'s", m.cue, id, other));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Show has duplicate mapping ids:\n  {}", problems.join("\n  ")))
+        }
+    }
+
+    /// merge a show that comes after this one, as if its files were pasted in below
+    /// this one's: lists are joined, and where both name the same color, clip, effect,
+    /// matrix or variable, or set the same option, the later show's wins
+    pub fn merge(self: &mut Self, later: ShowDefinition) {
+        let ShowDefinition { receivers, colors, mappings, clips, effects, matrices, songs, variables, controls,
+            dmx, scenes, cue_list, assertions, simulate_clips, radio, include: _ } = later;
+        self.receivers.extend(receivers);
+        self.colors.extend(colors);
+        self.mappings.extend(mappings);
+        self.clips.extend(clips);
+        merge_option(&mut self.effects, effects);
+        merge_option(&mut self.matrices, matrices);
+        merge_option(&mut self.songs, songs);
+        merge_option(&mut self.variables, variables);
+        merge_option(&mut self.controls, controls);
+        merge_option(&mut self.dmx, dmx);
+        merge_option(&mut self.scenes, scenes);
+        merge_option(&mut self.assertions, assertions);
+        self.cue_list = cue_list.or(self.cue_list.take());
+        self.simulate_clips = simulate_clips.or(self.simulate_clips);
+        self.radio = radio.or(self.radio.take());
+    }
+
+    /// check that every clip a mapping or StopOther step names exists, and that
+    /// MappingOff and Loop steps point at valid steps, reporting every problem found
+    pub fn validate_clip_references(self: &Self) -> anyhow::Result<()> {
+        let mut problems: Vec<String> = vec![];
+        let check_mapping = |m: &LightMapping, problems: &mut Vec<String>| {
+            if let LightMappingType::Clip(clip) = &m.light {
+                if !self.clips.contains_key(clip) {
+                    problems.push(format!("cue: {} refers to unknown clip: {}", m.cue, clip));
+                }
+            }
+        };
+        for m in self.mappings.iter() {
+            check_mapping(m, &mut problems);
+        }
+        for (name, steps) in self.clips.iter() {
+            for (index, step) in steps.iter().enumerate() {
+                match step {
+                    ClipStep::MappingOn(m) => check_mapping(m, &mut problems),
+                    ClipStep::StopOther(other) if !self.clips.contains_key(other) =>
+                        problems.push(format!("clip: {} step: {} stops unknown clip: {}", name, index, other)),
+                    ClipStep::MappingOff(on) if !matches!(steps.get(*on), Some(ClipStep::MappingOn(_))) =>
+                        problems.push(format!("clip: {} step: {} turns off step: {} which is not a mapping on step", name, index, on)),
+                    ClipStep::Loop(to) | ClipStep::LoopN { index: to, .. } if *to >= steps.len() =>
+                        problems.push(format!("clip: {} step: {} loops to missing step: {}", name, index, to)),
+                    ClipStep::LoopN { times: 0, .. } =>
+                        problems.push(format!("clip: {} step: {} loops no times", name, index)),
+                    ClipStep::BreakLoop if !matches!(ClipStep::enclosing_loop(steps, index).map(|at| &steps[at]), Some(ClipStep::LoopN { .. })) =>
+                        problems.push(format!("clip: {} step: {} breaks out of no counted loop", name, index)),
+                    ClipStep::WaitUntil { bar, beat } if *bar < 1 || *beat < 1.0 =>
+                        problems.push(format!("clip: {} step: {} waits until bar: {} beat: {}, which count from 1", name, index, bar, beat)),
+                    ClipStep::SetMeter(0) =>
+                        problems.push(format!("clip: {} step: {} sets a meter with no beats", name, index)),
+                    _ => {}
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Show has bad clip references:\n  {}", problems.join("\n  ")))
+        }
+    }
+
+    /// check that every variable has values and every variable reference is to a defined variable
+    pub fn validate_variables(self: &Self) -> anyhow::Result<()> {
+        let mut problems: Vec<String> = vec![];
+        let variables = self.variables.as_ref();
+        for (name, variable) in variables.iter().flat_map(|v| v.iter()) {
+            if variable.values.is_empty() {
+                problems.push(format!("variable: {} has no values", name));
+            }
+        }
+        let clip_mappings = self.clips.values().flatten().filter_map(|step|
+            if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+        for m in self.mappings.iter().chain(clip_mappings) {
+            for name in m.variables() {
+                if !variables.is_some_and(|v| v.contains_key(name)) {
+                    problems.push(format!("cue: {} refers to unknown variable: {}", m.cue, name));
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Show has bad variables:\n  {}", problems.join("\n  ")))
+        }
+    }
+
+    /// rewrite every note in a midi mapping (top level or embedded in a clip) to its
+    /// canonical spelling, so C#4, Db4 and 61 all become C#4, reporting every note that
+    /// won't parse
+    pub fn normalize_notes(self: &mut Self) -> anyhow::Result<()> {
+        let mut problems: Vec<String> = vec![];
+        let clip_mappings = self.clips.values_mut().flatten().filter_map(|step|
+            if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+        for m in self.mappings.iter_mut().chain(clip_mappings) {
+            if let Some(MidiMappingType::Note { note, .. }) = &mut m.midi {
+                match parse_note(note) {
+                    Ok(midi) => *note = note_name(midi),
+                    Err(e) => problems.push(format!("cue: {}: {}", m.cue, e))
+                }
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Show has bad notes:\n  {}", problems.join("\n  ")))
+        }
+    }
+
+    /// rewrite every note range mapping into a mapping per note, named for the note, each
+    /// targeting the next of the range's targets. reports every range that doesn't have
+    /// a target for each of its notes
+    pub fn expand_note_ranges(self: &mut Self) -> anyhow::Result<()> {
+        let mut problems: Vec<String> = vec![];
+        let mut mappings: Vec<LightMapping> = Vec::with_capacity(self.mappings.len());
+        for m in self.mappings.drain(..) {
+            let Some(MidiMappingType::NoteRange { channel, low, high }) = &m.midi else {
+                mappings.push(m);
+                continue
+            };
+            let (low, high) = match (parse_note(low), parse_note(high)) {
+                (Ok(low), Ok(high)) if low <= high => (low, high),
+                (Ok(_), Ok(_)) => {
+                    problems.push(format!("cue: {}: note range runs from {} down to {}", m.cue, low, high));
+                    continue
+                },
+                (Err(e), _) | (_, Err(e)) => {
+                    problems.push(format!("cue: {}: {}", m.cue, e));
+                    continue
+                }
+            };
+            let targets = m.targets.clone().unwrap_or_default();
+            let notes = (high - low) as usize + 1;
+            if targets.len() != notes {
+                problems.push(format!("cue: {}: note range has {} notes but {} targets", m.cue, notes, targets.len()));
+                continue
+            }
+            for (note, target) in (low..=high).zip(targets) {
+                mappings.push(LightMapping {
+                    id: None,
+                    cue: format!("{} {}", m.cue, note_name(note)),
+                    midi: Some(MidiMappingType::Note { channel: *channel, note: note_name(note) }),
+                    targets: Some(vec![target]),
+                    ..m.clone()
+                });
+            }
+        }
+        self.mappings = mappings;
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("Show has bad note ranges:\n  {}", problems.join("\n  ")))
+        }
+    }
+
+    /// rewrite every mapping (top level or embedded in a clip) for which synthesize returns
+    /// clip steps into a reference to a new clip with those steps. used for transmitter-side
+    /// meta-effects that decompose into ordinary packets at load time
+    pub fn synthesize_clips<F>(self: &mut Self, prefix: &str, mut synthesize: F) -> anyhow::Result<()>
+    where F: FnMut(&LightMapping, &ShowDefinition) -> anyhow::Result<Option<Vec<ClipStep>>> {
+        let mut new_clips: Vec<(String, Vec<ClipStep>)> = vec![];
+        let mut rewrite = |mapping: &mut LightMapping, show: &ShowDefinition| -> anyhow::Result<()> {
+            if let Some(steps) = synthesize(mapping, show)? {
+                let clip_name = format!("__{}_{}_{}", prefix, mapping.cue, new_clips.len());
+                info!("Synthesized clip: {} with {} steps for cue: {}", clip_name, steps.len(), mapping.cue);
+                new_clips.push((clip_name.clone(), steps));
+                mapping.light = LightMappingType::Clip(clip_name);
+            }
+            Ok(())
+        };
+
+        // synthesis may need to read the rest of the show while mappings are rewritten, so work on copies
+        let mut mappings = self.mappings.clone();
+        for m in mappings.iter_mut() {
+            rewrite(m, self)?;
+        }
+        let mut clips = self.clips.clone();
+        for steps in clips.values_mut() {
+            for step in steps.iter_mut() {
+                if let ClipStep::MappingOn(m) = step {
+                    rewrite(m, self)?;
+                }
+            }
+        }
+        self.mappings = mappings;
+        self.clips = clips;
+        self.clips.extend(new_clips);
+        Ok(())
+    }
 }
 
 impl LightMapping {
 
+    /// the mapping's id, which is 0 until the show numbers its mappings
     pub fn get_id(self: &Self) -> usize {
-        self as *const LightMapping as usize
+        self.id.unwrap_or_default()
     }
-    
+
+    /// the names of the runtime variables the mapping's color and targets refer to
+    pub fn variables(self: &Self) -> Vec<&str> {
+        let targets = self.targets.iter().flatten().filter_map(|t| t.as_str().and_then(variable_reference));
+        variable_reference(&self.color).into_iter().chain(targets).collect()
+    }
+
 }
 
-#[derive(Debug,Deserialize,Clone)]
+#[derive(Debug,Serialize,Deserialize,Clone)]
 pub enum ClipStep {
     /// instruction to trigger the contained mapping
     MappingOn(LightMapping),
@@ -137,8 +963,20 @@ pub enum ClipStep {
     WaitBeats(f32),
     /// wait the specified number of milliseconds
     WaitMillis(u32),
+    /// wait until a musical position: bar and beat, counting from 1 at the start of the
+    /// song while a midi clock is playing one (after a start or continue, and from any song
+    /// position pointer), otherwise at the start of the clip (or its last loop), at the
+    /// clip's live tempo
+    WaitUntil { bar: u32, beat: f32 },
+    /// set the number of beats in a bar for the clip's positions, 4 if not set
+    SetMeter(u8),
     /// go back to the clip step at the index
     Loop(usize),
+    /// go back to the clip step at the index until the steps from it have played the
+    /// number of times, then carry on with the next step
+    LoopN { index: usize, times: u32 },
+    /// leave the counted loop this step is in on its last time round, for the step after it
+    BreakLoop,
     /// set the current clip-wide color
     SetColor(Color),
     /// set the current clip-wide tempo
@@ -149,4 +987,15 @@ pub enum ClipStep {
     StopOther(String),
     /// terminate the clip
     End,
-}
\ No newline at end of file
+}
+
+impl ClipStep {
+
+    /// the loop step a clip's step is in: the first loop after it going back to it or before
+    pub fn enclosing_loop(steps: &[ClipStep], at: usize) -> Option<usize> {
+        steps.iter().enumerate().skip(at + 1).find_map(|(index, step)| match step {
+            ClipStep::Loop(to) | ClipStep::LoopN { index: to, .. } if *to <= at => Some(index),
+            _ => None
+        })
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::{anyhow,Context,Result};
+use json_comments::StripComments;
+use log::info;
+
+use crate::show::{ClipStep, LightMapping, ShowDefinition, variable_reference};
+use crate::showstate::{build_target_lookup, resolve_targets};
+use crate::{flash, matrix, preset};
+
+///
+/// This module loads show files, either as (comment-tolerant) JSON or as
+/// compiled binary cue files produced by --compile. Files ending .yaml or .yml
+/// are read as YAML, and .json5 as JSON5 (trailing commas, unquoted keys and
+/// so on), for shows that are easier to edit by hand that way. A compiled show
+/// has already had its meta-effects expanded and its names resolved, so loading
+/// one skips parsing and all of that work
+///
+
+/// compiled cue files start with this, followed by a format version byte
+const CUE_FILE_MAGIC: &[u8] = b"CHSCUE";
+const CUE_FILE_VERSION: u8 = 32;
+
+/// load a show from either JSON or a compiled cue file, telling them apart by content
+pub fn load(path: &Path) -> Result<ShowDefinition> {
+    let buf = fs::read(path).context("Could not open file")?;
+    if let Some(compiled) = buf.strip_prefix(CUE_FILE_MAGIC) {
+        return match compiled.split_first() {
+            Some((&CUE_FILE_VERSION, body)) => {
+                info!("Loading compiled show: {:?}", path);
+                let mut show: ShowDefinition = rmp_serde::from_slice(body).context("Could not decode compiled show")?;
+                show.assign_mapping_ids()?;
+                show.validate_clip_references()?;
+                show.validate_variables()?;
+                Ok(show)
+            },
+            _ => Err(anyhow!("Compiled show is not format version {}, recompile it", CUE_FILE_VERSION))
+        }
+    }
+    let mut including = vec![path.canonicalize().context("Could not open file")?];
+    let mut included = vec![];
+    let mut show = merge_includes(parse(path, &buf)?, path, &mut including, &mut included)?;
+    if !included.is_empty() {
+        show.include = Some(included.iter().map(|p| p.to_string_lossy().into_owned()).collect());
+    }
+    show.expand_note_ranges()?;
+    show.normalize_notes()?;
+    preset::expand_presets(&mut show)?;
+    matrix::expand_matrix_effects(&mut show).context("Could not expand matrix effects")?;
+    flash::expand_flash_text(&mut show).context("Could not expand flashed text")?;
+    show.assign_mapping_ids()?;
+    show.validate_clip_references()?;
+    show.validate_variables()?;
+    Ok(show)
+}
+
+/// parse a show written as JSON, JSON5 or YAML, by the file's extension
+fn parse(path: &Path, buf: &[u8]) -> Result<ShowDefinition> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+        // read by way of a JSON value, so effects and the like are written as they are in
+        // JSON ({Effect: ...}) rather than with YAML tags (!Effect ...)
+        Some("yaml" | "yml") => {
+            let value: serde_json::Value = serde_norway::from_slice(buf).context("Could not parse YAML file")?;
+            serde_json::from_value(value).context("Could not parse YAML file")
+        },
+        Some("json5") => json5::from_str(std::str::from_utf8(buf).context("File is not UTF-8")?).context("Could not parse JSON5 file"),
+        _ => serde_json::from_reader(StripComments::new(buf)).context("Could not parse file")
+    }
+}
+
+/// merge the files a show includes into it (and the files they include), as if each
+/// were pasted in ahead of it, in order. including holds the files whose includes are
+/// being merged, from the show file down, so a file including itself (however
+/// indirectly) is caught, while two files may both include a third. included gathers
+/// every file included, once each, for the show file watcher
+fn merge_includes(show: ShowDefinition, path: &Path, including: &mut Vec<PathBuf>, included: &mut Vec<PathBuf>) -> Result<ShowDefinition> {
+    let Some(include) = show.include.clone() else {
+        return Ok(show)
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut merged = ShowDefinition::default();
+    for name in include.iter() {
+        let included_path = dir.join(name);
+        let canonical = included_path.canonicalize().with_context(|| format!("Could not open included file: {}", name))?;
+        if including.contains(&canonical) {
+            return Err(anyhow!("Show file: {} includes itself", name))
+        }
+        if !included.contains(&canonical) {
+            included.push(canonical.clone());
+        }
+        including.push(canonical);
+        let buf = fs::read(&included_path).with_context(|| format!("Could not open included file: {}", name))?;
+        let result = parse(&included_path, &buf)
+            .and_then(|show| merge_includes(show, &included_path, including, included))
+            .with_context(|| format!("Could not load included file: {}", name));
+        including.pop();
+        merged.merge(result?);
+    }
+    merged.merge(show);
+    Ok(merged)
+}
+
+/// replace every target name in the show with its numeric receiver or group id, check
+/// that every color reference exists, and drop colors nothing uses (clip references
+/// were already checked when the show loaded)
+fn resolve(show: &mut ShowDefinition) -> Result<()> {
+    let (target_lookup, _) = build_target_lookup(show);
+    let matrix_targets = matrix::matrix_targets(show)?;
+    let mut used_colors: HashSet<String> = HashSet::new();
+    let colors = &show.colors;
+    let variables = show.variables.clone().unwrap_or_default();
+
+    let mut resolve_mapping = |m: &mut LightMapping| -> Result<()> {
+        if let Some(targets) = &m.targets {
+            // variable references stay as they are, to be resolved as the show runs
+            let mut resolved: Vec<serde_json::Value> = vec![];
+            for target in targets {
+                if target.as_str().and_then(variable_reference).is_some() {
+                    resolved.push(target.clone());
+                } else {
+                    resolved.extend(resolve_targets(&Some(vec![target.clone()]), &target_lookup, &matrix_targets)
+                        .with_context(|| format!("Could not resolve targets of cue: {}", m.cue))?
+                        .into_iter().map(serde_json::Value::from));
+                }
+            }
+            m.targets = Some(resolved);
+        }
+        // a variable color could be any of the variable's values
+        let color_names = match variable_reference(&m.color) {
+            Some(name) => variables.get(name).map_or(vec![], |v| v.values.clone()),
+            None => vec![m.color.clone()]
+        };
+        for color in color_names {
+            if !colors.contains_key(&color) {
+                return Err(anyhow!("Named color: {} in cue: {} not in color map", color, m.cue))
+            }
+            used_colors.insert(color);
+        }
+        Ok(())
+    };
+
+    let mut mappings = show.mappings.clone();
+    for m in mappings.iter_mut() {
+        resolve_mapping(m)?;
+    }
+    let mut resolved_clips = show.clips.clone();
+    for steps in resolved_clips.values_mut() {
+        for step in steps.iter_mut() {
+            if let ClipStep::MappingOn(m) = step {
+                resolve_mapping(m)?;
+            }
+        }
+    }
+    show.mappings = mappings;
+    show.clips = resolved_clips;
+    show.colors.retain(|name, _| used_colors.contains(name));
+    Ok(())
+}
+
+/// compile the show at show_path into a binary cue file at out_path
+pub fn compile(show_path: &Path, out_path: &Path) -> Result<()> {
+    let mut show = load(show_path)?;
+    resolve(&mut show)?;
+    let mut buf = CUE_FILE_MAGIC.to_vec();
+    buf.push(CUE_FILE_VERSION);
+    buf.extend(rmp_serde::to_vec(&show).context("Could not encode compiled show")?);
+    fs::write(out_path, &buf).context("Could not write compiled show")?;
+    println!("Compiled {:?} to {:?} ({} bytes)", show_path, out_path, buf.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    /// a loaded show as json, to compare shows by (ShowDefinition isn't PartialEq)
+    fn loaded(name: &str) -> serde_json::Value {
+        serde_json::to_value(load(&fixture(name)).unwrap()).unwrap()
+    }
+
+    /// write the named files (json) to a directory of their own, returning its path
+    fn write_shows(files: &[(&str, serde_json::Value)]) -> PathBuf {
+        static RUN: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("chs-includes-{}-{}", std::process::id(),
+            RUN.fetch_add(1, std::sync::atomic::Ordering::Relaxed)));
+        for (name, show) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, show.to_string()).unwrap();
+        }
+        dir
+    }
+
+    fn receiver(id: u8, name: &str) -> serde_json::Value {
+        json!({ "id": id, "name": name, "led_count": 30 })
+    }
+
+    fn red(v: u8) -> serde_json::Value {
+        json!({ "h": 0, "s": 255, "v": v })
+    }
+
+    #[test]
+    fn later_files_win_and_lists_join_in_order() {
+        let dir = write_shows(&[
+            ("show.json", json!({ "include": [ "first.json", "second.json" ],
+                "receivers": [ receiver(3, "show") ], "colors": { "red": red(3) } })),
+            ("first.json", json!({ "receivers": [ receiver(1, "first") ],
+                "colors": { "red": red(1), "blue": { "h": 170, "s": 255, "v": 255 } }, "simulate_clips": true })),
+            ("second.json", json!({ "receivers": [ receiver(2, "second") ], "colors": { "red": red(2) },
+                "simulate_clips": false }))
+        ]);
+        let show = load(&dir.join("show.json")).unwrap();
+        let names: Vec<&str> = show.receivers.iter().filter_map(|r| r.name.as_deref()).collect();
+        assert_eq!(names, vec!["first", "second", "show"]);
+        assert_eq!(show.colors["red"].v, 3);
+        assert_eq!(show.colors["blue"].h, 170);
+        assert_eq!(show.simulate_clips, Some(false));
+        assert_eq!(show.include.unwrap().len(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn nested_includes_are_found_from_the_file_including_them() {
+        let dir = write_shows(&[
+            ("show.json", json!({ "include": [ "parts/rig.json" ] })),
+            ("parts/rig.json", json!({ "include": [ "left.json", "../right.json" ] })),
+            ("parts/left.json", json!({ "receivers": [ receiver(1, "left") ] })),
+            ("right.json", json!({ "receivers": [ receiver(2, "right") ] }))
+        ]);
+        let show = load(&dir.join("show.json")).unwrap();
+        let names: Vec<&str> = show.receivers.iter().filter_map(|r| r.name.as_deref()).collect();
+        assert_eq!(names, vec!["left", "right"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_may_share_an_include_but_not_include_themselves() {
+        let dir = write_shows(&[
+            ("show.json", json!({ "include": [ "left.json", "right.json" ] })),
+            ("left.json", json!({ "include": [ "colors.json" ], "receivers": [ receiver(1, "left") ] })),
+            ("right.json", json!({ "include": [ "colors.json" ], "receivers": [ receiver(2, "right") ] })),
+            ("colors.json", json!({ "colors": { "red": red(255) } })),
+            ("loop.json", json!({ "include": [ "back.json" ] })),
+            ("back.json", json!({ "include": [ "loop.json" ] }))
+        ]);
+        let show = load(&dir.join("show.json")).unwrap();
+        assert_eq!(show.receivers.len(), 2);
+        assert_eq!(show.include.unwrap().len(), 3);
+        let error = format!("{:#}", load(&dir.join("loop.json")).unwrap_err());
+        assert!(error.contains("loop.json includes itself"), "{}", error);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// each mapping's cue and id, the top level ones then the clips' in name order
+    fn mapping_ids(show: &ShowDefinition) -> Vec<(String, usize)> {
+        let mut clips: Vec<_> = show.clips.iter().collect();
+        clips.sort_by_key(|(name, _)| name.as_str());
+        let clip_mappings = clips.into_iter().flat_map(|(_, steps)| steps.iter()).filter_map(|step|
+            if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+        show.mappings.iter().chain(clip_mappings).map(|m| (m.cue.clone(), m.get_id())).collect()
+    }
+
+    #[test]
+    fn mapping_ids_are_the_same_every_time_a_show_loads() {
+        let step = |cue: &str| json!({ "MappingOn": { "cue": cue, "light": { "Effect": "Pop" }, "color": "red" } });
+        let clips: serde_json::Map<String, serde_json::Value> = ["delta", "alpha", "echo", "charlie", "bravo"].iter()
+            .map(|name| (name.to_string(), json!([ step(&format!("{} 1", name)), step(&format!("{} 2", name)), "End" ])))
+            .collect();
+        let dir = write_shows(&[
+            ("show.json", json!({ "include": [ "more.json" ], "colors": { "red": red(255) }, "receivers": [ receiver(1, "a"), receiver(2, "b"), receiver(3, "c") ],
+                "mappings": [
+                    { "cue": "run", "midi": { "NoteRange": { "channel": 0, "low": "C4", "high": "D4" } }, "light": { "Effect": "Pop" },
+                      "color": "red", "targets": [ "a", "b", "c" ] },
+                    { "cue": "clip", "midi": { "Note": { "channel": 0, "note": "F4" } }, "light": { "Clip": "alpha" }, "color": "red" }
+                ],
+                "clips": clips })),
+            ("more.json", json!({ "mappings": [
+                { "cue": "included", "midi": { "Note": { "channel": 1, "note": "C4" } }, "light": { "Effect": "Pop" }, "color": "red" }
+            ] }))
+        ]);
+        let first = mapping_ids(&load(&dir.join("show.json")).unwrap());
+        let ids: Vec<usize> = first.iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids, (0..first.len()).collect::<Vec<_>>());
+        assert_eq!(first[first.len() - 10], ("alpha 1".to_string(), first.len() - 10));
+        // whatever order the clips come back in
+        for _ in 0..4 {
+            assert_eq!(mapping_ids(&load(&dir.join("show.json")).unwrap()), first);
+        }
+        // and compiled, which resolves the show first
+        compile(&dir.join("show.json"), &dir.join("show.cue")).unwrap();
+        assert_eq!(mapping_ids(&load(&dir.join("show.cue")).unwrap()), first);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn mappings_keep_the_ids_they_are_given() {
+        let mapping = |cue: &str, id: Option<usize>| json!({ "cue": cue, "id": id, "light": { "Effect": "Pop" }, "color": "red" });
+        let show = |mappings: Vec<serde_json::Value>| json!({ "colors": { "red": red(255) }, "receivers": [ receiver(1, "a") ], "mappings": mappings });
+        let dir = write_shows(&[
+            ("show.json", show(vec![ mapping("intro", Some(100)), mapping("verse", None), mapping("chorus", Some(200)) ])),
+            ("edited.json", show(vec![ mapping("bridge", None), mapping("chorus", Some(200)), mapping("intro", Some(100)) ])),
+            ("twice.json", show(vec![ mapping("intro", Some(100)), mapping("outro", Some(100)) ])),
+            ("clash.json", show(vec![ mapping("intro", Some(1)), mapping("verse", None) ]))
+        ]);
+        let ids = |file: &str| mapping_ids(&load(&dir.join(file)).unwrap());
+        assert_eq!(ids("show.json"), vec![("intro".to_string(), 100), ("verse".to_string(), 1), ("chorus".to_string(), 200)]);
+        assert_eq!(ids("edited.json"), vec![("bridge".to_string(), 0), ("chorus".to_string(), 200), ("intro".to_string(), 100)]);
+        compile(&dir.join("show.json"), &dir.join("show.cue")).unwrap();
+        assert_eq!(ids("show.cue"), ids("show.json"));
+        // the same id twice, even when one of them is a position, is an error
+        for (file, message) in [("twice.json", "cue: outro: id 100 is already cue: intro's"), ("clash.json", "cue: verse: id 1 is already cue: intro's")] {
+            let error = format!("{:#}", load(&dir.join(file)).unwrap_err());
+            assert!(error.contains(message), "{}", error);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn yaml_and_json5_shows_load_as_their_json_does() {
+        let json = loaded("show.json");
+        assert_eq!(json["mappings"].as_array().unwrap().len(), 3);
+        assert_eq!(loaded("show.yaml"), json);
+        assert_eq!(loaded("show.json5"), json);
+    }
+}
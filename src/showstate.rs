@@ -1,23 +1,71 @@
-use log::{debug,info};
+use log::{debug,info,warn,error};
 use std::cmp::min;
 use std::rc::Rc;
 use std::time::{Duration,Instant};
-use std::collections::{HashMap};
+use std::thread::sleep;
+use std::collections::{BTreeMap,BTreeSet,HashMap,HashSet,VecDeque};
 use std::cell::RefCell;
-use midly::live::LiveEvent;
+use midly::live::{LiveEvent,SystemCommon,SystemRealtime};
 use midly::MidiMessage;
 use midly::num::{u4,u7};
-use musical_note::ResolvedNote;
-use anyhow::{Result, anyhow};
-
-use crate::config::ConfigFile;
-use crate::radio::{Radio,RadioError};
-use crate::show::{ClipStep, Color, Effect, LightMapping, LightMappingType, MidiMappingType, ShowDefinition};
-use crate::packet::{Command, Packet, PacketPayload, ShowPacket, GROUP_ID_RANGE};
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize,Serialize};
+use rand::seq::SliceRandom;
+use crossbeam_channel::Sender;
+
+use crate::config::{BrightnessSchedule, ConfigFile};
+use crate::radio::{LinkQuality,Radio,RadioError,ReceiverTelemetry};
+use crate::show::{ClipStep, Color, ControlDefinition, ControlledParameter, DmxColorMode, DmxPatch, Effect, LightMapping, LightMappingType, MidiMappingType, ModulationTarget, RandomSubset, ReceiverConfiguration, RetriggerPolicy, ShowDefinition, SongDefinition, parse_note, substitute_targets, variable_reference};
+use crate::packet::{Command, EffectId, Packet, PacketPayload, ShowPacket, CURRENT_FIRMWARE, GROUP_ID_RANGE, POLL_FIRMWARE, PRESET_FIRMWARE, SLEEP_FIRMWARE, STATUS_FIRMWARE, ANNOUNCE_FIRMWARE};
 use crate::clip::ClipEngine;
+use crate::feedback::Pad;
+use crate::padsetup::AssignedPad;
+use crate::midiclock::{MidiClock, SongPosition};
+use crate::timecode::{self, FrameRate, MtcReader};
+use crate::hooks::HookRunner;
+use crate::matrix;
+use crate::clock;
+use crate::error::ChsError;
 
 const SUSTAIN_CONTROLLER: u8 = 64;
 const TEST_CONTROLLER : u8 = 102;
+const HUE_SHIFT_CONTROLLER: u8 = 104;
+const SATURATION_TRIM_CONTROLLER: u8 = 105;
+const VALUE_TRIM_CONTROLLER: u8 = 106;
+const REPLAY_CONTROLLER: u8 = 107;
+const TEMPO_LEARN_CONTROLLER: u8 = 108;
+const GRAND_MASTER_CONTROLLER: u8 = 109;
+const CROSSFADE_CONTROLLER: u8 = 110;
+const PREVIEW_CONTROLLER: u8 = 111;
+/// the controllers on the control channel the transmitter itself answers to
+const SPECIAL_CONTROLLERS: [u8; 10] = [SUSTAIN_CONTROLLER, TEST_CONTROLLER, HUE_SHIFT_CONTROLLER, SATURATION_TRIM_CONTROLLER,
+    VALUE_TRIM_CONTROLLER, REPLAY_CONTROLLER, TEMPO_LEARN_CONTROLLER, GRAND_MASTER_CONTROLLER, CROSSFADE_CONTROLLER, PREVIEW_CONTROLLER];
+
+const DEFAULT_HISTORY_DEPTH: usize = 64;
+const DEFAULT_CONFIG_PACKET_SPACING: u64 = 2;
+const DEFAULT_CROSSFADE_MILLIS: u32 = 2000;
+/// the longest attack or release a packet can carry
+const MAX_CROSSFADE_MILLIS: u32 = 12_700;
+/// how often the time of day is checked against the brightness schedule
+const BRIGHTNESS_CHECK_PERIOD: Duration = Duration::from_secs(30);
+const DEFAULT_CONFIG_CHUNK_SIZE: usize = 8;
+const DEFAULT_CONFIG_CHUNK_PAUSE: u64 = 50;
+const DEFAULT_ACK_TIMEOUT: u64 = 30;
+const DEFAULT_ACK_RETRIES: u32 = 3;
+const DEFAULT_POLL_TIMEOUT: u64 = 50;
+const DEFAULT_MARGINAL_RSSI: i16 = -80;
+const DEFAULT_LOW_BATTERY_MILLIVOLTS: u16 = 3500;
+/// how many of each receiver's most recent link polls adaptive repeats go by
+const LINK_HISTORY_LENGTH: usize = 8;
+const DEFAULT_ADAPTIVE_REPEAT_BUDGET: u32 = 20;
+/// how long to listen for announcements each time, long enough to hear a packet or two
+const ANNOUNCEMENT_LISTEN_WINDOW: Duration = Duration::from_millis(20);
+const DEFAULT_SYNTH_FADE_STEP: u64 = 50;
+const DEFAULT_MODULATION_DEPTH: u8 = 100;
+/// the most packets a synthesized fade sends, however long it is
+const MAX_SYNTH_FADE_STEPS: u32 = 64;
+/// the furthest the time code can move on between positions and still be playing, rather than located
+const MAX_TIMECODE_STEP: Duration = Duration::from_secs(1);
 
 const ALL_RECIPIENTS: Vec<u8> = vec![];
 
@@ -58,17 +106,103 @@ pub struct ShowState<'a,'b> {
     /// a map to lookup the u8 ids for named targets
     target_lookup: HashMap<String,u8>,
 
+    /// named targets that expand to several receivers (matrices and their rows/columns)
+    matrix_targets: HashMap<String,Vec<u8>>,
+
+    /// firmware generation of receivers marked as running older firmware
+    receiver_firmware: HashMap<u8,u8>,
+
+    /// receivers whose strips run the opposite way to the rest
+    reversed_receivers: HashSet<u8>,
+
+    /// how likely receivers given a weight are to be picked for random subsets
+    random_weights: HashMap<u8,f32>,
+
     /// midi channel/note to light mapping key
     note_mappings: HashMap<(u4,u7), Vec<usize>>,
 
     /// midi channel/cc to light mapping key
     controller_mappings: HashMap<(u4,u7), Vec<usize>>,
+
+    /// light mapping keys with the time code position (hh, mm, ss, ff) that fires them
+    timecode_mappings: Vec<(usize, [u8; 4])>,
+
+    /// midi channel/cc to the runtime variable it picks the value of
+    variable_controls: HashMap<(u4,u7), String>,
+
+    /// midi channel/cc to the parameter it continuously sets
+    controls: HashMap<(u4,u7), Control<'b>>,
+
+    /// the receivers patched into DMX, with the recipients they resolve to
+    dmx_patches: Vec<(&'b DmxPatch, Vec<u8>)>,
+
+    /// the light mapping keys of each scene's cues
+    scene_mappings: HashMap<String, Vec<usize>>,
+
+    /// the light mapping keys of the cues of each step of the cue list
+    cue_list_steps: Vec<Vec<usize>>,
+
+    /// midi channel/note and channel/cc to the move through the cue list they make
+    cue_list_notes: HashMap<(u4,u7), CueListAction>,
+    cue_list_controllers: HashMap<(u4,u7), CueListAction>,
+
+    /// the channel/cc whose value jumps to a step of the cue list
+    cue_list_jump: Option<(u4,u7)>,
+
+    /// the group id of the configured preview group
+    preview_group: Option<u8>,
+
+    /// the configured brightness through the day
+    brightness_schedule: Option<BrightnessSchedule>,
+
+    /// runs mappings' exec hooks, if the config enables them
+    hooks: Option<HookRunner>,
     
     /// a map from a named clip to the play state of that clip
     /// note that the clip engine uses interior mutability so we can treat it as immutable
     clip_engine: ClipEngine<'b>,
 }
 
+/// a continuous control, with the receivers and mappings it sets resolved
+struct Control<'b> {
+    definition: &'b ControlDefinition,
+    recipients: Vec<u8>,
+    /// the light mapping keys of a Param1 control's cue
+    mappings: Vec<usize>
+}
+
+/// a continuous input whose sends are spaced out by an interval: a control (or the tempo
+/// knob) by its channel and cc, or a channel's pitch bend or pressure
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+enum Sweep {
+    Control(u4,u7),
+    PitchBend(u4),
+    Pressure(u4)
+}
+
+/// what a continuous input last sent, and any value held back by its interval
+#[derive(Debug,Clone,Copy)]
+struct ControlSend {
+    sent: Instant,
+    value: u8,
+    pending: bool
+}
+
+/// what the radio heard back from a receiver in the background, for the director to
+/// hand back to take_report
+#[derive(Debug)]
+pub enum RadioReport {
+    /// how a receiver answered a link poll, or None if it didn't
+    LinkPolled { receiver: u8, quality: Option<LinkQuality> },
+
+    /// how a receiver answered a status query, or None if it didn't. the last query of a
+    /// round asked for from the console carries where to send the status afterwards
+    StatusQueried { receiver: u8, telemetry: Option<ReceiverTelemetry>, reply: Option<Sender<Result<ShowStatus>>> },
+
+    /// the receivers heard announcing themselves (with their firmware) since last listened for
+    Announced(Vec<(u8,u8)>)
+}
+
 /// mutable state associated with the show (receiver and clip state)
 /// as well as things with references to the immutable show state. 
 /// (including those things in the immutable show state would create 
@@ -80,6 +214,35 @@ pub struct MutableShowState<'a> {
 
     /// the last time we sent a timeout-driven "lights out" packet
     last_lights_out: Instant,
+
+    /// the last time we sent a "transmitter alive" heartbeat packet
+    last_heartbeat: Instant,
+
+    /// the last time we polled a receiver for link quality, and which to poll next
+    last_poll: Instant,
+    next_poll: usize,
+
+    /// the link quality each polled receiver last answered with, or None if it didn't answer
+    links: BTreeMap<u8,Option<LinkQuality>>,
+
+    /// whether each of a receiver's most recent link polls came back marginal, oldest first
+    link_history: BTreeMap<u8,VecDeque<bool>>,
+
+    /// when the current second of adaptive repeats started, and how many it has sent
+    adaptive_repeats: (Instant, u32),
+
+    /// the last time we listened for receivers announcing themselves
+    last_listen: Instant,
+
+    /// the last time we queried a receiver's status, and which to query next
+    last_telemetry_poll: Instant,
+    next_telemetry_poll: usize,
+
+    /// what each queried receiver last reported about itself (None if it didn't answer), and when
+    telemetry: BTreeMap<u8,(Option<ReceiverTelemetry>,Instant)>,
+
+    /// where the radio reports what it heard in the background, if anyone is listening
+    reports: Option<Sender<RadioReport>>,
     
     /// quick lookup from light mapping key to the data about that light mapping
     light_mappings: HashMap<usize,LightMappingMeta<'a>>,
@@ -91,9 +254,276 @@ pub struct MutableShowState<'a> {
     sustain: bool,
 
     /// a buffer of pending effect ids that should be disabled 
-    pending_off: Vec<usize>
+    pending_off: Vec<usize>,
+
+    /// live operator adjustment applied to the color of every outgoing show packet
+    color_transform: ColorTransform,
+
+    /// bounded history of the most recent live (midi) activations and deactivations
+    history: VecDeque<HistoryEntry>,
+
+    /// history entries rescheduled relative to now, waiting to be re-fired by tick
+    replay_queue: VecDeque<HistoryEntry>,
+
+    /// fades the transmitter is stepping through for synth_fade mappings
+    synth_fades: Vec<SynthFade>,
+
+    /// the song whose bank of mappings currently responds to midi, if the show has songs
+    active_song: Option<String>,
+
+    /// the step of the cue list last gone to, if it's started
+    cue_list_step: Option<usize>,
+
+    /// the channel/cc of the tempo knob, if configured or learned
+    tempo_binding: Option<(u4,u7)>,
+
+    /// is the next controller message going to be learned as the tempo knob
+    tempo_learn: bool,
+
+    /// tempo set from the tempo knob, which newly started clips use too
+    tempo_override: Option<f32>,
+
+    /// the tempo of the midi clock, if following it, which beats the tempo knob
+    midi_clock: MidiClock,
+
+    /// the hue offset each channel's pitch bend adds to the cues triggered from it
+    hue_bends: HashMap<u8,u8>,
+
+    /// the pressure (channel aftertouch) on each channel, modulating the cues triggered from it
+    pressures: HashMap<u8,u8>,
+
+    /// what each continuous input last sent, and the first effect parameter the
+    /// Param1 controls have set mappings to
+    control_sends: HashMap<Sweep,ControlSend>,
+    param1_overrides: HashMap<usize,u8>,
+
+    /// the color last sent to each DMX patch, by its place in the show's list
+    dmx_colors: HashMap<usize,Color>,
+
+    /// the midi time code position, as it's pieced together and where it last was
+    mtc: MtcReader,
+    timecode: Option<Duration>,
+
+    /// packets for realtime mappings, marshalled ahead of time so they can be sent
+    /// with as little work as possible. rebuilt when the color transform changes
+    realtime_packets: HashMap<usize,Vec<u8>>,
+
+    /// cues fired live since the director last collected them, for the session log
+    fired_cues: Vec<String>,
+
+    /// when the midi message currently being processed arrived
+    midi_received: Option<Instant>,
+
+    /// groups muted for rehearsal, and their member receivers. effects skip these targets
+    muted: HashSet<u8>,
+
+    /// is every cue being redirected to the preview group, leaving the real targets alone
+    preview: bool,
+
+    /// have the receivers been put to sleep. lights-out and heartbeat packets are held
+    /// back meanwhile, so they don't wake receivers that wake on any packet
+    asleep: bool,
+
+    /// the current value of each runtime variable
+    variables: HashMap<String,String>,
+
+    /// tags whose cues are muted, and live changes to the envelopes of tagged cues
+    muted_tags: HashSet<String>,
+    tag_adjustments: BTreeMap<String,EffectOverrides>,
+
+    /// mappings that were showing when a variable they use changed, which pick up
+    /// the new value the next time they're activated (so their off goes where their on went)
+    stale_mappings: HashSet<usize>,
+
+    /// when the brightness schedule was last checked
+    brightness_checked: Option<Instant>,
+
+    /// latency from midi arriving to packets being sent, for realtime mappings and the rest
+    pub realtime_latency: LatencyStats,
+    pub standard_latency: LatencyStats
+}
+
+/// a cue as the HTTP API lists it
+#[derive(Debug,Serialize,Deserialize)]
+pub struct CueListing {
+    pub cue: String,
+    /// the songs its mappings are limited to
+    pub songs: Vec<String>,
+    /// showing on at least one receiver, or its clip playing
+    pub active: bool
+}
+
+/// a receiver as the dashboard shows it
+pub struct ReceiverSummary {
+    pub id: u8,
+    pub name: Option<String>,
+    pub group: Option<String>,
+    /// the cue it's showing
+    pub cue: Option<String>,
+    /// when it was last sent a packet, if the radio is tracking that
+    pub last_sent: Option<Instant>
+}
+
+/// what the websocket and MQTT watch for changes to, taken after every turn of the show loop
+#[derive(Debug,Clone,PartialEq)]
+pub struct ShowSnapshot {
+    /// cues showing on at least one receiver
+    pub active_cues: BTreeSet<String>,
+    pub playing_clips: BTreeSet<String>,
+    /// the cue each receiver is showing, by receiver name
+    pub receivers: BTreeMap<String,Option<String>>,
+    /// when the last lights-out packet went out
+    pub last_lights_out: Instant
+}
+
+impl ShowSnapshot {
+    /// everything needed to start following the show
+    pub fn state_event(self: &Self) -> ShowEvent {
+        ShowEvent::State {
+            active_cues: self.active_cues.clone(),
+            playing_clips: self.playing_clips.clone(),
+            receivers: self.receivers.clone()
+        }
+    }
+
+    /// the events taking the show from this snapshot to the next
+    pub fn changes_to(self: &Self, after: &ShowSnapshot) -> Vec<ShowEvent> {
+        let mut events: Vec<ShowEvent> = vec![];
+        events.extend(self.active_cues.difference(&after.active_cues).map(|cue| ShowEvent::CueDeactivated { cue: cue.clone() }));
+        events.extend(after.active_cues.difference(&self.active_cues).map(|cue| ShowEvent::CueActivated { cue: cue.clone() }));
+        events.extend(self.playing_clips.difference(&after.playing_clips).map(|clip| ShowEvent::ClipStopped { clip: clip.clone() }));
+        events.extend(after.playing_clips.difference(&self.playing_clips).map(|clip| ShowEvent::ClipStarted { clip: clip.clone() }));
+        events.extend(after.receivers.iter()
+            .filter(|(receiver, cue)| self.receivers.get(*receiver) != Some(cue))
+            .map(|(receiver, cue)| ShowEvent::Receiver { receiver: receiver.clone(), cue: cue.clone() }));
+        if after.last_lights_out != self.last_lights_out {
+            events.push(ShowEvent::LightsOut);
+        }
+        events
+    }
+}
+
+/// something happening in the show, as the websocket and MQTT send it
+#[derive(Debug,Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ShowEvent {
+    /// everything a client needs to start following the show
+    State { active_cues: BTreeSet<String>, playing_clips: BTreeSet<String>, receivers: BTreeMap<String,Option<String>> },
+    CueActivated { cue: String },
+    CueDeactivated { cue: String },
+    ClipStarted { clip: String },
+    ClipStopped { clip: String },
+    /// the cue a receiver is showing changed, none when it went dark
+    Receiver { receiver: String, cue: Option<String> },
+    LightsOut,
+    /// an error the show carried on after, or that stopped it
+    Error { error: String }
+}
+
+/// a snapshot of what the show is doing, for the control socket
+#[derive(Debug,Serialize,Deserialize)]
+pub struct ShowStatus {
+    pub show_file: String,
+    pub active_song: Option<String>,
+    /// cues showing on at least one receiver
+    pub active_cues: Vec<String>,
+    pub playing_clips: Vec<String>,
+    pub tempo: Option<f32>,
+    pub muted_groups: Vec<String>,
+    pub preview: bool,
+    pub asleep: bool,
+    pub variables: BTreeMap<String,String>,
+    pub muted_tags: Vec<String>,
+    /// the step of the cue list last gone to, counting from 0, if it's started
+    pub cue_list_step: Option<usize>,
+    /// the last poll of each receiver polled for link quality
+    pub links: Vec<ReceiverLink>,
+    /// the id the next packet goes out with, once one has been sent
+    pub packet_id: Option<u8>,
+    /// what each receiver queried for its status last reported
+    pub telemetry: Vec<ReceiverStatus>
+}
+
+/// what a receiver reported the last time it was asked for its status
+#[derive(Debug,Serialize,Deserialize)]
+pub struct ReceiverStatus {
+    pub receiver: String,
+    /// None if the receiver didn't answer
+    pub telemetry: Option<ReceiverTelemetry>,
+    /// how long ago it was asked, in seconds
+    pub age_secs: u64,
+    pub low_battery: bool
+}
+
+/// how well the transmitter and a receiver heard each other when last polled
+#[derive(Debug,Serialize,Deserialize)]
+pub struct ReceiverLink {
+    pub receiver: String,
+    /// None if the receiver didn't answer
+    pub quality: Option<LinkQuality>,
+    pub marginal: bool
+}
+
+/// running statistics on how long activations took
+#[derive(Default)]
+pub struct LatencyStats {
+    count: u32,
+    total: Duration,
+    max: Duration
+}
+
+impl LatencyStats {
+    fn record(self: &mut Self, latency: Duration) {
+        self.count = self.count + 1;
+        self.total = self.total + latency;
+        self.max = self.max.max(latency);
+    }
+}
+
+impl std::fmt::Display for LatencyStats {
+    fn fmt(self: &Self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.count == 0 {
+            write!(f, "no samples")
+        } else {
+            write!(f, "{} samples, mean {}us, max {}us", self.count, 
+                self.total.as_micros() / self.count as u128, self.max.as_micros())
+        }
+    }
+}
+
+/// a live trigger of a mapping, as remembered for "instant replay"
+#[derive(Clone,Copy)]
+pub struct HistoryEntry {
+    pub at: Instant,
+    pub mapping_id: usize,
+    pub on: bool
+}
+
+/// a global hue offset and saturation/value trim, controlled live from the
+/// control channel, so the look of the whole field can be warmed or cooled on the fly
+#[derive(Clone,Copy)]
+struct ColorTransform {
+    /// added to the hue (wrapping around the color wheel)
+    hue_offset: u8,
+    /// saturation is scaled by saturation_trim/127
+    saturation_trim: u8,
+    /// value is scaled by value_trim/127
+    value_trim: u8
+}
+
+impl ColorTransform {
+    const IDENTITY: ColorTransform = ColorTransform { hue_offset: 0, saturation_trim: 127, value_trim: 127 };
+
+    pub fn apply(self: &Self, color: Color) -> Color {
+        Color {
+            h: color.h.wrapping_add(self.hue_offset),
+            s: (color.s as u16 * self.saturation_trim as u16 / 127) as u8,
+            v: (color.v as u16 * self.value_trim as u16 / 127) as u8
+        }
+    }
 }
 
+#[derive(Clone,Default)]
 pub struct EffectOverrides {
     pub color: Option<Color>,
     pub tempo: Option<f32>,
@@ -102,50 +532,109 @@ pub struct EffectOverrides {
     pub release: Option<u32>
 }
 
-/// tracks the last instruction sent to a particular receiver, so
-/// we know what it's doing
-#[derive(Clone,Copy)]
+/// a change made live to every cue carrying a tag, without editing the show file
+#[derive(Debug,Clone,Serialize,Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum TagOperation {
+    /// the tagged cues do nothing when triggered
+    Mute,
+    Unmute,
+    /// override the tagged cues' envelope times, in milliseconds
+    Adjust { attack: Option<u32>, sustain: Option<u32>, release: Option<u32> },
+    /// drop the adjustments to the tagged cues
+    Reset
+}
+
+/// a move through the show's cue list
+#[derive(Debug,Clone,Copy,PartialEq,Serialize,Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CueListAction {
+    /// on to the next step (the first, if the cue list hasn't started)
+    Go,
+    /// back a step, or off the first one
+    Back,
+    /// straight to the step, counting from 0
+    Jump(usize)
+}
+
+/// tracks the mappings on a particular receiver, so we know what it's doing: the one
+/// showing, and those layered beneath it that show again once it's released
+#[derive(Clone)]
 struct ReceiverState {
     pub id: u8,
-    trigger_mapping: usize
+    /// the ids and priorities of the mappings on the receiver, in the order they were
+    /// activated. the latest of the highest priority is the one showing
+    layers: Vec<(usize, u8)>
 }
 
 impl ReceiverState {
-    const INACTIVE: usize = 0;
 
     pub fn new(id: u8) -> Self {
         Self {
             id,
-            trigger_mapping: Self::INACTIVE
+            layers: vec![]
         }
     }
 
+    /// a one shot covers everything up to its priority, which is gone once it fades
     pub fn activate(self: &mut Self, mapping: &LightMapping) {
-        self.trigger_mapping = match mapping {
-            _ if !mapping.one_shot.unwrap_or(false) => mapping.get_id(),
-            _ => Self::INACTIVE
+        let priority = mapping.priority.unwrap_or(0);
+        if mapping.one_shot.unwrap_or(false) {
+            if !self.holds_above(priority) {
+                self.layers.clear();
+            }
+        } else {
+            self.layers.retain(|(id, _)| *id != mapping.get_id());
+            self.layers.push((mapping.get_id(), priority));
         }
     }
 
+    /// the id of the mapping showing on the receiver, if any
+    pub fn showing(self: &Self) -> Option<usize> {
+        // max_by_key picks the last of equals, ie the latest activated
+        self.layers.iter().max_by_key(|(_, priority)| *priority).map(|(id, _)| *id)
+    }
+
+    /// the id of the mapping that shows once the one showing is released, if any
+    pub fn beneath(self: &Self) -> Option<usize> {
+        let showing = self.showing()?;
+        self.layers.iter().filter(|(id, _)| *id != showing).max_by_key(|(_, priority)| *priority).map(|(id, _)| *id)
+    }
+
     pub fn activated_by(self: &Self, mapping: &LightMapping) -> bool {
-        let mapping_id = mapping.get_id();
-        self.trigger_mapping == mapping_id
+        self.showing() == Some(mapping.get_id())
     }
 
+    /// is a mapping of higher priority than this showing on the receiver
+    pub fn holds_above(self: &Self, priority: u8) -> bool {
+        self.layers.iter().any(|(_, p)| *p > priority)
+    }
+
+    /// take the mapping off the receiver, returning whether it was the one showing
     pub fn deactivate(self: &mut Self, mapping: &LightMapping) -> bool {
-        let result = self.trigger_mapping == mapping.get_id();
-        if result {
-            self.trigger_mapping = Self::INACTIVE;
-        }
+        let result = self.activated_by(mapping);
+        self.layers.retain(|(id, _)| *id != mapping.get_id());
         result
     }
 
+    pub fn clear(self: &mut Self) {
+        self.layers.clear();
+    }
+
     pub fn is_active(self: &Self) -> bool {
-        self.trigger_mapping != Self::INACTIVE
+        !self.layers.is_empty()
     }
 
 }
 
+/// the attack, sustain and release of a mapping's effect in milliseconds, overrides first
+fn envelope(mapping_meta: &LightMappingMeta, overrides: &Option<EffectOverrides>) -> (u32, u32, u32) {
+    let overrides = overrides.as_ref();
+    (overrides.and_then(|o| o.attack).or(mapping_meta.source.attack).unwrap_or(0),
+        overrides.and_then(|o| o.sustain).or(mapping_meta.source.sustain).unwrap_or(0),
+        overrides.and_then(|o| o.release).or(mapping_meta.source.release).unwrap_or(0))
+}
+
 /// in JSON we represent time as milliseconds, but the radio format is a bit tricker to save space
 /// attack and decay values less then 1.279 seconds are sent in units of hundredths of a second,
 /// while values greaten than that are sent in tenths of seconds (idea being the resolution matters
@@ -159,7 +648,7 @@ fn convert_millis_adr(millis: u32) -> u8 {
 
 /// sustain is sent in tenths of seconds up until 12.799 seconds, then whole seconds after that
 /// sustain of zero means "on until an off command"
-fn convert_millis_sustain(millis: u32) -> u8 {
+pub fn convert_millis_sustain(millis: u32) -> u8 {
     match millis {
         0 => 255, 
         1..=12799 => ((millis / 100) & 0x7F) as u8,
@@ -167,6 +656,49 @@ fn convert_millis_sustain(millis: u32) -> u8 {
     }
 }
 
+/// a fade in the transmitter is synthesizing for a synth_fade mapping: Pop packets with
+/// the brightness stepped up over the attack, then the effect itself without an attack
+struct SynthFade {
+    mapping_id: usize,
+    recipients: Vec<u8>,
+    /// the effect to send once the fade is done
+    packet: ShowPacket,
+    start: Instant,
+    duration: Duration,
+    steps: u32,
+    /// how many steps have gone out
+    done: u32
+}
+
+impl SynthFade {
+    fn new(mapping_id: usize, recipients: Vec<u8>, packet: ShowPacket, start: Instant, duration: Duration, step: Duration) -> SynthFade {
+        let steps = (duration.as_millis().div_ceil(step.as_millis().max(1)) as u32).clamp(1, MAX_SYNTH_FADE_STEPS);
+        SynthFade { mapping_id, recipients, packet: ShowPacket { attack: 0, ..packet }, start, duration, steps, done: 0 }
+    }
+
+    /// the latest step due by now, if it hasn't gone out yet
+    fn due_step(self: &Self, now: Instant) -> Option<u32> {
+        let elapsed = now.saturating_duration_since(self.start).as_millis();
+        let step = (elapsed * self.steps as u128 / self.duration.as_millis().max(1)).min(self.steps as u128) as u32;
+        (step > self.done).then_some(step)
+    }
+
+    /// when the next step is due
+    fn next_at(self: &Self) -> Instant {
+        self.start + self.duration.mul_f64((self.done + 1) as f64 / self.steps as f64)
+    }
+
+    /// the packet for a step: a Pop part of the way up to the effect's brightness, or
+    /// the effect itself for the last
+    fn step_packet(self: &Self, step: u32) -> ShowPacket {
+        if step >= self.steps {
+            return self.packet
+        }
+        let v = (self.packet.color.v as u32 * step / self.steps) as u8;
+        ShowPacket { effect: EffectId::Pop, color: Color { v, ..self.packet.color }, sustain: 255, ..ShowPacket::OFF_PACKET }
+    }
+}
+
 /// a wrapper around a light mapping that stashes a reference to the source mapping,
 /// and the resolved target vector for packets, as well as a vector to references
 /// to all the receiver state instances to update when the mapping is triggered
@@ -174,7 +706,29 @@ struct LightMappingMeta<'a> {
     pub color: Color,
     pub source: &'a LightMapping,
     pub targets: Vec<u8>,
-    pub receivers: Vec<Rc<RefCell<ReceiverState>>>
+    pub receivers: Vec<Rc<RefCell<ReceiverState>>>,
+    /// for one shot mappings with a finite sustain, when the last activation's envelope ends
+    pub envelope_end: Option<Instant>,
+    /// if some targeted receivers run firmware too old for the effect, the targets for
+    /// the unmodified packet (the rest of the receivers) and the substitutes for the old ones
+    pub primary_targets: Option<Vec<u8>>,
+    pub shims: Vec<EffectShim>,
+    /// targeted receivers marked as reversed, which get a chase with its direction flipped
+    pub reversed_targets: Vec<u8>
+}
+
+/// receivers targeted by a mapping which need a substitute effect for their older firmware
+struct EffectShim {
+    pub firmware: u8,
+    pub recipients: Vec<u8>
+}
+
+impl<'a> LightMappingMeta<'a> {
+    /// is the effect of this mapping still showing on its receivers
+    fn is_active(self: &Self, now: Instant) -> bool {
+        self.envelope_end.is_some_and(|end| end > now) ||
+            self.receivers.iter().any(|r| r.borrow().activated_by(self.source))
+    }
 }
 
 /// given a target expressed as a json node of any type, convert
@@ -192,62 +746,282 @@ fn convert_target(json_value: &serde_json::Value) -> Result<String> {
     }
 }
 
+/// build the map from target names (receiver ids, receiver names, group names and group ids)
+/// to u8 ids, and the map from group ids to their members. group ids are assigned in the
+/// order groups first appear in the roster
+pub fn build_target_lookup(show: &ShowDefinition) -> (HashMap<String,u8>, HashMap<u8,Vec<u8>>) {
+    let mut target_lookup: HashMap<String,u8> = HashMap::new();
+    let mut group_members: HashMap<u8,Vec<u8>> = HashMap::new();
+    let mut group_id = GROUP_ID_RANGE.start;
+
+    for r in show.receivers.iter() {
+        // update the target lookup map
+        target_lookup.insert(r.id.to_string(), r.id);
+        if let Some(receiver_name) = &r.name {
+            target_lookup.insert(receiver_name.clone(), r.id);
+        }
+        // if the receiver is a group member, add it to the group
+        if let Some(group_name) = &r.group_name {
+            if !target_lookup.contains_key(group_name) {
+                target_lookup.insert(group_name.clone(), group_id);
+                // groups can also be targeted by their resolved id (eg in compiled shows)
+                target_lookup.insert(group_id.to_string(), group_id);
+                group_id = group_id + 1;
+            }
+            let group_id = target_lookup.get(group_name).unwrap();
+            group_members.entry(*group_id).or_insert_with(Vec::new).push(r.id);
+        }
+    }
+    (target_lookup, group_members)
+}
+
+/// picks the mappings triggered from a midi channel
+fn on_channel(channel: u4) -> impl Fn(&LightMapping) -> bool {
+    move |m| m.midi.as_ref().and_then(|m| m.channel()) == Some(channel.into())
+}
+
+/// resolve a mapping's target list to u8 receiver and group ids. no targets means all receivers
+pub fn resolve_targets(targets: &Option<Vec<serde_json::Value>>, target_lookup: &HashMap<String,u8>, 
+    matrix_targets: &HashMap<String,Vec<u8>>) -> Result<Vec<u8>> {
+    match targets {
+        None => Ok(ALL_RECIPIENTS), 
+        Some(tgts) => {
+            let mut result: Vec<u8> = vec![];
+            for json_tgt in tgts.iter() {
+                let tgt_val = convert_target(json_tgt)?;
+                if let Some(ids) = matrix_targets.get(&tgt_val) {
+                    result.extend(ids);
+                    continue;
+                }
+                let otgt = target_lookup.get(&tgt_val);
+                match otgt {
+                    Some(id) => result.push(*id),
+                    None => return Err(anyhow!("Target in target list does not match any known group or receiver: {}", tgt_val))
+                }
+            }
+            Ok(result)
+        }
+    }
+}
+
 // 'a is the lifetime of the radio (forever)
 // 'b is the lifetime of the show definition
 impl<'a,'b> ShowState<'a,'b> {
     pub fn new(show: &'b ShowDefinition, radio: &'a Radio, config: &'a ConfigFile) -> Result<ShowState<'a,'b>> {
 
-        let mut target_lookup: HashMap<String,u8> = HashMap::new();
-        let mut group_members: HashMap<u8,Vec<u8>> = HashMap::new();
-        let mut group_id = GROUP_ID_RANGE.start;
+        let (target_lookup, group_members) = build_target_lookup(show);
         let mut note_mappings: HashMap<(u4,u7), Vec<usize>> = HashMap::new();
         let mut controller_mappings: HashMap<(u4,u7), Vec<usize>> = HashMap::new();
+        let mut timecode_mappings: Vec<(usize, [u8; 4])> = vec![];
 
-        // preprocess receivers
-        for r in show.receivers.iter() {
-            // update the target lookup map
-            target_lookup.insert(r.id.to_string(), r.id);
-            if let Some(receiver_name) = &r.name {
-                target_lookup.insert(receiver_name.clone(), r.id);
-            }
-            // if the receiver is a group member, add it to the group
-            if let Some(group_name) = &r.group_name {
-                if !target_lookup.contains_key(group_name) {
-                    target_lookup.insert(group_name.clone(), group_id);
-                    group_id = group_id + 1;
-                }
-                let group_id = target_lookup.get(group_name).unwrap();
-                group_members.entry(*group_id).or_insert_with(Vec::new).push(r.id);
-            }
-        }
-        
         // build maps from midi triggers to mappings
         for m in show.mappings.iter() {
+            if let Some(song) = &m.song {
+                if !show.songs.iter().flatten().any(|s| &s.name == song) {
+                    return Err(anyhow!("Mapping for cue: {} refers to unknown song: {}", m.cue, song));
+                }
+            }
             match &m.midi {
                 Some(MidiMappingType::Note { channel, note }) => {
-                    note_mappings.entry(((*channel).into(), ResolvedNote::from_str(&note).unwrap().midi.into()))
+                    let note = parse_note(note).with_context(|| format!("Mapping for cue: {} has a bad note", m.cue))?;
+                    note_mappings.entry(((*channel).into(), note.into()))
                     .or_insert_with(Vec::new).push(m.get_id());
                 },
                 Some(MidiMappingType::Controller { channel, cc }) => {
                     controller_mappings.entry(((*channel).into(), (*cc).into()))
                     .or_insert_with(Vec::new).push(m.get_id());
                 },
+                Some(MidiMappingType::NoteRange { .. }) => {
+                    return Err(anyhow!("Note range for cue: {} was not expanded when the show loaded", m.cue));
+                },
+                Some(MidiMappingType::Timecode { hh, mm, ss, ff }) => {
+                    if *mm > 59 || *ss > 59 || *ff > 29 {
+                        return Err(anyhow!("Mapping for cue: {} has a bad timecode: {:02}:{:02}:{:02}:{:02}", m.cue, hh, mm, ss, ff));
+                    }
+                    timecode_mappings.push((m.get_id(), [*hh, *mm, *ss, *ff]));
+                },
                 None => {
                     return Err(anyhow!("Non-clip mapping missing a midi mapping element: {:?}", m));
                 }
             }
         }
 
-        Ok(ShowState { 
+        let mut variable_controls: HashMap<(u4,u7), String> = HashMap::new();
+        for (name, variable) in show.variables.iter().flatten() {
+            if let Some(cc) = variable.cc {
+                let channel = variable.channel.unwrap_or(config.midi_control_channel.into());
+                variable_controls.insert((channel.into(), cc.into()), name.clone());
+            }
+        }
+
+        let matrix_targets = matrix::matrix_targets(show)?;
+        let mut controls: HashMap<(u4,u7), Control> = HashMap::new();
+        for definition in show.controls.iter().flatten() {
+            let channel = definition.channel.unwrap_or(config.midi_control_channel);
+            let mappings: Vec<usize> = match &definition.parameter {
+                ControlledParameter::Param1 { cue } => {
+                    let ids: Vec<usize> = show.mappings.iter()
+                        .filter(|m| &m.cue == cue && matches!(m.light, LightMappingType::Effect(_)))
+                        .map(|m| m.get_id())
+                        .collect();
+                    if ids.is_empty() {
+                        return Err(anyhow!("Control on channel: {} cc: {} refers to unknown effect cue: {}", channel, definition.cc, cue));
+                    }
+                    ids
+                },
+                _ => vec![]
+            };
+            let recipients = resolve_targets(&definition.targets, &target_lookup, &matrix_targets)
+                .with_context(|| format!("Control on channel: {} cc: {} has bad targets", channel, definition.cc))?;
+            controls.insert((channel.into(), definition.cc.into()), Control { definition, recipients, mappings });
+        }
+
+        let mut dmx_patches: Vec<(&DmxPatch, Vec<u8>)> = vec![];
+        for patch in show.dmx.iter().flatten() {
+            if !(1..=509).contains(&patch.address) {
+                return Err(anyhow!("DMX patch for: {} has an address out of range: {}", patch.target, patch.address));
+            }
+            let recipients = resolve_targets(&Some(vec![patch.target.clone()]), &target_lookup, &matrix_targets)
+                .with_context(|| format!("DMX patch for: {} has a bad target", patch.target))?;
+            dmx_patches.push((patch, recipients));
+        }
+
+        let mut scene_mappings: HashMap<String, Vec<usize>> = HashMap::new();
+        for scene in show.scenes.iter().flatten() {
+            let mut ids: Vec<usize> = vec![];
+            for cue in scene.cues.iter() {
+                let m = show.mappings.iter().find(|m| &m.cue == cue)
+                    .ok_or_else(|| anyhow!("Scene: {} refers to unknown cue: {}", scene.name, cue))?;
+                ids.push(m.get_id());
+            }
+            scene_mappings.insert(scene.name.clone(), ids);
+        }
+
+        let mut cue_list_steps: Vec<Vec<usize>> = vec![];
+        let mut cue_list_notes: HashMap<(u4,u7), CueListAction> = HashMap::new();
+        let mut cue_list_controllers: HashMap<(u4,u7), CueListAction> = HashMap::new();
+        if let Some(cue_list) = &show.cue_list {
+            if cue_list.steps.is_empty() {
+                return Err(anyhow!("Cue list has no steps"));
+            }
+            for (n, step) in cue_list.steps.iter().enumerate() {
+                let mut ids: Vec<usize> = vec![];
+                for cue in step.cues.iter() {
+                    let len = ids.len();
+                    ids.extend(show.mappings.iter().filter(|m| &m.cue == cue).map(|m| m.get_id()));
+                    if ids.len() == len {
+                        return Err(anyhow!("Cue list step: {} refers to unknown cue: {}", n, cue));
+                    }
+                }
+                cue_list_steps.push(ids);
+            }
+            for (trigger, action) in [(Some(&cue_list.go), CueListAction::Go), (cue_list.back.as_ref(), CueListAction::Back)] {
+                match trigger {
+                    Some(MidiMappingType::Note { channel, note }) => {
+                        let note = parse_note(note).with_context(|| format!("Cue list {:?} trigger has a bad note", action))?;
+                        cue_list_notes.insert(((*channel).into(), note.into()), action);
+                    },
+                    Some(MidiMappingType::Controller { channel, cc }) => {
+                        cue_list_controllers.insert(((*channel).into(), (*cc).into()), action);
+                    },
+                    Some(other) => return Err(anyhow!("Cue list {:?} trigger must be a note or a controller, not: {:?}", action, other)),
+                    None => {}
+                }
+            }
+            // the cue list would swallow the trigger, so the mapping could never fire
+            if cue_list_notes.keys().any(|k| note_mappings.contains_key(k))
+                || cue_list_controllers.keys().any(|k| controller_mappings.contains_key(k)) {
+                return Err(anyhow!("Cue list triggers can't also trigger mappings"));
+            }
+        }
+        let cue_list_jump = show.cue_list.as_ref().and_then(|c| c.jump_cc)
+            .map(|cc| (config.midi_control_channel.into(), cc.into()));
+
+        let preview_group = match &config.preview_group {
+            Some(name) => Some(*target_lookup.get(name).filter(|id| group_members.contains_key(id))
+                .ok_or_else(|| ChsError::Config(anyhow!("Preview group: {} is not a group in the show", name)))?),
+            None => None
+        };
+
+        let random_weights: HashMap<u8,f32> = show.receivers.iter()
+            .filter_map(|r| r.random_weight.map(|weight| (r.id, weight)))
+            .collect();
+        if let Some((id, weight)) = random_weights.iter().find(|(_, weight)| !(weight.is_finite() && **weight >= 0.0)) {
+            return Err(anyhow!("Receiver: {} has a random_weight that isn't a number from 0 up: {}", id, weight));
+        }
+
+        if config.exec_hooks.is_none() && show.mappings.iter().any(|m| m.exec.is_some()) {
+            warn!("The show has exec hooks, but the config doesn't enable them so they won't run");
+        }
+
+        let mut show_state = ShowState {
             config,
             radio,
             show,
             group_members,
             target_lookup,
+            matrix_targets,
+            receiver_firmware: show.receivers.iter()
+                .filter_map(|r| r.firmware.map(|fw| (r.id, fw)))
+                .collect(),
+            reversed_receivers: show.receivers.iter()
+                .filter(|r| r.reversed.unwrap_or(false))
+                .map(|r| r.id)
+                .collect(),
+            random_weights,
             note_mappings, 
             controller_mappings,
+            timecode_mappings,
+            variable_controls,
+            controls,
+            dmx_patches,
+            scene_mappings,
+            cue_list_steps,
+            cue_list_notes,
+            cue_list_controllers,
+            cue_list_jump,
+            preview_group,
+            brightness_schedule: config.brightness_schedule.as_deref().map(BrightnessSchedule::new).transpose()
+                .context("Invalid brightness schedule").map_err(ChsError::Config)?,
+            hooks: config.exec_hooks.as_ref().map(HookRunner::new),
             clip_engine: ClipEngine::new(&show.clips)
-     })
+        };
+        // nor can the cue list's jump controller share one, the tempo knob's included
+        let jump = show_state.cue_list_jump.take();
+        if let Some((channel, cc)) = jump.filter(|key| show_state.controller_in_use(*key) || show_state.configured_tempo_knob() == Some(*key)) {
+            return Err(anyhow!("The cue list jump controller on channel: {} cc: {} is also used by the show or the transmitter", channel, cc));
+        }
+        show_state.cue_list_jump = jump;
+        // the tempo knob would swallow its controller, so nothing else could use it
+        if let Some((channel, cc)) = show_state.configured_tempo_knob().filter(|knob| show_state.controller_in_use(*knob)) {
+            return Err(anyhow!("The tempo knob on channel: {} cc: {} is also used by the show or the transmitter", channel, cc));
+        }
+        // targets named through a variable are checked with each of its values as the mutable state is built
+        for m in show_state.all_mappings() {
+            let named: Vec<serde_json::Value> = m.targets.iter().flatten()
+                .filter(|t| t.as_str().and_then(variable_reference).is_none())
+                .cloned().collect();
+            resolve_targets(&Some(named), &show_state.target_lookup, &show_state.matrix_targets)
+                .with_context(|| format!("Could not resolve targets of cue: {}", m.cue))?;
+        }
+        Ok(show_state)
+    }
+
+    /// the channel/cc of the tempo knob, if the config gives one rather than leaving it to be learned
+    fn configured_tempo_knob(self: &Self) -> Option<(u4,u7)> {
+        self.config.tempo_control.as_ref().and_then(|tc| tc.cc.map(|cc|
+            (tc.channel.unwrap_or(self.config.midi_control_channel).into(), cc.into())))
+    }
+
+    /// whether the show (or the transmitter itself, on the control channel) already
+    /// answers to the controller, other than by the tempo knob
+    fn controller_in_use(self: &Self, key: (u4,u7)) -> bool {
+        (key.0 == self.config.midi_control_channel && SPECIAL_CONTROLLERS.contains(&key.1.into()))
+            || self.controller_mappings.contains_key(&key)
+            || self.controls.contains_key(&key)
+            || self.variable_controls.contains_key(&key)
+            || self.cue_list_controllers.contains_key(&key)
+            || self.cue_list_jump == Some(key)
     }
     
     pub fn create_mutable_state(self: &Self) -> anyhow::Result<MutableShowState> {
@@ -258,105 +1032,516 @@ impl<'a,'b> ShowState<'a,'b> {
             receiver_state.insert(r.id, Rc::new(RefCell::new(ReceiverState::new(r.id))));
         }
 
-        // preprocess light mappings
-        for m in self.show.mappings.iter() {
-            light_mappings.insert(m.get_id(), self.create_light_mapping_meta( m, &receiver_state)?);
-        }
-        
-        // preprocess clip-embedded light mappings
-        for clip_steps in self.show.clips.values() {
-            for step in clip_steps.iter() {
-                match step {
-                    ClipStep::MappingOn(m) => {
-                        light_mappings.insert(m.get_id(), self.create_light_mapping_meta(m, &receiver_state)?);
-                    },
-                    _ => {}
+        // every variable starts at its first value
+        let variables: HashMap<String,String> = self.show.variables.iter().flatten()
+            .map(|(name, v)| (name.clone(), v.values[0].clone()))
+            .collect();
+
+        // preprocess light mappings, and clip-embedded light mappings
+        for m in self.all_mappings() {
+            light_mappings.insert(m.get_id(), self.create_light_mapping_meta(m, &receiver_state, &variables)?);
+            // make sure the mapping works with every value of its variables, not just the initial ones
+            for name in m.variables() {
+                for value in self.show.variables.as_ref().unwrap()[name].values.iter() {
+                    let mut trial = variables.clone();
+                    trial.insert(name.to_string(), value.clone());
+                    self.create_light_mapping_meta(m, &receiver_state, &trial)
+                        .with_context(|| format!("cue: {} does not work with variable: {} set to: {}", m.cue, name, value))?;
                 }
             }
         }
 
-        Ok(MutableShowState {
-            last_effect: Instant::now(),
-            last_lights_out: Instant::now(),
+        let mut state = MutableShowState {
+            last_effect: clock::now(),
+            last_lights_out: clock::now(),
+            last_heartbeat: clock::now(),
+            last_poll: clock::now(),
+            next_poll: 0,
+            links: BTreeMap::new(),
+            link_history: BTreeMap::new(),
+            adaptive_repeats: (clock::now(), 0),
+            last_listen: clock::now(),
+            last_telemetry_poll: clock::now(),
+            next_telemetry_poll: 0,
+            telemetry: BTreeMap::new(),
+            reports: None,
             light_mappings,
             receiver_state,
             sustain: false,
-            pending_off: Vec::<usize>::new()
-        })
+            pending_off: Vec::<usize>::new(),
+            color_transform: ColorTransform::IDENTITY,
+            history: VecDeque::new(),
+            replay_queue: VecDeque::new(),
+            synth_fades: vec![],
+            active_song: self.show.songs.as_ref().and_then(|songs| songs.first()).map(|song| song.name.clone()),
+            cue_list_step: None,
+            tempo_binding: self.configured_tempo_knob(),
+            tempo_learn: false,
+            tempo_override: None,
+            midi_clock: MidiClock::default(),
+            hue_bends: HashMap::new(),
+            pressures: HashMap::new(),
+            control_sends: HashMap::new(),
+            param1_overrides: HashMap::new(),
+            dmx_colors: HashMap::new(),
+            mtc: MtcReader::default(),
+            timecode: None,
+            realtime_packets: HashMap::new(),
+            fired_cues: vec![],
+            midi_received: None,
+            muted: HashSet::new(),
+            muted_tags: HashSet::new(),
+            tag_adjustments: BTreeMap::new(),
+            preview: false,
+            asleep: false,
+            variables,
+            stale_mappings: HashSet::new(),
+            brightness_checked: None,
+            realtime_latency: LatencyStats::default(),
+            standard_latency: LatencyStats::default()
+        };
+        self.premarshal_realtime(&mut state);
+        Ok(state)
     }
 
-    fn create_light_mapping_meta<'c>(self: &Self,
-        m: &'c LightMapping, 
-        receiver_state: &HashMap<u8,Rc<RefCell<ReceiverState>>>) -> Result<LightMappingMeta<'c>> {
-
-        let resolved_targets = match &m.targets {
-            None => ALL_RECIPIENTS, 
-            Some(tgts) => {
-                let mut result: Vec<u8> = vec![];
-                for json_tgt in tgts.iter() {
-                    let tgt_val = convert_target(json_tgt)?;
-                    let otgt = self.target_lookup.get(&tgt_val);
-                    match otgt {
-                        Some(id) => result.push(*id),
-                        None => return Err(anyhow!("Target in target list does not match any known group or receiver: {}", tgt_val))
-                    }
-                }
-                result
-            }
+    /// mute every cue to the named groups (replacing any earlier muting), so a section
+    /// can rehearse separately. an empty list unmutes everything
+    pub fn set_muted_groups(self: &Self, groups: &[String], state: &mut MutableShowState) -> Result<()> {
+        let mut muted = HashSet::new();
+        for name in groups {
+            let group_id = self.target_lookup.get(name).filter(|id| self.group_members.contains_key(id))
+                .ok_or_else(|| anyhow!("Cannot mute {}, it is not a group", name))?;
+            muted.insert(*group_id);
+            muted.extend(self.group_members[group_id].iter());
+        }
+        if groups.is_empty() {
+            info!("all groups unmuted");
+        } else {
+            info!("muted groups: {}", groups.join(", "));
+        }
+        state.muted = muted;
+        Ok(())
+    }
+
+    /// the targets an effect should actually be sent to with muted groups, and receivers
+    /// held by a higher priority mapping, left out. None if that leaves nobody to send it to
+    fn available_targets(self: &Self, targets: &Vec<u8>, held: &HashSet<u8>, state: &MutableShowState) -> Option<Vec<u8>> {
+        let available: Vec<u8> = if targets.is_empty() {
+            // everybody, less the muted and held, has to be spelled out
+            let mut receivers: Vec<u8> = state.receiver_state.keys().copied()
+                .filter(|r| !state.muted.contains(r) && !held.contains(r))
+                .collect();
+            receivers.sort();
+            receivers
+        } else if held.is_empty() {
+            targets.iter().copied().filter(|t| !state.muted.contains(t)).collect()
+        } else {
+            // a group with some of its members held has to be spelled out too
+            let mut receivers: Vec<u8> = targets.iter()
+                .flat_map(|t| self.group_members.get(t).cloned().unwrap_or_else(|| vec![*t]))
+                .filter(|r| !state.muted.contains(r) && !held.contains(r))
+                .collect();
+            receivers.sort();
+            receivers.dedup();
+            receivers
         };
-        let resolved_receivers = self.expand_groups(receiver_state, &resolved_targets);
+        if available.is_empty() { None } else { Some(available) }
+    }
 
-        let resolved_color = self.show.colors.get(&m.color)
-            .ok_or_else(|| anyhow!("Named color: {} not in color map", m.color))?;
+    /// mute or adjust every cue carrying the tag
+    pub fn apply_tag_operation(self: &Self, tag: &str, operation: &TagOperation, state: &mut MutableShowState) -> Result<()> {
+        if !self.all_mappings().any(|m| m.tags.iter().flatten().any(|t| t == tag)) {
+            return Err(anyhow!("No cues are tagged: {}", tag))
+        }
+        match operation {
+            TagOperation::Mute => { state.muted_tags.insert(tag.to_string()); },
+            TagOperation::Unmute => { state.muted_tags.remove(tag); },
+            TagOperation::Adjust { attack, sustain, release } => {
+                let adjustment = state.tag_adjustments.entry(tag.to_string()).or_default();
+                adjustment.attack = attack.or(adjustment.attack);
+                adjustment.sustain = sustain.or(adjustment.sustain);
+                adjustment.release = release.or(adjustment.release);
+            },
+            TagOperation::Reset => { state.tag_adjustments.remove(tag); }
+        }
+        info!("cues tagged: {}, {:?}", tag, operation);
+        Ok(())
+    }
 
-        Ok(LightMappingMeta {
-            color: resolved_color.clone(),
-            source: m,
-            targets: resolved_targets,
-            receivers: resolved_receivers
-        })
+    /// the mapping's overrides with its tags' adjustments filled in (overrides the
+    /// show makes itself, eg a crossfade's, come first)
+    fn tag_adjusted(self: &Self, mapping: &LightMapping, overrides: Option<EffectOverrides>, state: &MutableShowState) -> Option<EffectOverrides> {
+        let mut adjustments = state.tag_adjustments.iter()
+            .filter(|(tag, _)| mapping.tags.iter().flatten().any(|t| t == *tag))
+            .map(|(_, adjustment)| adjustment)
+            .peekable();
+        if adjustments.peek().is_none() {
+            return overrides
+        }
+        let mut adjusted = overrides.unwrap_or_default();
+        for adjustment in adjustments {
+            adjusted.attack = adjusted.attack.or(adjustment.attack);
+            adjusted.sustain = adjusted.sustain.or(adjustment.sustain);
+            adjusted.release = adjusted.release.or(adjustment.release);
+        }
+        Some(adjusted)
+    }
 
+    /// every light mapping in the show, top level or embedded in a clip
+    fn all_mappings(self: &Self) -> impl Iterator<Item = &'b LightMapping> {
+        let clip_mappings = self.show.clips.values().flatten().filter_map(|step|
+            if let ClipStep::MappingOn(m) = step { Some(m) } else { None });
+        self.show.mappings.iter().chain(clip_mappings)
     }
-    
-    /// a helper function that expands a target list of u8s to a list of receiver state references
-    /// (ids representing groups are expanded to references to their underlying receivers)
-    fn expand_groups<'c>(self: &Self, receiver_state: &'c HashMap<u8,Rc<RefCell<ReceiverState>>>, targets: &Vec<u8>) 
-    -> Vec<Rc<RefCell<ReceiverState>>> {
 
-        if targets.is_empty() {
-            receiver_state.values().map(|rc| rc.clone()).collect()
-        } else {
-            targets.iter().flat_map(|e|   
-                self.group_members.get(&e)
-                    .map_or_else(|| vec![*e].into_iter(), |v| v.clone().into_iter()))
-                    .map(|k| receiver_state.get(&k).unwrap().clone())
-                    .collect()
+    /// set a runtime variable. mappings using it take the new value from their next
+    /// activation on
+    pub fn set_variable(self: &Self, name: &str, value: &str, state: &mut MutableShowState) -> Result<()> {
+        let variable = self.show.variables.as_ref().and_then(|v| v.get(name))
+            .ok_or_else(|| anyhow!("No variable named: {}", name))?;
+        if !variable.values.iter().any(|v| v == value) {
+            return Err(anyhow!("{} is not a value of variable: {}", value, name));
+        }
+        if state.variables.get(name).is_some_and(|current| current == value) {
+            return Ok(())
+        }
+        info!("variable: {} set to: {}", name, value);
+        state.variables.insert(name.to_string(), value.to_string());
+        let now = clock::now();
+        for m in self.all_mappings().filter(|m| m.variables().contains(&name)) {
+            if state.light_mappings[&m.get_id()].is_active(now) {
+                state.stale_mappings.insert(m.get_id());
+            } else {
+                self.refresh_light_mapping(m.get_id(), state)?;
+            }
         }
+        self.premarshal_realtime(state);
+        Ok(())
     }
 
-    /// Send control packets to all the receivers telling them
-    /// what group they're in and how many leds they have
-    pub fn initialize(self: &Self) -> Result<(), RadioError> {
-        // reset everybody because receiving a 
-        self.radio.send(&GLOBAL_RESET_PACKET)?;
-        for receiver in self.show.receivers.iter() {
+    /// rebuild a mapping's resolved color and targets from the current variable values
+    fn refresh_light_mapping(self: &Self, mapping_id: usize, state: &mut MutableShowState) -> Result<()> {
+        let old = state.light_mappings.get(&mapping_id).unwrap();
+        let mut meta = self.create_light_mapping_meta(old.source, &state.receiver_state, &state.variables)?;
+        meta.envelope_end = old.envelope_end;
+        state.light_mappings.insert(mapping_id, meta);
+        state.stale_mappings.remove(&mapping_id);
+        Ok(())
+    }
 
-            if let Some(group_name) = &receiver.group_name {
-                self.radio.send(&Packet {
-                    recipients: &vec![receiver.id],
-                    payload: PacketPayload::Control(
-                        Command::SetGroup { group_id: 
-                            *self.target_lookup.get(group_name).unwrap() })
-                })?;
+    /// crossfade from whatever is showing to the named scene: the outgoing mappings are
+    /// re-sent with the crossfade time as their release and then turned off, while the
+    /// scene's mappings come up with it as their attack. the receivers' envelopes do the
+    /// fading, and the packets for both looks go out interleaved in one burst
+    pub fn crossfade(self: &Self, scene: &str, state: &mut MutableShowState) -> Result<()> {
+        let definition = self.show.scenes.iter().flatten().find(|s| s.name == scene)
+            .ok_or_else(|| anyhow!("No scene named: {}", scene))?;
+        let mut fade = definition.crossfade_millis.or(self.config.crossfade_millis).unwrap_or(DEFAULT_CROSSFADE_MILLIS);
+        if fade > MAX_CROSSFADE_MILLIS {
+            warn!("crossfade of {}ms to scene: {} is longer than a packet can carry, using {}ms", fade, scene, MAX_CROSSFADE_MILLIS);
+            fade = MAX_CROSSFADE_MILLIS;
+        }
+        info!("crossfade to scene: {} over {}ms", scene, fade);
+
+        let incoming = &self.scene_mappings[scene];
+        // receivers the incoming look takes over don't need fading out, the new effect replaces the old
+        let taken: HashSet<u8> = incoming.iter()
+            .flat_map(|id| state.light_mappings[id].receivers.iter().map(|r| r.borrow().id))
+            .collect();
+        // mappings layered beneath others go without fading, nothing of them is showing
+        for meta in state.light_mappings.values() {
+            for receiver in meta.receivers.iter() {
+                if !receiver.borrow().activated_by(meta.source) {
+                    receiver.borrow_mut().deactivate(meta.source);
+                }
             }
-            self.radio.send(&Packet {
-                recipients: &vec![receiver.id],
-                payload: PacketPayload::Control(
-                    Command::SetLedCount { led_count: receiver.led_count })
-            })?;
+        }
+        let now = clock::now();
+        let mut outgoing: Vec<usize> = state.light_mappings.iter()
+            .filter(|(id, meta)| !incoming.contains(id) && !meta.source.one_shot.unwrap_or(false) && meta.is_active(now))
+            .map(|(id, _)| *id)
+            .collect();
+        outgoing.sort();
+
+        self.radio.start_burst();
+        let mut result = Ok(());
+        for i in 0..outgoing.len().max(incoming.len()) {
+            if let Some(id) = outgoing.get(i) {
+                result = result.and_then(|_| self.fade_out(*id, fade, &taken, state));
+            }
+            if let Some(id) = incoming.get(i) {
+                let overrides = EffectOverrides { color: None, tempo: None, attack: Some(fade), sustain: None, release: None };
+                result = result.and_then(|_| self.activate(*id, Some(overrides), state));
+            }
+        }
+        self.radio.finish_burst(self.config.merge_identical_packets())?;
+        result
+    }
+
+    /// move through the cue list: the cues of the step showing are released, then those of
+    /// the step moved to fired. moving past either end of the list does nothing
+    pub fn move_cue_list(self: &Self, action: CueListAction, state: &mut MutableShowState) -> Result<()> {
+        if self.cue_list_steps.is_empty() {
+            return Err(anyhow!("The show has no cue list"))
+        }
+        let last = self.cue_list_steps.len() - 1;
+        let next = match (action, state.cue_list_step) {
+            (CueListAction::Go, None) => Some(0),
+            (CueListAction::Go, Some(step)) if step < last => Some(step + 1),
+            (CueListAction::Back, Some(step)) => step.checked_sub(1),
+            (CueListAction::Jump(step), _) if step <= last => Some(step),
+            (CueListAction::Jump(step), _) => {
+                warn!("cue list jump to step: {}, which the cue list doesn't have", step);
+                return Ok(())
+            },
+            (_, current) => {
+                warn!("cue list can't {:?} from step: {:?}", action, current);
+                return Ok(())
+            }
+        };
+        let name = |step: usize| self.show.cue_list.as_ref().and_then(|c| c.steps[step].name.clone()).unwrap_or_default();
+        match next {
+            Some(step) => info!("cue list to step: {} {}", step, name(step)),
+            None => info!("cue list back to the start")
+        }
+
+        self.radio.start_burst();
+        let mut result = Ok(());
+        for id in state.cue_list_step.map_or(&[][..], |step| &self.cue_list_steps[step][..]) {
+            result = result.and_then(|_| self.deactivate(*id, state));
+            self.record_history(*id, false, state);
+        }
+        for id in next.map_or(&[][..], |step| &self.cue_list_steps[step][..]) {
+            result = result.and_then(|_| self.activate(*id, None, state));
+            self.record_history(*id, true, state);
+        }
+        state.cue_list_step = next;
+        self.radio.finish_burst(self.config.merge_identical_packets())?;
+        result
+    }
+
+    /// fade out a mapping's effect over the given time on the receivers it still holds
+    /// (less those in taken), or stop it if it's a clip
+    fn fade_out(self: &Self, mapping_id: usize, fade: u32, taken: &HashSet<u8>, state: &mut MutableShowState) -> Result<()> {
+        let meta = state.light_mappings.get(&mapping_id).unwrap();
+        let effect = match &meta.source.light {
+            LightMappingType::Effect(effect) => effect,
+            _ => return self.deactivate(mapping_id, state)
+        };
+        let held: Vec<Rc<RefCell<ReceiverState>>> = meta.receivers.iter()
+            .filter(|r| r.borrow().activated_by(meta.source))
+            .cloned()
+            .collect();
+        let recipients: Vec<u8> = held.iter().map(|r| r.borrow().id).filter(|id| !taken.contains(id)).collect();
+        if !recipients.is_empty() {
+            info!("fade out cue: {}", meta.source.cue);
+            // re-sent without an attack so it doesn't visibly restart, only the release changes
+            let overrides = Some(EffectOverrides { color: None, tempo: None, attack: None, sustain: None, release: Some(fade) });
+            let show_packet = self.build_show_packet(meta, effect, &overrides, true, &self.color_transform_for(meta, state), state);
+            self.radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(show_packet) })?;
+            self.radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(ShowPacket::OFF_PACKET) })?;
+        }
+        for receiver in held.iter() {
+            receiver.borrow_mut().deactivate(meta.source);
+        }
+        Ok(())
+    }
+
+    /// put every receiver into low power idle until woken
+    pub fn sleep(self: &Self, wake_on_packet: bool, state: &mut MutableShowState) -> Result<()> {
+        let old: Vec<u8> = self.show.receivers.iter()
+            .filter(|r| r.firmware.unwrap_or(CURRENT_FIRMWARE) < SLEEP_FIRMWARE)
+            .map(|r| r.id)
+            .collect();
+        if !old.is_empty() {
+            warn!("Receivers {:?} have firmware too old to sleep, they will stay awake", old);
+        }
+        info!("putting receivers to sleep, {}", if wake_on_packet { "any packet wakes them" } else { "until reset" });
+        self.radio.send(&Packet {
+            recipients: &ALL_RECIPIENTS,
+            payload: PacketPayload::Control(Command::Sleep { wake_on_packet })
+        })?;
+        state.asleep = true;
+        Ok(())
+    }
+
+    /// wake sleeping receivers with a reset, and configure them again for the show
+    pub fn wake(self: &Self, state: &mut MutableShowState) -> Result<()> {
+        info!("waking receivers");
+        self.initialize()?;
+        state.asleep = false;
+        state.last_effect = clock::now();
+        Ok(())
+    }
+
+    /// dim (or restore) the intensity of everything sent from now on
+    pub fn set_grand_master(self: &Self, percent: u8, state: &mut MutableShowState) {
+        if self.radio.grand_master() != percent {
+            info!("grand master set to: {}%", percent);
+            self.radio.set_grand_master(percent);
+            self.premarshal_realtime(state);
+        }
+    }
+
+    /// dim (or restore) everything sent from now on as the brightness schedule calls for
+    fn set_scheduled_brightness(self: &Self, percent: u8, state: &mut MutableShowState) {
+        if self.radio.scheduled_brightness() != percent {
+            info!("scheduled brightness set to: {}%", percent);
+            self.radio.set_scheduled_brightness(percent);
+            self.premarshal_realtime(state);
+        }
+    }
+
+    /// marshal the packets for realtime mappings ahead of time. mappings whose effect some
+    /// receivers need substituted or reversed take the normal path
+    fn premarshal_realtime(self: &Self, state: &mut MutableShowState) {
+        state.realtime_packets.clear();
+        for (id, meta) in state.light_mappings.iter() {
+            if let LightMappingType::Effect(effect) = &meta.source.light {
+                if meta.source.realtime.unwrap_or(false) && meta.shims.is_empty() && meta.reversed_targets.is_empty() {
+                    let packet = Packet {
+                        recipients: &meta.targets,
+                        payload: PacketPayload::Show(self.build_show_packet(meta, effect, &None, false, &self.color_transform_for(meta, state), state))
+                    };
+                    state.realtime_packets.insert(*id, self.radio.marshal(&packet));
+                }
+            }
+        }
+    }
+
+    fn create_light_mapping_meta<'c>(self: &Self,
+        m: &'c LightMapping, 
+        receiver_state: &HashMap<u8,Rc<RefCell<ReceiverState>>>,
+        variables: &HashMap<String,String>) -> Result<LightMappingMeta<'c>> {
+
+        // variable references resolve to the variables' current values
+        let targets = substitute_targets(&m.targets, |name| vec![variables[name].clone()]);
+        let resolved_targets = resolve_targets(&targets, &self.target_lookup, &self.matrix_targets)?;
+        let resolved_receivers = self.expand_groups(receiver_state, &resolved_targets);
+
+        let color = variable_reference(&m.color).map_or(&m.color, |name| &variables[name]);
+        let resolved_color = self.show.colors.get(color)
+            .ok_or_else(|| anyhow!("Named color: {} not in color map", color))?;
+
+        // work out which targeted receivers can't perform the effect
+        let mut shims: Vec<EffectShim> = vec![];
+        let mut primary_targets: Option<Vec<u8>> = None;
+        let mut reversed_targets: Vec<u8> = vec![];
+        if let LightMappingType::Effect(effect) = &m.light {
+            let effect_id = effect.to_effect_id();
+            let mut old: Vec<(u8,u8)> = resolved_receivers.iter()
+                .map(|r| r.borrow().id)
+                .filter_map(|id| self.receiver_firmware.get(&id).map(|fw| (id, *fw)))
+                .filter(|(_, fw)| effect_id.min_firmware() > *fw)
+                .collect();
+            if !old.is_empty() {
+                old.sort_by_key(|(_, fw)| *fw);
+                for (id, fw) in old.iter() {
+                    match shims.last_mut() {
+                        Some(shim) if shim.firmware == *fw => shim.recipients.push(*id),
+                        _ => shims.push(EffectShim { firmware: *fw, recipients: vec![*id] })
+                    }
+                }
+                for shim in shims.iter() {
+                    let substitute = ShowPacket { effect: effect_id, ..ShowPacket::OFF_PACKET }.downgrade(shim.firmware);
+                    info!("cue: {} effect: {:?} is substituted with {:?} for firmware {} receivers: {:?}", 
+                        m.cue, effect_id, substitute.map(|p| p.effect), shim.firmware, shim.recipients);
+                }
+            }
+            // receivers strung the other way round need a chase's direction flipped
+            // (substitutes for old firmware go out as they are)
+            if effect.is_directional() {
+                reversed_targets = resolved_receivers.iter()
+                    .map(|r| r.borrow().id)
+                    .filter(|id| self.reversed_receivers.contains(id) && !old.iter().any(|(old_id, _)| old_id == id))
+                    .collect();
+            }
+            if !old.is_empty() || !reversed_targets.is_empty() {
+                primary_targets = Some(resolved_receivers.iter()
+                    .map(|r| r.borrow().id)
+                    .filter(|id| !old.iter().any(|(old_id, _)| old_id == id) && !reversed_targets.contains(id))
+                    .collect());
+            }
+        }
+
+        Ok(LightMappingMeta {
+            color: resolved_color.clone(),
+            source: m,
+            targets: resolved_targets,
+            receivers: resolved_receivers,
+            envelope_end: None,
+            primary_targets,
+            shims,
+            reversed_targets
+        })
+
+    }
+    
+    /// a helper function that expands a target list of u8s to a list of receiver state references
+    /// (ids representing groups are expanded to references to their underlying receivers)
+    fn expand_groups<'c>(self: &Self, receiver_state: &'c HashMap<u8,Rc<RefCell<ReceiverState>>>, targets: &Vec<u8>) 
+    -> Vec<Rc<RefCell<ReceiverState>>> {
+
+        if targets.is_empty() {
+            receiver_state.values().map(|rc| rc.clone()).collect()
+        } else {
+            targets.iter().flat_map(|e|   
+                self.group_members.get(&e)
+                    .map_or_else(|| vec![*e].into_iter(), |v| v.clone().into_iter()))
+                    .map(|k| receiver_state.get(&k).unwrap().clone())
+                    .collect()
+        }
+    }
+
+    /// Send control packets to all the receivers telling them
+    /// what group they're in and how many leds they have. packets are paced
+    /// (and receivers configured in chunks) so big rosters don't overrun receivers.
+    /// receivers that acknowledge packets get the ones they miss again. the show's
+    /// radio settings take effect here, within the config's limits
+    pub fn initialize(self: &Self) -> Result<(), RadioError> {
+        let overrides = self.show.radio.clone().unwrap_or_default();
+        let power = match overrides.transmitter_power {
+            Some(power) if power > self.config.transmitter_power => {
+                warn!("Show asks for {} dBm, more than the configured {} dBm, using the configured power",
+                    power, self.config.transmitter_power);
+                self.config.transmitter_power
+            },
+            Some(power) => power,
+            None => self.config.transmitter_power
+        };
+        self.radio.set_power(power)?;
+        let show_repeats = self.config.show_packet_repeats.unwrap_or(0);
+        let control_repeats = self.config.control_packet_repeats.unwrap_or(0);
+        self.radio.set_repeats(show_repeats.min(overrides.show_packet_repeats.unwrap_or(show_repeats)),
+            control_repeats.min(overrides.control_packet_repeats.unwrap_or(control_repeats)));
+        let spacing = self.config_packet_spacing();
+        let chunk_size = self.config.config_chunk_size.unwrap_or(DEFAULT_CONFIG_CHUNK_SIZE)
+            .min(overrides.config_chunk_size.unwrap_or(usize::MAX)).max(1);
+        let chunk_pause = Duration::from_millis(self.config.config_chunk_pause_millis.unwrap_or(DEFAULT_CONFIG_CHUNK_PAUSE)
+            .max(overrides.config_chunk_pause_millis.unwrap_or(0)));
+        let receiver_count = self.show.receivers.len();
 
-            info!("Configured receiver: {} with group id: {} and led count: {}", 
-            receiver.id, receiver.group_name.as_ref().map_or("none", |g| g.as_str()), receiver.led_count);
+        // reset everybody because receiving a 
+        self.radio.send(&GLOBAL_RESET_PACKET)?;
+        sleep(spacing);
+        let mut unconfirmed = vec![];
+        for (n, receiver) in self.show.receivers.iter().enumerate() {
+            if !self.configure_receiver(receiver, spacing)? {
+                unconfirmed.push(receiver.id);
+            }
+
+            if (n + 1) % chunk_size == 0 || n + 1 == receiver_count {
+                info!("Configured {} of {} receivers", n + 1, receiver_count);
+                if n + 1 < receiver_count {
+                    sleep(chunk_pause);
+                }
+            }
+        }
+        if self.radio.expects_acks() {
+            info!("{} of {} receivers confirmed their configuration", receiver_count - unconfirmed.len(), receiver_count);
+            if !unconfirmed.is_empty() {
+                warn!("Receivers {:?} did not confirm their configuration", unconfirmed);
+            }
         }
 
         // now send a reset packet to all receivers
@@ -367,14 +1552,79 @@ impl<'a,'b> ShowState<'a,'b> {
 
         // if the configuration specifies a clip to launch, launch that clip
         if let Some(autoplay_clip) = &self.config.autoplay_clip {
-            let _ = self.clip_engine.start_clip(&autoplay_clip, None, 120.0);
+            if let Err(e) = self.clip_engine.start_clip(&autoplay_clip, None, 120.0) {
+                error!("Could not autoplay clip, error: {}", e);
+            }
         }
 
         Ok(())
     }
+
+    /// the gap between configuration packets, the show's if it asks for more than the config
+    fn config_packet_spacing(self: &Self) -> Duration {
+        let show_spacing = self.show.radio.as_ref().and_then(|r| r.config_packet_spacing_millis).unwrap_or(0);
+        Duration::from_millis(self.config.config_packet_spacing_millis.unwrap_or(DEFAULT_CONFIG_PACKET_SPACING).max(show_spacing))
+    }
+
+    /// send one receiver its group, led count and presets from the roster, a spacing apart,
+    /// returning whether it confirmed them all
+    fn configure_receiver(self: &Self, receiver: &ReceiverConfiguration, spacing: Duration) -> Result<bool, RadioError> {
+        let mut confirmed = true;
+
+        if let Some(group_name) = &receiver.group_name {
+            confirmed &= self.send_configuration(&Packet {
+                recipients: &vec![receiver.id],
+                payload: PacketPayload::Control(
+                    Command::SetGroup { group_id: 
+                        *self.target_lookup.get(group_name).unwrap() })
+            })?;
+            sleep(spacing);
+        }
+        confirmed &= self.send_configuration(&Packet {
+            recipients: &vec![receiver.id],
+            payload: PacketPayload::Control(
+                Command::SetLedCount { led_count: receiver.led_count })
+        })?;
+        sleep(spacing);
+
+        let presets = receiver.presets.iter().flatten();
+        if receiver.firmware.unwrap_or(CURRENT_FIRMWARE) < PRESET_FIRMWARE {
+            if presets.count() > 0 {
+                warn!("Receiver {} firmware is too old for presets, not sending them", receiver.id);
+            }
+        } else {
+            for preset in presets {
+                let mut params = ShowPacket::OFF_PACKET;
+                preset.populate_effect_params(&mut params);
+                confirmed &= self.send_configuration(&Packet {
+                    recipients: &vec![receiver.id],
+                    payload: PacketPayload::Control(
+                        Command::SetPreset { effect: preset.to_effect_id(), param1: params.param1, param2: params.param2 })
+                })?;
+                sleep(spacing);
+            }
+        }
+
+        debug!("Configured receiver: {} with group id: {} and led count: {}", 
+        receiver.id, receiver.group_name.as_ref().map_or("none", |g| g.as_str()), receiver.led_count);
+        Ok(confirmed)
+    }
     
-    pub fn process_midi(self: &Self, midi_event: &LiveEvent, state: &mut MutableShowState) -> anyhow::Result<()> {
+    /// send a packet configuring one receiver, returning whether the receiver confirmed
+    /// it. without acknowledgements every packet is taken as confirmed
+    fn send_configuration(self: &Self, packet: &Packet) -> Result<bool, RadioError> {
+        if self.radio.expects_acks() {
+            let timeout = Duration::from_millis(self.config.ack_timeout_millis.unwrap_or(DEFAULT_ACK_TIMEOUT));
+            self.radio.send_acknowledged(packet, self.config.ack_retries.unwrap_or(DEFAULT_ACK_RETRIES), timeout)
+        } else {
+            self.radio.send(packet).map(|_| true)
+        }
+    }
+
+    /// act on a midi event that arrived at the given moment
+    pub fn process_midi(self: &Self, midi_event: &LiveEvent, received: Instant, state: &mut MutableShowState) -> anyhow::Result<()> {
         debug!("Received MIDI event: {:?}", midi_event);
+        state.midi_received = Some(received);
         match midi_event {
             LiveEvent::Midi { channel, message } => {
                 match message {
@@ -387,13 +1637,324 @@ impl<'a,'b> ShowState<'a,'b> {
                     MidiMessage::NoteOff { key, vel } => {
                         self.process_note_off(*channel, *key, *vel, state)
                     },
+                    MidiMessage::ChannelAftertouch { vel } => {
+                        self.process_aftertouch(*channel, *vel, state)
+                    },
+                    MidiMessage::PitchBend { bend } => {
+                        self.process_pitch_bend(*channel, bend.as_int(), state)
+                    },
+                    MidiMessage::ProgramChange { program } if *channel == self.config.midi_control_channel => {
+                        self.select_song(|song| song.number == Some(program.as_int()), state);
+                        Ok(())
+                    },
                     _ => Ok(())
                 }
             },
+            LiveEvent::Common(SystemCommon::SongSelect(number)) => {
+                self.select_song(|song| song.number == Some(number.as_int()), state);
+                Ok(())
+            },
+            LiveEvent::Common(SystemCommon::SongPosition(position)) => {
+                state.midi_clock.locate(position.as_int());
+                // the latest song starting at or before the pointer
+                let song = self.show.songs.iter().flatten()
+                    .filter(|song| song.position.is_some_and(|p| p <= position.as_int()))
+                    .max_by_key(|song| song.position)
+                    .map(|song| song.name.clone());
+                if let Some(song) = song {
+                    self.select_song(|s| s.name == song, state);
+                }
+                Ok(())
+            },
+            LiveEvent::Common(SystemCommon::MidiTimeCodeQuarterFrame(piece, value)) => {
+                match state.mtc.quarter_frame(*piece, *value) {
+                    Some((at, rate)) => self.follow_timecode(at, rate, state),
+                    None => Ok(())
+                }
+            },
+            LiveEvent::Common(SystemCommon::SysEx(data)) => {
+                match state.mtc.sysex(u7::slice_as_int(data)) {
+                    Some((at, rate)) => self.follow_timecode(at, rate, state),
+                    None => Ok(())
+                }
+            },
+            LiveEvent::Realtime(realtime) if self.config.midi_clock_sync.unwrap_or(false) => {
+                match realtime {
+                    SystemRealtime::TimingClock => {
+                        if let Some(tempo) = state.midi_clock.tick(received) {
+                            self.follow_clock_tempo(tempo, state);
+                        }
+                    },
+                    SystemRealtime::Start => state.midi_clock.start(),
+                    SystemRealtime::Continue => state.midi_clock.resume(),
+                    SystemRealtime::Stop => state.midi_clock.stop(),
+                    _ => {}
+                }
+                Ok(())
+            },
             _ => Ok(())
         }
     }
 
+    /// the time code has moved on: fire the cues it passed. a jump (a locate, or the code
+    /// starting) fires only the cues at exactly the new position, not everything skipped
+    fn follow_timecode(self: &Self, at: Duration, rate: FrameRate, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let passed = match state.timecode.replace(at) {
+            Some(previous) if at > previous && at - previous <= MAX_TIMECODE_STEP => previous,
+            Some(previous) if at == previous => return Ok(()),
+            _ => {
+                info!("timecode at: {:.2}s", at.as_secs_f32());
+                at.saturating_sub(Duration::from_nanos(1))
+            }
+        };
+        for (id, [hh, mm, ss, ff]) in self.timecode_mappings.iter() {
+            let cue_at = timecode::position(*hh, *mm, *ss, *ff, rate);
+            if cue_at > passed && cue_at <= at && self.in_active_song(*id, state) {
+                self.activate_from_midi(*id, None, state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// bend the hue of the cues triggered from the channel, if its pitch bend is configured
+    /// to, sending the cues showing again in their new color
+    fn process_pitch_bend(self: &Self, channel: u4, bend: i16, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let Some(pitch_bend_hue) = self.config.pitch_bend_hue.as_ref().filter(|p| p.channels.contains(&channel.into())) else {
+            return Ok(())
+        };
+        let offset = pitch_bend_hue.hue_offset(bend);
+        self.move_sweep(Sweep::PitchBend(channel), offset, state)
+    }
+
+    /// modulate the cues triggered from the channel that have a modulation target by
+    /// the pressure on it, sending those showing again modulated
+    fn process_aftertouch(self: &Self, channel: u4, pressure: u7, state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.move_sweep(Sweep::Pressure(channel), pressure.into(), state)
+    }
+
+    /// a continuous input moved to a new value: send it straight away, unless it last sent
+    /// within its interval, in which case the latest value goes once the interval is up.
+    /// bends and pressures that haven't changed aren't sent again
+    fn move_sweep(self: &Self, key: Sweep, value: u8, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let last = state.control_sends.get(&key).copied();
+        if !matches!(key, Sweep::Control(..)) && last.map_or(0, |send| send.value) == value {
+            return Ok(())
+        }
+        match last {
+            Some(send) if clock::now() - send.sent < self.sweep_interval(key) => {
+                state.control_sends.insert(key, ControlSend { value, pending: true, ..send });
+                Ok(())
+            },
+            _ => self.send_sweep(key, value, state)
+        }
+    }
+
+    /// the least time between sends of a continuous input
+    fn sweep_interval(self: &Self, key: Sweep) -> Duration {
+        match key {
+            Sweep::Control(channel, cc) => match self.controls.get(&(channel, cc)) {
+                Some(control) => control.definition.interval(),
+                None => self.config.tempo_control.as_ref().map_or(Duration::ZERO, |tc| tc.interval())
+            },
+            Sweep::PitchBend(_) => self.config.pitch_bend_hue.as_ref().map_or(Duration::ZERO, |p| p.interval()),
+            Sweep::Pressure(_) => self.config.aftertouch_interval()
+        }
+    }
+
+    fn send_sweep(self: &Self, key: Sweep, value: u8, state: &mut MutableShowState) -> anyhow::Result<()> {
+        state.control_sends.insert(key, ControlSend { sent: clock::now(), value, pending: false });
+        match key {
+            Sweep::Control(channel, cc) => self.send_control((channel, cc), value, state),
+            Sweep::PitchBend(channel) => {
+                debug!("channel: {} hue bent by: {}", channel, value as i8);
+                state.hue_bends.insert(channel.into(), value);
+                self.premarshal_realtime(state);
+                self.resend_showing(on_channel(channel), state)
+            },
+            Sweep::Pressure(channel) => {
+                state.pressures.insert(channel.into(), value);
+                self.resend_showing(|m| on_channel(channel)(m) && m.modulation_target.is_some(), state)
+            }
+        }
+    }
+
+    fn send_control(self: &Self, key: (u4,u7), value: u8, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let Some(control) = self.controls.get(&key) else {
+            // the tempo knob, which has already set the tempo here
+            debug!("tempo knob sent tempo: {}", value);
+            self.radio.send(&Packet { recipients: &ALL_RECIPIENTS, payload: PacketPayload::Control(Command::NewTempo { tempo: value }) })?;
+            return Ok(())
+        };
+        match &control.definition.parameter {
+            ControlledParameter::Brightness => {
+                debug!("brightness control set brightness to: {}", value);
+                self.radio.send(&Packet { recipients: &control.recipients, payload: PacketPayload::Control(Command::NewBrightness { brightness: value }) })?;
+            },
+            ControlledParameter::Param1 { cue } => {
+                debug!("param1 control set cue: {} param1 to: {}", cue, value);
+                for id in control.mappings.iter() {
+                    state.param1_overrides.insert(*id, value);
+                }
+                self.premarshal_realtime(state);
+                self.resend_showing(|m| control.mappings.contains(&m.get_id()), state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// set the receivers patched into a DMX universe to the colors its levels give them,
+    /// where they've changed. levels missing (eg from a terminated stream) count as zero
+    pub fn process_dmx(self: &Self, universe: u16, levels: &[u8], state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.radio.start_burst();
+        let result = self.dmx_patches.iter().enumerate()
+            .filter(|(_, (patch, _))| patch.universe == universe)
+            .try_for_each(|(index, (patch, recipients))| {
+                let level = |offset: u16| levels.get((patch.address + offset - 1) as usize).copied().unwrap_or(0);
+                let color = match patch.color.unwrap_or_default() {
+                    DmxColorMode::Rgb => Color::from_rgb(level(1), level(2), level(3)),
+                    DmxColorMode::Hsv => Color { h: level(1), s: level(2), v: level(3) }
+                };
+                let color = Color { v: (color.v as u16 * level(0) as u16 / 255) as u8, ..color };
+                let was = state.dmx_colors.get(&index).copied().unwrap_or(Color { h: 0, s: 0, v: 0 });
+                if was == color || (was.v == 0 && color.v == 0) {
+                    return Ok(())
+                }
+                debug!("DMX set: {} to: {:?}", patch.target, color);
+                state.dmx_colors.insert(index, color);
+                let show_packet = if color.v == 0 {
+                    ShowPacket::OFF_PACKET
+                } else {
+                    ShowPacket { effect: EffectId::Pop, color, sustain: convert_millis_sustain(0), ..ShowPacket::OFF_PACKET }
+                };
+                state.last_effect = clock::now();
+                self.radio.send(&Packet { recipients, payload: PacketPayload::Show(show_packet) })
+            });
+        self.radio.finish_burst(self.config.merge_identical_packets())?;
+        Ok(result?)
+    }
+
+    /// send the values continuous inputs held back, once their intervals are up,
+    /// returning when the next is due
+    fn send_held_controls(self: &Self, state: &mut MutableShowState, now: Instant) -> anyhow::Result<Option<Instant>> {
+        let held: Vec<(Sweep, ControlSend)> = state.control_sends.iter()
+            .filter(|(_, send)| send.pending)
+            .map(|(key, send)| (*key, *send))
+            .collect();
+        let mut next_at: Option<Instant> = None;
+        for (key, send) in held {
+            let due = send.sent + self.sweep_interval(key);
+            if due <= now {
+                self.send_sweep(key, send.value, state)?;
+            } else {
+                next_at = Some(next_at.map_or(due, |at| at.min(due)));
+            }
+        }
+        Ok(next_at)
+    }
+
+    /// send the picked effect cues that are still showing again, as they look now, to
+    /// the receivers still showing them
+    fn resend_showing(self: &Self, pick: impl Fn(&LightMapping) -> bool, state: &MutableShowState) -> anyhow::Result<()> {
+        let mut showing: Vec<(&LightMappingMeta, &Effect, Vec<u8>)> = vec![];
+        for meta in state.light_mappings.values() {
+            if let LightMappingType::Effect(effect) = &meta.source.light {
+                let held: Vec<u8> = meta.receivers.iter()
+                    .filter(|r| r.borrow().activated_by(meta.source))
+                    .map(|r| r.borrow().id)
+                    .collect();
+                if pick(meta.source) && !held.is_empty() {
+                    showing.push((meta, effect, held));
+                }
+            }
+        }
+        // in a fixed order, whatever order the mappings are kept in
+        showing.sort_by_key(|(meta, _, _)| meta.source.get_id());
+        self.radio.start_burst();
+        let mut result = Ok(());
+        for (meta, effect, recipients) in showing {
+            // re-sent without an attack so it doesn't visibly restart
+            let overrides = self.modulated(meta, None, state);
+            let show_packet = self.build_show_packet(meta, effect, &overrides, true, &self.color_transform_for(meta, state), state);
+            for (recipients, packet) in self.fan_out(meta, Some(&recipients), show_packet) {
+                result = result.and_then(|_| self.radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(packet) }));
+            }
+        }
+        self.radio.finish_burst(self.config.merge_identical_packets())?;
+        Ok(result?)
+    }
+
+    /// for mappings with a modulation target, the overrides with the pressure on the
+    /// mapping's channel applied: brightness swells towards full, or tempo speeds up, by
+    /// up to the modulation depth (a percentage) at full pressure
+    fn modulated(self: &Self, meta: &LightMappingMeta, overrides: Option<EffectOverrides>, state: &MutableShowState) -> Option<EffectOverrides> {
+        let (Some(target), Some(channel)) = (meta.source.modulation_target, meta.source.midi.as_ref().and_then(|m| m.channel())) else {
+            return overrides
+        };
+        let pressure = state.pressures.get(&channel).copied().unwrap_or(0);
+        if pressure == 0 {
+            return overrides
+        }
+        let amount = pressure as f32 / 127.0 * meta.source.modulation.unwrap_or(DEFAULT_MODULATION_DEPTH) as f32 / 100.0;
+        let mut overrides = overrides.unwrap_or(EffectOverrides { color: None, tempo: None, attack: None, sustain: None, release: None });
+        match target {
+            ModulationTarget::Brightness => {
+                let color = overrides.color.unwrap_or(meta.color);
+                let v = color.v as f32 + (255 - color.v) as f32 * amount.min(1.0);
+                overrides.color = Some(Color { v: v.round() as u8, ..color });
+            },
+            ModulationTarget::Tempo => {
+                let tempo = overrides.tempo.or(state.midi_clock.tempo()).or(meta.source.tempo).unwrap_or(120.0);
+                overrides.tempo = Some((tempo * (1.0 + amount)).min(255.0));
+            }
+        }
+        Some(overrides)
+    }
+
+    /// the color transform for a mapping's packets: the global one, plus the pitch bend
+    /// of the channel that triggers it
+    fn color_transform_for(self: &Self, meta: &LightMappingMeta, state: &MutableShowState) -> ColorTransform {
+        let mut transform = state.color_transform;
+        if let Some(bend) = meta.source.midi.as_ref().and_then(|m| m.channel()).and_then(|c| state.hue_bends.get(&c)) {
+            transform.hue_offset = transform.hue_offset.wrapping_add(*bend);
+        }
+        transform
+    }
+
+    /// the midi clock has settled on a new tempo: playing clips change to it, and mappings
+    /// send it in place of their own
+    fn follow_clock_tempo(self: &Self, tempo: f32, state: &mut MutableShowState) {
+        debug!("midi clock tempo now: {:.1}", tempo);
+        self.clip_engine.set_tempo(tempo);
+        self.premarshal_realtime(state);
+    }
+
+    /// switch the active song bank to the first song matching the predicate, if any
+    fn select_song<F>(self: &Self, predicate: F, state: &mut MutableShowState) where F: Fn(&SongDefinition) -> bool {
+        if let Some(song) = self.show.songs.iter().flatten().find(|s| predicate(s)) {
+            if state.active_song.as_ref() != Some(&song.name) {
+                info!("switching to song: {}", song.name);
+                state.active_song = Some(song.name.clone());
+            }
+        }
+    }
+
+    /// switch the active song bank to the named song, as its program change would
+    pub fn switch_song(self: &Self, name: &str, state: &mut MutableShowState) -> anyhow::Result<()> {
+        if !self.show.songs.iter().flatten().any(|s| s.name == name) {
+            return Err(anyhow!("No song named: {}", name))
+        }
+        self.select_song(|s| s.name == name, state);
+        Ok(())
+    }
+
+    /// is the mapping triggerable from midi in the active song
+    fn in_active_song(self: &Self, mapping_id: usize, state: &MutableShowState) -> bool {
+        match &state.light_mappings.get(&mapping_id).unwrap().source.song {
+            Some(song) => state.active_song.as_ref() == Some(song),
+            None => true
+        }
+    }
+
     fn process_special_controllers(self: &Self, channel: u4, controller: u7, value: u7, state: &mut MutableShowState) -> anyhow::Result<bool> {
         if channel == self.config.midi_control_channel {
             match controller.into() {
@@ -404,10 +1965,11 @@ impl<'a,'b> ShowState<'a,'b> {
                     } else if value == 0 {
                         info!("sustain released, performing buffered deactivations");
                         state.sustain = false;
+                        self.radio.start_burst();
                         // clone to appease the borrow checker
-                        for e in state.pending_off.clone().iter() {
-                            self.deactivate(*e, state)?;
-                        }
+                        let result = state.pending_off.clone().iter().try_for_each(|e| self.deactivate(*e, state));
+                        self.radio.finish_burst(self.config.merge_identical_packets())?;
+                        result?;
                         state.pending_off.clear();
                     }
                     Ok(true)
@@ -416,12 +1978,76 @@ impl<'a,'b> ShowState<'a,'b> {
                     if value == 127 {
                         info!("midi test received, firing test packet");
                         self.radio.send(&GLOBAL_TEST_PACKET)?;
-                        state.last_effect = Instant::now();
+                        state.last_effect = clock::now();
                     } else {
                         self.radio.send(&GLOBAL_OFF_PACKET)?;
                     }
                     Ok(true)
                 },
+                HUE_SHIFT_CONTROLLER => {
+                    // the full range of the knob maps to one trip around the color wheel
+                    state.color_transform.hue_offset = u8::from(value) << 1;
+                    info!("global hue offset set to: {}", state.color_transform.hue_offset);
+                    self.premarshal_realtime(state);
+                    Ok(true)
+                },
+                SATURATION_TRIM_CONTROLLER => {
+                    state.color_transform.saturation_trim = value.into();
+                    info!("global saturation trim set to: {}/127", state.color_transform.saturation_trim);
+                    self.premarshal_realtime(state);
+                    Ok(true)
+                },
+                VALUE_TRIM_CONTROLLER => {
+                    state.color_transform.value_trim = value.into();
+                    info!("global value trim set to: {}/127", state.color_transform.value_trim);
+                    self.premarshal_realtime(state);
+                    Ok(true)
+                },
+                REPLAY_CONTROLLER => {
+                    if value == 127 {
+                        self.replay(None, state)?;
+                    }
+                    Ok(true)
+                },
+                GRAND_MASTER_CONTROLLER => {
+                    self.set_grand_master((u8::from(value) as u16 * 100 / 127) as u8, state);
+                    Ok(true)
+                },
+                CROSSFADE_CONTROLLER => {
+                    // the value picks the scene, in the order the show lists them
+                    match self.show.scenes.iter().flatten().nth(u8::from(value) as usize) {
+                        Some(scene) => self.crossfade(&scene.name, state)?,
+                        None => warn!("crossfade requested to scene number: {}, which the show doesn't have", value)
+                    }
+                    Ok(true)
+                },
+                PREVIEW_CONTROLLER => {
+                    match self.preview_group {
+                        Some(_) if value == 127 && !state.preview => {
+                            info!("preview on, cues go to the preview group only");
+                            state.preview = true;
+                        },
+                        Some(group) if value == 0 && state.preview => {
+                            info!("preview off, cues go to their targets");
+                            state.preview = false;
+                            self.radio.send(&Packet { recipients: &vec![group], payload: PacketPayload::Show(ShowPacket::OFF_PACKET) })?;
+                        },
+                        Some(_) => {},
+                        None => warn!("preview requested but no preview group is configured")
+                    }
+                    Ok(true)
+                },
+                TEMPO_LEARN_CONTROLLER => {
+                    if value == 127 {
+                        if self.config.tempo_control.is_some() {
+                            info!("tempo learn armed, move the knob to bind it");
+                            state.tempo_learn = true;
+                        } else {
+                            warn!("tempo learn requested but no tempo control is configured");
+                        }
+                    }
+                    Ok(true)
+                },
                 _ => Ok(false)
             }
         } else {
@@ -433,11 +2059,46 @@ impl<'a,'b> ShowState<'a,'b> {
         if self.process_special_controllers( channel, controller, value, state)? {
             return Ok(())
         }
+        if let Some(action) = self.cue_list_controllers.get(&(channel, controller)) {
+            return if value == 127 { self.move_cue_list(*action, state) } else { Ok(()) }
+        }
+        if self.cue_list_jump == Some((channel, controller)) {
+            return self.move_cue_list(CueListAction::Jump(u8::from(value) as usize), state)
+        }
+        if let Some(tempo_control) = &self.config.tempo_control {
+            if state.tempo_learn {
+                if self.controller_in_use((channel, controller)) {
+                    warn!("channel: {} cc: {} is already in use, move another knob to bind the tempo", channel, controller);
+                } else {
+                    info!("tempo knob bound to channel: {} cc: {}", channel, controller);
+                    state.tempo_binding = Some((channel, controller));
+                    state.tempo_learn = false;
+                }
+            }
+            if state.tempo_binding == Some((channel, controller)) {
+                let tempo = tempo_control.bpm(value.into());
+                debug!("tempo knob set tempo to: {}", tempo);
+                state.tempo_override = Some(tempo);
+                self.clip_engine.set_tempo(tempo);
+                return self.move_sweep(Sweep::Control(channel, controller), tempo.round().min(255.0) as u8, state)
+            }
+        }
+        if let Some(name) = self.variable_controls.get(&(channel, controller)) {
+            // the controller's range is split evenly among the values
+            let values = &self.show.variables.as_ref().unwrap()[name].values;
+            let value = &values[u8::from(value) as usize * values.len() / 128];
+            return self.set_variable(name, value, state)
+        }
+        if let Some(control) = self.controls.get(&(channel, controller)) {
+            return self.move_sweep(Sweep::Control(channel, controller), control.definition.scaled(value.into()), state)
+        }
         match self.controller_mappings.get(&(channel, controller)) {
             Some(ids) => {
+                // deactivations aren't filtered by song, so a cue that was on when the song
+                // changed still turns off (turning off an inactive cue does nothing)
                 for id in ids {
                     match u8::from(value) {
-                        127 => self.activate(*id, None, state)?,
+                        127 if self.in_active_song(*id, state) => self.activate_from_midi(*id, None, state)?,
                         0 => self.deactivate_from_midi(*id, state)?,
                         _ => ()
                     }
@@ -448,11 +2109,18 @@ impl<'a,'b> ShowState<'a,'b> {
         }
     }
 
-    fn process_note_on(self: &Self, channel: u4, key: u7, _velocity: u7, state: &mut MutableShowState) -> anyhow::Result<()> {
+    fn process_note_on(self: &Self, channel: u4, key: u7, velocity: u7, state: &mut MutableShowState) -> anyhow::Result<()> {
+        if let Some(action) = self.cue_list_notes.get(&(channel, key)) {
+            return self.move_cue_list(*action, state)
+        }
         match self.note_mappings.get(&(channel, key)) {
             Some(ids) => {
                 for id in ids {
-                    self.activate(*id, None, state)?;
+                    if self.in_active_song(*id, state) {
+                        let overrides = self.velocity_overrides(*id, velocity, state);
+                        let overrides = self.modulated(state.light_mappings.get(id).unwrap(), overrides, state);
+                        self.activate_from_midi(*id, overrides, state)?;
+                    }
                 }
                 Ok(())
             },
@@ -460,6 +2128,17 @@ impl<'a,'b> ShowState<'a,'b> {
         }
     }
 
+    /// for mappings whose brightness follows velocity, the mapping's color scaled by it
+    fn velocity_overrides(self: &Self, mapping_id: usize, velocity: u7, state: &MutableShowState) -> Option<EffectOverrides> {
+        let meta = state.light_mappings.get(&mapping_id)?;
+        if !meta.source.velocity_to_brightness.unwrap_or(false) {
+            return None
+        }
+        let v = (meta.color.v as u16 * u8::from(velocity) as u16 / 127) as u8;
+        Some(EffectOverrides { color: Some(Color { v, ..meta.color }), tempo: None, attack: None, sustain: None, release: None })
+    }
+
+    /// note offs aren't filtered by song, see process_controller
     fn process_note_off(self: &Self, channel: u4, key: u7, _velocity: u7, state: &mut MutableShowState) -> anyhow::Result<()> {
         match self.note_mappings.get(&(channel, key)) {
             Some(ids) => {
@@ -473,36 +2152,341 @@ impl<'a,'b> ShowState<'a,'b> {
     }
 
     pub fn activate(self: &Self, mapping_id: usize, overrides: Option<EffectOverrides>, state: &mut MutableShowState) -> anyhow::Result<()> {        
-        let light = &state.light_mappings.get(&mapping_id).unwrap().source.light;
-        match light {
+        if state.stale_mappings.contains(&mapping_id) {
+            self.refresh_light_mapping(mapping_id, state)?;
+            self.premarshal_realtime(state);
+        }
+        let source = state.light_mappings.get(&mapping_id).unwrap().source;
+        if let Some(tag) = source.tags.iter().flatten().find(|t| state.muted_tags.contains(*t)) {
+            debug!("cue: {} is muted by its tag: {}", source.cue, tag);
+            return Ok(())
+        }
+        let overrides = self.tag_adjusted(source, overrides, state);
+        match &source.light {
             LightMappingType::Effect(effect) => self.activate_effect(mapping_id, &effect, overrides, state),
-            LightMappingType::Clip(clip) => self.activate_clip( mapping_id, &clip, state)
+            LightMappingType::Clip(clip) => self.activate_clip( mapping_id, &clip, state),
+            LightMappingType::Matrix(_) | LightMappingType::FlashText(_) | LightMappingType::Preset(_) => 
+                Err(anyhow!("Meta-effect was not expanded into a clip when the show loaded"))
+        }?;
+        self.run_hook(mapping_id, true, state);
+        Ok(())
+    }
+
+    /// run the mapping's exec hook, if hooks are enabled. previewed cues don't
+    /// run them, as nothing is really happening on the field
+    fn run_hook(self: &Self, mapping_id: usize, on: bool, state: &MutableShowState) {
+        if let (Some(hooks), Some(meta)) = (&self.hooks, state.light_mappings.get(&mapping_id)) {
+            if meta.source.exec.is_some() && !state.preview {
+                let receivers: Vec<u8> = meta.receivers.iter().map(|r| r.borrow().id).collect();
+                hooks.run(meta.source, on, &receivers);
+            }
         }
     }
 
+    /// stop exec hooks running, eg for a dry run of the show
+    pub fn disable_hooks(self: &mut Self) {
+        self.hooks = None;
+    }
+
     fn activate_effect(self: &Self, mapping_id: usize, effect: &Effect, overrides: Option<EffectOverrides>, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let now = clock::now();
         let mapping_meta = state.light_mappings.get(&mapping_id).unwrap();
+        let retrigger = mapping_meta.source.retrigger.unwrap_or_default();
+        let retriggered = retrigger != RetriggerPolicy::Restart && mapping_meta.is_active(now);
+        if retriggered && retrigger == RetriggerPolicy::Ignore {
+            debug!("ignoring retrigger of active cue: {}", mapping_meta.source.cue);
+            return Ok(())
+        }
         info!("activate cue: {}", mapping_meta.source.cue);
 
+        let (attack, sustain, release) = envelope(mapping_meta, &overrides);
+
+        // a randomized mapping picks the receivers that take this activation
+        let subset = mapping_meta.source.random_subset.map(|subset| self.pick_random_subset(subset, mapping_meta, state));
+
+        // receivers showing a higher priority mapping keep it, with this one layered beneath
+        let priority = mapping_meta.source.priority.unwrap_or(0);
+        let held: HashSet<u8> = mapping_meta.receivers.iter()
+            .filter(|r| r.borrow().holds_above(priority))
+            .map(|r| r.borrow().id)
+            .collect();
+
+        // realtime mappings triggered live send the packet marshalled ahead of time
+        let muting = !state.muted.is_empty() || !held.is_empty();
+        let preview_group = self.preview_group.filter(|_| state.preview);
+        // a synthesized fade holds its packets back to step them in from tick
+        let synth_fade = mapping_meta.source.synth_fade.unwrap_or(false) && attack > 0 && !retriggered;
+        let mut fading: Vec<(Vec<u8>, ShowPacket)> = vec![];
+        // what a critical mapping sent, to repeat to receivers with poor links
+        let critical = mapping_meta.source.critical.unwrap_or(false) && self.config.adaptive_repeats.is_some();
+        let mut sent: Vec<(Vec<u8>, ShowPacket)> = vec![];
+        let color_transform = self.color_transform_for(mapping_meta, state);
+        match state.realtime_packets.get_mut(&mapping_id).filter(|_| overrides.is_none() && !retriggered && !muting && preview_group.is_none() && subset.is_none() && !synth_fade) {
+            Some(marshalled) => {
+                self.radio.send_marshalled(marshalled)?;
+                if critical {
+                    sent.push((mapping_meta.targets.clone(), self.build_show_packet(mapping_meta, effect, &overrides, retriggered, &color_transform, state)));
+                }
+            },
+            None if preview_group.is_some() => {
+                let show_packet = self.build_show_packet(mapping_meta, effect, &overrides, retriggered, &color_transform, state);
+                self.radio.send(&Packet { recipients: &vec![preview_group.unwrap()], payload: PacketPayload::Show(show_packet) })?;
+                // the real targets are untouched, so their state is too
+                state.last_effect = now;
+                return Ok(())
+            },
+            None => {
+                let show_packet = self.build_show_packet(mapping_meta, effect, &overrides, retriggered, &color_transform, state);
+                let mut send_show = |recipients: &Vec<u8>, packet: ShowPacket| -> Result<(), RadioError> {
+                    if synth_fade {
+                        fading.push((recipients.clone(), packet));
+                        Ok(())
+                    } else {
+                        if critical {
+                            sent.push((recipients.clone(), packet));
+                        }
+                        self.radio.send(&Packet { recipients, payload: PacketPayload::Show(packet) })
+                    }
+                };
+                for (recipients, packet) in self.fan_out(mapping_meta, subset.as_deref(), show_packet) {
+                    let recipients = if muting { self.available_targets(&recipients, &held, state) } else { Some(recipients) };
+                    if let Some(recipients) = recipients {
+                        send_show(&recipients, packet)?;
+                    }
+                }
+            }
+        }
+        // update the receivers triggered by this mapping as active via this mapping
+        mapping_meta.receivers.iter()
+            .filter(|r| !state.muted.contains(&r.borrow().id))
+            .filter(|r| subset.as_ref().map_or(true, |picked| picked.contains(&r.borrow().id)))
+            .for_each(|r| r.borrow_mut().activate(&mapping_meta.source));
+        // one shots aren't tracked by receiver state, so remember when their envelope ends
+        if mapping_meta.source.one_shot.unwrap_or(false) && sustain > 0 {
+            let envelope = Duration::from_millis((if retriggered { 0 } else { attack } + sustain + release) as u64);
+            state.light_mappings.get_mut(&mapping_id).unwrap().envelope_end = Some(now + envelope);
+        }
+        if synth_fade {
+            let step = Duration::from_millis(self.config.synth_fade_step_millis.unwrap_or(DEFAULT_SYNTH_FADE_STEP));
+            state.synth_fades.retain(|f| f.mapping_id != mapping_id);
+            state.synth_fades.extend(fading.into_iter().map(|(recipients, packet)|
+                SynthFade::new(mapping_id, recipients, packet, now, Duration::from_millis(attack as u64), step)));
+        }
+        state.last_effect = now;
+        if !sent.is_empty() {
+            self.send_adaptive_repeats(&sent, state)?;
+        }
+        Ok(())
+    }
+
+    /// split the recipients of a mapping's show packet by how it goes to them: as it is,
+    /// with a chase's direction flipped for receivers strung the other way round, and as
+    /// the substitute old firmware takes, if there is one. with nobody picked it goes to
+    /// all the mapping's targets, an empty list of which means everybody
+    fn fan_out(self: &Self, meta: &LightMappingMeta, picked: Option<&[u8]>, show_packet: ShowPacket) -> Vec<(Vec<u8>, ShowPacket)> {
+        let pick = |ids: &[u8]| -> Vec<u8> { ids.iter().copied().filter(|id| picked.map_or(true, |p| p.contains(id))).collect() };
+        let mut sends = vec![];
+        let primary = match picked {
+            Some(picked) => picked.iter().copied()
+                .filter(|id| !meta.reversed_targets.contains(id) && !meta.shims.iter().any(|shim| shim.recipients.contains(id)))
+                .collect(),
+            None => meta.primary_targets.clone().unwrap_or_else(|| meta.targets.clone())
+        };
+        // everybody only if nobody needed flipping or a substitute
+        if !primary.is_empty() || (picked.is_none() && meta.primary_targets.is_none()) {
+            sends.push((primary, show_packet));
+        }
+        let reversed = pick(&meta.reversed_targets);
+        if !reversed.is_empty() {
+            sends.push((reversed, ShowPacket { param2: show_packet.param2 ^ 1, ..show_packet }));
+        }
+        for shim in meta.shims.iter() {
+            let shimmed = pick(&shim.recipients);
+            if let Some(substitute) = show_packet.downgrade(shim.firmware).filter(|_| !shimmed.is_empty()) {
+                sends.push((shimmed, substitute));
+            }
+        }
+        sends
+    }
+
+    /// pick a random subset of the mapping's unmuted receivers, by their weights, in id order
+    fn pick_random_subset(self: &Self, subset: RandomSubset, mapping_meta: &LightMappingMeta, state: &MutableShowState) -> Vec<u8> {
+        let weight = |id: &u8| self.random_weights.get(id).copied().unwrap_or(1.0);
+        let candidates: Vec<u8> = mapping_meta.receivers.iter()
+            .map(|r| r.borrow().id)
+            .filter(|id| !state.muted.contains(id) && weight(id) > 0.0)
+            .collect();
+        let mut picked: Vec<u8> = candidates.choose_multiple_weighted(&mut rand::thread_rng(), subset.count(candidates.len()), weight)
+            .expect("weights are checked when the show loads")
+            .copied()
+            .collect();
+        picked.sort();
+        debug!("cue: {} picked receivers: {:?}", mapping_meta.source.cue, picked);
+        picked
+    }
+
+    fn build_show_packet(self: &Self, mapping_meta: &LightMappingMeta, effect: &Effect, overrides: &Option<EffectOverrides>,
+        retriggered: bool, color_transform: &ColorTransform, state: &MutableShowState) -> ShowPacket {
+        let (attack, sustain, release) = envelope(mapping_meta, overrides);
         let mut show_packet = ShowPacket {
             effect: effect.to_effect_id(),
-            color: overrides.as_ref().and_then(|o| o.color).unwrap_or(mapping_meta.color),
-            attack: convert_millis_adr(overrides.as_ref().and_then(|o| o.attack).or(mapping_meta.source.attack).unwrap_or(0)),
-            sustain: convert_millis_sustain(overrides.as_ref().and_then(|o| o.sustain).or(mapping_meta.source.sustain).unwrap_or(0)),
-            release: convert_millis_adr(overrides.as_ref().and_then(|o| o.release).or(mapping_meta.source.release).unwrap_or(0)),
+            color: color_transform.apply(overrides.as_ref().and_then(|o| o.color).unwrap_or(mapping_meta.color)),
+            // an extended retrigger skips the attack so there's no visible re-attack
+            attack: if retriggered { 0 } else { convert_millis_adr(attack) },
+            sustain: convert_millis_sustain(sustain),
+            release: convert_millis_adr(release),
             param1: 0,
             param2: 0,
-            tempo: overrides.as_ref().and_then(|o| o.tempo).or(mapping_meta.source.tempo).unwrap_or(120.0) as u8
+            // the tempo being played, if following midi clock, over the one the show was written at
+            tempo: overrides.as_ref().and_then(|o| o.tempo).or(state.midi_clock.tempo()).or(mapping_meta.source.tempo).unwrap_or(120.0) as u8
         };
         effect.populate_effect_params(&mut show_packet);
-        let packet = Packet {
-            recipients: &mapping_meta.targets,
-            payload: PacketPayload::Show(show_packet),
-        };
-        self.radio.send(&packet)?;
-        // update the receivers triggered by this mapping as active via this mapping
-        mapping_meta.receivers.iter().for_each(|r| r.borrow_mut().activate(&mapping_meta.source));
-        state.last_effect = Instant::now();
+        // a continuous control's setting beats the effect's own
+        if let Some(param1) = state.param1_overrides.get(&mapping_meta.source.get_id()) {
+            show_packet.param1 = *param1;
+        }
+        show_packet
+    }
+
+    /// play the configured startup clip, if any
+    pub fn play_startup_clip(self: &Self) {
+        if let Some(startup_clip) = &self.config.startup_clip {
+            info!("Playing startup clip: {}", startup_clip);
+            if let Err(e) = self.clip_engine.start_clip(startup_clip, None, 120.0) {
+                error!("Could not play startup clip, error: {}", e);
+            }
+        }
+    }
+
+    /// turn everything off straight away: stop clips and replays, drop buffered offs, and
+    /// forget what the receivers were showing so the show carries on from dark
+    pub fn blackout(self: &Self, state: &mut MutableShowState) -> anyhow::Result<()> {
+        info!("blackout");
+        self.clip_engine.stop_all(&self, state)?;
+        state.replay_queue.clear();
+        state.pending_off.clear();
+        state.synth_fades.clear();
+        for receiver in state.receiver_state.values() {
+            receiver.borrow_mut().clear();
+        }
+        self.radio.send(&GLOBAL_OFF_PACKET)?;
+        Ok(())
+    }
+
+    /// what the show is doing right now. muted groups are kept by the director, so are left empty
+    pub fn status(self: &Self, state: &MutableShowState) -> ShowStatus {
+        let mut active_cues: Vec<String> = state.light_mappings.values()
+            .filter(|m| m.receivers.iter().any(|r| r.borrow().activated_by(m.source)))
+            .map(|m| m.source.cue.clone())
+            .collect();
+        active_cues.sort();
+        active_cues.dedup();
+        let mut muted_tags: Vec<String> = state.muted_tags.iter().cloned().collect();
+        muted_tags.sort();
+        let links = state.links.iter().map(|(id, quality)| ReceiverLink {
+            receiver: self.receiver_name(*id),
+            quality: *quality,
+            marginal: self.is_marginal(quality)
+        }).collect();
+        ShowStatus {
+            show_file: self.config.show_file.clone(),
+            active_song: state.active_song.clone(),
+            active_cues,
+            playing_clips: self.clip_engine.playing_clips(),
+            tempo: self.master_tempo(),
+            muted_groups: vec![],
+            preview: state.preview,
+            asleep: state.asleep,
+            variables: state.variables.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            muted_tags,
+            cue_list_step: state.cue_list_step,
+            links,
+            packet_id: self.radio.packet_id(),
+            telemetry: state.telemetry.iter().map(|(id, (telemetry, at))| ReceiverStatus {
+                receiver: self.receiver_name(*id),
+                telemetry: *telemetry,
+                age_secs: (clock::now() - *at).as_secs(),
+                low_battery: self.is_low_battery(telemetry)
+            }).collect()
+        }
+    }
+
+    /// the channel and note of each realtime mapping triggered by a note
+    pub fn realtime_notes(self: &Self, state: &MutableShowState) -> HashSet<(u8,u8)> {
+        self.note_mappings.iter()
+            .filter(|(_, ids)| ids.iter().any(|id| state.light_mappings.get(id).is_some_and(|meta| meta.source.realtime.unwrap_or(false))))
+            .map(|((channel, note), _)| (u8::from(*channel), u8::from(*note)))
+            .collect()
+    }
+
+    /// every cue in the show by name, in order, and whether it's showing
+    pub fn cues(self: &Self, state: &MutableShowState) -> Vec<CueListing> {
+        let playing = self.clip_engine.playing_clips();
+        let mut cues: BTreeMap<&str,CueListing> = BTreeMap::new();
+        for mapping in self.show.mappings.iter() {
+            let listing = cues.entry(&mapping.cue).or_insert_with(|| CueListing { cue: mapping.cue.clone(), songs: vec![], active: false });
+            if let Some(song) = mapping.song.as_ref().filter(|song| !listing.songs.contains(song)) {
+                listing.songs.push(song.clone());
+            }
+            listing.active |= state.light_mappings.get(&mapping.get_id()).is_some_and(|meta| match &meta.source.light {
+                LightMappingType::Clip(clip) => playing.contains(clip),
+                _ => meta.receivers.iter().any(|r| r.borrow().activated_by(meta.source))
+            });
+        }
+        cues.into_values().collect()
+    }
+
+    /// start a clip by name, at the tempo the show is running at
+    pub fn play_clip(self: &Self, clip: &str, state: &MutableShowState) -> anyhow::Result<()> {
+        let tempo = state.midi_clock.tempo().or(state.tempo_override).or(self.master_tempo()).unwrap_or(120f32);
+        self.clip_engine.start_clip(clip, None, tempo)
+    }
+
+    /// where the song the midi clock is playing has got to, if it is following one
+    pub fn song_position(self: &Self, state: &MutableShowState) -> Option<SongPosition> {
+        state.midi_clock.song_position()
+    }
+
+    pub fn stop_clip(self: &Self, clip: &str, state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.clip_engine.stop_clip(clip, self, state)
+    }
+
+    /// every receiver in the show, in order, and what it's doing
+    pub fn receiver_summaries(self: &Self, state: &MutableShowState) -> Vec<ReceiverSummary> {
+        let mut receivers: Vec<&ReceiverConfiguration> = self.show.receivers.iter().collect();
+        receivers.sort_by_key(|r| r.id);
+        receivers.into_iter().map(|r| {
+            let group_id = r.group_name.as_ref().and_then(|g| self.target_lookup.get(g)).copied();
+            ReceiverSummary {
+                id: r.id,
+                name: r.name.clone(),
+                group: r.group_name.clone(),
+                cue: state.receiver_state.get(&r.id)
+                    .and_then(|rs| rs.borrow().showing().and_then(|id| state.light_mappings.get(&id)))
+                    .map(|m| m.source.cue.clone()),
+                last_sent: self.radio.last_sent_to(&[Some(r.id), group_id].into_iter().flatten().collect::<Vec<u8>>())
+            }
+        }).collect()
+    }
+
+    /// the little a websocket client needs to follow the show, cheap enough to take often
+    pub fn snapshot(self: &Self, state: &MutableShowState) -> ShowSnapshot {
+        let receivers: BTreeMap<String,Option<String>> = state.receiver_state.iter().map(|(id, rs)| {
+            let cue = rs.borrow().showing().and_then(|id| state.light_mappings.get(&id)).map(|m| m.source.cue.clone());
+            (self.receiver_name(*id), cue)
+        }).collect();
+        ShowSnapshot {
+            active_cues: receivers.values().flatten().cloned().collect(),
+            playing_clips: self.clip_engine.playing_clips().into_iter().collect(),
+            receivers,
+            last_lights_out: state.last_lights_out
+        }
+    }
+
+    /// the show is over: stop every clip and turn every receiver off
+    pub fn end_show(self: &Self, state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.clip_engine.stop_all(&self, state)?;
+        self.radio.send(&GLOBAL_OFF_PACKET)?;
         Ok(())
     }
 
@@ -510,15 +2494,278 @@ impl<'a,'b> ShowState<'a,'b> {
     /// on every iteration of the show loop, returns the maximum amout of time to wait before
     /// calling tick again.
     pub fn tick(self: &Self, state: &mut MutableShowState) -> anyhow::Result<Duration> {
-        let now = Instant::now();
+        // whatever comes due together (eg the mappings of a clip step) goes out back to back
+        self.radio.start_burst();
+        let result = self.perform_due(state);
+        self.radio.finish_burst(self.config.merge_identical_packets())?;
+        let wait = result?;
+        // polls go after the burst rather than holding it up
+        let poll_at = [self.listen_for_announcements(state)?, self.poll_link(state)?, self.poll_telemetry(state)?]
+            .into_iter().flatten().min();
+        match poll_at {
+            Some(poll_at) => Ok(wait.min(poll_at.saturating_duration_since(clock::now()))),
+            None => Ok(wait)
+        }
+    }
+
+    /// if it's time, listen for receivers announcing themselves. any that have since we last
+    /// listened (eg one powered up late or given a fresh battery mid-show) come back in a
+    /// report, to be configured then. returns when to listen next
+    fn listen_for_announcements(self: &Self, state: &mut MutableShowState) -> Result<Option<Instant>, RadioError> {
+        let Some(period) = self.config.announcement_listen_delay() else { return Ok(None) };
+        let now = clock::now();
+        if now - state.last_listen >= period {
+            state.last_listen = now;
+            let reports = state.reports.clone();
+            self.radio.listen_for_announcements(ANNOUNCEMENT_LISTEN_WINDOW, move |announced| {
+                if let Some(reports) = reports.filter(|_| !announced.is_empty()) {
+                    let _ = reports.send(RadioReport::Announced(announced));
+                }
+            })?;
+        }
+        Ok(Some(state.last_listen + period))
+    }
+
+    /// configure receivers that announced themselves from the roster, without disturbing the others
+    fn configure_announced(self: &Self, announced: Vec<(u8,u8)>, state: &MutableShowState) -> Result<(), RadioError> {
+        for (id, firmware) in announced {
+            let Some(receiver) = self.show.receivers.iter().find(|r| r.id == id) else {
+                warn!("receiver: {} announced itself but is not in the show's roster", id);
+                continue
+            };
+            let rostered = receiver.firmware.unwrap_or(CURRENT_FIRMWARE);
+            if firmware != rostered && rostered >= ANNOUNCE_FIRMWARE {
+                warn!("receiver: {} announced firmware {} but the roster says {}", self.receiver_name(id), firmware, rostered);
+            }
+            if state.asleep {
+                info!("receiver: {} joined late while asleep, it will be configured on waking", self.receiver_name(id));
+                continue
+            }
+            info!("receiver: {} joined late, configuring it", self.receiver_name(id));
+            if !self.configure_receiver(receiver, self.config_packet_spacing())? && self.radio.expects_acks() {
+                warn!("receiver: {} did not confirm its configuration", self.receiver_name(id));
+            }
+        }
+        Ok(())
+    }
+
+    /// the receivers whose firmware can report their status
+    fn queryable_receivers(self: &Self) -> Vec<u8> {
+        self.show.receivers.iter()
+            .filter(|r| r.firmware.unwrap_or(CURRENT_FIRMWARE) >= STATUS_FIRMWARE)
+            .map(|r| r.id)
+            .collect()
+    }
+
+    /// if a status query is due, query the next receiver in turn, returning when the next query is due
+    fn poll_telemetry(self: &Self, state: &mut MutableShowState) -> Result<Option<Instant>, RadioError> {
+        let Some(poll_delay) = self.config.telemetry_poll_delay() else { return Ok(None) };
+        let now = clock::now();
+        if now - state.last_telemetry_poll >= poll_delay && !state.asleep {
+            let queryable = self.queryable_receivers();
+            if !queryable.is_empty() {
+                let receiver = queryable[state.next_telemetry_poll % queryable.len()];
+                state.next_telemetry_poll = (state.next_telemetry_poll + 1) % queryable.len();
+                self.query_status(receiver, state, None)?;
+            }
+            state.last_telemetry_poll = now;
+        }
+        Ok(Some(state.last_telemetry_poll + poll_delay))
+    }
+
+    /// ask every receiver that can answer for its battery and uptime now, eg for a pre-show
+    /// battery report. the show carries on while they answer, and once the last has (or
+    /// hasn't) the status goes back through the reply
+    pub fn query_all_status(self: &Self, state: &mut MutableShowState, reply: &Sender<Result<ShowStatus>>) -> anyhow::Result<()> {
+        if state.asleep {
+            return Err(anyhow!("Receivers are asleep, wake them before asking for their status"))
+        }
+        let queryable = self.queryable_receivers();
+        info!("querying the status of {} receivers", queryable.len());
+        for (i, receiver) in queryable.iter().enumerate() {
+            let last = i + 1 == queryable.len();
+            self.query_status(*receiver, state, last.then(|| reply.clone()))?;
+        }
+        if queryable.is_empty() {
+            let _ = reply.send(Ok(self.status(state)));
+        }
+        Ok(())
+    }
+
+    /// ask a receiver for its status, the answer coming back later as a report
+    fn query_status(self: &Self, receiver: u8, state: &mut MutableShowState, reply: Option<Sender<Result<ShowStatus>>>) -> Result<(), RadioError> {
+        let timeout = Duration::from_millis(self.config.link_poll_timeout_millis.unwrap_or(DEFAULT_POLL_TIMEOUT));
+        let reports = state.reports.clone();
+        self.radio.query_status(receiver, timeout, move |telemetry| {
+            if let Some(reports) = reports {
+                let _ = reports.send(RadioReport::StatusQueried { receiver, telemetry, reply });
+            }
+        })
+    }
+
+    fn is_low_battery(self: &Self, telemetry: &Option<ReceiverTelemetry>) -> bool {
+        telemetry.is_some_and(|t| t.battery_millivolts < self.config.low_battery_millivolts.unwrap_or(DEFAULT_LOW_BATTERY_MILLIVOLTS))
+    }
+
+    /// if a link poll is due, poll the next receiver in turn, returning when the next poll is due.
+    /// the answer comes back later, as a report
+    fn poll_link(self: &Self, state: &mut MutableShowState) -> Result<Option<Instant>, RadioError> {
+        let Some(poll_delay) = self.config.link_poll_delay() else { return Ok(None) };
+        let now = clock::now();
+        // polls would wake receivers that wake on any packet
+        if now - state.last_poll >= poll_delay && !state.asleep {
+            let pollable: Vec<u8> = self.show.receivers.iter()
+                .filter(|r| r.firmware.unwrap_or(CURRENT_FIRMWARE) >= POLL_FIRMWARE)
+                .map(|r| r.id)
+                .collect();
+            if !pollable.is_empty() {
+                let receiver = pollable[state.next_poll % pollable.len()];
+                state.next_poll = (state.next_poll + 1) % pollable.len();
+                let timeout = Duration::from_millis(self.config.link_poll_timeout_millis.unwrap_or(DEFAULT_POLL_TIMEOUT));
+                let reports = state.reports.clone();
+                self.radio.poll(receiver, timeout, move |quality| {
+                    if let Some(reports) = reports {
+                        let _ = reports.send(RadioReport::LinkPolled { receiver, quality });
+                    }
+                })?;
+            }
+            state.last_poll = now;
+        }
+        Ok(Some(state.last_poll + poll_delay))
+    }
+
+    /// have the radio report what it hears back in the background (link polls' and status
+    /// queries' answers, and announcements)
+    /// to the director, which hands each report back to take_report
+    pub fn report_radio_to(self: &Self, reports: Sender<RadioReport>, state: &mut MutableShowState) {
+        state.reports = Some(reports);
+    }
+
+    /// take in what the radio heard back in the background
+    pub fn take_report(self: &Self, report: RadioReport, state: &mut MutableShowState) -> Result<()> {
+        match report {
+            RadioReport::LinkPolled { receiver, quality } => {
+                let name = self.receiver_name(receiver);
+                match &quality {
+                    None => warn!("receiver: {} did not answer a link poll", name),
+                    Some(q) if self.is_marginal(&quality) =>
+                        warn!("link to receiver: {} is marginal, heard at {} dBm, answered at {}", name, q.at_receiver, describe_rssi(q.at_transmitter)),
+                    Some(q) => info!("link to receiver: {} heard at {} dBm, answered at {}", name, q.at_receiver, describe_rssi(q.at_transmitter))
+                }
+                let history = state.link_history.entry(receiver).or_default();
+                history.push_back(self.is_marginal(&quality));
+                if history.len() > LINK_HISTORY_LENGTH {
+                    history.pop_front();
+                }
+                state.links.insert(receiver, quality);
+            },
+            RadioReport::StatusQueried { receiver, telemetry, reply } => {
+                let name = self.receiver_name(receiver);
+                match &telemetry {
+                    None => warn!("receiver: {} did not answer a status query", name),
+                    Some(t) if self.is_low_battery(&telemetry) =>
+                        warn!("receiver: {} battery is low at {:.2} V, up {} minutes", name, t.battery_millivolts as f32 / 1000.0, t.uptime_minutes),
+                    Some(t) => info!("receiver: {} battery at {:.2} V, up {} minutes", name, t.battery_millivolts as f32 / 1000.0, t.uptime_minutes)
+                }
+                state.telemetry.insert(receiver, (telemetry, clock::now()));
+                if let Some(reply) = reply {
+                    let _ = reply.send(Ok(self.status(state)));
+                }
+            },
+            RadioReport::Announced(announced) => self.configure_announced(announced, state)?
+        }
+        Ok(())
+    }
+
+    /// a receiver is marginal if it didn't answer, or either direction is weak
+    fn is_marginal(self: &Self, quality: &Option<LinkQuality>) -> bool {
+        quality.map_or(true, |q| q.weakest() < self.config.marginal_rssi.unwrap_or(DEFAULT_MARGINAL_RSSI) as f32)
+    }
+
+    /// how many extra copies of a critical cue a receiver gets: the configured most,
+    /// scaled (rounding up) by the share of its recent link polls that came back marginal
+    fn adaptive_repeats_for(self: &Self, receiver: u8, state: &MutableShowState) -> u8 {
+        let (Some(most), Some(history)) = (self.config.adaptive_repeats, state.link_history.get(&receiver)) else { return 0 };
+        let marginal = history.iter().filter(|m| **m).count();
+        ((most as usize * marginal).div_ceil(history.len())) as u8
+    }
+
+    /// send a critical cue's packets again to each receiver with a poor link, to it alone,
+    /// a round at a time so the receivers share what's left of the airtime budget
+    fn send_adaptive_repeats(self: &Self, sent: &[(Vec<u8>, ShowPacket)], state: &mut MutableShowState) -> Result<(), RadioError> {
+        let mut repeats: Vec<(u8, ShowPacket, u8)> = vec![];
+        for (recipients, packet) in sent {
+            let mut receivers: Vec<u8> = self.expand_groups(&state.receiver_state, recipients).iter()
+                .map(|r| r.borrow().id)
+                .collect();
+            receivers.sort();
+            for receiver in receivers {
+                let count = self.adaptive_repeats_for(receiver, state);
+                if count > 0 && !repeats.iter().any(|(r, _, _)| *r == receiver) {
+                    repeats.push((receiver, *packet, count));
+                }
+            }
+        }
+        let now = clock::now();
+        if now - state.adaptive_repeats.0 >= Duration::from_secs(1) {
+            state.adaptive_repeats = (now, 0);
+        }
+        let budget = self.config.adaptive_repeat_budget.unwrap_or(DEFAULT_ADAPTIVE_REPEAT_BUDGET);
+        let rounds = repeats.iter().map(|(_, _, count)| *count).max().unwrap_or(0);
+        for round in 0..rounds {
+            for (receiver, packet, _) in repeats.iter().filter(|(_, _, count)| *count > round) {
+                if state.adaptive_repeats.1 >= budget {
+                    debug!("adaptive repeat budget spent, not repeating to receiver: {}", self.receiver_name(*receiver));
+                    return Ok(())
+                }
+                self.radio.send(&Packet { recipients: &vec![*receiver], payload: PacketPayload::Show(*packet) })?;
+                state.adaptive_repeats.1 += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn receiver_name(self: &Self, id: u8) -> String {
+        self.show.receivers.iter().find(|r| r.id == id).and_then(|r| r.name.clone()).unwrap_or_else(|| id.to_string())
+    }
+
+    fn perform_due(self: &Self, state: &mut MutableShowState) -> anyhow::Result<Duration> {
+        let now = clock::now();
+
+        // re-fire any replayed history that has come due
+        while state.replay_queue.front().is_some_and(|e| e.at <= now) {
+            let entry = state.replay_queue.pop_front().unwrap();
+            if entry.on {
+                self.activate(entry.mapping_id, None, state)?;
+            } else {
+                self.deactivate(entry.mapping_id, state)?;
+            }
+        }
+        let replay_at = state.replay_queue.front().map(|e| e.at);
+
+        // follow the brightness schedule, looking at the time of day now and then
+        let mut brightness_at: Option<Instant> = None;
+        if let Some(schedule) = &self.brightness_schedule {
+            if state.brightness_checked.map_or(true, |at| now - at >= BRIGHTNESS_CHECK_PERIOD) {
+                self.set_scheduled_brightness(schedule.percent_now(), state);
+                state.brightness_checked = Some(now);
+            }
+            brightness_at = state.brightness_checked.map(|at| at + BRIGHTNESS_CHECK_PERIOD);
+        }
 
         // advance any clips that are playing
         let play_clips_at = self.clip_engine.play_clips( &self, state);
 
+        let synth_fade_at = self.step_synth_fades(state, now)?;
+
+        let control_at = self.send_held_controls(state, now)?;
+
         // if no receivers and no clips are active, and it's been n (configurable) seconds since the last midi event,
         // send a lights-out packet once every m (configurable) seconds
-        let receiver_active = state.receiver_state.values().any(|rs| rs.borrow().is_active());
-        if !receiver_active && !self.clip_engine.is_playing() && 
+        // receivers a console holds lit over DMX count too
+        let receiver_active = state.receiver_state.values().any(|rs| rs.borrow().is_active()) ||
+            state.dmx_colors.values().any(|c| c.v > 0);
+        if !receiver_active && !self.clip_engine.is_playing() && !state.asleep &&
             self.config.lights_out_window().contains(&(now - state.last_effect)) && 
             now - state.last_lights_out >= self.config.lights_out_delay() {
 
@@ -526,21 +2773,186 @@ impl<'a,'b> ShowState<'a,'b> {
             self.radio.send(&GLOBAL_OFF_PACKET)?;
             state.last_lights_out = now;
         }
+
+        // the heartbeat is low priority, so it goes out after anything else this tick had to do
+        let mut heartbeat_at: Option<Instant> = None;
+        if let Some(heartbeat_delay) = self.config.heartbeat_delay() {
+            if now - state.last_heartbeat >= heartbeat_delay {
+                if !state.asleep {
+                    debug!("heartbeat");
+                    self.radio.send(&Packet {
+                        recipients: &ALL_RECIPIENTS,
+                        payload: PacketPayload::Control(
+                            Command::Heartbeat { period: heartbeat_delay.as_secs_f32().ceil().min(255.0) as u8 })
+                    })?;
+                }
+                state.last_heartbeat = now;
+            }
+            heartbeat_at = Some(state.last_heartbeat + heartbeat_delay);
+        }
+
         let lights_out_delay = self.config.lights_out_delay();
+        let wake_at = [play_clips_at, heartbeat_at, replay_at, brightness_at, synth_fade_at, control_at].into_iter().flatten().min();
         Ok(min(lights_out_delay, 
-            play_clips_at.map_or(lights_out_delay, |play_clips_at| play_clips_at - now)))
+            wake_at.map_or(lights_out_delay, |wake_at| wake_at.saturating_duration_since(now))))
+    }
+
+    /// send the steps of synthesized fades that have come due, returning when the next is
+    /// due. a fade is dropped once its mapping no longer has any of its receivers (it was
+    /// released, or another cue took them over)
+    fn step_synth_fades(self: &Self, state: &mut MutableShowState, now: Instant) -> Result<Option<Instant>, RadioError> {
+        let mut fades = std::mem::take(&mut state.synth_fades);
+        fades.retain(|fade| state.light_mappings.get(&fade.mapping_id)
+            .is_some_and(|m| m.receivers.iter().any(|r| r.borrow().activated_by(m.source))));
+        let result = fades.iter_mut().try_for_each(|fade| {
+            if let Some(step) = fade.due_step(now) {
+                self.radio.send(&Packet { recipients: &fade.recipients, payload: PacketPayload::Show(fade.step_packet(step)) })?;
+                fade.done = step;
+            }
+            Ok(())
+        });
+        fades.retain(|fade| fade.done < fade.steps);
+        let next_at = fades.iter().map(|fade| fade.next_at()).min();
+        state.synth_fades = fades;
+        result.map(|_| next_at)
+    }
+
+    /// the pads the show's mappings claim, and what each is to do. mappings in different
+    /// songs may share a pad, as long as they share its trigger; the first sets its color
+    pub fn assigned_pads(self: &Self, state: &MutableShowState) -> anyhow::Result<Vec<AssignedPad>> {
+        let mut metas: Vec<&LightMappingMeta> = state.light_mappings.values().filter(|m| m.source.pad.is_some()).collect();
+        metas.sort_by_key(|m| m.source.get_id());
+        let mut pads: BTreeMap<u8, (AssignedPad, &str)> = BTreeMap::new();
+        for meta in metas {
+            let pad = meta.source.pad.unwrap();
+            let trigger = match &meta.source.midi {
+                Some(MidiMappingType::Note { channel, note }) => Pad::Note { channel: *channel, note: parse_note(note)? },
+                Some(MidiMappingType::Controller { channel, cc }) => Pad::Controller { channel: *channel, controller: *cc },
+                _ => return Err(anyhow!("Mapping for cue: {} has a pad but no note or controller for it to send", meta.source.cue))
+            };
+            let assigned = AssignedPad { id: pad.id, trigger, toggle: pad.toggle.unwrap_or(false), color: meta.color };
+            match pads.get(&pad.id) {
+                Some((other, cue)) if other.trigger != trigger || other.toggle != assigned.toggle =>
+                    return Err(anyhow!("Pad: {} is set up differently by cues: {} and {}", pad.id, cue, meta.source.cue)),
+                Some(_) => {},
+                None => { pads.insert(pad.id, (assigned, &meta.source.cue)); }
+            }
+        }
+        Ok(pads.into_values().map(|(pad, _)| pad).collect())
+    }
+
+    /// the pads whose cues are showing: an effect on any receiver, or a clip playing
+    pub fn lit_pads(self: &Self, state: &MutableShowState) -> BTreeSet<Pad> {
+        let playing = self.clip_engine.playing_clips();
+        let lit = |ids: &Vec<usize>| ids.iter().filter_map(|id| state.light_mappings.get(id)).any(|meta| match &meta.source.light {
+            LightMappingType::Clip(clip) => playing.contains(clip),
+            _ => meta.receivers.iter().any(|r| r.borrow().activated_by(meta.source))
+        });
+        let notes = self.note_mappings.iter().filter(|(_, ids)| lit(ids))
+            .map(|((channel, note), _)| Pad::Note { channel: channel.as_int(), note: note.as_int() });
+        let controllers = self.controller_mappings.iter().filter(|(_, ids)| lit(ids))
+            .map(|((channel, controller), _)| Pad::Controller { channel: channel.as_int(), controller: controller.as_int() });
+        notes.chain(controllers).collect()
+    }
+
+    /// the tempo the show is currently running at, if any clips are playing
+    pub fn master_tempo(self: &Self) -> Option<f32> {
+        self.clip_engine.master_tempo()
     }
 
     fn activate_clip(self: &Self, mapping_id: usize, clip: &str, state: &mut MutableShowState) -> anyhow::Result<()> {
         let light_mapping = state.light_mappings.get(&mapping_id).unwrap();
         let override_color = if light_mapping.source.override_clip_color.unwrap_or(false) 
             { Some(light_mapping.color) } else { None };
-        self.clip_engine.start_clip(&clip, override_color, light_mapping.source.tempo.unwrap_or(120f32))
+        let tempo = state.midi_clock.tempo().or(state.tempo_override).or(light_mapping.source.tempo).unwrap_or(120f32);
+        self.clip_engine.start_clip(&clip, override_color, tempo)
+    }
+
+    /// remember a live trigger so it can be replayed, forgetting the oldest once the history is full
+    fn record_history(self: &Self, mapping_id: usize, on: bool, state: &mut MutableShowState) {
+        if state.history.len() >= self.config.history_depth.unwrap_or(DEFAULT_HISTORY_DEPTH) {
+            state.history.pop_front();
+        }
+        state.history.push_back(HistoryEntry { at: clock::now(), mapping_id, on });
+    }
+
+    /// a wrapper around activate calls coming from a live source, which are recorded in the history
+    fn activate_from_midi(self: &Self, mapping_id: usize, overrides: Option<EffectOverrides>, state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.activate(mapping_id, overrides, state)?;
+        if let Some(received) = state.midi_received {
+            let latency = clock::now() - received;
+            if state.realtime_packets.contains_key(&mapping_id) {
+                state.realtime_latency.record(latency);
+            } else {
+                state.standard_latency.record(latency);
+            }
+        }
+        // remember it after the fact, to keep history out of the latency
+        self.record_history(mapping_id, true, state);
+        let cue = state.light_mappings.get(&mapping_id).unwrap().source.cue.clone();
+        state.fired_cues.push(cue);
+        Ok(())
+    }
+
+    /// the cues fired live since this was last called
+    pub fn take_fired_cues(self: &Self, state: &mut MutableShowState) -> Vec<String> {
+        std::mem::take(&mut state.fired_cues)
+    }
+
+    /// fire (or release) a cue by name, as its midi trigger would. where mappings in
+    /// several songs share the name, only those in the current song fire
+    pub fn fire_cue(self: &Self, cue: &str, on: bool, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let ids: Vec<usize> = self.show.mappings.iter().filter(|m| m.cue == cue).map(|m| m.get_id()).collect();
+        if ids.is_empty() {
+            return Err(anyhow!("No cue named: {}", cue))
+        }
+        if on {
+            let active: Vec<usize> = ids.into_iter().filter(|id| self.in_active_song(*id, state)).collect();
+            if active.is_empty() {
+                return Err(anyhow!("Cue: {} is not in the current song", cue))
+            }
+            for id in active {
+                self.activate(id, None, state)?;
+                self.record_history(id, true, state);
+            }
+        } else {
+            for id in ids {
+                self.deactivate_from_midi(id, state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// the most recent live triggers, oldest first
+    pub fn history<'c>(self: &Self, state: &'c MutableShowState) -> &'c VecDeque<HistoryEntry> {
+        &state.history
+    }
+
+    /// re-fire history with its original relative timing. with no window, replays from the last
+    /// activation onward (ie "do that again"), otherwise replays everything in the last window
+    pub fn replay(self: &Self, window: Option<Duration>, state: &mut MutableShowState) -> anyhow::Result<()> {
+        let now = clock::now();
+        let start = match window {
+            None => state.history.iter().rposition(|e| e.on),
+            Some(window) => state.history.iter().position(|e| now - e.at <= window)
+        };
+        match start {
+            Some(start) => {
+                let origin = state.history[start].at;
+                info!("replaying {} history entries", state.history.len() - start);
+                state.replay_queue = state.history.iter().skip(start)
+                    .map(|e| HistoryEntry { at: now + (e.at - origin), ..*e })
+                    .collect();
+            },
+            None => info!("nothing in history to replay")
+        }
+        Ok(())
     }
 
     /// a wrapper around deactivate calls coming from a live source,
     /// as such calls need to be buffered if we're in "sustain" mode
     fn deactivate_from_midi(self: &Self, mapping_id: usize, state: &mut MutableShowState) -> anyhow::Result<()> {
+        self.record_history(mapping_id, false, state);
         if state.sustain {
             state.pending_off.push(mapping_id);
             Ok(())
@@ -552,22 +2964,41 @@ impl<'a,'b> ShowState<'a,'b> {
     pub fn deactivate(self: &Self, mapping_id: usize, state: &mut MutableShowState) -> anyhow::Result<()>{
         let mapping_meta = state.light_mappings.get(&mapping_id).unwrap();
         if !mapping_meta.source.one_shot.unwrap_or(false) {
+            self.run_hook(mapping_id, false, state);
             match &mapping_meta.source.light {
-                LightMappingType::Effect(e) => self.deactivate_effect(mapping_meta, e),
-                LightMappingType::Clip(c) => self.clip_engine.stop_clip(&c, &self, state)
+                LightMappingType::Effect(e) => {
+                    if let Some(group) = self.preview_group.filter(|_| state.preview) {
+                        self.radio.send(&Packet { recipients: &vec![group], payload: PacketPayload::Show(ShowPacket::OFF_PACKET) })?;
+                    }
+                    // the real targets still need turning off if the cue was on before preview started
+                    self.deactivate_effect(mapping_meta, e, state)
+                },
+                LightMappingType::Clip(c) => self.clip_engine.stop_clip(&c, &self, state),
+                LightMappingType::Matrix(_) | LightMappingType::FlashText(_) | LightMappingType::Preset(_) => 
+                    Err(anyhow!("Meta-effect was not expanded into a clip when the show loaded"))
             }
         } else {
             Ok(())
         }
     }
 
-    fn deactivate_effect(self: &Self, mapping_meta: &LightMappingMeta, _effect: &Effect) -> anyhow::Result<()> {
+    fn deactivate_effect(self: &Self, mapping_meta: &LightMappingMeta, _effect: &Effect, state: &MutableShowState) -> anyhow::Result<()> {
         info!("deactivate cue: {}",  mapping_meta.source.cue);
 
+        // receivers with a mapping layered beneath this one go back to it rather than off
+        let mut restored: BTreeMap<usize, Vec<u8>> = BTreeMap::new();
+        for receiver in mapping_meta.receivers.iter() {
+            let receiver = receiver.borrow();
+            if let Some(beneath) = receiver.beneath().filter(|_| receiver.activated_by(mapping_meta.source)) {
+                restored.entry(beneath).or_default().push(receiver.id);
+            }
+        }
+        let restoring = |r: &Rc<RefCell<ReceiverState>>| restored.values().any(|ids| ids.contains(&r.borrow().id));
+
         // we can take the simple path if all receivers activated by this effect are still
-        // activated by this effect
+        // activated by this effect, with nothing beneath it
         let simple_off_path = mapping_meta.receivers.iter().all(
-            |r| r.borrow().activated_by(&mapping_meta.source));
+            |r| r.borrow().activated_by(&mapping_meta.source) && !restoring(r));
 
         let dynamic_recipients = if simple_off_path {
             None
@@ -575,7 +3006,7 @@ impl<'a,'b> ShowState<'a,'b> {
             // otherwise we have to calculate receivers to deactivate individually by finding ones
             // this effect activated
             Some(mapping_meta.receivers.iter()
-                .filter(|r| r.borrow().activated_by(&mapping_meta.source))
+                .filter(|r| r.borrow().activated_by(&mapping_meta.source) && !restoring(r))
                 .map(|r| r.borrow().id)
                 .collect())
         };
@@ -590,12 +3021,35 @@ impl<'a,'b> ShowState<'a,'b> {
         // (all receivers were captured by another effect, so there's nothing to do)
         if dynamic_recipients.is_none() || dynamic_recipients.as_ref().is_some_and(|r| !r.is_empty()) {
             self.radio.send(&packet)?;
-            // update each receiver state as deactivated
-            for receiver in &mapping_meta.receivers {
-                receiver.borrow_mut().deactivate(&mapping_meta.source);
-            }
+        }
+        // update each receiver state as deactivated, including those it was layered beneath another on
+        for receiver in &mapping_meta.receivers {
+            receiver.borrow_mut().deactivate(&mapping_meta.source);
+        }
+        for (mapping_id, recipients) in restored {
+            self.restore(mapping_id, &recipients, state)?;
+        }
+        Ok(())
+    }
+
+    /// show a mapping again on receivers a higher priority mapping has let go of, as it
+    /// was set up (overrides its activation came with, like velocity, are gone)
+    fn restore(self: &Self, mapping_id: usize, recipients: &[u8], state: &MutableShowState) -> anyhow::Result<()> {
+        let meta = state.light_mappings.get(&mapping_id).unwrap();
+        let LightMappingType::Effect(effect) = &meta.source.light else {
+            return Ok(())
+        };
+        info!("restore cue: {} to receivers: {:?}", meta.source.cue, recipients);
+        let overrides = self.modulated(meta, None, state);
+        let show_packet = self.build_show_packet(meta, effect, &overrides, false, &self.color_transform_for(meta, state), state);
+        for (recipients, packet) in self.fan_out(meta, Some(recipients), show_packet) {
+            self.radio.send(&Packet { recipients: &recipients, payload: PacketPayload::Show(packet) })?;
         }
         Ok(())
     }
     
 }
+
+fn describe_rssi(rssi: Option<f32>) -> String {
+    rssi.map_or("an unknown strength".to_string(), |rssi| format!("{:.0} dBm", rssi))
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::Result;
+use linux_embedded_hal::gpio_cdev::{Chip, LineRequestFlags};
+use log::{error,info};
+use serde::Deserialize;
+
+///
+/// A status LED on a GPIO line, so field staff can tell the transmitter's health
+/// at a glance without a screen: solid when a show is loaded, blinking while midi
+/// is arriving, blinking fast after an error stopped the show, and off when idle
+///
+
+/// how often the LED thread updates the line
+const REFRESH: Duration = Duration::from_millis(25);
+
+/// how long after the last midi message the LED keeps blinking
+const MIDI_ACTIVE: Duration = Duration::from_secs(1);
+
+/// half periods of the blink patterns
+const BLINK: Duration = Duration::from_millis(250);
+const FAST_BLINK: Duration = Duration::from_millis(60);
+
+#[derive(Debug,Deserialize)]
+pub struct StatusLedConfig {
+    /// the gpio line the LED is wired to
+    pub line: u32,
+
+    /// the gpio device the line belongs to, defaults to the radio's gpio_device
+    pub gpio_device: Option<String>,
+
+    /// whether the LED lights when the line is low, defaults to false
+    pub active_low: Option<bool>
+}
+
+#[derive(Clone,Copy,PartialEq)]
+pub enum Health {
+    /// no show is running
+    Idle,
+    /// a show is loaded and running
+    Loaded,
+    /// the show stopped with an error (eg the radio failing)
+    Error
+}
+
+struct LedState {
+    health: Health,
+    last_midi: Option<Instant>
+}
+
+/// a handle on the LED thread, cheap to clone
+#[derive(Clone)]
+pub struct StatusLed {
+    state: Arc<Mutex<LedState>>
+}
+
+impl StatusLed {
+
+    /// claim the LED's gpio line and start the thread that drives it
+    pub fn start(config: &StatusLedConfig, default_gpio_device: &str) -> Result<StatusLed> {
+        let mut chip = Chip::new(config.gpio_device.as_deref().unwrap_or(default_gpio_device))?;
+        let active_low = config.active_low.unwrap_or(false);
+        let handle = chip.get_line(config.line)?.request(LineRequestFlags::OUTPUT, active_low as u8, "chs-lights-status")?;
+        info!("Driving status LED on gpio line {}", config.line);
+
+        let led = StatusLed { state: Arc::new(Mutex::new(LedState { health: Health::Idle, last_midi: None })) };
+        let state = led.state.clone();
+        let epoch = Instant::now();
+        thread::spawn(move || loop {
+            let now = Instant::now();
+            let lit = state.lock().unwrap().lit(now, now - epoch);
+            if let Err(e) = handle.set_value((lit != active_low) as u8) {
+                error!("Could not drive status LED, giving up on it. Error: {}", e);
+                return
+            }
+            thread::sleep(REFRESH);
+        });
+        Ok(led)
+    }
+
+    pub fn set_health(self: &Self, health: Health) {
+        self.state.lock().unwrap().health = health;
+    }
+
+    /// note that a midi message arrived
+    pub fn midi_activity(self: &Self) {
+        self.state.lock().unwrap().last_midi = Some(Instant::now());
+    }
+}
+
+impl LedState {
+    /// should the LED be lit at this point in the pattern
+    fn lit(self: &Self, now: Instant, since_start: Duration) -> bool {
+        let blink = |half_period: Duration| (since_start.as_millis() / half_period.as_millis()) % 2 == 0;
+        match self.health {
+            Health::Idle => false,
+            Health::Error => blink(FAST_BLINK),
+            Health::Loaded if self.last_midi.is_some_and(|at| now - at < MIDI_ACTIVE) => blink(BLINK),
+            Health::Loaded => true
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::time::Duration;
+use midly::live::MtcQuarterFrameMessage;
+use midly::num::u4;
+
+///
+/// MIDI time code (MTC) carries SMPTE time (hours, minutes, seconds and frames) from
+/// whatever is playing the backing track, so cues can be fired at positions in it.
+/// While playing, the position arrives a quarter frame at a time, eight messages to the
+/// full position; a locate arrives as a single full frame sysex message
+///
+
+/// the rate frames are counted at, from the top bits of the hours
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum FrameRate {
+    Fps24,
+    Fps25,
+    /// 29.97 drop frame, treated as 30 counted frames a second
+    Fps2997Drop,
+    Fps30
+}
+
+impl FrameRate {
+    fn from_bits(bits: u8) -> FrameRate {
+        match bits & 0x03 {
+            0 => FrameRate::Fps24,
+            1 => FrameRate::Fps25,
+            2 => FrameRate::Fps2997Drop,
+            _ => FrameRate::Fps30
+        }
+    }
+
+    /// frames counted to the second
+    pub fn frames(self: &Self) -> u8 {
+        match self {
+            FrameRate::Fps24 => 24,
+            FrameRate::Fps25 => 25,
+            FrameRate::Fps2997Drop | FrameRate::Fps30 => 30
+        }
+    }
+}
+
+/// a position in the time code, as a time from zero at the given frame rate
+pub fn position(hh: u8, mm: u8, ss: u8, ff: u8, rate: FrameRate) -> Duration {
+    Duration::from_secs(hh as u64 * 3600 + mm as u64 * 60 + ss as u64) +
+        Duration::from_secs_f64(ff as f64 / rate.frames() as f64)
+}
+
+/// assembles positions from quarter frame and full frame messages
+#[derive(Default)]
+pub struct MtcReader {
+    /// the nibbles of the position, frames low first, as they arrive
+    nibbles: [u8; 8],
+    /// which nibbles have arrived since the last complete position
+    received: u8
+}
+
+impl MtcReader {
+
+    /// take in a quarter frame, returning the position once all eight pieces are in
+    pub fn quarter_frame(self: &mut Self, piece: MtcQuarterFrameMessage, value: u4) -> Option<(Duration, FrameRate)> {
+        let index = match piece {
+            MtcQuarterFrameMessage::FramesLow => 0,
+            MtcQuarterFrameMessage::FramesHigh => 1,
+            MtcQuarterFrameMessage::SecondsLow => 2,
+            MtcQuarterFrameMessage::SecondsHigh => 3,
+            MtcQuarterFrameMessage::MinutesLow => 4,
+            MtcQuarterFrameMessage::MinutesHigh => 5,
+            MtcQuarterFrameMessage::HoursLow => 6,
+            MtcQuarterFrameMessage::HoursHigh => 7
+        };
+        // a run of pieces starts again with the frames
+        if index == 0 {
+            self.received = 0;
+        }
+        self.nibbles[index] = value.as_int();
+        self.received |= 1 << index;
+        if index != 7 || self.received != 0xFF {
+            return None
+        }
+        self.received = 0;
+        let byte = |i: usize| self.nibbles[i] | (self.nibbles[i + 1] << 4);
+        let hours = byte(6);
+        let rate = FrameRate::from_bits(hours >> 5);
+        // the pieces took two frames to send, so the position they spell is two frames old
+        let at = position(hours & 0x1F, byte(4), byte(2), byte(0), rate);
+        Some((at + Duration::from_secs_f64(2.0 / rate.frames() as f64), rate))
+    }
+
+    /// take in a sysex message (without its F0 and F7), returning the position if it's
+    /// a full frame message
+    pub fn sysex(self: &mut Self, data: &[u8]) -> Option<(Duration, FrameRate)> {
+        match data {
+            [0x7F, _, 0x01, 0x01, hours, minutes, seconds, frames, ..] => {
+                self.received = 0;
+                let rate = FrameRate::from_bits(hours >> 5);
+                Some((position(hours & 0x1F, *minutes, *seconds, *frames, rate), rate))
+            },
+            _ => None
+        }
+    }
+}
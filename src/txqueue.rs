@@ -0,0 +1,452 @@
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use log::{debug, error};
+
+use crate::config::ConfigFile;
+use crate::packet::{Command, DecodedPacket, EffectId, HeaderMode, PacketPayload, targets_overlap};
+use crate::radio::{Listener, RadioBackend, RadioError, exchange, open_backend};
+
+///
+/// Putting a packet on the air takes a millisecond or two of spi traffic, more with
+/// repeats, and none of that should hold up the director. So the radio backend runs
+/// on a thread of its own, fed through a queue: sending only hands the packet over.
+/// When the queue backs up, an Off or a Reset goes out ahead of show packets, dropping
+/// the waiting ones it would undo anyway, and an Off already waiting for the same
+/// recipients isn't queued twice. Other control packets keep their place among show
+/// packets, since a SetGroup changes who the show packets around it reach. Requests
+/// that wait for a receiver's answer, like link polls, are heard out on the thread too
+///
+
+type Request = Box<dyn FnOnce(&mut dyn RadioBackend) + Send>;
+
+enum TxMessage {
+    /// a marshalled packet to put on the air
+    Send(Vec<u8>),
+    /// hold the next packet of each kind back until the gap has passed since the last
+    Pause(Duration),
+    /// something to do with the backend once everything queued before it has gone out
+    Request(Request)
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+enum Priority {
+    Show,
+    /// control packets can change who show packets reach, so they go out in order with them
+    Control,
+    /// an Off or a Reset, which leaves nothing of show packets to the same recipients
+    Clearing
+}
+
+struct Queued {
+    marshalled: Vec<u8>,
+    priority: Priority,
+    targets: Vec<u8>,
+    /// how long after the last packet of its kind it may go out
+    gap: Duration,
+    /// for show and control packets, the order they were queued in. for clearing packets,
+    /// the last packet that must go out first (a show packet it might or might not undo,
+    /// or a control packet), or 0
+    seq: u64
+}
+
+enum Next {
+    Send(Queued),
+    Wait(Instant),
+    Idle
+}
+
+/// a radio backend driven from a thread of its own, see above
+pub struct TxQueue {
+    tx: Option<Sender<TxMessage>>,
+    thread: Option<JoinHandle<()>>,
+    /// the last failure the thread had sending, for the next caller to hear about
+    failure: Arc<Mutex<Option<RadioError>>>
+}
+
+impl TxQueue {
+    /// start a thread sending through the backend
+    pub fn start(backend: Box<dyn RadioBackend>, header_mode: HeaderMode) -> Result<TxQueue, RadioError> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let failure = Arc::new(Mutex::new(None));
+        let sender = TxThread {
+            backend,
+            header_mode,
+            priority: VecDeque::new(),
+            show: VecDeque::new(),
+            requests: VecDeque::new(),
+            gaps: (Duration::ZERO, Duration::ZERO),
+            last_priority: Instant::now(),
+            last_show: Instant::now(),
+            next_seq: 1,
+            failure: failure.clone()
+        };
+        let thread = thread::Builder::new().name("radio-tx".to_string()).spawn(move || sender.run(rx))?;
+        Ok(TxQueue { tx: Some(tx), thread: Some(thread), failure })
+    }
+
+    fn post(self: &Self, message: TxMessage) -> Result<(), RadioError> {
+        if let Some(e) = self.failure.lock().unwrap().take() {
+            return Err(e)
+        }
+        self.tx.as_ref().and_then(|tx| tx.send(message).ok()).ok_or_else(stopped)
+    }
+
+    /// run something with the backend once the queue has emptied, waiting for the outcome
+    fn ask<T, F>(self: &Self, f: F) -> Result<T, RadioError>
+    where T: Send + 'static, F: FnOnce(&mut dyn RadioBackend) -> T + Send + 'static {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        self.post(TxMessage::Request(Box::new(move |backend| { let _ = reply_tx.send(f(backend)); })))?;
+        reply_rx.recv().map_err(|_| stopped())
+    }
+}
+
+impl RadioBackend for TxQueue {
+    fn init(config: &ConfigFile) -> Result<TxQueue, RadioError> {
+        TxQueue::start(open_backend(config)?, config.header_mode.unwrap_or_default())
+    }
+
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError> {
+        self.post(TxMessage::Send(marshalled.to_vec()))
+    }
+
+    fn pause(&mut self, gap: Duration) {
+        let _ = self.post(TxMessage::Pause(gap));
+    }
+
+    fn set_power(&mut self, power: i8) -> Result<(), RadioError> {
+        self.ask(move |backend| backend.set_power(power))?
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        self.ask(move |backend| backend.recv(timeout))?
+    }
+
+    fn rssi(&mut self) -> Option<f32> {
+        self.ask(|backend| backend.rssi()).unwrap_or(None)
+    }
+
+    fn take_sent(&mut self) -> Vec<(Instant, Vec<u8>)> {
+        self.ask(|backend| backend.take_sent()).unwrap_or_default()
+    }
+
+    fn exchange(&mut self, marshalled: Option<Vec<u8>>, timeout: Duration, listener: Box<dyn Listener>) -> Result<(), RadioError> {
+        self.post(TxMessage::Request(Box::new(move |backend| {
+            if let Err(e) = exchange(backend, marshalled, timeout, listener) {
+                error!("Radio thread could not exchange packets, error: {}", e);
+            }
+        })))
+    }
+}
+
+impl Drop for TxQueue {
+    /// let the thread send whatever is still queued (eg the Off at shutdown) before going
+    fn drop(&mut self) {
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stopped() -> RadioError {
+    RadioError::SpiError(std::io::Error::new(ErrorKind::BrokenPipe, "The radio thread has stopped"))
+}
+
+struct TxThread {
+    backend: Box<dyn RadioBackend>,
+    header_mode: HeaderMode,
+    /// clearing packets, and show and control packets, each in the order queued
+    priority: VecDeque<Queued>,
+    show: VecDeque<Queued>,
+    requests: VecDeque<Request>,
+    /// the gap asked for before the next clearing and show (or control) packet
+    gaps: (Duration, Duration),
+    last_priority: Instant,
+    last_show: Instant,
+    next_seq: u64,
+    failure: Arc<Mutex<Option<RadioError>>>
+}
+
+impl TxThread {
+    fn run(mut self: Self, rx: Receiver<TxMessage>) {
+        loop {
+            // take in everything waiting, so priorities are decided over the whole backlog
+            for message in rx.try_iter() {
+                self.accept(message);
+            }
+            match self.next() {
+                Next::Send(queued) => self.transmit(queued),
+                Next::Wait(until) => match rx.recv_timeout(until.saturating_duration_since(Instant::now())) {
+                    Ok(message) => self.accept(message),
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => thread::sleep(until.saturating_duration_since(Instant::now()))
+                },
+                Next::Idle => match self.requests.pop_front() {
+                    Some(request) => request(self.backend.as_mut()),
+                    None => match rx.recv() {
+                        Ok(message) => self.accept(message),
+                        Err(_) => break
+                    }
+                }
+            }
+        }
+    }
+
+    fn accept(self: &mut Self, message: TxMessage) {
+        match message {
+            TxMessage::Send(marshalled) => self.enqueue(marshalled),
+            TxMessage::Pause(gap) => self.gaps = (gap, gap),
+            TxMessage::Request(request) => self.requests.push_back(request)
+        }
+    }
+
+    fn enqueue(self: &mut Self, marshalled: Vec<u8>) {
+        let decoded = DecodedPacket::unmarshal(&marshalled, self.header_mode).ok();
+        let priority = match decoded.as_ref().map(|d| d.payload) {
+            Some(PacketPayload::Show(show)) if show.effect == EffectId::Off => Priority::Clearing,
+            Some(PacketPayload::Control(Command::Reset)) => Priority::Clearing,
+            Some(PacketPayload::Control(_)) => Priority::Control,
+            _ => Priority::Show
+        };
+        let targets = decoded.map_or(vec![], |d| if d.to == 0xFF { d.recipients } else { vec![d.to] });
+        let mut queued = Queued { marshalled, priority, targets, gap: Duration::ZERO, seq: 0 };
+
+        if priority != Priority::Clearing {
+            queued.gap = std::mem::take(&mut self.gaps.1);
+            queued.seq = self.next_seq;
+            self.next_seq += 1;
+            self.show.push_back(queued);
+            return
+        }
+        queued.gap = std::mem::take(&mut self.gaps.0);
+        let before = self.show.len();
+        self.show.retain(|s| s.priority == Priority::Control || !covers(&queued.targets, &s.targets));
+        if self.show.len() < before {
+            debug!("Dropped {} queued show packets to {:?} for a clearing packet", before - self.show.len(), queued.targets);
+        }
+        // a show packet that might reach the same receivers (eg through a group) has to go
+        // first, as does any control packet, which might change what reaches them
+        queued.seq = self.show.iter().rev()
+            .find(|s| s.priority == Priority::Control || targets_overlap(&queued.targets, &s.targets))
+            .map_or(0, |s| s.seq);
+        let header_len = self.header_mode.header_len();
+        if self.priority.iter().any(|p| p.seq == queued.seq &&
+                p.marshalled[1] == queued.marshalled[1] && p.marshalled[header_len..] == queued.marshalled[header_len..]) {
+            debug!("Coalesced a clearing packet to {:?} with one already queued", queued.targets);
+            return
+        }
+        self.priority.push_back(queued);
+    }
+
+    /// the packet to send now, or when one can be
+    fn next(self: &mut Self) -> Next {
+        let now = Instant::now();
+        let mut wait: Option<Instant> = None;
+        if let Some(front) = self.priority.front() {
+            let blocked = front.seq > 0 && self.show.front().is_some_and(|s| s.seq <= front.seq);
+            let due = self.last_priority + front.gap;
+            if !blocked && due <= now {
+                return Next::Send(self.priority.pop_front().unwrap())
+            } else if !blocked {
+                wait = Some(due);
+            }
+        }
+        if let Some(front) = self.show.front() {
+            let due = self.last_show + front.gap;
+            if due <= now {
+                return Next::Send(self.show.pop_front().unwrap())
+            }
+            wait = Some(wait.map_or(due, |w| w.min(due)));
+        }
+        wait.map_or(Next::Idle, Next::Wait)
+    }
+
+    fn transmit(self: &mut Self, queued: Queued) {
+        if let Err(e) = self.backend.send(&queued.marshalled) {
+            error!("Radio failed sending a queued packet: {}", e);
+            self.failure.lock().unwrap().replace(e);
+        }
+        if queued.priority == Priority::Clearing {
+            self.last_priority = Instant::now();
+        } else {
+            self.last_show = Instant::now();
+        }
+    }
+}
+
+/// whether a packet to the first targets reaches every receiver a packet to the second does
+fn covers(clearing: &[u8], show: &[u8]) -> bool {
+    clearing.is_empty() || (!show.is_empty() && show.iter().all(|t| clearing.contains(t)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{Packet, ShowPacket};
+    use crate::radio::MockRadio;
+    use crate::show::Color;
+
+    fn tx_thread() -> TxThread {
+        TxThread {
+            backend: Box::new(MockRadio::default()),
+            header_mode: HeaderMode::default(),
+            priority: VecDeque::new(),
+            show: VecDeque::new(),
+            requests: VecDeque::new(),
+            gaps: (Duration::ZERO, Duration::ZERO),
+            last_priority: Instant::now(),
+            last_show: Instant::now(),
+            next_seq: 1,
+            failure: Arc::new(Mutex::new(None))
+        }
+    }
+
+    fn marshal(payload: PacketPayload, to: &[u8]) -> Vec<u8> {
+        Packet { recipients: &to.to_vec(), payload }.marshal(HeaderMode::default(), 1, 0, 0)
+    }
+
+    fn effect(effect: EffectId, to: &[u8]) -> TxMessage {
+        let color = Color { h: 0, s: 255, v: 255 };
+        TxMessage::Send(marshal(PacketPayload::Show(ShowPacket { effect, color, attack: 0, sustain: 0, release: 0,
+            param1: 0, param2: 0, tempo: 120 }), to))
+    }
+
+    fn control(command: Command, to: &[u8]) -> TxMessage {
+        TxMessage::Send(marshal(PacketPayload::Control(command), to))
+    }
+
+    /// take in everything at once, as a backed up queue would, then send it all,
+    /// describing what went out in order, eg "pop>1", "off>2,3", "reset>all"
+    fn send_backlog(messages: Vec<TxMessage>) -> Vec<String> {
+        let mut thread = tx_thread();
+        for message in messages {
+            thread.accept(message);
+        }
+        while let Next::Send(queued) = thread.next() {
+            thread.transmit(queued);
+        }
+        thread.backend.take_sent().into_iter().map(|(_, buf)| {
+            let packet = DecodedPacket::unmarshal(&buf, HeaderMode::default()).unwrap();
+            let name = match packet.payload {
+                PacketPayload::Show(show) => format!("{:?}", show.effect),
+                PacketPayload::Control(command) => format!("{:?}", command).split([' ', '{']).next().unwrap().to_string()
+            };
+            let to = if packet.to != 0xFF { vec![packet.to] } else { packet.recipients };
+            let to = if to.is_empty() { "all".to_string() } else { to.iter().map(u8::to_string).collect::<Vec<_>>().join(",") };
+            format!("{}>{}", name.to_lowercase(), to)
+        }).collect()
+    }
+
+    #[test]
+    fn clearing_packets_go_ahead_dropping_what_they_undo() {
+        assert_eq!(send_backlog(vec![effect(EffectId::Pop, &[1]), effect(EffectId::Chase, &[2]), effect(EffectId::Off, &[1])]),
+            vec!["off>1", "chase>2"]);
+        assert_eq!(send_backlog(vec![effect(EffectId::Pop, &[1]), effect(EffectId::Pop, &[2]), control(Command::Reset, &[])]),
+            vec!["reset>all"]);
+    }
+
+    #[test]
+    fn clearing_packets_wait_for_show_packets_that_might_overlap() {
+        // a group might hold receiver 1, so the pop goes first, and isn't dropped
+        assert_eq!(send_backlog(vec![effect(EffectId::Pop, &[10]), effect(EffectId::Chase, &[2]), effect(EffectId::Off, &[1])]),
+            vec!["pop>10", "off>1", "chase>2"]);
+    }
+
+    #[test]
+    fn control_packets_keep_their_place_among_show_packets() {
+        assert_eq!(send_backlog(vec![effect(EffectId::Pop, &[10]), control(Command::SetGroup { group_id: 10 }, &[3]),
+            effect(EffectId::Pop, &[10])]),
+            vec!["pop>10", "setgroup>3", "pop>10"]);
+        // nor does a clearing packet go ahead of one, though it drops the show packets around it
+        assert_eq!(send_backlog(vec![effect(EffectId::Pop, &[10]), control(Command::SetGroup { group_id: 10 }, &[3]),
+            effect(EffectId::Pop, &[10]), control(Command::Reset, &[])]),
+            vec!["setgroup>3", "reset>all"]);
+    }
+
+    #[test]
+    fn identical_clearing_packets_are_sent_once() {
+        assert_eq!(send_backlog(vec![effect(EffectId::Off, &[1, 2]), effect(EffectId::Pop, &[1, 2]), effect(EffectId::Off, &[1, 2])]),
+            vec!["off>1,2"]);
+        // unless a packet has to go out between them
+        assert_eq!(send_backlog(vec![effect(EffectId::Off, &[1]), effect(EffectId::Pop, &[10]), effect(EffectId::Off, &[1])]),
+            vec!["off>1", "pop>10", "off>1"]);
+    }
+
+    /// a backend whose every send fails
+    struct FailingRadio;
+
+    impl RadioBackend for FailingRadio {
+        fn init(_config: &ConfigFile) -> Result<FailingRadio, RadioError> {
+            Ok(FailingRadio)
+        }
+
+        fn send(&mut self, _marshalled: &[u8]) -> Result<(), RadioError> {
+            Err(RadioError::SpiError(std::io::Error::other("radio fell over")))
+        }
+    }
+
+    #[test]
+    fn send_failures_are_reported_to_the_next_caller_once() {
+        let mut queue = TxQueue::start(Box::new(FailingRadio), HeaderMode::default()).unwrap();
+        let TxMessage::Send(packet) = effect(EffectId::Pop, &[1]) else { unreachable!() };
+        assert!(queue.send(&packet).is_ok());
+        // requests wait for the queue to empty, so by the time one is answered the
+        // send has failed, and either it or the next call hears about it
+        let failure = match queue.recv(Duration::ZERO) {
+            Err(e) => e,
+            Ok(_) => queue.send(&packet).unwrap_err()
+        };
+        assert!(format!("{:?}", failure).contains("radio fell over"));
+        assert!(queue.recv(Duration::ZERO).is_ok());
+    }
+
+    /// a backend that takes its time to hear anything
+    struct SlowRadio;
+
+    impl RadioBackend for SlowRadio {
+        fn init(_config: &ConfigFile) -> Result<SlowRadio, RadioError> {
+            Ok(SlowRadio)
+        }
+
+        fn send(&mut self, _marshalled: &[u8]) -> Result<(), RadioError> {
+            Ok(())
+        }
+
+        fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+            thread::sleep(timeout);
+            Ok(Some(vec![1, 2, 3]))
+        }
+    }
+
+    /// passes on what it heard once it's done
+    struct Collect {
+        heard: Vec<Vec<u8>>,
+        done: Sender<Vec<Vec<u8>>>
+    }
+
+    impl Listener for Collect {
+        fn heard(&mut self, buf: &[u8], _rssi: Option<f32>) -> bool {
+            self.heard.push(buf.to_vec());
+            true
+        }
+
+        fn finish(self: Box<Self>) {
+            let _ = self.done.send(self.heard);
+        }
+    }
+
+    #[test]
+    fn exchanges_are_heard_out_on_the_thread() {
+        let mut queue = TxQueue::start(Box::new(SlowRadio), HeaderMode::default()).unwrap();
+        let (done_tx, done_rx) = crossbeam_channel::bounded(1);
+        let TxMessage::Send(packet) = control(Command::Poll, &[1]) else { unreachable!() };
+        queue.exchange(Some(packet), Duration::from_millis(200), Box::new(Collect { heard: vec![], done: done_tx })).unwrap();
+        // the caller is long gone by the time anything is heard
+        assert!(done_rx.try_recv().is_err());
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap(), vec![vec![1, 2, 3]]);
+    }
+}
@@ -0,0 +1,65 @@
+use log::info;
+use serde::Deserialize;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::config::ConfigFile;
+use crate::packet::MAX_PACKET_LEN;
+use crate::radio::{RadioBackend, RadioError};
+
+///
+/// A stand-in for the radio that sends each marshalled packet (length byte and all) as
+/// a UDP datagram, so the whole transmitter can run on a laptop and drive a software
+/// receiver simulator. Datagrams sent back to the bound address (acks, poll replies)
+/// are received as the radio would receive them
+///
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:6969";
+const DEFAULT_BIND: &str = "0.0.0.0:0";
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct UdpConfig {
+    /// where to send packets, eg "192.168.1.255:6969" to broadcast to a subnet.
+    /// defaults to port 6969 on localhost
+    pub address: Option<String>,
+    /// the local address to send from and receive replies on, defaults to any free port
+    pub bind: Option<String>
+}
+
+pub struct UdpBackend {
+    socket: UdpSocket,
+    destination: SocketAddr
+}
+
+impl RadioBackend for UdpBackend {
+    fn init(config: &ConfigFile) -> Result<UdpBackend, RadioError> {
+        let udp = config.udp.clone().unwrap_or_default();
+        let destination = udp.address.as_deref().unwrap_or(DEFAULT_ADDRESS).to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "The udp address did not resolve"))?;
+        let socket = UdpSocket::bind(udp.bind.as_deref().unwrap_or(DEFAULT_BIND))?;
+        socket.set_broadcast(true)?;
+        info!("Sending packets over UDP to {} from {}", destination, socket.local_addr()?);
+        Ok(UdpBackend { socket, destination })
+    }
+
+    fn send(&mut self, marshalled: &[u8]) -> Result<(), RadioError> {
+        self.socket.send_to(marshalled, self.destination)?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>, RadioError> {
+        // a zero timeout would block forever
+        self.socket.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let mut buf = vec![0u8; MAX_PACKET_LEN + 1];
+        match self.socket.recv_from(&mut buf) {
+            Ok((len, _)) => {
+                buf.truncate(len);
+                Ok(Some(buf))
+            },
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(e.into())
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::path::Path;
+use anyhow::{Context, Result, anyhow};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::check;
+use crate::config::ConfigFile;
+use crate::radio::Radio;
+use crate::show::ShowDefinition;
+use crate::showfile;
+use crate::showstate::ShowState;
+
+///
+/// Validation runs a show through everything loading it on the transmitter would,
+/// against a radio that sends nothing, so a broken show turns up on a laptop rather
+/// than on the Pi at the stadium: parsing (and expanding) the file, building the show
+/// state (songs, notes, colors, targets, receiver ids), setting up what changes as it
+/// runs, then its checks (assertions and a dry run of every clip). Each stage that
+/// can't run because an earlier one failed is reported as skipped
+///
+
+#[derive(Debug,Clone,Copy,ValueEnum)]
+pub enum ReportFormat {
+    /// a line per stage, with its problems beneath it
+    Text,
+    /// the report as JSON
+    Json
+}
+
+#[derive(Serialize)]
+pub struct ValidationReport {
+    pub show_file: String,
+    pub valid: bool,
+    pub stages: Vec<StageReport>
+}
+
+#[derive(Serialize)]
+pub struct StageReport {
+    pub stage: &'static str,
+    /// false when an earlier stage failed
+    pub ran: bool,
+    pub problems: Vec<String>
+}
+
+impl ValidationReport {
+
+    fn stage(self: &mut Self, stage: &'static str, problems: Option<Vec<String>>) {
+        self.valid &= problems.as_ref().is_some_and(|p| p.is_empty());
+        self.stages.push(StageReport { stage, ran: problems.is_some(), problems: problems.unwrap_or_default() });
+    }
+
+    pub fn render(self: &Self, format: ReportFormat) -> String {
+        match format {
+            ReportFormat::Json => serde_json::to_string_pretty(self).unwrap() + "\n",
+            ReportFormat::Text => {
+                let mut text = format!("show: {}\n", self.show_file);
+                for stage in self.stages.iter() {
+                    let outcome = match (stage.ran, stage.problems.is_empty()) {
+                        (false, _) => "skipped",
+                        (true, true) => "ok",
+                        (true, false) => "FAILED"
+                    };
+                    text += &format!("  {:<14} {}\n", stage.stage, outcome);
+                    for problem in stage.problems.iter() {
+                        text += &format!("    {}\n", problem);
+                    }
+                }
+                text += if self.valid { "show is valid\n" } else { "show is not valid\n" };
+                text
+            }
+        }
+    }
+}
+
+/// run the show file through every stage of loading it, reporting the problems of each
+pub fn validate(show_file: &str, config: &ConfigFile) -> ValidationReport {
+    validate_show(show_file, config).0
+}
+
+/// validate the show file, returning the show it loads as well as the report, so the
+/// show validated is the show used even if the file changes again meanwhile
+pub fn validate_show(show_file: &str, config: &ConfigFile) -> (ValidationReport, Option<ShowDefinition>) {
+    let mut report = ValidationReport { show_file: show_file.to_string(), valid: true, stages: vec![] };
+    let problem = |e: anyhow::Error| vec![format!("{:#}", e)];
+
+    let show = match showfile::load(Path::new(show_file)).context("Could not load show") {
+        Ok(show) => show,
+        Err(e) => {
+            report.stage("parse", Some(problem(e)));
+            for stage in ["structure", "mutable state", "checks"] {
+                report.stage(stage, None);
+            }
+            return (report, None)
+        }
+    };
+    report.stage("parse", Some(vec![]));
+
+    let radio = Radio::mock(config);
+    let state = ShowState::new(&show, &radio, config);
+    let (structure, mutable_state) = match &state {
+        Ok(state) => (vec![], Some(state.create_mutable_state().map(|_| vec![]).unwrap_or_else(problem))),
+        Err(e) => (vec![format!("{:#}", e)], None)
+    };
+    // clips can only be dry run against a show state that built
+    let simulate_clips = mutable_state.as_ref().is_some_and(|p| p.is_empty());
+    report.stage("structure", Some(structure));
+    report.stage("mutable state", mutable_state);
+
+    // the checks resolve targets as the show state does, so they wait on it
+    let checks = state.is_ok().then(|| check::find_problems(&show, config, simulate_clips).unwrap_or_else(problem));
+    report.stage("checks", checks);
+    (report, Some(show))
+}
+
+/// validate the show file and print the report, failing if the show isn't valid
+pub fn run(config: &ConfigFile, format: ReportFormat) -> Result<()> {
+    let report = validate(&config.show_file, config);
+    print!("{}", report.render(format));
+    if report.valid {
+        Ok(())
+    } else {
+        Err(anyhow!("Show {} is not valid", config.show_file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn a_bad_target_fails_the_structure_and_skips_what_depends_on_it() {
+        let show_path = std::env::temp_dir().join(format!("chs-validate-{}.json", std::process::id()));
+        std::fs::write(&show_path, json!({
+            "colors": { "red": { "h": 0, "s": 255, "v": 255 } },
+            "receivers": [ { "id": 1, "name": "left", "led_count": 30 } ],
+            "mappings": [ { "cue": "pop", "midi": { "Note": { "channel": 0, "note": "C4" } },
+                "light": { "Effect": "Pop" }, "color": "red", "targets": [ "nowhere" ] } ]
+        }).to_string()).unwrap();
+        let config: ConfigFile = serde_json::from_value(json!({
+            "spi_device": "/dev/null", "gpio_device": "/dev/null", "reset_line": 0, "frequency": 915,
+            "transmitter_id": 1, "transmitter_power": 0, "midi_client_name": "test", "midi_control_channel": 15,
+            "show_file": show_path.to_string_lossy(), "lights_out_window_open": 5.0, "lights_out_window_close": 60.0,
+            "lights_out_period": 2.0
+        })).unwrap();
+        let report = validate(&config.show_file, &config);
+        std::fs::remove_file(&show_path).unwrap();
+
+        assert!(!report.valid);
+        let outcomes: Vec<(&str, bool, usize)> = report.stages.iter().map(|s| (s.stage, s.ran, s.problems.len())).collect();
+        assert_eq!(outcomes, vec![("parse", true, 0), ("structure", true, 1), ("mutable state", false, 0), ("checks", false, 0)]);
+        assert!(report.stages[1].problems[0].contains("nowhere"), "{:?}", report.stages[1].problems);
+        let text = report.render(ReportFormat::Text);
+        assert!(text.contains("structure      FAILED") && text.contains("checks         skipped"), "{}", text);
+    }
+}
@@ -0,0 +1,215 @@
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, unbounded};
+use log::{debug, info, warn, error};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::config::ConfigFile;
+use crate::director::DirectorMessage;
+use crate::session::SessionLog;
+use crate::show::ShowDefinition;
+use crate::showfile;
+use crate::validate::{self, ReportFormat};
+
+///
+/// Watching the show file reloads the show whenever it (or a file it includes) changes,
+/// as SIGHUP would, so a show edited on a laptop and copied over goes live without
+/// anyone logging in to the transmitter. Editors save in several steps, so a reload
+/// waits for the files to settle, and the changed show is validated first: one that
+/// doesn't load leaves the current show running, and the next save is tried again.
+/// The show that validated is handed to the director as it is, so a save landing after
+/// validation waits for a reload of its own rather than going live unchecked. The
+/// directories holding the files are watched rather than the files themselves, since
+/// many editors save by replacing the file
+///
+
+/// how long the show's files have to go unchanged before the show is reloaded
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// the files a show is loaded from, and the directories watched for them
+#[derive(Default)]
+struct ShowFiles {
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>
+}
+
+impl ShowFiles {
+
+    /// the show file and everything it includes, as it loads now
+    fn find(show_path: &Path) -> ShowFiles {
+        match showfile::load(show_path) {
+            Ok(show) => ShowFiles::of(show_path, Some(&show)),
+            Err(e) => {
+                warn!("Could not load the show to find what it includes, only watching {}. Error: {:#}", show_path.display(), e);
+                ShowFiles::of(show_path, None)
+            }
+        }
+    }
+
+    /// the show file and everything the show loaded from it includes
+    fn of(show_path: &Path, show: Option<&ShowDefinition>) -> ShowFiles {
+        let mut files = vec![show_path.to_path_buf()];
+        files.extend(show.and_then(|show| show.include.clone()).unwrap_or_default().into_iter().map(PathBuf::from));
+        // events name files by the path of the watched directory they're in
+        let files: Vec<PathBuf> = files.iter()
+            .filter_map(|file| Some(file.parent()?.canonicalize().ok()?.join(file.file_name()?)))
+            .collect();
+        let mut dirs: Vec<PathBuf> = files.iter().filter_map(|file| file.parent().map(Path::to_path_buf)).collect();
+        dirs.sort();
+        dirs.dedup();
+        ShowFiles { files, dirs }
+    }
+
+    fn changed_by(self: &Self, event: &Event) -> bool {
+        !event.kind.is_access() && event.paths.iter().any(|path| self.files.contains(path))
+    }
+
+    /// wait until the show's files have gone unchanged for the settle time. other files
+    /// in the same directories (logs, recordings) can change all they like meanwhile.
+    /// false if the watcher went away
+    fn settle(self: &Self, events: &Receiver<notify::Result<Event>>) -> bool {
+        let mut settled_at = Instant::now() + SETTLE_TIME;
+        loop {
+            match events.recv_deadline(settled_at) {
+                Ok(Ok(event)) if self.changed_by(&event) => settled_at = Instant::now() + SETTLE_TIME,
+                Ok(_) => {},
+                Err(RecvTimeoutError::Timeout) => return true,
+                Err(RecvTimeoutError::Disconnected) => return false
+            }
+        }
+    }
+
+    /// stop watching directories no longer needed, and start on those newly needed
+    fn watch(self: &Self, watcher: &mut RecommendedWatcher, previous: &ShowFiles) -> Result<()> {
+        for dir in previous.dirs.iter().filter(|dir| !self.dirs.contains(dir)) {
+            let _ = watcher.unwatch(dir);
+        }
+        for dir in self.dirs.iter().filter(|dir| !previous.dirs.contains(dir)) {
+            watcher.watch(dir, RecursiveMode::NonRecursive).with_context(|| format!("Could not watch {}", dir.display()))?;
+        }
+        Ok(())
+    }
+}
+
+/// watch the show's files, telling the director to reload when a change to them
+/// validates. the config is the watcher's own, to validate against
+pub fn start(config: ConfigFile, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<()> {
+    let show_path = PathBuf::from(&config.show_file);
+    let (event_tx, event_rx) = unbounded();
+    let mut watcher = notify::recommended_watcher(event_tx).context("Could not start watching the show file")?;
+    let mut show_files = ShowFiles::find(&show_path);
+    show_files.watch(&mut watcher, &ShowFiles::default())?;
+    info!("Watching {} file(s) of the show for changes", show_files.files.len());
+
+    thread::spawn(move || loop {
+        match event_rx.recv() {
+            Ok(Ok(event)) if show_files.changed_by(&event) => debug!("Show file changed: {:?}", event),
+            Ok(Ok(_)) => continue,
+            Ok(Err(e)) => {
+                warn!("Error watching the show file: {}", e);
+                continue
+            },
+            Err(_) => return
+        }
+        // wait for the editor to finish saving
+        if !show_files.settle(&event_rx) {
+            return
+        }
+
+        let show = match validate::validate_show(&config.show_file, &config) {
+            (report, Some(show)) if report.valid => show,
+            (report, _) => {
+                error!("Show file changed, but the new show is not valid, so the current show keeps running:\n{}",
+                    report.render(ReportFormat::Text));
+                continue
+            }
+        };
+        // the show may include different files now
+        let changed_files = ShowFiles::of(&show_path, Some(&show));
+        if let Err(e) = changed_files.watch(&mut watcher, &show_files) {
+            warn!("Could not watch every file of the show. Error: {:#}", e);
+        }
+        show_files = changed_files;
+
+        info!("Show file changed, reloading the show");
+        session_log.record("watch", "reload", &config.show_file);
+        if tx.send(DirectorMessage::LoadShow(Box::new(show))).is_err() {
+            return
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use notify::EventKind;
+    use notify::event::{AccessKind, ModifyKind};
+
+    fn event(kind: EventKind, path: &Path) -> notify::Result<Event> {
+        Ok(Event::new(kind).add_path(path.to_path_buf()))
+    }
+
+    /// a directory holding a show that includes another file, and the show's files
+    fn show_dir(name: &str) -> (PathBuf, ShowFiles) {
+        let dir = std::env::temp_dir().join(format!("chs-watch-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let show = ShowDefinition {
+            include: Some(vec![dir.join("receivers.json").to_string_lossy().into_owned()]),
+            ..Default::default()
+        };
+        let files = ShowFiles::of(&dir.join("show.json"), Some(&show));
+        (dir, files)
+    }
+
+    #[test]
+    fn only_changes_to_the_shows_files_count() {
+        let (dir, files) = show_dir("changes");
+        let dir = dir.canonicalize().unwrap();
+        assert_eq!(files.dirs, vec![dir.clone()]);
+        let modify = EventKind::Modify(ModifyKind::Any);
+        assert!(files.changed_by(&event(modify, &dir.join("show.json")).unwrap()));
+        assert!(files.changed_by(&event(modify, &dir.join("receivers.json")).unwrap()));
+        assert!(!files.changed_by(&event(modify, &dir.join("session.csv")).unwrap()));
+        assert!(!files.changed_by(&event(EventKind::Access(AccessKind::Any), &dir.join("show.json")).unwrap()));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn other_files_changing_dont_hold_off_a_reload() {
+        let (dir, files) = show_dir("others");
+        let dir = dir.canonicalize().unwrap();
+        let (tx, rx) = unbounded();
+        let log = dir.join("session.csv");
+        thread::spawn(move || for _ in 0..40 {
+            if tx.send(event(EventKind::Modify(ModifyKind::Any), &log)).is_err() {
+                return
+            }
+            thread::sleep(Duration::from_millis(50));
+        });
+        let started = Instant::now();
+        assert!(files.settle(&rx));
+        assert!(started.elapsed() < Duration::from_millis(1500), "settling took {:?}", started.elapsed());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_shows_files_changing_hold_off_a_reload() {
+        let (dir, files) = show_dir("saves");
+        let dir = dir.canonicalize().unwrap();
+        let (tx, rx) = unbounded();
+        let show = dir.join("show.json");
+        thread::spawn(move || for _ in 0..10 {
+            let _ = tx.send(event(EventKind::Modify(ModifyKind::Any), &show));
+            thread::sleep(Duration::from_millis(100));
+        });
+        let started = Instant::now();
+        // the sender finishing closes the channel, once the files have settled
+        files.settle(&rx);
+        assert!(started.elapsed() >= Duration::from_millis(900), "settled after {:?}", started.elapsed());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use anyhow::{Context, Result, anyhow, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use crossbeam_channel::{Sender, Receiver, TrySendError, bounded};
+use log::{debug, info, warn, error};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::arbitration::ControlSource;
+use crate::control::{self, SocketCommand, SocketReply};
+use crate::director::DirectorMessage;
+use crate::session::SessionLog;
+use crate::showstate::{ShowEvent, ShowSnapshot};
+
+///
+/// The websocket server lets a browser (eg a tablet on the sideline) follow the show and
+/// drive it. Each client is sent a state event when it connects, and again whenever a
+/// show loads, with the cues showing, the clips playing and the cue each receiver is
+/// showing; then an event for each change: cues activated and deactivated, clips started
+/// and stopped, receivers changing cue, lights-out packets going out, and errors. Events are
+/// JSON text messages tagged by "event", eg {"event": "cue_activated", "cue": "intro"}.
+/// Clients send the control socket's commands, eg {"command": "fire", "cue": "intro"},
+/// and get its replies back, as messages with "ok" rather than "event". Anyone who can
+/// reach the port can drive the show, so it listens on this machine only unless bound
+/// to another address
+///
+
+const DEFAULT_PORT: u16 = 9001;
+const DEFAULT_BIND: &str = "127.0.0.1";
+/// appended to the key a client sends, to prove the server speaks websocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// the longest message accepted from a client
+const MAX_MESSAGE: u64 = 65536;
+/// messages waiting for a client, beyond which it's too slow to keep and is dropped
+const QUEUE_DEPTH: usize = 256;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug,Deserialize,Clone,Default)]
+pub struct WebSocketConfig {
+    /// the TCP port to listen on, defaults to 9001
+    pub port: Option<u16>,
+    /// the local address to listen on, defaults to 127.0.0.1 (this machine only)
+    pub bind: Option<String>
+}
+
+/// a frame on its way to a client
+enum Outgoing {
+    Text(String),
+    Pong(Vec<u8>),
+    Close
+}
+
+/// a connected client: its queue, and its stream to cut it off with if it falls behind
+struct Client {
+    tx: Sender<Outgoing>,
+    stream: TcpStream
+}
+
+#[derive(Default)]
+struct Clients {
+    connected: Vec<Client>,
+    /// the snapshot the clients were last told about, none until a show is running
+    last: Option<ShowSnapshot>
+}
+
+/// the director's handle on the websocket clients
+#[derive(Clone)]
+pub struct EventStream {
+    clients: Arc<Mutex<Clients>>
+}
+
+impl EventStream {
+
+    /// tell the clients what changed since the last snapshot, if anything did
+    pub fn publish(self: &Self, snapshot: ShowSnapshot) {
+        let mut clients = self.clients.lock().unwrap();
+        let events = match &clients.last {
+            Some(last) if *last == snapshot => return,
+            Some(last) => last.changes_to(&snapshot),
+            None => vec![snapshot.state_event()]
+        };
+        for event in events.iter() {
+            send_to_all(&mut clients.connected, event);
+        }
+        clients.last = Some(snapshot);
+    }
+
+    /// tell the clients the show hit an error
+    pub fn error(self: &Self, error: &str) {
+        send_to_all(&mut self.clients.lock().unwrap().connected, &ShowEvent::Error { error: error.to_string() });
+    }
+
+    /// a new show is loading, so the clients get its whole state once it's running
+    pub fn restart(self: &Self) {
+        self.clients.lock().unwrap().last = None;
+    }
+}
+
+/// queue an event for every client, dropping those gone or too far behind
+fn send_to_all(connected: &mut Vec<Client>, event: &ShowEvent) {
+    let text = serde_json::to_string(event).unwrap();
+    connected.retain(|client| match client.tx.try_send(Outgoing::Text(text.clone())) {
+        Ok(()) => true,
+        Err(TrySendError::Full(_)) => {
+            warn!("websocket client too slow to keep up, disconnecting it");
+            let _ = client.stream.shutdown(Shutdown::Both);
+            false
+        },
+        Err(TrySendError::Disconnected(_)) => false
+    });
+}
+
+/// listen for websocket clients, passing their commands to the director. the stream
+/// returned is for the director to publish what the show is doing
+pub fn start(config: &WebSocketConfig, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<EventStream> {
+    let bind = config.bind.as_deref().unwrap_or(DEFAULT_BIND);
+    let port = config.port.unwrap_or(DEFAULT_PORT);
+    let listener = TcpListener::bind((bind, port)).with_context(|| format!("Could not listen for websockets on {}:{}", bind, port))?;
+    info!("Listening for websockets on {}:{}", bind, port);
+    let stream = EventStream { clients: Arc::new(Mutex::new(Clients::default())) };
+    let clients = stream.clients.clone();
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(connection) => {
+                    let clients = clients.clone();
+                    let tx = tx.clone();
+                    let session_log = session_log.clone();
+                    thread::spawn(move || {
+                        let peer = connection.peer_addr().map_or("unknown".to_string(), |a| a.to_string());
+                        match serve(connection, clients, tx, session_log) {
+                            Ok(()) => info!("websocket client {} disconnected", peer),
+                            Err(e) => warn!("websocket connection from {} closed with error: {}", peer, e)
+                        }
+                    });
+                },
+                Err(e) => error!("Could not accept websocket connection: {}", e)
+            }
+        }
+    });
+    Ok(stream)
+}
+
+/// upgrade the connection, then follow its commands until it closes
+fn serve(connection: TcpStream, clients: Arc<Mutex<Clients>>, tx: Sender<DirectorMessage>, session_log: SessionLog) -> Result<()> {
+    let mut reader = FrameReader::new(BufReader::new(connection.try_clone()?));
+    handshake(&mut reader.reader, &connection)?;
+    info!("websocket client connected from {}", connection.peer_addr()?);
+
+    let (out_tx, out_rx) = bounded(QUEUE_DEPTH);
+    let writer = connection.try_clone()?;
+    thread::spawn(move || write_frames(writer, out_rx));
+    {
+        let mut clients = clients.lock().unwrap();
+        if let Some(last) = &clients.last {
+            out_tx.try_send(Outgoing::Text(serde_json::to_string(&last.state_event())?))?;
+        }
+        clients.connected.push(Client { tx: out_tx.clone(), stream: connection.try_clone()? });
+    }
+    let result = follow_commands(&mut reader, &out_tx, &tx, &session_log);
+    // however the client went, its writer closes the connection and stops taking events
+    let _ = out_tx.try_send(Outgoing::Close);
+    result
+}
+
+/// answer the client's commands until it closes the connection
+fn follow_commands<R: Read>(reader: &mut FrameReader<R>, out_tx: &Sender<Outgoing>, tx: &Sender<DirectorMessage>, session_log: &SessionLog) -> Result<()> {
+    loop {
+        let (op, payload) = reader.read_message()?;
+        match op {
+            OP_TEXT => {
+                let text = String::from_utf8(payload).map_err(|_| anyhow!("text message is not UTF-8"))?;
+                debug!("websocket command: {}", text);
+                let result = serde_json::from_str::<SocketCommand>(&text)
+                    .map_err(|e| anyhow!("Could not parse command: {}", e))
+                    .and_then(|command| control::execute(command, ControlSource::WebSocket, tx, session_log));
+                out_tx.send(Outgoing::Text(serde_json::to_string(&SocketReply::from(result))?))?;
+            },
+            OP_PING => out_tx.send(Outgoing::Pong(payload))?,
+            OP_PONG => {},
+            OP_CLOSE => return Ok(()),
+            _ => debug!("ignoring websocket message with opcode: {}", op)
+        }
+    }
+}
+
+/// answer the client's upgrade request, or turn it away if it isn't one
+fn handshake<R: BufRead, W: Write>(reader: &mut R, mut connection: W) -> Result<()> {
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut key: Option<String> = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            bail!("connection closed during the handshake")
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+    let Some(key) = key else {
+        connection.write_all(b"HTTP/1.1 426 Upgrade Required\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\n\r\n")?;
+        bail!("not a websocket request: {}", request.trim_end())
+    };
+    let accept = accept_key(&key);
+    write!(connection, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept)?;
+    Ok(())
+}
+
+/// the answer to a client's key that proves the server speaks websocket
+fn accept_key(key: &str) -> String {
+    BASE64.encode(Sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// send a client's frames until it's closed or its queue goes away
+fn write_frames(mut connection: TcpStream, rx: Receiver<Outgoing>) {
+    for outgoing in rx {
+        let result = match &outgoing {
+            Outgoing::Text(text) => write_frame(&mut connection, OP_TEXT, text.as_bytes()),
+            Outgoing::Pong(payload) => write_frame(&mut connection, OP_PONG, payload),
+            Outgoing::Close => write_frame(&mut connection, OP_CLOSE, &[])
+        };
+        if result.is_err() || matches!(outgoing, Outgoing::Close) {
+            break
+        }
+    }
+    let _ = connection.shutdown(Shutdown::Both);
+}
+
+/// server frames are never masked or fragmented
+fn write_frame(connection: &mut TcpStream, op: u8, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame = vec![0x80 | op];
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(payload);
+    connection.write_all(&frame)
+}
+
+/// reads a client's messages, putting fragmented ones back together
+struct FrameReader<R: Read> {
+    reader: R,
+    /// the fragments of a message so far, and its opcode
+    partial: Vec<u8>,
+    partial_op: Option<u8>
+}
+
+impl <R: Read> FrameReader<R> {
+
+    fn new(reader: R) -> FrameReader<R> {
+        FrameReader { reader, partial: vec![], partial_op: None }
+    }
+
+    /// the opcode and payload of the next message. control frames can arrive between
+    /// the fragments of a message, and are returned as they do
+    fn read_message(self: &mut Self) -> Result<(u8, Vec<u8>)> {
+        loop {
+            let mut header = [0u8; 2];
+            self.reader.read_exact(&mut header)?;
+            let fin = header[0] & 0x80 != 0;
+            let op = header[0] & 0x0F;
+            if header[1] & 0x80 == 0 {
+                bail!("client frame is not masked")
+            }
+            let len = match header[1] & 0x7F {
+                126 => {
+                    let mut len = [0u8; 2];
+                    self.reader.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as u64
+                },
+                127 => {
+                    let mut len = [0u8; 8];
+                    self.reader.read_exact(&mut len)?;
+                    u64::from_be_bytes(len)
+                },
+                len => len as u64
+            };
+            if self.partial.len() as u64 + len > MAX_MESSAGE {
+                bail!("message longer than {} bytes", MAX_MESSAGE)
+            }
+            let mut mask = [0u8; 4];
+            self.reader.read_exact(&mut mask)?;
+            let mut payload = vec![0u8; len as usize];
+            self.reader.read_exact(&mut payload)?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+            if op >= OP_CLOSE {
+                return Ok((op, payload))
+            }
+            if op != OP_CONTINUATION {
+                self.partial_op = Some(op);
+            } else if self.partial_op.is_none() {
+                bail!("continuation frame with no message to continue")
+            }
+            self.partial.extend(payload);
+            if fin {
+                return Ok((self.partial_op.take().unwrap(), std::mem::take(&mut self.partial)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a client frame: masked, as clients' frames must be
+    fn frame(fin: bool, op: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xFA, 0x21, 0x3D];
+        let mut frame = vec![if fin { 0x80 | op } else { op }];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xFFFF => {
+                frame.push(0x80 | 126);
+                frame.extend((len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend((len as u64).to_be_bytes());
+            }
+        }
+        frame.extend(mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    fn read_from(frames: &[Vec<u8>]) -> FrameReader<std::io::Cursor<Vec<u8>>> {
+        FrameReader::new(std::io::Cursor::new(frames.concat()))
+    }
+
+    #[test]
+    fn handshake_answers_the_rfc_sample_key() {
+        let request = "GET /chat HTTP/1.1\r\nHost: server.example.com\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let mut response = vec![];
+        handshake(&mut request.as_bytes(), &mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+    }
+
+    #[test]
+    fn handshake_turns_away_plain_http() {
+        let mut response = vec![];
+        assert!(handshake(&mut "GET / HTTP/1.1\r\nHost: x\r\n\r\n".as_bytes(), &mut response).is_err());
+        assert!(String::from_utf8(response).unwrap().starts_with("HTTP/1.1 426"));
+    }
+
+    #[test]
+    fn messages_are_unmasked_and_fragments_put_back_together() {
+        let long = "x".repeat(300);
+        let mut reader = read_from(&[
+            frame(true, OP_TEXT, b"hello"),
+            frame(false, OP_TEXT, b"{\"command\": "),
+            frame(false, OP_CONTINUATION, b"\"status\""),
+            frame(true, OP_CONTINUATION, b"}"),
+            frame(true, OP_TEXT, long.as_bytes())
+        ]);
+        assert_eq!(reader.read_message().unwrap(), (OP_TEXT, b"hello".to_vec()));
+        assert_eq!(reader.read_message().unwrap(), (OP_TEXT, b"{\"command\": \"status\"}".to_vec()));
+        assert_eq!(reader.read_message().unwrap(), (OP_TEXT, long.into_bytes()));
+    }
+
+    #[test]
+    fn control_frames_between_fragments_come_first() {
+        let mut reader = read_from(&[
+            frame(false, OP_TEXT, b"frag"),
+            frame(true, OP_PING, b"are you there"),
+            frame(true, OP_CONTINUATION, b"ment")
+        ]);
+        assert_eq!(reader.read_message().unwrap(), (OP_PING, b"are you there".to_vec()));
+        assert_eq!(reader.read_message().unwrap(), (OP_TEXT, b"fragment".to_vec()));
+    }
+
+    #[test]
+    fn over_long_and_unmasked_messages_are_refused() {
+        let half = vec![b'x'; MAX_MESSAGE as usize / 2 + 1];
+        let mut reader = read_from(&[frame(false, OP_TEXT, &half), frame(true, OP_CONTINUATION, &half)]);
+        assert!(reader.read_message().unwrap_err().to_string().contains("longer than"));
+
+        let mut reader = read_from(&[frame(true, OP_TEXT, &vec![b'x'; MAX_MESSAGE as usize + 1])]);
+        assert!(reader.read_message().is_err());
+
+        let mut unmasked = frame(true, OP_TEXT, b"hi");
+        unmasked[1] &= 0x7F;
+        assert!(read_from(&[unmasked]).read_message().unwrap_err().to_string().contains("not masked"));
+
+        let mut reader = read_from(&[frame(true, OP_CONTINUATION, b"stray")]);
+        assert!(reader.read_message().is_err());
+    }
+}